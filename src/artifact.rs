@@ -0,0 +1,603 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+use crate::train::TrainedRMI;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// A language neutral file holding a trained RMI: its layers and all of
+// their parameters, for programs that implement the lookup arithmetic
+// themselves instead of compiling the generated C++. ARTIFACT_SCHEMA
+// describes the format, and is written next to every artifact.
+// ArtifactRMI reads one back and evaluates it like RuntimeRMI.
+
+const MAGIC: &[u8; 4] = b"RMIA";
+const VERSION: u32 = 1;
+
+const TAG_HEADER: u8 = 1;
+const TAG_LAYER: u8 = 2;
+const TAG_ERRORS: u8 = 3;
+
+// the models whose arithmetic the schema describes, by the name of their
+// generated C function
+const SUPPORTED_MODELS: &[&str] = &["linear", "cubic", "radix", "connected_spline"];
+
+pub const ARTIFACT_SCHEMA: &str = "RMI artifact, version 1
+
+All integers are unsigned and little-endian, and floats are IEEE 754
+doubles, also little-endian.
+
+file    := magic:\"RMIA\" version:u32 record*
+record  := tag:u8 length:u64 value:byte[length]
+
+Readers skip records with unknown tags. The records are:
+
+tag 1, header (first, once):
+    num_positions:u64   lookups return a position below this
+    record_size:u64     0 for element positions, else positions are byte
+                        offsets of records of this size
+    num_layers:u32
+
+tag 2, layer (num_layers times, from the top layer down):
+    name_len:u8 name:byte[name_len]   the model type (see below)
+    num_models:u64
+    num_params:u8
+    then num_models times, num_params times:
+        kind:u8 value
+    where kind is
+        0  u64
+        1  f64
+        2  count:u64 u16[count]
+        3  count:u64 u64[count]
+        4  count:u64 u32[count]
+        5  count:u64 f64[count]
+
+tag 3, errors (optional): one u64 per model of the last layer, the
+    largest distance from the position a lookup returns to the position of
+    any key routed to that model (in bytes, for offsets)
+
+A lookup of key (a u64) starts at model 0 of the top layer. Each model
+computes a prediction from the key; for every layer but the last, the
+prediction is turned into the index of the model of the next layer to use,
+where n is the size of the next layer:
+
+    float predictions:   0 if below 0 (or NaN), else min(floor(p), n - 1)
+    integer predictions: min(p, n - 1)
+
+The last layer's prediction becomes the position in the same way, with
+n = num_positions, and is then rounded down to a multiple of record_size
+(if it is not 0). With errors, the key's position is within
+[pos - err, pos + err] of it, where err is the errors value of the last
+layer model used.
+
+Models, with x the key converted to a double, and fma(a, b, c) = a * b + c
+rounded once:
+
+    linear (params: alpha:f64 beta:f64), float prediction
+        fma(beta, x, alpha)
+    cubic (params: a:f64 b:f64 c:f64 d:f64), float prediction
+        fma(fma(fma(a, x, b), x, c), x, d)
+    radix (params: prefix_length:u64 bits:u64), integer prediction
+        0 if bits is 0, else (key << prefix_length) >> (64 - bits), on
+        64-bit integers
+    connected_spline (params: knots:f64[2k], (key, position) pairs sorted
+                      by key), float prediction
+        the first position if x <= the first key, the last position if
+        x >= the last key, else, for the last knot a with a.key <= x and
+        the knot b after it,
+        a.pos + (b.pos - a.pos) * ((x - a.key) / (b.key - a.key))
+";
+
+// why an artifact could not be read
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArtifactError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    Malformed(String),
+    UnsupportedModel(String)
+}
+
+impl fmt::Display for ArtifactError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            ArtifactError::BadMagic => write!(f, "not an RMI artifact"),
+            ArtifactError::UnsupportedVersion(v) => write!(f, "unsupported artifact version {}", v),
+            ArtifactError::Truncated => write!(f, "the artifact is truncated"),
+            ArtifactError::Malformed(why) => write!(f, "malformed artifact: {}", why),
+            ArtifactError::UnsupportedModel(name) =>
+                write!(f, "artifacts do not support {} models", name)
+        };
+    }
+}
+
+fn write_param<W: Write>(out: &mut W, param: &ModelParam) -> Result<(), std::io::Error> {
+    match param {
+        ModelParam::Int(v) => {
+            out.write_u8(0)?;
+            out.write_u64::<LittleEndian>(*v)?;
+        },
+        ModelParam::Float(v) => {
+            out.write_u8(1)?;
+            out.write_f64::<LittleEndian>(*v)?;
+        },
+        ModelParam::ShortArray(a) => {
+            out.write_u8(2)?;
+            out.write_u64::<LittleEndian>(a.len() as u64)?;
+            for v in a { out.write_u16::<LittleEndian>(*v)?; }
+        },
+        ModelParam::IntArray(a) => {
+            out.write_u8(3)?;
+            out.write_u64::<LittleEndian>(a.len() as u64)?;
+            for v in a { out.write_u64::<LittleEndian>(*v)?; }
+        },
+        ModelParam::Int32Array(a) => {
+            out.write_u8(4)?;
+            out.write_u64::<LittleEndian>(a.len() as u64)?;
+            for v in a { out.write_u32::<LittleEndian>(*v)?; }
+        },
+        ModelParam::FloatArray(a) => {
+            out.write_u8(5)?;
+            out.write_u64::<LittleEndian>(a.len() as u64)?;
+            for v in a { out.write_f64::<LittleEndian>(*v)?; }
+        }
+    };
+    return Ok(());
+}
+
+fn write_record<W: Write>(out: &mut W, tag: u8, value: &[u8]) -> Result<(), std::io::Error> {
+    out.write_u8(tag)?;
+    out.write_u64::<LittleEndian>(value.len() as u64)?;
+    return out.write_all(value);
+}
+
+// writes the trained RMI as an artifact (see ARTIFACT_SCHEMA), with the
+// errors of the last layer models if last_layer_errors is set. num_rows is
+// the number of positions, as for codegen. Only RMIs of linear, cubic,
+// radix, and connected spline models, without key transforms, merged or
+// pruned leaves, can be written; others are an InvalidInput error.
+pub fn write_artifact<W: Write>(out: &mut W, trained: &TrainedRMI, num_rows: usize,
+                                last_layer_errors: bool) -> Result<(), std::io::Error> {
+    let unsupported = |why: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, why);
+    if trained.key_transform.is_some() || trained.leaf_map.is_some()
+        || trained.pruned.is_some() {
+        return Err(unsupported(String::from(
+            "artifacts do not support key transforms, merged leaves, or pruned leaves")));
+    }
+
+    let record_size = match trained.position_unit {
+        PositionUnit::Element => 0,
+        PositionUnit::Offset(record_size) => record_size
+    };
+
+    out.write_all(MAGIC)?;
+    out.write_u32::<LittleEndian>(VERSION)?;
+
+    let mut header = Vec::new();
+    header.write_u64::<LittleEndian>(trained.num_lookup_positions(num_rows) as u64)?;
+    header.write_u64::<LittleEndian>(record_size)?;
+    header.write_u32::<LittleEndian>(trained.rmi.len() as u32)?;
+    write_record(out, TAG_HEADER, &header)?;
+
+    for layer in trained.rmi.iter() {
+        let name = layer[0].function_name();
+        if !SUPPORTED_MODELS.contains(&name.as_str()) {
+            return Err(unsupported(ArtifactError::UnsupportedModel(name).to_string()));
+        }
+
+        let mut value = Vec::new();
+        value.write_u8(name.len() as u8)?;
+        value.write_all(name.as_bytes())?;
+        value.write_u64::<LittleEndian>(layer.len() as u64)?;
+        value.write_u8(layer[0].params().len() as u8)?;
+        for model in layer.iter() {
+            assert_eq!(model.function_name(), name, "every model of a layer has the same type");
+            for param in model.params().iter() {
+                write_param(&mut value, param)?;
+            }
+        }
+        write_record(out, TAG_LAYER, &value)?;
+    }
+
+    if last_layer_errors {
+        let mut value = Vec::new();
+        for err in trained.last_layer_max_l1s.iter() {
+            value.write_u64::<LittleEndian>(trained.position_unit.align_error(*err))?;
+        }
+        write_record(out, TAG_ERRORS, &value)?;
+    }
+
+    return Ok(());
+}
+
+// writes namespace.rmia, and its schema as namespace.rmia.txt
+pub fn output_artifact(namespace: &str, trained: &TrainedRMI, num_rows: usize,
+                       last_layer_errors: bool) -> Result<(), std::io::Error> {
+    let f = File::create(format!("{}.rmia", namespace)).expect("Could not write RMI artifact");
+    let mut bw = BufWriter::new(f);
+    write_artifact(&mut bw, trained, num_rows, last_layer_errors)?;
+    bw.flush()?;
+
+    return std::fs::write(format!("{}.rmia.txt", namespace), ARTIFACT_SCHEMA);
+}
+
+fn truncated(_: std::io::Error) -> ArtifactError {
+    return ArtifactError::Truncated;
+}
+
+// the length of an array parameter, checked against the bytes left
+fn read_count(input: &mut &[u8], width: usize) -> Result<usize, ArtifactError> {
+    let count = input.read_u64::<LittleEndian>().map_err(truncated)?;
+    if count > (input.len() / width) as u64 {
+        return Err(ArtifactError::Truncated);
+    }
+    return Ok(count as usize);
+}
+
+fn read_param(input: &mut &[u8]) -> Result<ModelParam, ArtifactError> {
+    let param = match input.read_u8().map_err(truncated)? {
+        0 => ModelParam::Int(input.read_u64::<LittleEndian>().map_err(truncated)?),
+        1 => ModelParam::Float(input.read_f64::<LittleEndian>().map_err(truncated)?),
+        2 => ModelParam::ShortArray((0..read_count(input, 2)?)
+            .map(|_| input.read_u16::<LittleEndian>().map_err(truncated))
+            .collect::<Result<Vec<u16>, ArtifactError>>()?),
+        3 => ModelParam::IntArray((0..read_count(input, 8)?)
+            .map(|_| input.read_u64::<LittleEndian>().map_err(truncated))
+            .collect::<Result<Vec<u64>, ArtifactError>>()?),
+        4 => ModelParam::Int32Array((0..read_count(input, 4)?)
+            .map(|_| input.read_u32::<LittleEndian>().map_err(truncated))
+            .collect::<Result<Vec<u32>, ArtifactError>>()?),
+        5 => ModelParam::FloatArray((0..read_count(input, 8)?)
+            .map(|_| input.read_f64::<LittleEndian>().map_err(truncated))
+            .collect::<Result<Vec<f64>, ArtifactError>>()?),
+        kind => return Err(ArtifactError::Malformed(format!("unknown parameter kind {}", kind)))
+    };
+    return Ok(param);
+}
+
+// a model of an artifact, with its parameters checked against its type
+enum ArtifactModel {
+    Linear { alpha: f64, beta: f64 },
+    Cubic { a: f64, b: f64, c: f64, d: f64 },
+    Radix { prefix_length: u64, bits: u64 },
+    ConnectedSpline { knots: Vec<f64> }
+}
+
+// a float or integer prediction, which become indexes differently
+enum Prediction {
+    Float(f64),
+    Int(u64)
+}
+
+impl ArtifactModel {
+    fn new(name: &str, params: Vec<ModelParam>) -> Result<ArtifactModel, ArtifactError> {
+        let bad_params = || ArtifactError::Malformed(format!("bad parameters for {}", name));
+        let model = match (name, params.as_slice()) {
+            ("linear", [ModelParam::Float(alpha), ModelParam::Float(beta)]) =>
+                ArtifactModel::Linear { alpha: *alpha, beta: *beta },
+            ("cubic", [ModelParam::Float(a), ModelParam::Float(b), ModelParam::Float(c),
+                       ModelParam::Float(d)]) =>
+                ArtifactModel::Cubic { a: *a, b: *b, c: *c, d: *d },
+            ("radix", [ModelParam::Int(prefix_length), ModelParam::Int(bits)]) => {
+                if *bits > 64 || *prefix_length >= 64 {
+                    return Err(bad_params());
+                }
+                ArtifactModel::Radix { prefix_length: *prefix_length, bits: *bits }
+            },
+            ("connected_spline", [ModelParam::FloatArray(knots)]) => {
+                if knots.is_empty() || knots.len() % 2 != 0 {
+                    return Err(bad_params());
+                }
+                ArtifactModel::ConnectedSpline { knots: knots.clone() }
+            },
+            _ if SUPPORTED_MODELS.contains(&name) => return Err(bad_params()),
+            _ => return Err(ArtifactError::UnsupportedModel(String::from(name)))
+        };
+        return Ok(model);
+    }
+
+    fn predict(&self, key: u64) -> Prediction {
+        let x = key as f64;
+        return match self {
+            ArtifactModel::Linear { alpha, beta } => Prediction::Float(beta.mul_add(x, *alpha)),
+            ArtifactModel::Cubic { a, b, c, d } =>
+                Prediction::Float(a.mul_add(x, *b).mul_add(x, *c).mul_add(x, *d)),
+            ArtifactModel::Radix { prefix_length, bits } => Prediction::Int(if *bits == 0 {
+                0
+            } else {
+                (key << prefix_length) >> (64 - bits)
+            }),
+            ArtifactModel::ConnectedSpline { knots } => {
+                let last = knots.len() / 2 - 1;
+                Prediction::Float(if x <= knots[0] {
+                    knots[1]
+                } else if x >= knots[2 * last] {
+                    knots[2 * last + 1]
+                } else {
+                    // knots[2 * lo] <= x < knots[2 * hi]
+                    let (mut lo, mut hi) = (0, last);
+                    while hi - lo > 1 {
+                        let mid = (lo + hi) / 2;
+                        if knots[2 * mid] <= x { lo = mid; } else { hi = mid; }
+                    }
+                    let a = &knots[2 * lo..2 * lo + 4];
+                    a[1] + (a[3] - a[1]) * ((x - a[0]) / (a[2] - a[0]))
+                })
+            }
+        };
+    }
+}
+
+impl Prediction {
+    // the index (or position) in [0, n) the prediction selects
+    fn index(&self, n: u64) -> u64 {
+        let idx = match self {
+            Prediction::Float(p) => f64::max(0.0, p.floor()) as u64,
+            Prediction::Int(p) => *p
+        };
+        return u64::min(idx, n.saturating_sub(1));
+    }
+}
+
+// an RMI read from an artifact, evaluated as ARTIFACT_SCHEMA describes
+pub struct ArtifactRMI {
+    num_positions: u64,
+    record_size: u64,
+    layers: Vec<Vec<ArtifactModel>>,
+    errors: Option<Vec<u64>>
+}
+
+impl ArtifactRMI {
+    pub fn from_bytes(bytes: &[u8]) -> Result<ArtifactRMI, ArtifactError> {
+        let mut input = bytes;
+        let mut magic = [0u8; 4];
+        std::io::Read::read_exact(&mut input, &mut magic).map_err(|_| ArtifactError::BadMagic)?;
+        if &magic != MAGIC {
+            return Err(ArtifactError::BadMagic);
+        }
+        let version = input.read_u32::<LittleEndian>().map_err(truncated)?;
+        if version != VERSION {
+            return Err(ArtifactError::UnsupportedVersion(version));
+        }
+
+        let mut header: Option<(u64, u64, u32)> = None;
+        let mut layers = Vec::new();
+        let mut errors = None;
+        while !input.is_empty() {
+            let tag = input.read_u8().map_err(truncated)?;
+            let length = input.read_u64::<LittleEndian>().map_err(truncated)?;
+            if length > input.len() as u64 {
+                return Err(ArtifactError::Truncated);
+            }
+            let (mut value, rest) = input.split_at(length as usize);
+            input = rest;
+
+            match tag {
+                TAG_HEADER => header = Some((
+                    value.read_u64::<LittleEndian>().map_err(truncated)?,
+                    value.read_u64::<LittleEndian>().map_err(truncated)?,
+                    value.read_u32::<LittleEndian>().map_err(truncated)?
+                )),
+                TAG_LAYER => {
+                    let name_len = value.read_u8().map_err(truncated)? as usize;
+                    if name_len > value.len() {
+                        return Err(ArtifactError::Truncated);
+                    }
+                    let (name, rest) = value.split_at(name_len);
+                    let name = String::from_utf8_lossy(name).to_string();
+                    value = rest;
+
+                    let num_models = value.read_u64::<LittleEndian>().map_err(truncated)?;
+                    let num_params = value.read_u8().map_err(truncated)?;
+                    // every parameter takes at least 9 bytes
+                    if num_models.saturating_mul(u64::from(num_params)) > value.len() as u64 {
+                        return Err(ArtifactError::Truncated);
+                    }
+
+                    let mut models = Vec::with_capacity(num_models as usize);
+                    for _ in 0..num_models {
+                        let params = (0..num_params)
+                            .map(|_| read_param(&mut value))
+                            .collect::<Result<Vec<ModelParam>, ArtifactError>>()?;
+                        models.push(ArtifactModel::new(&name, params)?);
+                    }
+                    layers.push(models);
+                },
+                TAG_ERRORS => errors = Some(
+                    (0..value.len() / 8)
+                        .map(|_| value.read_u64::<LittleEndian>().map_err(truncated))
+                        .collect::<Result<Vec<u64>, ArtifactError>>()?
+                ),
+                _ => {} // from a later version
+            }
+        }
+
+        let (num_positions, record_size, num_layers) = header.ok_or_else(
+            || ArtifactError::Malformed(String::from("no header")))?;
+        if layers.len() != num_layers as usize || layers.iter().any(|l| l.is_empty()) {
+            return Err(ArtifactError::Malformed(String::from("missing or empty layers")));
+        }
+        if layers[0].len() != 1 {
+            return Err(ArtifactError::Malformed(String::from("the top layer has one model")));
+        }
+        if errors.as_ref().map(|e| e.len() != layers[layers.len() - 1].len()).unwrap_or(false) {
+            return Err(ArtifactError::Malformed(
+                String::from("one error per last layer model is needed")));
+        }
+
+        return Ok(ArtifactRMI { num_positions, record_size, layers, errors });
+    }
+
+    // the index of the last layer model the key is routed to
+    pub fn route(&self, key: u64) -> usize {
+        let mut idx = 0;
+        for layer_idx in 0..self.layers.len() - 1 {
+            let next_layer_size = self.layers[layer_idx + 1].len() as u64;
+            idx = self.layers[layer_idx][idx].predict(key).index(next_layer_size) as usize;
+        }
+        return idx;
+    }
+
+    // the position the lookup of the key returns
+    pub fn predict(&self, key: u64) -> u64 {
+        let leaf = &self.layers[self.layers.len() - 1][self.route(key)];
+        let pos = leaf.predict(key).index(self.num_positions);
+        if self.record_size == 0 {
+            return pos;
+        }
+        return pos - pos % self.record_size;
+    }
+
+    // the error of the last layer model the key is routed to, if the
+    // artifact has errors
+    pub fn error_bound_for(&self, key: u64) -> Option<u64> {
+        return self.errors.as_ref().map(|errors| errors[self.route(key)]);
+    }
+
+    // finds the lower bound of the key in the sorted keys the RMI was
+    // trained on (with element positions), like RuntimeRMI::lookup: within
+    // the error window around the prediction if it is there, or by
+    // searching the rest of the keys otherwise
+    pub fn lookup(&self, keys: &[u64], key: u64) -> usize {
+        assert_eq!(self.record_size, 0, "lookup needs element positions");
+        if keys.is_empty() {
+            return 0;
+        }
+
+        let pred = u64::min(self.predict(key), keys.len() as u64 - 1) as usize;
+        let err = self.error_bound_for(key).unwrap_or(keys.len() as u64) as usize;
+        let lo = pred.saturating_sub(err);
+        let hi = usize::min(keys.len(), pred.saturating_add(err).saturating_add(1));
+        let (lo, hi) = if lo > 0 && keys[lo - 1] >= key {
+            (0, lo)
+        } else if hi < keys.len() && keys[hi - 1] < key {
+            (hi, keys.len())
+        } else {
+            (lo, hi)
+        };
+
+        return lo + keys[lo..hi].partition_point(|k| *k < key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::RuntimeRMI;
+    use crate::train::train;
+
+    fn to_bytes(trained: &TrainedRMI, num_rows: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_artifact(&mut bytes, trained, num_rows, true).unwrap();
+        return bytes;
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let keys: Vec<u64> = (0..10_000u64).map(|i| i * i + i % 5).collect();
+        for (spec, record_size) in &[("linear,linear", 1), ("cubic,linear", 1),
+                                     ("radix,linear", 1), ("connected_spline16,linear", 1),
+                                     ("linear,linear,linear", 1), ("linear,linear", 16)] {
+            let mut data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                                     .map(|(i, k)| (*k, i as u64)).collect());
+            let unit = if *record_size == 1 {
+                PositionUnit::Element
+            } else {
+                data.positions_to_offsets(*record_size);
+                PositionUnit::Offset(*record_size)
+            };
+            let mut container = ModelDataWrapper::new(&data);
+            container.set_position_unit(unit);
+            let num_rows = container.num_positions();
+            let trained = train(&mut container, spec, 64).unwrap();
+
+            let bytes = to_bytes(&trained, num_rows);
+            let read = ArtifactRMI::from_bytes(&bytes).unwrap();
+            let rmi = RuntimeRMI::new(trained);
+
+            let probes = keys.iter().copied().chain(vec![0, 3, u64::MAX - 1, u64::MAX]);
+            for key in probes {
+                let expected = unit.lookup_position(rmi.predict(key.into()), num_rows as u64);
+                assert_eq!(read.predict(key), expected, "{}: key {}", spec, key);
+                assert_eq!(read.error_bound_for(key),
+                           Some(unit.align_error(rmi.error_bound_for(key.into()))),
+                           "{}: key {}", spec, key);
+            }
+
+            if *record_size == 1 {
+                for (i, key) in keys.iter().enumerate() {
+                    assert_eq!(read.lookup(&keys, *key), i, "{}: key {}", spec, key);
+                }
+                assert_eq!(read.lookup(&keys, u64::MAX), keys.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_without_errors() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * 3, i)).collect());
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap();
+        let mut bytes = Vec::new();
+        write_artifact(&mut bytes, &trained, 1000, false).unwrap();
+
+        let read = ArtifactRMI::from_bytes(&bytes).unwrap();
+        assert_eq!(read.error_bound_for(30), None);
+
+        // the whole keys are searched instead of a window
+        let keys: Vec<u64> = (0..1000).map(|i| i * 3).collect();
+        assert_eq!(read.lookup(&keys, 30), 10);
+        assert_eq!(read.lookup(&keys, 31), 11);
+    }
+
+    #[test]
+    fn test_unsupported_models() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * 3, i)).collect());
+        for spec in &["linear,pgm", "isotonic,linear"] {
+            let trained = train(&mut ModelDataWrapper::new(&data), spec, 16).unwrap();
+            let err = write_artifact(&mut Vec::new(), &trained, 1000, true).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_bad_artifacts() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * 3, i)).collect());
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap();
+        let bytes = to_bytes(&trained, 1000);
+
+        assert_eq!(ArtifactRMI::from_bytes(b"RMI").err(), Some(ArtifactError::BadMagic));
+        assert_eq!(ArtifactRMI::from_bytes(b"ABCD\x01\x00\x00\x00").err(),
+                   Some(ArtifactError::BadMagic));
+
+        let mut later = bytes.clone();
+        later[4] = 2;
+        assert_eq!(ArtifactRMI::from_bytes(&later).err(),
+                   Some(ArtifactError::UnsupportedVersion(2)));
+
+        for len in &[9, 20, bytes.len() / 2, bytes.len() - 1] {
+            assert_eq!(ArtifactRMI::from_bytes(&bytes[..*len]).err(),
+                       Some(ArtifactError::Truncated), "{} bytes", len);
+        }
+
+        // a model type the reader does not know
+        let mut renamed = bytes.clone();
+        let at = renamed.windows(6).position(|w| w == b"linear").unwrap();
+        renamed[at..at + 6].copy_from_slice(b"lineaz");
+        assert_eq!(ArtifactRMI::from_bytes(&renamed).err(),
+                   Some(ArtifactError::UnsupportedModel(String::from("lineaz"))));
+
+        // records with unknown tags are skipped
+        let mut extended = bytes.clone();
+        write_record(&mut extended, 42, b"from a later version").unwrap();
+        let read = ArtifactRMI::from_bytes(&extended).unwrap();
+        assert_eq!(read.predict(30), ArtifactRMI::from_bytes(&bytes).unwrap().predict(30));
+    }
+}
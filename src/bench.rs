@@ -0,0 +1,225 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::{Model, ModelInput};
+use crate::runtime::RuntimeRMI;
+use std::time::{Duration, Instant};
+use log::*;
+
+#[derive(Debug)]
+pub struct BenchReport {
+    // number of timed lookups (keys times iterations)
+    pub lookups: usize,
+    pub ns_per_lookup: f64,
+
+    // keys compared during the last-mile search, per lookup
+    pub avg_probes: f64,
+    pub max_probes: usize,
+
+    // sum of all returned positions, so the lookups can't be optimized away
+    pub checksum: u64
+}
+
+// a fixed-seed xorshift, so every config sees the same query order
+fn shuffle(keys: &mut [u64]) {
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    for i in (1..keys.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        keys.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+// times RuntimeRMI::lookup on each of the keys, in a shuffled order, iters
+// times over. The keys should be the keys the RMI was trained on; a sorted
+// copy is searched. One untimed pass is done first to warm up the caches.
+pub fn benchmark_lookups(rmi: &RuntimeRMI, keys: &[u64], iters: usize) -> BenchReport {
+    let mut sorted = keys.to_vec();
+    sorted.sort_unstable();
+
+    let mut queries = keys.to_vec();
+    shuffle(&mut queries);
+
+    let mut checksum: u64 = 0;
+    let mut total_probes = 0;
+    let mut max_probes = 0;
+    for key in queries.iter() {
+        let (pos, probes) = rmi.lookup(&sorted, *key);
+        checksum = checksum.wrapping_add(pos as u64);
+        total_probes += probes;
+        max_probes = usize::max(max_probes, probes);
+    }
+
+    let start = Instant::now();
+    for _ in 0..iters {
+        for key in queries.iter() {
+            checksum = checksum.wrapping_add(rmi.lookup(&sorted, *key).0 as u64);
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let lookups = queries.len() * iters;
+    return BenchReport {
+        lookups,
+        ns_per_lookup: if lookups == 0 { 0.0 } else { elapsed.as_nanos() as f64 / lookups as f64 },
+        avg_probes: if queries.is_empty() { 0.0 } else { total_probes as f64 / queries.len() as f64 },
+        max_probes,
+        checksum
+    };
+}
+
+#[derive(Debug)]
+pub struct Comparison {
+    pub rmi_ns_per_lookup: f64,
+    pub binary_search_ns_per_lookup: f64,
+
+    // binary search time over RMI time; above 1 means the RMI is faster
+    pub speedup: f64,
+
+    // queries for which the two searches found different positions (for
+    // duplicate keys, any position holding the key counts as the same)
+    pub mismatches: usize
+}
+
+// times RuntimeRMI::lookup against slice::binary_search on the sorted keys
+// in data, over the given queries (in a shuffled order), iters times over,
+// and checks that both find the same positions. The RMI must have been
+// trained on data.
+pub fn compare_against_binary_search(rmi: &RuntimeRMI, data: &[u64], keys: &[u64],
+                                     iters: usize) -> Comparison {
+    let mut queries = keys.to_vec();
+    shuffle(&mut queries);
+
+    let binary_search = |key: u64| match data.binary_search(&key) {
+        Ok(pos) | Err(pos) => pos
+    };
+
+    let mismatches = queries.iter()
+        .filter(|key| {
+            let (rmi_pos, bs_pos) = (rmi.lookup(data, **key).0, binary_search(**key));
+            rmi_pos != bs_pos && data.get(rmi_pos) != Some(*key)
+        }).count();
+
+    let mut checksum: u64 = 0;
+    let start = Instant::now();
+    for _ in 0..iters {
+        for key in queries.iter() {
+            checksum = checksum.wrapping_add(rmi.lookup(data, *key).0 as u64);
+        }
+    }
+    let rmi_time = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..iters {
+        for key in queries.iter() {
+            checksum = checksum.wrapping_add(binary_search(*key) as u64);
+        }
+    }
+    let bs_time = start.elapsed();
+    trace!("Comparison checksum: {}", checksum);
+
+    let lookups = (queries.len() * iters) as f64;
+    let per_lookup = |nanos: u128| if lookups == 0.0 { 0.0 } else { nanos as f64 / lookups };
+    let (rmi_ns, bs_ns) = (per_lookup(rmi_time.as_nanos()), per_lookup(bs_time.as_nanos()));
+    return Comparison {
+        rmi_ns_per_lookup: rmi_ns,
+        binary_search_ns_per_lookup: bs_ns,
+        speedup: if rmi_ns == 0.0 { 0.0 } else { bs_ns / rmi_ns },
+        mismatches
+    };
+}
+
+// times evaluating a single model (with predict_batch) on all of the keys,
+// iters times over, to compare the cost of model types before choosing the
+// layers of an RMI. This is the Rust evaluation, not the generated code.
+pub fn evaluate_n(model: &dyn Model, keys: &[ModelInput], iters: usize) -> Duration {
+    let mut checksum = 0.0;
+    let start = Instant::now();
+    for _ in 0..iters {
+        checksum += model.predict_batch(keys).iter().sum::<f64>();
+    }
+    let elapsed = start.elapsed();
+    trace!("Evaluation checksum for {}: {}", model.function_name(), checksum);
+
+    return elapsed;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::*;
+    use crate::train::train;
+
+    #[test]
+    fn test_benchmark_lookups() {
+        let keys: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let rmi = RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap()
+        );
+
+        let report = benchmark_lookups(&rmi, &keys, 3);
+        assert_eq!(report.lookups, 3000);
+        assert!(report.avg_probes > 0.0);
+        assert!(report.max_probes as f64 >= report.avg_probes);
+
+        // the warm up pass plus three timed passes over every position
+        let positions: u64 = (0..1000).sum();
+        assert_eq!(report.checksum, 4 * positions);
+    }
+
+    #[test]
+    fn test_compare_against_binary_search() {
+        let data: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        let md = ModelData::IntKeyToIntPos(data.iter().enumerate()
+                                           .map(|(i, k)| (*k, i as u64)).collect());
+        let rmi = RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&md), "linear,linear", 16).unwrap()
+        );
+
+        // present keys, absent keys, and keys past either end
+        let mut keys: Vec<u64> = data.iter().flat_map(|k| vec![*k, k + 1]).collect();
+        keys.push(u64::MAX);
+        let cmp = compare_against_binary_search(&rmi, &data, &keys, 2);
+        assert_eq!(cmp.mismatches, 0);
+        assert!(cmp.rmi_ns_per_lookup > 0.0 && cmp.binary_search_ns_per_lookup > 0.0);
+        assert!(cmp.speedup > 0.0);
+    }
+
+    #[test]
+    fn test_shuffle() {
+        let mut keys: Vec<u64> = (0..100).collect();
+        shuffle(&mut keys);
+        assert_ne!(keys, (0..100).collect::<Vec<u64>>());
+
+        keys.sort_unstable();
+        assert_eq!(keys, (0..100).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_evaluate_n() {
+        let md = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let container = ModelDataWrapper::new(&md);
+        let keys: Vec<ModelInput> = (0..1000u64).map(|i| (i * i + 1).into()).collect();
+
+        let models: Vec<Box<dyn Model>> = vec![Box::new(LinearModel::new(&container)),
+                                               Box::new(CubicSplineModel::new(&container)),
+                                               Box::new(RadixTable::new(&container, 8))];
+        for model in models.iter() {
+            let batch = model.predict_batch(&keys);
+            assert_eq!(batch.len(), keys.len());
+            for (key, pred) in keys.iter().zip(batch.iter()) {
+                assert_eq!(*pred, model.predict_to_float(*key));
+            }
+
+            assert!(evaluate_n(model.as_ref(), &keys, 5) > Duration::from_nanos(0));
+        }
+    }
+}
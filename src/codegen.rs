@@ -14,22 +14,144 @@ use log::*;
 use std::collections::HashSet;
 use std::io::Write;
 use std::str;
-use crate::train::TrainedRMI;
+use crate::train;
+use crate::train::{TrainedRMI, PrunedLeaves};
+use crate::runtime::RuntimeRMI;
 use std::fs::File;
 use std::io::BufWriter;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::fmt;
 
 
 enum StorageConf {
     Embed,
-    Disk(String)
+    // directory to write parameters to, and whether to compress them
+    Disk(String, bool)
+}
+
+// the language of the generated code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dialect {
+    Cpp,
+    // plain C89 for toolchains without <cstdint>, inline functions, or //
+    // comments. Fixed-width types are typedef'd, namespaces become name
+    // prefixes, and only the plain lookup with embedded parameters is
+    // supported, for models that have a c89_code.
+    C89
+}
+
+impl Default for Dialect {
+    fn default() -> Dialect {
+        return Dialect::Cpp;
+    }
+}
+
+impl Dialect {
+    // the name of a C type in this dialect
+    fn c_type(&self, c_type: &'static str) -> &'static str {
+        return match (self, c_type) {
+            (Dialect::C89, "uint64_t") => "rmi_u64",
+            (Dialect::C89, "uint32_t") => "rmi_u32",
+            _ => c_type
+        };
+    }
+
+    // a literal of the 64-bit unsigned type. unsigned long has only 32 bits
+    // on LLP64 targets (such as Windows), so C++ uses unsigned long long.
+    // C89 has no long long, and its rmi_u64 is an unsigned long checked to
+    // have 64 bits.
+    fn u64_literal(&self, v: u64) -> String {
+        return match self {
+            Dialect::Cpp => format!("{}ULL", v),
+            Dialect::C89 => format!("{}UL", v)
+        };
+    }
+
+    // the storage class of constants in the data header. C constants have
+    // external linkage by default, C++ constants do not.
+    fn const_decl(&self) -> &'static str {
+        return match self {
+            Dialect::Cpp => "const",
+            Dialect::C89 => "static const"
+        };
+    }
+}
+
+// options for all of the RMIs emitted into the same files
+#[derive(Clone, Default)]
+pub struct CodegenOptions {
+    // report the error of the last layer's models along with each lookup
+    // (requires data_dir)
+    pub last_layer_errors: bool,
+
+    // write the parameters to files in this directory, read by load,
+    // instead of embedding them in the code
+    pub data_dir: Option<String>,
+
+    // zstd compress the parameter files written to data_dir. The generated
+    // code must then be linked with libzstd.
+    pub compress_data: bool,
+
+    // mark lookup pure where it is side-effect free (embedded parameters,
+    // no error reporting)
+    pub pure_hint: bool,
+
+    // declare and allocate parameter arrays with this alignment (a power of
+    // two, in bytes), and pad each model's parameters in a mixed array to it
+    pub alignment: Option<usize>,
+
+    // declare the error and position output pointers and the loaded
+    // parameter pointers restrict (RMI_RESTRICT), so compilers may assume
+    // they do not alias
+    pub restrict_pointers: bool,
+
+    // with Dialect::C89, the code is written to file_name.c instead
+    pub dialect: Dialect,
+
+    // if last level errors are reported, lookup returns the position and
+    // error together in an RmiResult (declared in the header) instead of
+    // writing the error through a pointer
+    pub struct_result: bool,
+
+    // evaluate the models with integer arithmetic only (see FixedPointLine),
+    // for targets without an FPU. Only lines and integer-only models (radix)
+    // can be, and the errors are not reported.
+    pub fixed_point: bool,
+
+    // mark the branches taken only for keys outside of the trained range or
+    // their error window RMI_UNLIKELY (__builtin_expect on GCC and Clang).
+    // The searches' comparisons are close to even, so they are left alone.
+    pub branch_hints: bool,
+
+    // report the last level errors as ceil(log2(err + 1)) instead of err
+    // (see log2_error)
+    pub log2_errors: bool,
+
+    // split embedded parameter arrays with more than this many elements
+    // into arrays of whole models (see LayerParams::chunked)
+    pub max_array_elements: Option<usize>,
+
+    // also generate lookup_batch, which evaluates linear and radix top
+    // models with AVX2 when it is enabled
+    pub simd_batch: bool
 }
 
 enum LayerParams {
     Constant(usize, Vec<ModelParam>),
     Array(usize, usize, Vec<ModelParam>),
-    MixedArray(usize, usize, Vec<ModelParam>)
+    // the last field is the alignment of each model's parameters, in bytes
+    // (they are padded to a multiple of it)
+    MixedArray(usize, usize, Vec<ModelParam>, usize),
+    // an embedded array split into arrays of whole models, reached through
+    // an array of pointers to them. The last field is the number of models
+    // in each array (a power of two).
+    ChunkedArray(usize, usize, Vec<ModelParam>, usize),
+    // a single model's parameters, with each array parameter of more than
+    // the last field's elements split into rows of that many elements of a
+    // two-dimensional array. The rows are contiguous, so the model indexes
+    // the split array just as it would the single one.
+    ChunkedConstant(usize, Vec<ModelParam>, usize)
 }
 
 macro_rules! constant_name {
@@ -50,31 +172,66 @@ impl LayerParams {
     fn new(idx: usize,
            array_access: bool,
            params_per_model: usize,
-           params: Vec<ModelParam>) -> LayerParams {
-        // first, if the underlying data is mixed, we can only support array mode.
+           params: Vec<ModelParam>,
+           alignment: Option<usize>) -> LayerParams {
+        // first, if the underlying data is mixed, we can only support array
+        // mode for several models. A single model's parameters are each
+        // their own constant, so their types can differ.
         let first_param = params.first().unwrap();
         let mixed = !params.iter().all(|p| first_param.is_same_type(p));
 
-        if mixed {
-            return LayerParams::MixedArray(idx, params_per_model, params);
+        if mixed && array_access {
+            return LayerParams::MixedArray(idx, params_per_model, params,
+                                           alignment.unwrap_or(1));
         }
 
+        // large parameters are stored as one array (read by load, unless
+        // they are embedded), which a single model's parameters can only be
+        // if they are scalars of the same type, or one array parameter
         let param_size_bytes: usize = params.iter().map(|p| p.size()).sum();
-        if array_access || param_size_bytes > 4096 {
+        let one_array = !mixed && (params.len() == 1 || !first_param.is_array());
+        if array_access || (param_size_bytes > 4096 && one_array) {
             return LayerParams::Array(idx, params_per_model, params);
         }
 
         return LayerParams::Constant(idx, params);
     }
+
+    // splits an array of scalar parameters with more than max_elements
+    // elements into arrays of at most max_elements, so that no single
+    // initializer is too large for the C compiler. Each array holds a power
+    // of two of models, so that finding a model's array is a shift. A
+    // single model's array parameters are split into rows instead.
+    fn chunked(self, max_elements: usize) -> LayerParams {
+        return match self {
+            LayerParams::Constant(idx, params) | LayerParams::Array(idx, _, params)
+                if params.iter().any(|p| p.is_array() && p.len() > max_elements) =>
+                LayerParams::ChunkedConstant(idx, params, max_elements),
+            LayerParams::Array(idx, ppm, params)
+                if !params[0].is_array() && params.len() > max_elements => {
+                let fit = usize::max(1, max_elements / ppm);
+                let models_per_chunk = if fit.is_power_of_two() {
+                    fit
+                } else {
+                    fit.next_power_of_two() / 2
+                };
+                LayerParams::ChunkedArray(idx, ppm, params, models_per_chunk)
+            },
+            other => other
+        };
+    }
     
-    fn to_code<T: Write>(&self, target: &mut T) -> Result<(), std::io::Error> {
+    fn to_code<T: Write>(&self, target: &mut T,
+                         alignment: Option<usize>,
+                         dialect: Dialect) -> Result<(), std::io::Error> {
         match self {
             LayerParams::Constant(idx, params) => {
                 for (p_idx, param) in params.iter().enumerate() {
                     writeln!(
                         target,
-                        "const {} {}{} = {};",
-                        param.c_type(),
+                        "{} {} {}{} = {};",
+                        dialect.const_decl(),
+                        dialect.c_type(param.c_type()),
                         constant_name!(idx, p_idx),
                         param.c_type_mod(),
                         param.c_val()
@@ -85,20 +242,58 @@ impl LayerParams {
             LayerParams::Array(idx, _, params) => {
                 write!(
                     target,
-                    "const {} {}[] = {{",
-                    params[0].c_type(),
+                    "{}{} {} {}[] = {{",
+                    align_decl(alignment),
+                    dialect.const_decl(),
+                    dialect.c_type(params[0].c_type()),
                     array_name!(idx)
                 )?;
 
-                let (last, rest) = params.split_last().unwrap();
-                for param in rest {
-                    write!(target, "{},", param.c_val())?;
+                // a single array parameter is the array itself
+                let values: Vec<String> = if params[0].is_array() {
+                    params[0].c_elements()
+                } else {
+                    params.iter().map(|p| p.c_val()).collect()
+                };
+                writeln!(target, "{}}};", values.join(","))?;
+            },
+
+            LayerParams::ChunkedConstant(idx, params, row_len) => {
+                for (p_idx, param) in params.iter().enumerate() {
+                    let name = constant_name!(idx, p_idx);
+                    let c_type = dialect.c_type(param.c_type());
+                    if !param.is_array() || param.len() <= *row_len {
+                        writeln!(target, "{} {} {}{} = {};", dialect.const_decl(), c_type,
+                                 name, param.c_type_mod(), param.c_val())?;
+                        continue;
+                    }
+
+                    // the last row is zero-filled
+                    let rows: Vec<String> = param.c_elements().chunks(*row_len)
+                        .map(|row| format!("{{{}}}", row.join(",")))
+                        .collect();
+                    writeln!(target, "{} {} {}_ROWS[][{}] = {{{}}};", dialect.const_decl(),
+                             c_type, name, row_len, rows.join(",\n"))?;
+                    writeln!(target, "{} {}* const {} = {}_ROWS[0];", dialect.const_decl(),
+                             c_type, name, name)?;
                 }
-                write!(target, "{}", last.c_val())?;
-                writeln!(target, "}};")?;
             },
 
-            LayerParams::MixedArray(_, _, _) => {
+            LayerParams::ChunkedArray(idx, ppm, params, models_per_chunk) => {
+                let c_type = dialect.c_type(params[0].c_type());
+                let mut chunk_names = Vec::new();
+                for (chunk_idx, chunk) in params.chunks(ppm * models_per_chunk).enumerate() {
+                    let name = format!("{}_{}", array_name!(idx), chunk_idx);
+                    let values: Vec<String> = chunk.iter().map(|p| p.c_val()).collect();
+                    writeln!(target, "{}{} {} {}[] = {{{}}};", align_decl(alignment),
+                             dialect.const_decl(), c_type, name, values.join(","))?;
+                    chunk_names.push(name);
+                }
+                writeln!(target, "{} {}* const {}[] = {{ {} }};", dialect.const_decl(), c_type,
+                         array_name!(idx), chunk_names.join(", "))?;
+            },
+
+            LayerParams::MixedArray(_, _, _, _) => {
                 panic!("Cannot hardcode mixed array.");
             }
         };
@@ -112,8 +307,9 @@ impl LayerParams {
                 let array_size: usize = params.iter().map(|p| p.size()).sum();
                 return array_size >= 4 * 1024;
             },
-            LayerParams::MixedArray(_, _, _) => true,
-            LayerParams::Constant(_, _) => false,
+            LayerParams::MixedArray(_, _, _, _) => true,
+            LayerParams::Constant(_, _) | LayerParams::ChunkedConstant(_, _, _) |
+            LayerParams::ChunkedArray(_, _, _, _) => false,
         }; 
     }
 
@@ -121,15 +317,21 @@ impl LayerParams {
         assert!(self.requires_malloc());
         return match self {
             LayerParams::Array(_, _, params) => params[0].c_type(),
-            LayerParams::MixedArray(_, _, _) => "char",
-            LayerParams::Constant(_, _) => panic!("No pointer type for constant params")
+            LayerParams::MixedArray(_, _, _, _) => "char",
+            LayerParams::Constant(_, _) | LayerParams::ChunkedConstant(_, _, _) |
+            LayerParams::ChunkedArray(_, _, _, _) =>
+                panic!("No pointer type for embedded params")
         };
     }
     
-    fn to_decl<T: Write>(&self, target: &mut T) -> Result<(), std::io::Error> {
+    fn to_decl<T: Write>(&self, target: &mut T,
+                         alignment: Option<usize>,
+                         restrict: bool) -> Result<(), std::io::Error> {
+        let qualifier = if restrict { " RMI_RESTRICT" } else { "" };
         match self {
-            LayerParams::Constant(_, _) => {
-                panic!("Cannot forward-declare constants");
+            LayerParams::Constant(_, _) | LayerParams::ChunkedConstant(_, _, _) |
+            LayerParams::ChunkedArray(_, _, _, _) => {
+                panic!("Cannot forward-declare embedded parameters");
             }
 
             LayerParams::Array(idx, _, params) => {
@@ -137,7 +339,8 @@ impl LayerParams {
                     let num_items: usize = params.iter().map(|p| p.len()).sum();
                     writeln!(
                         target,
-                        "{} {}[{}];",
+                        "{}{} {}[{}];",
+                        align_decl(alignment),
                         params[0].c_type(),
                         array_name!(idx),
                         num_items
@@ -145,18 +348,20 @@ impl LayerParams {
                 } else { 
                     writeln!(
                         target,
-                        "{}* {};",
+                        "{}*{} {};",
                         params[0].c_type(),
+                        qualifier,
                         array_name!(idx)
                     )?;
                 }
             },
 
-            LayerParams::MixedArray(idx, _, _) => {
+            LayerParams::MixedArray(idx, _, _, _) => {
                 assert!(self.requires_malloc());
                 writeln!(
                     target,
-                    "char* {};",
+                    "char*{} {};",
+                    qualifier,
                     array_name!(idx)
                 )?;
             }
@@ -168,62 +373,97 @@ impl LayerParams {
 
     fn write_to<T: Write>(&self, target: &mut T) -> Result<(), std::io::Error> {
         match self {   
-            LayerParams::Array(_idx, _, params) |
-            LayerParams::MixedArray(_idx, _, params) => {
+            LayerParams::MixedArray(_idx, ppm, params, _) => {
+                // pad each model's parameters to the alignment
+                let padding = self.padded_bytes_per_model() - bytes_per_model(params, *ppm);
+                for model_params in params.chunks(*ppm) {
+                    for itm in model_params {
+                        itm.write_to(target)?;
+                    }
+                    target.write_all(&vec![0; padding])?;
+                }
+                return Ok(());
+            },
+            LayerParams::Array(_idx, _, params) => {
                 let (first, rest) = params.split_first().unwrap();
 
                 first.write_to(target)?;
                 for itm in rest {
-                    if let LayerParams::Array(_, _, _) = self {
-                        assert!(first.is_same_type(itm));
-                    }
+                    assert!(first.is_same_type(itm));
                     itm.write_to(target)?;
                 }
                 return Ok(());
             },
-            LayerParams::Constant(_, _) =>
-                panic!("Cannot write constant parameters to binary file.")
+            LayerParams::Constant(_, _) | LayerParams::ChunkedConstant(_, _, _) |
+            LayerParams::ChunkedArray(_, _, _, _) =>
+                panic!("Cannot write embedded parameters to binary file.")
         };
     }
 
+    // like write_to, but the written parameters are zstd compressed
+    fn write_to_compressed<T: Write>(&self, target: &mut T) -> Result<(), std::io::Error> {
+        let mut raw = Vec::with_capacity(self.size());
+        self.write_to(&mut raw)?;
+        return target.write_all(&compress(&raw)?);
+    }
+
     fn params(&self) -> &[ModelParam] {
         return match self {
             LayerParams::Array(_, _, params) |
-            LayerParams::MixedArray(_, _, params)
+            LayerParams::MixedArray(_, _, params, _) |
+            LayerParams::ChunkedArray(_, _, params, _)
                 => params,
-            LayerParams::Constant(_, params) => params
+            LayerParams::Constant(_, params) |
+            LayerParams::ChunkedConstant(_, params, _) => params
         };
     }
 
     fn index(&self) -> usize {
         return match self {
             LayerParams::Array(idx, _, _) |
-            LayerParams::MixedArray(idx, _, _)
+            LayerParams::MixedArray(idx, _, _, _) |
+            LayerParams::ChunkedArray(idx, _, _, _)
                 => *idx,
-            LayerParams::Constant(idx, _) => *idx
+            LayerParams::Constant(idx, _) |
+            LayerParams::ChunkedConstant(idx, _, _) => *idx
         };
     }
 
     fn params_per_model(&self) -> usize {
         return match self {
             LayerParams::Array(_idx, ppm, _params) |
-            LayerParams::MixedArray(_idx, ppm, _params)
+            LayerParams::MixedArray(_idx, ppm, _params, _) |
+            LayerParams::ChunkedArray(_idx, ppm, _params, _)
                 => *ppm,
-            LayerParams::Constant(_, params) => params.len()
+            LayerParams::Constant(_, params) |
+            LayerParams::ChunkedConstant(_, params, _) => params.len()
         };
     }
 
     fn size(&self) -> usize {
+        if let LayerParams::MixedArray(_, ppm, params, _) = self {
+            return self.padded_bytes_per_model() * (params.len() / ppm);
+        }
         return self.params().iter().map(|p| p.size()).sum();
     }
 
+    // the bytes between the start of consecutive models' parameters in a
+    // mixed array
+    fn padded_bytes_per_model(&self) -> usize {
+        return match self {
+            LayerParams::MixedArray(_, ppm, params, alignment) =>
+                bytes_per_model(params, *ppm).div_ceil(*alignment) * alignment,
+            _ => panic!("Only mixed arrays are laid out by model")
+        };
+    }
+
 
     fn access_by_const<T: Write>(
         &self,
         target: &mut T,
         parameter_index: usize,
     ) -> Result<(), std::io::Error> {
-        if let LayerParams::Constant(idx, _) = self {
+        if let LayerParams::Constant(idx, _) | LayerParams::ChunkedConstant(idx, _, _) = self {
             write!(target, "{}", constant_name!(idx, parameter_index))?;
             return Result::Ok(());
         }
@@ -245,7 +485,7 @@ impl LayerParams {
         }
         
         match self {
-            LayerParams::Constant(idx, _) => {
+            LayerParams::Constant(idx, _) | LayerParams::ChunkedConstant(idx, _, _) => {
                 panic!(
                     "Cannot access constant parameters by reference on layer {}",
                     idx
@@ -261,12 +501,14 @@ impl LayerParams {
                 write!(target, "{}[{}]", array_name!(idx), expr)?;
             },
 
-            LayerParams::MixedArray(idx, params_per_model, params) => {
-                // determine the number of bytes for each model
-                let mut bytes_per_model = 0;
-                for item in params.iter().take(*params_per_model) {
-                    bytes_per_model += item.size();
-                }
+            LayerParams::ChunkedArray(idx, params_per_model, _, models_per_chunk) => {
+                write!(target, "{}[{m} / {mpc}][{}*({m} % {mpc}) + {}]", array_name!(idx),
+                       params_per_model, parameter_index, m=model_index, mpc=models_per_chunk)?;
+            },
+
+            LayerParams::MixedArray(idx, _, params, _) => {
+                // determine the number of bytes for each model, with padding
+                let bytes_per_model = self.padded_bytes_per_model();
                 // determine the byte offset of this parameter
                 let mut offset = 0;
                 for item in params.iter().take(parameter_index) {
@@ -290,7 +532,7 @@ impl LayerParams {
         return Result::Ok(());
     }
 
-    fn with_zipped_errors(&self, lle: Vec<u64>) -> LayerParams {
+    fn with_zipped_errors(&self, lle: Vec<u64>, alignment: Option<usize>) -> LayerParams {
         
         let params = self.params();
         // integrate the errors into the model parameters of the last
@@ -308,14 +550,10 @@ impl LayerParams {
                 to_r
             }).collect();
 
-        let is_constant = if let LayerParams::Constant(_, _) = self {
-            true
-        } else {
-            false
-        };
-        
-        return LayerParams::new(self.index(), is_constant, self.params_per_model() + 1,
-                                combined_lle_params);
+        // errors are only zipped into layers of several models, which are
+        // accessed as arrays
+        return LayerParams::new(self.index(), true, self.params_per_model() + 1,
+                                combined_lle_params, alignment);
                                 
     }
 }
@@ -329,35 +567,73 @@ impl fmt::Display for LayerParams {
             LayerParams::Array(idx, ppm, params) =>
                 write!(f, "Array(idx: {}, ppm: {}, len: {}, malloc: {})",
                        idx, ppm, params.len(), self.requires_malloc()),
-            LayerParams::MixedArray(idx, ppm, params) =>
+            LayerParams::MixedArray(idx, ppm, params, _) =>
                 write!(f, "MixedArray(idx: {}, ppm: {}, len: {}, malloc: {})",
-                       idx, ppm, params.len(), self.requires_malloc())
+                       idx, ppm, params.len(), self.requires_malloc()),
+            LayerParams::ChunkedArray(idx, ppm, params, models_per_chunk) =>
+                write!(f, "ChunkedArray(idx: {}, ppm: {}, len: {}, models per chunk: {})",
+                       idx, ppm, params.len(), models_per_chunk),
+            LayerParams::ChunkedConstant(idx, params, row_len) =>
+                write!(f, "ChunkedConstant(idx: {}, len: {}, row length: {})",
+                       idx, params.len(), row_len)
                 
         }
     }
 }
 
-fn params_for_layer(layer_idx: usize, models: &[Box<dyn Model>]) -> LayerParams {
+fn params_for_layer(layer_idx: usize, models: &[Box<dyn Model>],
+                    alignment: Option<usize>) -> LayerParams {
     let params_per_model = models[0].params().len();
-    let params = models.iter().flat_map(|m| m.params()).collect();
+    let params: Vec<ModelParam> = models.iter().flat_map(|m| m.params()).collect();
+    for (idx, param) in params.iter().enumerate() {
+        if let Err(err) = param.validate() {
+            panic!("Model {} of layer {} ({}) cannot be emitted: {}", idx / params_per_model,
+                   layer_idx, models[0].function_name(), err);
+        }
+    }
     return LayerParams::new(layer_idx,
                             models.len() > 1, // array access on non-singleton layers
                             params_per_model,
-                            params);
+                            params,
+                            alignment);
+}
+
+// the unpadded size of one model's parameters
+fn bytes_per_model(params: &[ModelParam], params_per_model: usize) -> usize {
+    return params.iter().take(params_per_model).map(|p| p.size()).sum();
+}
+
+fn align_decl(alignment: Option<usize>) -> String {
+    return match alignment {
+        Some(a) => format!("alignas({}) ", a),
+        None => String::new()
+    };
+}
+
+#[cfg(feature = "zstd")]
+fn compress(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    return zstd::encode_all(data, 0);
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress(_data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    panic!("Compressed parameters require building with the zstd feature");
 }
 
 macro_rules! model_index_from_output {
-    ($from: expr, $bound: expr, $needs_check: expr) => {
+    ($from: expr, $bound: expr, $needs_check: expr, $dialect: expr, $hints: expr) => {
         match $from {
             ModelDataType::Float => {
                 if $needs_check {
                     format!("FCLAMP(fpred, {}.0 - 1.0)", $bound)
                 } else {
-                    format!("(uint64_t) fpred")
+                    format!("({}) fpred", $dialect.c_type("uint64_t"))
                 }
             }
             ModelDataType::Int => {
-                if $needs_check {
+                if $needs_check && $hints {
+                    format!("(RMI_UNLIKELY(ipred > {0} - 1) ? {0} - 1 : ipred)", $bound)
+                } else if $needs_check {
                     format!("(ipred > {0} - 1 ? {0} - 1 : ipred)", $bound)
                 } else {
                     format!("ipred")
@@ -367,6 +643,35 @@ macro_rules! model_index_from_output {
     };
 }
 
+// the intrinsics the vectorized top models (see Model::avx2_code) use
+const AVX2_GUARD: &str = "#if defined(__AVX2__) && defined(__FMA__)";
+
+// the C condition of lookup_float for the keys the policy rejects
+fn non_finite_condition(policy: NonFinitePolicy) -> &'static str {
+    return match policy {
+        NonFinitePolicy::Clamp => "std::isnan(key)",
+        _ => "!std::isfinite(key)"
+    };
+}
+
+// converts an error reported with log2 errors (see train::log2_error_bits)
+// back to the largest absolute error with that many bits, saturating
+// instead of shifting by the width of size_t (which is undefined)
+const LOG2_ERROR_TO_ABSOLUTE: &str =
+    "(err >= sizeof(size_t) * 8 ? (size_t) -1 : ((size_t) 1 << err) - 1)";
+
+// every model of the RMI in fixed point (see to_fixed_point)
+fn fixed_point_rmi(rmi: Vec<Vec<Box<dyn Model>>>) -> Vec<Vec<Box<dyn Model>>> {
+    return rmi.into_iter().map(|layer| {
+        layer.into_iter().map(|model| {
+            let name = model.function_name();
+            to_fixed_point(model).unwrap_or_else(|| {
+                panic!("The {} model cannot be evaluated in fixed point", name)
+            })
+        }).collect::<Vec<Box<dyn Model>>>()
+    }).collect();
+}
+
 pub fn rmi_size(rmi: &[Vec<Box<dyn Model>>], report_last_layer_errors: bool) -> u64 {
     // compute the RMI size (used in the header, compute here before consuming)
     let mut num_total_bytes = 0;
@@ -384,98 +689,262 @@ pub fn rmi_size(rmi: &[Vec<Box<dyn Model>>], report_last_layer_errors: bool) ->
     return num_total_bytes as u64;
 }
 
+// the parts of the generated code that depend on the RMI (or its data)
+// rather than on the options for the whole file
+#[derive(Clone, Copy, Default)]
+struct LookupFeatures<'a> {
+    position_unit: PositionUnit,
+    exact_keys: Option<&'a [u64]>,
+    composite_key: Option<CompositeKey>,
+    pruned: Option<&'a PrunedLeaves>,
+    hinted_lookup: bool,
+    float_keys: bool,
+    non_finite_keys: NonFinitePolicy,
+    quantized_keys: Option<f64>,
+    string_keys: bool,
+    // the samples and the error bound rmi_selftest checks them against
+    selftest: Option<(&'a [(u64, u64)], u64)>,
+    key_transform: Option<KeyTransform>,
+    info: Option<RmiInfo>,
+    leaf_map: Option<&'a [u64]>,
+    // name the extern "C" lookup namespace_lookup rather than lookup, as
+    // it is a global symbol, which would collide with other RMIs' in the
+    // same program
+    prefix_extern_lookup: bool
+}
+
+#[allow(clippy::too_many_arguments)]
 fn generate_code<T: Write>(
     code_output: &mut T,
     data_output: &mut T,
     header_output: &mut T,
+    file_name: &str,
     namespace: &str,
     total_rows: usize,
     rmi: Vec<Vec<Box<dyn Model>>>,
     last_layer_errors: Option<Vec<u64>>,
     storage: StorageConf,
     build_time: u128,
+    features: LookupFeatures,
+    options: &CodegenOptions,
 ) -> Result<(), std::io::Error> {
+    let LookupFeatures {
+        position_unit, exact_keys, composite_key, pruned, hinted_lookup, float_keys,
+        non_finite_keys, quantized_keys, string_keys, selftest, key_transform, info, leaf_map,
+        prefix_extern_lookup
+    } = features;
+    let CodegenOptions {
+        pure_hint, alignment, restrict_pointers, dialect, struct_result, fixed_point,
+        branch_hints, log2_errors, max_array_elements, simd_batch, ..
+    } = *options;
+    let c89 = dialect == Dialect::C89;
+    if c89 {
+        assert!(matches!(storage, StorageConf::Embed) && last_layer_errors.is_none()
+                && exact_keys.is_none() && !hinted_lookup && composite_key.is_none()
+                && !float_keys && quantized_keys.is_none() && !string_keys
+                && alignment.is_none() && !pure_hint
+                && !restrict_pointers && !simd_batch,
+                "C89 code only supports the plain lookup with embedded parameters");
+    }
+    // the models are evaluated with integer arithmetic only. The errors
+    // (and the pruned leaves' scaling) were measured with floats, so they
+    // might not hold.
+    let rmi = if fixed_point {
+        assert!(last_layer_errors.is_none() && pruned.is_none(),
+                "fixed-point code does not support last level errors or pruned leaves");
+        fixed_point_rmi(rmi)
+    } else {
+        rmi
+    };
+    // lookup_batch evaluates the top model on four keys at once, if it has
+    // vectorized code. The keys would have to be transformed first, which
+    // takes a 64-bit division AVX2 does not have.
+    let top_avx2 = if simd_batch && key_transform.is_none() { rmi[0][0].avx2_code() } else { None };
+
+    // C has no namespaces, so C89 prefixes the public names instead
+    let prefix = if c89 { format!("{}_", namespace) } else { String::new() };
+    let u64_type = dialect.c_type("uint64_t");
+    let restrict = if restrict_pointers { " RMI_RESTRICT" } else { "" };
+    // marks a branch that is almost never taken: a key outside of the
+    // trained range, or outside of its error window
+    let unlikely = |cond: &str| {
+        if branch_hints { format!("RMI_UNLIKELY({})", cond) } else { cond.to_string() }
+    };
+    // with offsets, lookup rounds its prediction down to the start of a
+    // record, so the reported errors cover whole records
+    let last_layer_errors = last_layer_errors.map(|lle| {
+        lle.into_iter().map(|err| position_unit.align_error(err)).collect::<Vec<u64>>()
+    });
+    // the errors are reported as ceil(log2(err + 1)), computed here so the
+    // generated code only reads them
+    let last_layer_errors = last_layer_errors.map(|lle| {
+        lle.into_iter().map(|err| if log2_errors { train::log2_error_bits(err) } else { err })
+            .collect::<Vec<u64>>()
+    });
+
     // construct the code for the model parameters.
     let mut layer_params: Vec<LayerParams> = rmi
         .iter()
         .enumerate()
-        .map(|(layer_idx, models)| params_for_layer(layer_idx, models))
+        .map(|(layer_idx, models)| params_for_layer(layer_idx, models, alignment))
         .collect();
     
     let report_last_layer_errors = last_layer_errors.is_some();
+    // the error is returned along with the position in an RmiResult,
+    // rather than through an output pointer
+    let struct_result = struct_result && report_last_layer_errors;
+    let err_out = if struct_result { "res.err" } else { "*err" };
+    // the lookup functions taking an error pointer, and how to call lookup
+    // from the other generated functions
+    let err_param = report_last_layer_errors && !struct_result;
+    let result_type = if struct_result { "RmiResult" } else { "uint64_t" };
+    let mut lookup_with_err = String::from(if struct_result {
+        "RmiResult res = lookup(key);\n  size_t err = res.err;\n  uint64_t guess = res.pos;"
+    } else {
+        "size_t err;\n  uint64_t guess = lookup(key, &err);"
+    });
+    // the largest error with this many bits
+    let log2_errors = log2_errors && report_last_layer_errors;
+    if log2_errors {
+        lookup_with_err += &format!("\n  err = {};", LOG2_ERROR_TO_ABSOLUTE);
+    }
 
     let mut report_lle: Vec<u8> = Vec::new();
+    let mut zipped_errors = false;
     if report_last_layer_errors {
         if let Some(lle) = last_layer_errors {
             assert!(!lle.is_empty());
             if lle.len() > 1 {
                 let old_last = layer_params.pop().unwrap();
-                let new_last = old_last.with_zipped_errors(lle);
-
-                write!(report_lle, "  *err = ")?;
-                new_last.access_by_ref(&mut report_lle, "modelIndex",
-                                       new_last.params_per_model() - 1)?;
-                writeln!(report_lle, ";")?;
-                
-                layer_params.push(new_last);
-
+                layer_params.push(old_last.with_zipped_errors(lle, alignment));
+                zipped_errors = true;
             } else {
-                write!(report_lle, "  *err = {};", lle[0])?;
+                write!(report_lle, "  {} = {};", err_out, lle[0])?;
             }
         }
     }
 
+    // split large embedded arrays, which the accesses below go through
+    if let (StorageConf::Embed, Some(max_elements)) = (&storage, max_array_elements) {
+        layer_params = layer_params.into_iter().map(|lp| lp.chunked(max_elements)).collect();
+    }
+
+    if zipped_errors {
+        let new_last = layer_params.last().unwrap();
+        write!(report_lle, "  {} = ", err_out)?;
+        new_last.access_by_ref(&mut report_lle, "modelIndex", new_last.params_per_model() - 1)?;
+        writeln!(report_lle, ";")?;
+    }
+
+    // the vectorized top model call, on the __m256i keys
+    let mut top_avx2_call = Vec::new();
+    if top_avx2.is_some() {
+        write!(top_avx2_call, "{}_avx2(", rmi[0][0].function_name())?;
+        for pidx in 0..rmi[0][0].params().len() {
+            layer_params[0].access_by_const(&mut top_avx2_call, pidx)?;
+            write!(top_avx2_call, ", ")?;
+        }
+        write!(top_avx2_call, "_mm256_loadu_si256((const __m256i*) (keys + i)))")?;
+    }
+
     trace!("Layer parameters:");
     for lps in layer_params.iter() {
         trace!("{}", lps);
     }
 
-    writeln!(data_output, "namespace {} {{", namespace)?;    
+    if !c89 {
+        writeln!(data_output, "namespace {} {{", namespace)?;
+    }
     
     let mut read_code = Vec::new();
     match &storage {
         // embed the data directly inside of the header files
         StorageConf::Embed => {
             for lp in layer_params.iter() {
-                lp.to_code(data_output)?;
+                lp.to_code(data_output, alignment, dialect)?;
             }
         },
 
         // store the data on disk, add code to load it
-        StorageConf::Disk(path) => {
+        StorageConf::Disk(path, compressed) => {
             read_code.push("bool load(char const* dataPath) {".to_string());
             
             for lp in layer_params.iter() {
                 match lp {
                     // constants are still put directly in the header 
-                    LayerParams::Constant(_idx, _) => lp.to_code(data_output)?,
+                    LayerParams::Constant(_idx, _) => lp.to_code(data_output, alignment, dialect)?,
                     
+                    LayerParams::ChunkedArray(_, _, _, _) |
+                    LayerParams::ChunkedConstant(_, _, _) =>
+                        panic!("Only embedded parameters are split into chunks"),
+
                     LayerParams::Array(idx, _, _) |
-                    LayerParams::MixedArray(idx, _, _) => {
+                    LayerParams::MixedArray(idx, _, _, _) => {
                         let data_path = Path::new(&path).join(format!("{}_{}", namespace, array_name!(idx)));
                         let f = File::create(data_path).expect("Could not write data file -- does the RMI data directory exist?");
                         let mut bw = BufWriter::new(f);
 
-                        lp.write_to(&mut bw)?; // write to data file
-                        lp.to_decl(data_output)?; // write to source code
+                        // write to data file
+                        if *compressed {
+                            lp.write_to_compressed(&mut bw)?;
+                        } else {
+                            lp.write_to(&mut bw)?;
+                        }
+                        lp.to_decl(data_output, alignment, restrict_pointers)?; // write to source code
 
                         read_code.push("  {".to_string());
                         read_code.push(format!("    std::ifstream infile(std::filesystem::path(dataPath) / \"{ns}_{fn}\", std::ios::in | std::ios::binary);",
                                                ns=namespace, fn=array_name!(idx)));
                         read_code.push("    if (!infile.good()) return false;".to_string());
                         if lp.requires_malloc() {
-                            read_code.push(format!("    {} = ({}*) malloc({});",
-                                                   array_name!(idx), lp.pointer_type(), lp.size()));
+                            let alloc = match alignment {
+                                // aligned_alloc needs a multiple of the alignment
+                                Some(a) => format!("aligned_alloc({}, {})", a,
+                                                   lp.size().div_ceil(a) * a),
+                                None => format!("malloc({})", lp.size())
+                            };
+                            read_code.push(format!("    {} = ({}*) {};",
+                                                   array_name!(idx), lp.pointer_type(), alloc));
                             read_code.push(format!("    if ({} == NULL) return false;",
                                                    array_name!(idx)));
                         }
-                        read_code.push(format!("    infile.read((char*){fn}, {size});",
-                                               fn=array_name!(idx), size=lp.size()));
-                        read_code.push("    if (!infile.good()) return false;".to_string());
+                        if *compressed {
+                            // read the whole compressed file, then inflate it into the array
+                            read_code.push("    std::string compressed((std::istreambuf_iterator<char>(infile)), std::istreambuf_iterator<char>());".to_string());
+                            read_code.push(format!("    size_t res = ZSTD_decompress((void*){fn}, {size}, compressed.data(), compressed.size());",
+                                                   fn=array_name!(idx), size=lp.size()));
+                            read_code.push(format!("    if (ZSTD_isError(res) || res != {}) return false;",
+                                                   lp.size()));
+                        } else {
+                            read_code.push(format!("    infile.read((char*){fn}, {size});",
+                                                   fn=array_name!(idx), size=lp.size()));
+                            read_code.push("    if (!infile.good()) return false;".to_string());
+                        }
                         read_code.push("  }".to_string());
                     }
                 }
             }
+            // the keys themselves, for lookup_exact
+            if let Some(keys) = exact_keys {
+                let data_path = Path::new(&path).join(format!("{}_KEYS", namespace));
+                let f = File::create(data_path).expect("Could not write keys file -- does the RMI data directory exist?");
+                let mut bw = BufWriter::new(f);
+                for key in keys {
+                    bw.write_all(&key.to_le_bytes())?;
+                }
+                writeln!(data_output, "uint64_t*{} KEYS;", restrict)?;
+
+                read_code.push("  {".to_string());
+                read_code.push(format!("    std::ifstream infile(std::filesystem::path(dataPath) / \"{}_KEYS\", std::ios::in | std::ios::binary);",
+                                       namespace));
+                read_code.push("    if (!infile.good()) return false;".to_string());
+                read_code.push(format!("    KEYS = (uint64_t*) malloc({});", keys.len() * 8));
+                read_code.push("    if (KEYS == NULL) return false;".to_string());
+                read_code.push(format!("    infile.read((char*)KEYS, {});", keys.len() * 8));
+                read_code.push("    if (!infile.good()) return false;".to_string());
+                read_code.push("  }".to_string());
+            }
+
             read_code.push("  return true;".to_string());
             read_code.push("}".to_string());
 
@@ -483,40 +952,85 @@ fn generate_code<T: Write>(
     };
 
     let mut free_code = Vec::new();
-    free_code.push("void cleanup() {".to_string());
+    free_code.push(format!("void {}cleanup({}) {{", prefix, if c89 { "void" } else { "" }));
     // generate free code
     for lp in layer_params.iter() {
-        if !lp.requires_malloc() { continue; }
-        if let LayerParams::Array(idx, _, _) | LayerParams::MixedArray(idx, _, _) = lp {
+        // embedded arrays are never allocated, however large
+        if !lp.requires_malloc() || matches!(storage, StorageConf::Embed) { continue; }
+        if let LayerParams::Array(idx, _, _) | LayerParams::MixedArray(idx, _, _, _) = lp {
             free_code.push(format!("    free({});", array_name!(idx)));
             continue;
         }
         panic!();
     }
+    if exact_keys.is_some() {
+        free_code.push("    free(KEYS);".to_string());
+    }
     free_code.push("}".to_string());
 
-    writeln!(data_output, "}} // namespace")?;
+    // the stored bottom model for each output of the layer above it
+    if let Some(map) = leaf_map {
+        assert!(pruned.is_none(), "pruned leaves cannot be merged");
+        let map = ModelParam::int_array_compact(map.to_vec());
+        writeln!(data_output, "{} {} LEAF_MAP[] = {};",
+                 dialect.const_decl(), dialect.c_type(map.c_type()), map.c_val())?;
+    }
+
+    // one bit per bottom model, set if it was replaced by the top model
+    if let Some(pruned) = pruned {
+        let mut words = vec![0u64; pruned.leaves.len().div_ceil(64)];
+        for (idx, _) in pruned.leaves.iter().enumerate().filter(|(_, p)| **p) {
+            words[idx / 64] |= 1 << (idx % 64);
+        }
+        let itms: Vec<String> = words.iter().map(|w| dialect.u64_literal(*w)).collect();
+        writeln!(data_output, "{} {} PRUNED[] = {{ {} }};",
+                 dialect.const_decl(), u64_type, itms.join(", "))?;
+    }
+
+    if !c89 {
+        writeln!(data_output, "}} // namespace")?;
+    }
 
     // get all of the required stdlib function signatures together
     // TODO assumes all layers are homogenous
     let mut decls = HashSet::new();
     let mut sigs = HashSet::new();
+    let mut uses_memcpy = float_keys;
     for layer in rmi.iter() {
         for stdlib in layer[0].standard_functions() {
+            uses_memcpy |= stdlib == StdFunctions::DoubleFromBits;
             decls.insert(stdlib.decl().to_string());
             sigs.insert(stdlib.code().to_string());
         }
     }
 
-    writeln!(code_output, "#include \"{}.h\"", namespace)?;
-    writeln!(code_output, "#include \"{}_data.h\"", namespace)?;
-    writeln!(code_output, "#include <math.h>")?;
-    writeln!(code_output, "#include <cmath>")?;
-    writeln!(code_output, "#include <fstream>")?;
-    writeln!(code_output, "#include <filesystem>")?;
-    writeln!(code_output, "#include <iostream>")?;
+    writeln!(code_output, "#include \"{}.h\"", file_name)?;
+    writeln!(code_output, "#include \"{}_data.h\"", file_name)?;
+    if c89 {
+        // C89 has no fma, but the C99 math library does (link with -lm)
+        writeln!(code_output, "double fma(double x, double y, double z);")?;
+    } else {
+        writeln!(code_output, "#include <math.h>")?;
+        writeln!(code_output, "#include <cmath>")?;
+        writeln!(code_output, "#include <fstream>")?;
+        writeln!(code_output, "#include <filesystem>")?;
+        writeln!(code_output, "#include <iostream>")?;
+        if uses_memcpy {
+            writeln!(code_output, "#include <cstring>")?;
+        }
+        if top_avx2.is_some() {
+            writeln!(code_output, "{}", AVX2_GUARD)?;
+            writeln!(code_output, "#include <immintrin.h>")?;
+            writeln!(code_output, "#endif")?;
+        }
+        if let StorageConf::Disk(_, true) = storage {
+            writeln!(code_output, "#include <string>")?;
+            writeln!(code_output, "#include <iterator>")?;
+            writeln!(code_output, "#include <zstd.h>")?;
+        }
 
-    writeln!(code_output, "namespace {} {{", namespace)?;
+        writeln!(code_output, "namespace {} {{", namespace)?;
+    }
 
     for ln in read_code {
         writeln!(code_output, "{}", ln)?;
@@ -537,53 +1051,120 @@ fn generate_code<T: Write>(
     // next, the model sigs
     sigs = HashSet::new();
     for layer in rmi.iter() {
-        sigs.insert(layer[0].code());
+        if c89 {
+            sigs.insert(layer[0].c89_code().unwrap_or_else(|| {
+                panic!("The {} model cannot be emitted as C89", layer[0].function_name())
+            }));
+        } else {
+            sigs.insert(layer[0].code());
+        }
     }
 
     for sig in sigs {
         writeln!(code_output, "{}", sig)?;
     }
 
-    writeln!(
-        code_output,
-        "
-inline size_t FCLAMP(double inp, double bound) {{
-  if (inp < 0.0) return 0;
-  return (inp > bound ? bound : (size_t)inp);
-}}\n"
-    )?;
+    if let Some(code) = &top_avx2 {
+        writeln!(code_output, "{}{}\n#endif", AVX2_GUARD, code)?;
+    }
 
-    let lookup_sig = if report_last_layer_errors {
-        "uint64_t lookup(uint64_t key, size_t* err)"
+    if branch_hints {
+        // no-op on compilers without __builtin_expect (such as MSVC)
+        writeln!(code_output, "#ifndef RMI_UNLIKELY")?;
+        writeln!(code_output, "#if defined(__GNUC__) || defined(__clang__)")?;
+        writeln!(code_output, "#define RMI_LIKELY(x) __builtin_expect(!!(x), 1)")?;
+        writeln!(code_output, "#define RMI_UNLIKELY(x) __builtin_expect(!!(x), 0)")?;
+        writeln!(code_output, "#else")?;
+        writeln!(code_output, "#define RMI_LIKELY(x) (x)")?;
+        writeln!(code_output, "#define RMI_UNLIKELY(x) (x)")?;
+        writeln!(code_output, "#endif")?;
+        writeln!(code_output, "#endif")?;
+    }
+
+    // fixed-point models only output integers
+    if !fixed_point {
+        writeln!(
+            code_output,
+            "
+{storage}{index} FCLAMP(double inp, double bound) {{
+  if ({below}) return 0;
+  return ({above} ? bound : ({index})inp);
+}}\n",
+            storage = if c89 { "static " } else { "inline " },
+            index = if c89 { u64_type } else { "size_t" },
+            below = unlikely("inp < 0.0"),
+            above = unlikely("inp > bound")
+        )?;
+    }
+
+    // lookup only reads its argument and the parameters when they are
+    // embedded, and does not write through an error pointer, so only then
+    // is it safe to mark pure. With loaded parameters, load/cleanup change
+    // what lookup reads.
+    let is_pure = pure_hint && !report_last_layer_errors
+        && matches!(storage, StorageConf::Embed);
+    let lookup_sig = if struct_result {
+        String::from("RmiResult lookup(uint64_t key)")
+    } else if report_last_layer_errors {
+        format!("uint64_t lookup(uint64_t key, size_t*{} err)", restrict)
+    } else if is_pure {
+        String::from("RMI_PURE uint64_t lookup(uint64_t key)")
     } else {
-        "uint64_t lookup(uint64_t key)"
+        format!("{u64} {}lookup({u64} key)", prefix, u64 = u64_type)
     };
     writeln!(code_output, "{} {{", lookup_sig)?;
 
     // determine if we have any layers with float (fpred) or int (ipred) outputs
     let mut needed_vars = HashSet::new();
     if rmi.len() > 1 {
-        needed_vars.insert("size_t modelIndex;");
+        needed_vars.insert(format!("{} modelIndex;", if c89 { u64_type } else { "size_t" }));
     }
 
     for layer in rmi.iter() {
         match layer[0].output_type() {
-            ModelDataType::Int => needed_vars.insert("uint64_t ipred;"),
-            ModelDataType::Float => needed_vars.insert("double fpred;"),
+            ModelDataType::Int => needed_vars.insert(format!("{} ipred;", u64_type)),
+            ModelDataType::Float => needed_vars.insert(String::from("double fpred;")),
         };
     }
 
+    if struct_result {
+        needed_vars.insert(String::from("RmiResult res;"));
+    }
+
+    // C89 only allows declarations at the start of a block
+    if c89 && position_unit != PositionUnit::Element {
+        needed_vars.insert(format!("{} pos;", u64_type));
+    }
+
+    let mut lookup_vars = Vec::new();
     for var in needed_vars {
-        writeln!(code_output, "  {}", var)?;
+        writeln!(lookup_vars, "  {}", var)?;
+    }
+    code_output.write_all(&lookup_vars)?;
+
+    // the models were trained on transformed keys
+    if let Some(transform) = key_transform {
+        writeln!(code_output, "  key = (key > {o} ? key - {o} : 0) / {s};",
+                 o = dialect.u64_literal(transform.offset),
+                 s = dialect.u64_literal(transform.scale))?;
     }
 
-    let model_size_bytes = rmi_size(&rmi, report_last_layer_errors);
+    let model_size_bytes = rmi_size(&rmi, report_last_layer_errors)
+        + leaf_map.map(|m| ModelParam::int_array_compact(m.to_vec()).size() as u64).unwrap_or(0);
     info!("Generated model size: {:?} ({} bytes)", ByteSize(model_size_bytes), model_size_bytes);
 
     let mut last_model_output = ModelDataType::Int;
     let mut needs_bounds_check = true;
+    let num_layers = rmi.len();
+    let top_output = rmi[0][0].output_type();
+
+    // the top model's prediction, and everything after it, which
+    // lookup_batch runs on its vectorized top model predictions
+    let mut top_code: Vec<u8> = Vec::new();
+    let mut rest_code: Vec<u8> = Vec::new();
 
     for (layer_idx, layer) in rmi.into_iter().enumerate() {
+        let code_output = if layer_idx == 0 { &mut top_code } else { &mut rest_code };
         let layer_param = &layer_params[layer_idx];
         let required_type = layer[0].input_type();
 
@@ -594,6 +1175,10 @@ inline size_t FCLAMP(double inp, double bound) {{
             ModelDataType::Float => "fpred",
         };
 
+        // a pruned bottom model is skipped, and the top model's prediction
+        // (still in fpred) is scaled to a position instead
+        let passthrough = pruned.filter(|_| layer.len() > 1 && layer_idx == num_layers - 1);
+
         let num_parameters = layer[0].params().len();
         if layer.len() == 1 {
             // use constant indexing, only one model
@@ -610,12 +1195,26 @@ inline size_t FCLAMP(double inp, double bound) {{
             }
         } else {
             // we need to get the model index based on the previous
-            // prediction, and then use ref accessing
+            // prediction, and then use ref accessing. Merged bottom models
+            // are reached through the leaf map.
+            let leaf_map = leaf_map.filter(|_| layer_idx == num_layers - 1);
+            let num_outputs = leaf_map.map(|m| m.len()).unwrap_or(layer.len());
             writeln!(
                 code_output,
                 "  modelIndex = {};",
-                model_index_from_output!(last_model_output, layer.len(), needs_bounds_check)
+                model_index_from_output!(last_model_output, num_outputs, needs_bounds_check,
+                                         dialect, branch_hints)
             )?;
+            if leaf_map.is_some() {
+                writeln!(code_output, "  modelIndex = LEAF_MAP[modelIndex];")?;
+            }
+
+            if let Some(pruned) = passthrough {
+                writeln!(code_output, "  if ((PRUNED[modelIndex / 64] >> (modelIndex % 64)) & 1) {{")?;
+                writeln!(code_output, "    {} = fpred * {};", var_name,
+                         ModelParam::Float(pruned.scale).c_val())?;
+                writeln!(code_output, "  }} else {{")?;
+            }
 
             write!(
                 code_output,
@@ -629,29 +1228,447 @@ inline size_t FCLAMP(double inp, double bound) {{
                 write!(code_output, ", ")?;
             }
         }
-        writeln!(code_output, "({})key);", required_type.c_type())?;
+        writeln!(code_output, "({})key);", dialect.c_type(required_type.c_type()))?;
+        if passthrough.is_some() {
+            writeln!(code_output, "  }}")?;
+        }
 
         last_model_output = layer[0].output_type();
         needs_bounds_check = layer[0].needs_bounds_check();
     }
 
-    writeln!(code_output, "{}", str::from_utf8(&report_lle).unwrap())?;
+    writeln!(rest_code, "{}", str::from_utf8(&report_lle).unwrap())?;
 
-    writeln!(
-        code_output,
-        "  return {};",
-        model_index_from_output!(last_model_output, total_rows, true)
-    )?; // always bounds check the last level
+    match position_unit {
+        PositionUnit::Element if struct_result => {
+            writeln!(
+                rest_code,
+                "  res.pos = {};",
+                model_index_from_output!(last_model_output, total_rows, true, dialect, branch_hints)
+            )?;
+            writeln!(rest_code, "  return res;")?;
+        },
+        PositionUnit::Element => writeln!(
+            rest_code,
+            "  return {};",
+            model_index_from_output!(last_model_output, total_rows, true, dialect, branch_hints)
+        )?, // always bounds check the last level
+        PositionUnit::Offset(_) => {
+            writeln!(
+                rest_code,
+                "  {}pos = {};",
+                if c89 { "" } else { "uint64_t " },
+                model_index_from_output!(last_model_output, total_rows, true, dialect, branch_hints)
+            )?;
+            if struct_result {
+                writeln!(rest_code, "  res.pos = pos - pos % {}RECORD_SIZE;", prefix)?;
+                writeln!(rest_code, "  return res;")?;
+            } else {
+                writeln!(rest_code, "  return pos - pos % {}RECORD_SIZE;", prefix)?;
+            }
+        }
+    };
+    code_output.write_all(&top_code)?;
+    code_output.write_all(&rest_code)?;
     writeln!(code_output, "}}")?;
 
+    // looks up each of n keys, as lookup does. With AVX2 and FMA enabled
+    // (e.g. -mavx2 -mfma) and a vectorized top model, the top model is
+    // evaluated on four keys at a time, and lookup_from_top finishes each
+    // key from its prediction; otherwise, and for the last n % 4 keys, it
+    // calls lookup.
+    let batch_sig = if simd_batch {
+        let errs = if report_last_layer_errors {
+            format!(", size_t*{} errs", restrict)
+        } else {
+            String::new()
+        };
+        Some(format!("void lookup_batch(const uint64_t*{r} keys, size_t n, uint64_t*{r} out{})",
+                     errs, r = restrict))
+    } else {
+        None
+    };
+    if let Some(sig) = &batch_sig {
+        // the statements storing the result of a lookup of keys[idx]
+        let store = |call: String, idx: &str, indent: &str| -> String {
+            if struct_result {
+                return format!("{s}RmiResult res = {};\n{s}out[{i}] = res.pos;\n\
+                                {s}errs[{i}] = res.err;", call, s = indent, i = idx);
+            }
+            return format!("{}out[{}] = {};", indent, idx, call);
+        };
+        let err_arg = |idx: &str| {
+            if err_param { format!(", errs + {}", idx) } else { String::new() }
+        };
+
+        if top_avx2.is_some() {
+            let (top_type, top_var) = match top_output {
+                ModelDataType::Float => ("double", "fpred"),
+                ModelDataType::Int => ("uint64_t", "ipred")
+            };
+            let err = if err_param { ", size_t* err" } else { "" };
+            writeln!(code_output, "{}", AVX2_GUARD)?;
+            writeln!(code_output, "static inline {} lookup_from_top(uint64_t key, {} top{}) {{",
+                     result_type, top_type, err)?;
+            code_output.write_all(&lookup_vars)?;
+            writeln!(code_output, "  {} = top;", top_var)?;
+            code_output.write_all(&rest_code)?;
+            writeln!(code_output, "}}")?;
+            writeln!(code_output, "#endif")?;
+        }
+
+        writeln!(code_output, "{} {{", sig)?;
+        writeln!(code_output, "  size_t i = 0;")?;
+        if top_avx2.is_some() {
+            let (vector_store, pred_type) = match top_output {
+                ModelDataType::Float => ("_mm256_storeu_pd(preds, ", "double"),
+                ModelDataType::Int => ("_mm256_storeu_si256((__m256i*) preds, ", "uint64_t")
+            };
+            writeln!(code_output, "{}", AVX2_GUARD)?;
+            writeln!(code_output, "  {} preds[4];", pred_type)?;
+            writeln!(code_output, "  for (; i + 4 <= n; i += 4) {{")?;
+            writeln!(code_output, "    {}{});", vector_store,
+                     str::from_utf8(&top_avx2_call).unwrap())?;
+            writeln!(code_output, "    for (size_t j = 0; j < 4; j++) {{")?;
+            writeln!(code_output, "{}",
+                     store(format!("lookup_from_top(keys[i + j], preds[j]{})", err_arg("i + j")),
+                           "i + j", "      "))?;
+            writeln!(code_output, "    }}")?;
+            writeln!(code_output, "  }}")?;
+            writeln!(code_output, "#endif")?;
+        }
+        writeln!(code_output, "  for (; i < n; i++) {{")?;
+        writeln!(code_output, "{}",
+                 store(format!("lookup(keys[i]{})", err_arg("i")), "i", "    "))?;
+        writeln!(code_output, "  }}")?;
+        writeln!(code_output, "}}")?;
+    }
+
+    // packs the key columns exactly as ModelData::from_composite_keys does
+    let composite_sig = composite_key.map(|layout| {
+        let pure = if is_pure { "RMI_PURE " } else { "" };
+        let err = if err_param {
+            format!(", size_t*{} err", restrict)
+        } else {
+            String::new()
+        };
+        format!("{}{} lookup({} first, {} second{})", pure, result_type,
+                CompositeKey::c_type(layout.first_bits),
+                CompositeKey::c_type(layout.second_bits), err)
+    });
+    if let (Some(layout), Some(sig)) = (composite_key, &composite_sig) {
+        writeln!(code_output, "{} {{", sig)?;
+        writeln!(code_output, "  return lookup(((uint64_t) first << {}) | (uint64_t) second{});",
+                 layout.second_bits, if err_param { ", err" } else { "" })?;
+        writeln!(code_output, "}}")?;
+    }
+
+    // maps the key exactly as float_to_monotonic_u64 does
+    let float_sig = if float_keys {
+        let pure = if is_pure { "RMI_PURE " } else { "" };
+        let err = if err_param {
+            format!(", size_t*{} err", restrict)
+        } else {
+            String::new()
+        };
+        Some(format!("{}{} lookup_float(double key{})", pure, result_type, err))
+    } else {
+        None
+    };
+    if let Some(sig) = &float_sig {
+        writeln!(code_output, "{} {{", sig)?;
+        // NonFinitePolicy::Error has no position, so it returns one no key
+        // can have
+        let rejected_pos = non_finite_keys.position().unwrap_or(u64::MAX);
+        let mut non_finite = vec![(non_finite_condition(non_finite_keys),
+                                   dialect.u64_literal(rejected_pos))];
+        if non_finite_keys == NonFinitePolicy::Clamp {
+            let clamped = |key| {
+                dialect.u64_literal(non_finite_keys.clamped_position(key, total_rows as u64)
+                                    .unwrap())
+            };
+            let (first, last) = (clamped(f64::NEG_INFINITY), clamped(f64::INFINITY));
+            non_finite.push(("std::isinf(key)", format!("(key < 0 ? {} : {})", first, last)));
+        }
+        for (cond, pos) in non_finite {
+            writeln!(code_output, "  if ({}) {{", unlikely(cond))?;
+            if struct_result {
+                writeln!(code_output, "    RmiResult res;")?;
+                writeln!(code_output, "    res.pos = {};", pos)?;
+                writeln!(code_output, "    res.err = 0;")?;
+                writeln!(code_output, "    return res;")?;
+            } else {
+                if err_param {
+                    writeln!(code_output, "    *err = 0;")?;
+                }
+                writeln!(code_output, "    return {};", pos)?;
+            }
+            writeln!(code_output, "  }}")?;
+        }
+        writeln!(code_output, "  uint64_t bits;")?;
+        writeln!(code_output, "  std::memcpy(&bits, &key, sizeof(bits));")?;
+        writeln!(code_output, "  if (key == 0.0) bits = 0; // -0.0 is the same key as 0.0")?;
+        writeln!(code_output, "  bits = ((bits >> 63) ? ~bits : bits | ((uint64_t) 1 << 63));")?;
+        writeln!(code_output, "  return lookup(bits{});", if err_param { ", err" } else { "" })?;
+        writeln!(code_output, "}}")?;
+    }
+
+    // scales the key exactly as ModelData::quantize_to_int does, where the
+    // cast saturates (and maps NaN to 0)
+    let quantized_sig = if quantized_keys.is_some() {
+        let pure = if is_pure { "RMI_PURE " } else { "" };
+        let err = if err_param {
+            format!(", size_t*{} err", restrict)
+        } else {
+            String::new()
+        };
+        Some(format!("{}{} lookup_quantized(double key{})", pure, result_type, err))
+    } else {
+        None
+    };
+    if let (Some(key_scale), Some(sig)) = (quantized_keys, &quantized_sig) {
+        writeln!(code_output, "{} {{", sig)?;
+        writeln!(code_output, "  double scaled = std::floor(key * {:?});", key_scale)?;
+        writeln!(code_output, "  uint64_t qkey = (scaled >= 18446744073709551616.0 ? UINT64_MAX")?;
+        writeln!(code_output, "                  : (scaled > 0.0 ? (uint64_t) scaled : 0));")?;
+        writeln!(code_output, "  return lookup(qkey{});", if err_param { ", err" } else { "" })?;
+        writeln!(code_output, "}}")?;
+    }
+
+    // packs the key exactly as string_to_monotonic_u64 does
+    let string_sig = if string_keys {
+        let pure = if is_pure { "RMI_PURE " } else { "" };
+        let err = if err_param {
+            format!(", size_t*{} err", restrict)
+        } else {
+            String::new()
+        };
+        Some(format!("{}{} lookup_string(const char* key, size_t len{})", pure, result_type, err))
+    } else {
+        None
+    };
+    if let Some(sig) = &string_sig {
+        write_string_collision_note(code_output)?;
+        writeln!(code_output, "{} {{", sig)?;
+        writeln!(code_output, "  uint64_t prefix = 0;")?;
+        writeln!(code_output, "  for (size_t i = 0; i < 8; i++) {{")?;
+        writeln!(code_output,
+                 "    prefix = (prefix << 8) | (i < len ? (unsigned char) key[i] : 0);")?;
+        writeln!(code_output, "  }}")?;
+        writeln!(code_output, "  return lookup(prefix{});", if err_param { ", err" } else { "" })?;
+        writeln!(code_output, "}}")?;
+    }
+
+    // checks that lookup lands within the error bound (the key's last level
+    // error, or the given maximum error) of each sampled position. Returns
+    // 0 on success, or one more than the index of the first failing sample.
+    if let Some((samples, max_error)) = selftest {
+        assert!(!samples.is_empty(), "the self-test needs at least one sample");
+        let (keys, positions): (Vec<String>, Vec<String>) = samples.iter()
+            .map(|(key, pos)| (dialect.u64_literal(*key), dialect.u64_literal(*pos)))
+            .unzip();
+        writeln!(code_output, "static const {} SELFTEST_KEYS[] = {{ {} }};",
+                 u64_type, keys.join(", "))?;
+        writeln!(code_output, "static const {} SELFTEST_POSITIONS[] = {{ {} }};",
+                 u64_type, positions.join(", "))?;
+        writeln!(code_output, "int {}rmi_selftest(void) {{", prefix)?;
+        writeln!(code_output, "  {u64} i, pred, expected, diff;", u64 = u64_type)?;
+        if report_last_layer_errors {
+            writeln!(code_output, "  size_t err;")?;
+        }
+        if struct_result {
+            writeln!(code_output, "  RmiResult res;")?;
+        }
+        writeln!(code_output, "  for (i = 0; i < {}; i++) {{", samples.len())?;
+        if struct_result {
+            writeln!(code_output, "    res = lookup(SELFTEST_KEYS[i]);")?;
+            writeln!(code_output, "    pred = res.pos;")?;
+            writeln!(code_output, "    err = res.err;")?;
+        } else {
+            writeln!(code_output, "    pred = {}lookup(SELFTEST_KEYS[i]{});", prefix,
+                     if report_last_layer_errors { ", &err" } else { "" })?;
+        }
+        writeln!(code_output, "    expected = SELFTEST_POSITIONS[i];")?;
+        writeln!(code_output, "    diff = (pred > expected ? pred - expected : expected - pred);")?;
+        if log2_errors {
+            writeln!(code_output, "    if (diff > {}) return (int) i + 1;",
+                     LOG2_ERROR_TO_ABSOLUTE)?;
+        } else if report_last_layer_errors {
+            writeln!(code_output, "    if (diff > err) return (int) i + 1;")?;
+        } else {
+            writeln!(code_output, "    if (diff > {}) return (int) i + 1;",
+                     dialect.u64_literal(position_unit.align_error(max_error)))?;
+        }
+        writeln!(code_output, "  }}")?;
+        writeln!(code_output, "  return 0;")?;
+        writeln!(code_output, "}}")?;
+    }
+
+    // the info is all constants, so calls fold away when inlined
+    let info_type = if c89 { format!("struct {}RmiInfo", prefix) } else { String::from("RmiInfo") };
+    if let Some(info) = info {
+        writeln!(code_output, "{} {}rmi_info(void) {{", info_type, prefix)?;
+        writeln!(code_output, "  {} info = {{ {}, {}, {}, {} }};",
+                 info_type, dialect.u64_literal(num_layers as u64),
+                 dialect.u64_literal(info.branching_factor), dialect.u64_literal(model_size_bytes),
+                 dialect.u64_literal(position_unit.align_error(info.max_error)))?;
+        writeln!(code_output, "  return info;")?;
+        writeln!(code_output, "}}")?;
+        writeln!(code_output, "{} {}rmi_count(void) {{", u64_type, prefix)?;
+        writeln!(code_output, "  return {};", dialect.u64_literal(info.num_keys))?;
+        writeln!(code_output, "}}")?;
+    }
+
+    // search the error window around the prediction for the key itself.
+    // The window always contains the key's position if the key exists.
+    if let Some(keys) = exact_keys {
+        writeln!(code_output, "
+int lookup_exact(uint64_t key, uint64_t*{restrict} pos) {{
+  {lookup_with_err}
+  uint64_t lo = (guess > err ? guess - err : 0);
+  uint64_t hi = (guess + err + 1 < NUM_KEYS ? guess + err + 1 : NUM_KEYS);
+  while (lo < hi) {{
+    uint64_t mid = lo + (hi - lo) / 2;
+    if (KEYS[mid] < key) lo = mid + 1;
+    else hi = mid;
+  }}
+  if (lo < NUM_KEYS && KEYS[lo] == key) {{
+    *pos = lo;
+    return 1;
+  }}
+  return 0;
+}}", restrict=restrict, lookup_with_err=lookup_with_err)?;
+        assert_eq!(keys.len(), total_rows,
+                   "exact lookups need every key (is the data downsampled?)");
+    }
+
+    // the lower bound of the key, galloping outwards from the caller's hint
+    // when it is inside the error window (as RuntimeRMI::lookup_hinted)
+    if hinted_lookup {
+        assert!(exact_keys.is_some(), "hinted lookups require the keys");
+        writeln!(code_output, "
+uint64_t lookup_hinted(uint64_t key, uint64_t hint) {{
+  {lookup_with_err}
+  uint64_t lo = (guess > err ? guess - err : 0);
+  uint64_t hi = (guess + err + 1 < NUM_KEYS ? guess + err + 1 : NUM_KEYS);
+  if ({miss_below}) {{
+    hi = lo;
+    lo = 0;
+  }} else if ({miss_above}) {{
+    lo = hi;
+    hi = NUM_KEYS;
+  }} else if (hint >= lo && hint < hi) {{
+    uint64_t step = 1;
+    uint64_t prev = hint;
+    if (KEYS[hint] < key) {{
+      while (hint + step < hi) {{
+        if (KEYS[hint + step] >= key) {{
+          hi = hint + step;
+          break;
+        }}
+        prev = hint + step;
+        step *= 2;
+      }}
+      lo = prev + 1;
+    }} else {{
+      while (hint >= lo + step) {{
+        if (KEYS[hint - step] < key) {{
+          lo = hint - step + 1;
+          break;
+        }}
+        prev = hint - step;
+        step *= 2;
+      }}
+      hi = prev;
+    }}
+  }}
+  while (lo < hi) {{
+    uint64_t mid = lo + (hi - lo) / 2;
+    if (KEYS[mid] < key) lo = mid + 1;
+    else hi = mid;
+  }}
+  return lo;
+}}", lookup_with_err=lookup_with_err,
+           miss_below=unlikely("lo > 0 && KEYS[lo - 1] >= key"),
+           miss_above=unlikely("hi < NUM_KEYS && KEYS[hi - 1] < key"))?;
+
+        // the overlap of the error window and the caller's window around the
+        // hint, as a position and an error (as RuntimeRMI::hinted_window)
+        writeln!(code_output, "
+uint64_t lookup_hinted_window(uint64_t key, uint64_t hint, uint64_t hint_err,
+                              size_t*{restrict} window) {{
+  {lookup_with_err}
+  uint64_t lo = (guess > err ? guess - err : 0);
+  uint64_t hi = guess + err;
+  uint64_t hint_lo = (hint > hint_err ? hint - hint_err : 0);
+  uint64_t hint_hi = (hint_err < UINT64_MAX - hint ? hint + hint_err : UINT64_MAX);
+  if ({disjoint}) {{
+    *window = err;
+    return guess;
+  }}
+  if (hint_lo > lo) lo = hint_lo;
+  if (hint_hi < hi) hi = hint_hi;
+  *window = (hi - lo + 1) / 2;
+  return lo + (hi - lo) / 2;
+}}", restrict=restrict, lookup_with_err=lookup_with_err,
+           disjoint=unlikely("hint_lo > hi || hint_hi < lo"))?;
+    }
+
+    if c89 {
+        return write_c89_header(header_output, namespace, model_size_bytes, build_time,
+                                position_unit, &lookup_sig, selftest.is_some(), info.is_some());
+    }
+    if prefix_extern_lookup && !report_last_layer_errors {
+        writeln!(code_output, "
+#ifdef EXTERN_RMI_LOOKUP
+uint64_t {}_lookup(uint64_t key) {{
+  return lookup(key);
+}}
+#endif", namespace)?;
+    }
     writeln!(code_output, "}} // namespace")?;
 
     // write out our forward declarations
     writeln!(header_output, "#include <cstddef>")?;
     writeln!(header_output, "#include <cstdint>")?;
+    if is_pure {
+        // no-op on compilers without the GNU attribute syntax
+        writeln!(header_output, "#ifndef RMI_PURE")?;
+        writeln!(header_output, "#if defined(__GNUC__) || defined(__clang__)")?;
+        writeln!(header_output, "#define RMI_PURE __attribute__((pure))")?;
+        writeln!(header_output, "#else")?;
+        writeln!(header_output, "#define RMI_PURE")?;
+        writeln!(header_output, "#endif")?;
+        writeln!(header_output, "#endif")?;
+    }
+    if restrict_pointers {
+        // C99 restrict, or the equivalent extension in C++ compilers
+        writeln!(header_output, "#ifndef RMI_RESTRICT")?;
+        writeln!(header_output, "#if defined(__GNUC__) || defined(__clang__) || defined(_MSC_VER)")?;
+        writeln!(header_output, "#define RMI_RESTRICT __restrict")?;
+        writeln!(header_output, "#elif defined(__STDC_VERSION__) && __STDC_VERSION__ >= 199901L")?;
+        writeln!(header_output, "#define RMI_RESTRICT restrict")?;
+        writeln!(header_output, "#else")?;
+        writeln!(header_output, "#define RMI_RESTRICT")?;
+        writeln!(header_output, "#endif")?;
+        writeln!(header_output, "#endif")?;
+    }
     writeln!(header_output, "namespace {} {{", namespace)?;
 
-    if let StorageConf::Disk(_) = storage {
+    if struct_result {
+        // a stable layout for FFI: two 64-bit fields, in this order, with no
+        // padding (e.g. #[repr(C)] struct RmiResult { pos: u64, err: u64 })
+        writeln!(header_output, "// what lookup returns: the predicted position, and the maximum")?;
+        writeln!(header_output, "// distance from it to the key's position. 16 bytes, no padding:")?;
+        writeln!(header_output, "// pos at offset 0, err at offset 8.")?;
+        writeln!(header_output, "struct RmiResult {{")?;
+        writeln!(header_output, "  uint64_t pos;")?;
+        writeln!(header_output, "  uint64_t err;")?;
+        writeln!(header_output, "}};")?;
+        writeln!(header_output, "static_assert(sizeof(RmiResult) == 16, \"unexpected RmiResult layout\");")?;
+    }
+
+    if let StorageConf::Disk(_, _) = storage {
         writeln!(header_output, "bool load(char const* dataPath);")?;
     }
 
@@ -660,7 +1677,13 @@ inline size_t FCLAMP(double inp, double bound) {{
     
     if !report_last_layer_errors {
         writeln!(header_output, "#ifdef EXTERN_RMI_LOOKUP")?;
-        writeln!(header_output, "extern \"C\" uint64_t lookup(uint64_t key);")?;
+        let extern_prefix = if prefix_extern_lookup {
+            format!("{}_", namespace)
+        } else {
+            String::new()
+        };
+        writeln!(header_output, "extern \"C\" uint64_t {}lookup(uint64_t key);",
+                 extern_prefix)?;
         writeln!(header_output, "#endif")?;
     }
 
@@ -676,57 +1699,2042 @@ inline size_t FCLAMP(double inp, double bound) {{
         build_time
     )?;
     writeln!(header_output, "const char NAME[] = \"{}\";", namespace)?;
+    if let PositionUnit::Offset(record_size) = position_unit {
+        writeln!(header_output, "const uint64_t RECORD_SIZE = {};", record_size)?;
+    }
+    if let Some(keys) = exact_keys {
+        writeln!(header_output, "const size_t NUM_KEYS = {};", keys.len())?;
+    }
     writeln!(header_output, "{};", lookup_sig)?;
+    if let Some(sig) = composite_sig {
+        writeln!(header_output, "{};", sig)?;
+    }
+    if let Some(sig) = float_sig {
+        writeln!(header_output, "{};", sig)?;
+    }
+    if let Some(sig) = quantized_sig {
+        writeln!(header_output, "{};", sig)?;
+    }
+    if let Some(sig) = string_sig {
+        write_string_collision_note(header_output)?;
+        writeln!(header_output, "{};", sig)?;
+    }
+    if let Some(sig) = batch_sig {
+        writeln!(header_output, "{};", sig)?;
+    }
+    if exact_keys.is_some() {
+        writeln!(header_output, "int lookup_exact(uint64_t key, uint64_t*{} pos);", restrict)?;
+    }
+    if hinted_lookup {
+        writeln!(header_output, "uint64_t lookup_hinted(uint64_t key, uint64_t hint);")?;
+        writeln!(header_output, "uint64_t lookup_hinted_window(uint64_t key, uint64_t hint, \
+                                 uint64_t hint_err, size_t*{} window);", restrict)?;
+    }
+    if selftest.is_some() {
+        writeln!(header_output, "int rmi_selftest(void);")?;
+    }
+    if info.is_some() {
+        write_info_decl(header_output, "", Dialect::Cpp)?;
+    }
     writeln!(header_output, "}}")?;
 
     return Result::Ok(());
 }
 
+// the header of a C89 RMI: the public names of the C++ header, prefixed by
+// the namespace
+#[allow(clippy::too_many_arguments)]
+fn write_c89_header<T: Write>(header_output: &mut T,
+                              namespace: &str,
+                              model_size_bytes: u64,
+                              build_time: u128,
+                              position_unit: PositionUnit,
+                              lookup_sig: &str,
+                              selftest: bool,
+                              info: bool) -> Result<(), std::io::Error> {
+    // shared by every C89 RMI, so guarded against redefinition
+    writeln!(header_output, "#ifndef RMI_C89_TYPES")?;
+    writeln!(header_output, "#define RMI_C89_TYPES")?;
+    writeln!(header_output, "typedef unsigned long rmi_u64;")?;
+    writeln!(header_output, "typedef unsigned int rmi_u32;")?;
+    writeln!(header_output, "/* a compile error here means the types above have the wrong size */")?;
+    writeln!(header_output, "typedef char rmi_u64_check[sizeof(rmi_u64) == 8 ? 1 : -1];")?;
+    writeln!(header_output, "typedef char rmi_u32_check[sizeof(rmi_u32) == 4 ? 1 : -1];")?;
+    writeln!(header_output, "#endif")?;
+
+    writeln!(header_output, "void {}_cleanup(void);", namespace)?;
+    writeln!(header_output, "static const rmi_u64 {}_RMI_SIZE = {}UL;", namespace, model_size_bytes)?;
+    assert!(build_time <= u128::from(u64::MAX));
+    writeln!(header_output, "static const rmi_u64 {}_BUILD_TIME_NS = {}UL;", namespace, build_time)?;
+    writeln!(header_output, "static const char {0}_NAME[] = \"{0}\";", namespace)?;
+    if let PositionUnit::Offset(record_size) = position_unit {
+        writeln!(header_output, "static const rmi_u64 {}_RECORD_SIZE = {}UL;",
+                 namespace, record_size)?;
+    }
+    writeln!(header_output, "{};", lookup_sig)?;
+    if selftest {
+        writeln!(header_output, "int {}_rmi_selftest(void);", namespace)?;
+    }
+    if info {
+        write_info_decl(header_output, &format!("{}_", namespace), Dialect::C89)?;
+    }
 
-pub fn output_rmi(namespace: &str,
-                  last_layer_errors: bool,
-                  trained_model: TrainedRMI,
-                  num_rows: usize,
-                  build_time: u128,
-                  data_dir: Option<&str>) -> Result<(), std::io::Error> {
-    
-    let f1 = File::create(format!("{}.cpp", namespace)).expect("Could not write RMI CPP file");
+    return Result::Ok(());
+}
+
+// build-time facts about an RMI, returned by the generated rmi_info
+#[derive(Clone, Copy, Debug)]
+pub struct RmiInfo {
+    // the number of models in the last layer
+    pub branching_factor: u64,
+
+    // the largest error over the training data (see TrainedRMI)
+    pub max_error: u64,
+
+    // the number of keys the RMI indexes, returned by rmi_count
+    pub num_keys: u64
+}
+
+impl RmiInfo {
+    // num_positions is the number of positions the RMI is emitted for,
+    // which (unlike the keys it was trained on) includes the rows left out
+    // by downsampling
+    fn from_trained(trained: &TrainedRMI, num_positions: usize) -> RmiInfo {
+        return RmiInfo {
+            branching_factor: trained.branching_factor,
+            max_error: trained.model_max_error,
+            num_keys: trained.position_unit.num_records(num_positions) as u64
+        };
+    }
+}
+
+// strings are only told apart by their first 8 bytes, which lookup_string
+// leaves to the caller
+fn write_string_collision_note<T: Write>(output: &mut T) -> std::io::Result<()> {
+    writeln!(output, "// only the first 8 bytes of key count: the result is for the first \
+                      string")?;
+    writeln!(output, "// with the same 8 byte prefix. To find key among the strings that \
+                      share it,")?;
+    writeln!(output, "// compare whole strings from the start of the error window, as")?;
+    writeln!(output, "// RuntimeRMI::lookup_string does.")?;
+    return Ok(());
+}
+
+// declares the struct returned by rmi_info and the function itself, and
+// rmi_count, all with the given prefix. The fields are in this order, and
+// the size is in bytes.
+fn write_info_decl<T: Write>(header_output: &mut T, prefix: &str,
+                             dialect: Dialect) -> Result<(), std::io::Error> {
+    let struct_name = format!("{}RmiInfo", prefix);
+    writeln!(header_output, "struct {} {{", struct_name)?;
+    for field in &["num_layers", "branching_factor", "size_bytes", "max_error"] {
+        writeln!(header_output, "  {} {};", dialect.c_type("uint64_t"), field)?;
+    }
+    writeln!(header_output, "}};")?;
+    let return_type = if dialect == Dialect::C89 { "struct " } else { "" };
+    writeln!(header_output, "{}{} {}rmi_info(void);", return_type, struct_name, prefix)?;
+    writeln!(header_output, "{} {}rmi_count(void);", dialect.c_type("uint64_t"), prefix)?;
+    return Result::Ok(());
+}
+
+// count (key, position) pairs spread evenly over the data (including the
+// first and last), to embed in the generated code for rmi_selftest
+pub fn selftest_samples(data: &ModelData, count: usize) -> Vec<(u64, u64)> {
+    assert!(count > 0, "the self-test needs at least one sample");
+    if data.len() == 0 {
+        return Vec::new();
+    }
+
+    let count = usize::min(count, data.len());
+    let mut samples = Vec::with_capacity(count);
+    for i in 0..count {
+        let idx = if count == 1 { 0 } else { i * (data.len() - 1) / (count - 1) };
+        samples.push((data.get_key(idx), data.get(idx).1 as u64));
+    }
+    return samples;
+}
+
+// an RMI to emit as part of a bundle, along with the namespace its
+// functions and parameters are placed in.
+pub struct NamedRMI {
+    pub namespace: String,
+    pub trained_model: TrainedRMI,
+    pub num_rows: usize,
+    pub build_time: u128,
+
+    // the sorted keys the RMI was trained on. If given, an exact-match
+    // lookup_exact is also generated, which needs the keys at runtime, so
+    // they are written to the data directory (and read by load).
+    pub exact_keys: Option<Vec<u64>>,
+
+    // also generate lookup_hinted, which takes a position the key is
+    // expected to be near. Requires exact_keys.
+    pub hinted_lookup: bool,
+
+    // if the keys are two packed columns, a lookup taking the two columns
+    // is also generated
+    pub composite_key: Option<CompositeKey>,
+
+    // if the keys are floats mapped by float_to_monotonic_u64 (as in
+    // ModelData::from_float_keys), a lookup_float taking the float key is
+    // also generated
+    pub float_keys: bool,
+
+    // what lookup_float does with NaN and infinite keys
+    pub non_finite_keys: NonFinitePolicy,
+
+    // if the keys were quantized with ModelData::quantize_to_int, its
+    // key_scale, with which a lookup_quantized taking the float key scales
+    // it the same way (the positions must not have been scaled)
+    pub quantized_keys: Option<f64>,
+
+    // if the keys are strings mapped by string_to_monotonic_u64 (as in
+    // ModelData::from_string_keys), a lookup_string taking the string is
+    // also generated. Its result is near the first string with the same 8
+    // byte prefix; telling those strings apart is up to the caller.
+    pub string_keys: bool,
+
+    // sampled (key, position) pairs of the training data (see
+    // selftest_samples). If given, they are embedded in the code along with
+    // rmi_selftest, which checks each lookup is within the error bound.
+    pub selftest_samples: Option<Vec<(u64, u64)>>
+}
+
+impl NamedRMI {
+    // an RMI with only the plain lookup functions
+    pub fn new(namespace: &str, trained_model: TrainedRMI, num_rows: usize,
+               build_time: u128) -> NamedRMI {
+        return NamedRMI {
+            namespace: String::from(namespace),
+            trained_model, num_rows, build_time,
+            exact_keys: None,
+            hinted_lookup: false,
+            composite_key: None,
+            float_keys: false,
+            non_finite_keys: NonFinitePolicy::default(),
+            quantized_keys: None,
+            string_keys: false,
+            selftest_samples: None
+        };
+    }
+}
+
+// writes several RMIs into the same code, data, and header outputs. Each RMI
+// lives in its own namespace, so their symbols do not collide; with more
+// than one RMI, the extern "C" lookups (with EXTERN_RMI_LOOKUP) are named
+// namespace_lookup. Returns the size in bytes of each RMI, in order.
+fn generate_bundle<T: Write>(
+    code_output: &mut T,
+    data_output: &mut T,
+    header_output: &mut T,
+    file_name: &str,
+    rmis: Vec<NamedRMI>,
+    options: &CodegenOptions,
+) -> Result<Vec<u64>, std::io::Error> {
+    let last_layer_errors = options.last_layer_errors;
+    let data_dir = options.data_dir.as_deref();
+    // without namespaces, the parameters of several RMIs would collide
+    assert!(options.dialect == Dialect::Cpp || rmis.len() == 1,
+            "C89 code can only hold one RMI per file");
+
+    // the code for each RMI includes the (shared) headers
+    writeln!(header_output, "#pragma once")?;
+    writeln!(data_output, "#pragma once")?;
+
+    let prefix_extern_lookup = rmis.len() > 1;
+    let mut sizes = Vec::with_capacity(rmis.len());
+    for named in rmis {
+        sizes.push(rmi_size(&named.trained_model.rmi, last_layer_errors));
+
+        let info = RmiInfo::from_trained(&named.trained_model, named.num_rows);
+        // an RMI trained on pages returns page indexes
+        let num_rows = named.trained_model.num_lookup_positions(named.num_rows);
+        let position_unit = named.trained_model.position_unit;
+        let pruned = named.trained_model.pruned;
+        let leaf_map = named.trained_model.leaf_map;
+        let max_error = named.trained_model.model_max_error;
+        let key_transform = named.trained_model.key_transform;
+        let lle = if last_layer_errors {
+            Some(named.trained_model.last_layer_max_l1s)
+        } else {
+            None
+        };
+
+        let conf = match data_dir {
+            None => {
+                assert!(!last_layer_errors,
+                        "Cannot directly embed RMI data and track last level errors.");
+                StorageConf::Embed
+            },
+            Some(s) => StorageConf::Disk(String::from(s), options.compress_data)
+        };
+
+        if named.exact_keys.is_some() || named.hinted_lookup {
+            assert!(last_layer_errors && data_dir.is_some(),
+                    "Exact lookups require a data directory and last level errors.");
+            assert_eq!(position_unit, PositionUnit::Element,
+                       "Exact lookups require element positions.");
+            // the keys would have to be searched by their transform
+            assert!(key_transform.is_none(), "Exact lookups cannot transform their keys.");
+        }
+
+        generate_code(code_output, data_output, header_output, file_name, &named.namespace,
+                      num_rows, named.trained_model.rmi, lle, conf, named.build_time,
+                      LookupFeatures {
+                          position_unit,
+                          exact_keys: named.exact_keys.as_deref(),
+                          composite_key: named.composite_key,
+                          pruned: pruned.as_ref(),
+                          hinted_lookup: named.hinted_lookup,
+                          float_keys: named.float_keys,
+                          non_finite_keys: named.non_finite_keys,
+                          quantized_keys: named.quantized_keys,
+                          string_keys: named.string_keys,
+                          selftest: named.selftest_samples.as_deref()
+                              .map(|samples| (samples, max_error)),
+                          key_transform,
+                          info: Some(info),
+                          leaf_map: leaf_map.as_deref(),
+                          prefix_extern_lookup
+                      },
+                      options)?;
+    }
+
+    return Ok(sizes);
+}
+
+pub fn output_rmi(named: NamedRMI, options: &CodegenOptions) -> Result<(), std::io::Error> {
+    let namespace = named.namespace.clone();
+    return output_rmis(&namespace, vec![named], options);
+}
+
+// emits all of the given RMIs into a single set of files (file_name.cpp,
+// file_name.h, and file_name_data.h), so they can be built as one library.
+pub fn output_rmis(file_name: &str,
+                   rmis: Vec<NamedRMI>,
+                   options: &CodegenOptions) -> Result<(), std::io::Error> {
+    if let Some(a) = options.alignment {
+        assert!(a.is_power_of_two(), "alignment must be a power of two, not {}", a);
+    }
+
+    let extension = if options.dialect == Dialect::C89 { "c" } else { "cpp" };
+    let f1 = File::create(format!("{}.{}", file_name, extension)).expect("Could not write RMI CPP file");
     let mut bw1 = BufWriter::new(f1);
     
     let f2 =
-        File::create(format!("{}_data.h", namespace)).expect("Could not write RMI data file");
+        File::create(format!("{}_data.h", file_name)).expect("Could not write RMI data file");
     let mut bw2 = BufWriter::new(f2);
     
-    let f3 = File::create(format!("{}.h", namespace)).expect("Could not write RMI header file");
+    let f3 = File::create(format!("{}.h", file_name)).expect("Could not write RMI header file");
     let mut bw3 = BufWriter::new(f3);
-    
-    let lle = if last_layer_errors {
-        Some(trained_model.last_layer_max_l1s)
-    } else {
-        None
+
+    let namespaces: Vec<String> = rmis.iter().map(|r| r.namespace.clone()).collect();
+    let sizes = generate_bundle(&mut bw1, &mut bw2, &mut bw3, file_name, rmis, options)?;
+
+    if namespaces.len() > 1 {
+        for (namespace, size) in namespaces.iter().zip(sizes.iter()) {
+            info!("RMI {} size: {:?} ({} bytes)", namespace, ByteSize(*size), size);
+        }
+        let total: u64 = sizes.iter().sum();
+        info!("Total size of {} RMIs in {}: {:?} ({} bytes)",
+              namespaces.len(), file_name, ByteSize(total), total);
+    }
+
+    return Ok(());
+}
+
+// compiles the generated C++ code of the RMI (with embedded parameters)
+// and checks that its lookup returns the position RuntimeRMI predicts for
+// each of the keys, clamped and rounded to a record the same way. This
+// catches codegen mistakes, such as a parameter printed with too little
+// precision, that evaluating the models in Rust cannot. The code and a
+// small driver program, which reads the keys from a file and writes the
+// positions, are built with the given compiler in a new directory in dir,
+// which is removed again (so calls can run at the same time). Returns the
+// first mismatching key, or why the code did not build or run.
+pub fn verify_compiled(trained: TrainedRMI, num_rows: usize, keys: &[u64], compiler: &str,
+                       dir: &Path) -> Result<(), String> {
+    let num_positions = trained.num_lookup_positions(num_rows) as u64;
+    let runtime = RuntimeRMI::new(trained);
+    let position_unit = runtime.trained().position_unit;
+    let expected: Vec<u64> = keys.iter()
+        .map(|key| position_unit.lookup_position(runtime.predict((*key).into()), num_positions))
+        .collect();
+
+    let named = NamedRMI::new("roundtrip", runtime.into_trained(), num_rows, 0);
+    let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+    generate_bundle(&mut code, &mut data_h, &mut header, "rmi_roundtrip", vec![named],
+                    &CodegenOptions::default()).map_err(|e| e.to_string())?;
+
+    let main = "#include \"rmi_roundtrip.h\"
+#include <cstdio>
+int main(int argc, char** argv) {
+  if (argc != 3) return 1;
+  FILE* in = std::fopen(argv[1], \"rb\");
+  FILE* out = std::fopen(argv[2], \"wb\");
+  if (in == NULL || out == NULL) return 1;
+  uint64_t key;
+  while (std::fread(&key, sizeof(key), 1, in) == 1) {
+    uint64_t pos = roundtrip::lookup(key);
+    if (std::fwrite(&pos, sizeof(pos), 1, out) != 1) return 1;
+  }
+  std::fclose(in);
+  return std::fclose(out) == 0 ? 0 : 1;
+}";
+    let key_bytes: Vec<u8> = keys.iter().flat_map(|key| key.to_ne_bytes().to_vec()).collect();
+    let files: [(&str, Vec<u8>); 5] = [("rmi_roundtrip.cpp", code),
+                                       ("rmi_roundtrip.h", header),
+                                       ("rmi_roundtrip_data.h", data_h),
+                                       ("rmi_roundtrip_main.cpp", main.as_bytes().to_vec()),
+                                       ("rmi_roundtrip_keys", key_bytes)];
+    // unique within this process (by the counter) and among processes
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+    let dir = dir.join(format!("rmi_roundtrip_{}_{}", std::process::id(),
+                               NEXT_DIR.fetch_add(1, Ordering::Relaxed)));
+    let binary = dir.join("rmi_roundtrip_main");
+    let results = dir.join("rmi_roundtrip_positions");
+    let run = || -> Result<Vec<u8>, String> {
+        std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        for (file, contents) in files.iter() {
+            std::fs::write(dir.join(file), contents).map_err(|e| e.to_string())?;
+        }
+
+        let compiled = std::process::Command::new(compiler)
+            .args(["-std=c++17", "-O2", "-o"])
+            .arg(&binary)
+            .arg(dir.join(files[0].0))
+            .arg(dir.join(files[3].0))
+            .output()
+            .map_err(|e| format!("could not run {}: {}", compiler, e))?;
+        if !compiled.status.success() {
+            return Err(String::from_utf8_lossy(&compiled.stderr).to_string());
+        }
+
+        let status = std::process::Command::new(&binary)
+            .arg(dir.join(files[4].0))
+            .arg(&results)
+            .status()
+            .map_err(|e| e.to_string())?;
+        if !status.success() {
+            return Err(format!("the compiled lookup failed: {}", status));
+        }
+        return std::fs::read(&results).map_err(|e| e.to_string());
     };
+    let output = run();
+    // it may not exist if it could not be created
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let output = output?;
+    if output.len() != keys.len() * 8 {
+        return Err(format!("the compiled lookup returned {} positions for {} keys",
+                           output.len() / 8, keys.len()));
+    }
+    for (idx, chunk) in output.chunks(8).enumerate() {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(chunk);
+        let pos = u64::from_ne_bytes(bytes);
+        if pos != expected[idx] {
+            return Err(format!("key {}: the compiled lookup returned {}, RuntimeRMI {}",
+                               keys[idx], pos, expected[idx]));
+        }
+    }
 
-    let conf = match data_dir {
-        None => {
-            assert!(!last_layer_errors,
-                    "Cannot directly embed RMI data and track last level errors.");
-            StorageConf::Embed
-        },
-        Some(s) => StorageConf::Disk(String::from(s))
+    return Ok(());
+}
+
+// the Rust code of an RMI whose models are all integer only (as
+// fixed_point_rmi makes them), with the error of each last layer model
+fn generate_rust_no_std<T: Write>(
+    output: &mut T,
+    namespace: &str,
+    total_rows: usize,
+    trained: &TrainedRMI,
+    last_layer_errors: &[u64],
+    build_time: u128,
+) -> Result<(), std::io::Error> {
+    let rmi = &trained.rmi;
+    assert!(total_rows > 0, "Cannot generate a lookup without any positions");
+    assert!(build_time <= u128::from(u64::MAX));
+
+    writeln!(output, "// The {} RMI, for #![no_std] crates. Include this file as a module",
+             namespace)?;
+    writeln!(output, "// (e.g. #[path = \"{0}.rs\"] mod {0};): it only uses core, integer",
+             namespace)?;
+    writeln!(output, "// arithmetic, and static arrays.")?;
+    writeln!(output, "#![allow(dead_code)]")?;
+    writeln!(output)?;
+    writeln!(output, "pub const NAME: &str = \"{}\";", namespace)?;
+    let leaf_map_size = trained.leaf_map.as_ref().map(|m| m.len() as u64 * 8).unwrap_or(0);
+    writeln!(output, "pub const RMI_SIZE: u64 = {};", rmi_size(rmi, true) + leaf_map_size)?;
+    writeln!(output, "pub const BUILD_TIME_NS: u64 = {};", build_time)?;
+    if let PositionUnit::Offset(record_size) = trained.position_unit {
+        writeln!(output, "pub const RECORD_SIZE: u64 = {};", record_size)?;
+    }
+    writeln!(output)?;
+    writeln!(output, "// the number of keys the RMI indexes")?;
+    writeln!(output, "pub const fn rmi_count() -> u64 {{")?;
+    writeln!(output, "    {}", trained.position_unit.num_records(total_rows))?;
+    writeln!(output, "}}")?;
+    writeln!(output)?;
+
+    let array = |name: &str, values: Vec<u64>| -> String {
+        let items: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        return format!("static {}: [u64; {}] = [{}];", name, items.len(), items.join(", "));
+    };
+    for (layer_idx, layer) in rmi.iter().enumerate() {
+        let params: Vec<u64> = layer.iter().flat_map(|model| model.params()).map(|p| match p {
+            ModelParam::Int(v) => v,
+            _ => panic!("The {} model has parameters that are not integers",
+                        layer[0].function_name())
+        }).collect();
+        writeln!(output, "{}", array(&format!("L{}_PARAMETERS", layer_idx), params))?;
+    }
+    if let Some(leaf_map) = &trained.leaf_map {
+        writeln!(output, "{}", array("LEAF_MAP", leaf_map.clone()))?;
+    }
+    writeln!(output, "{}", array("LAST_LAYER_ERRORS", last_layer_errors.to_vec()))?;
+
+    let mut functions = HashSet::new();
+    for layer in rmi.iter() {
+        if functions.insert(layer[0].function_name()) {
+            writeln!(output, "{}", layer[0].rust_code().unwrap_or_else(|| {
+                panic!("The {} model cannot be emitted as no_std Rust", layer[0].function_name())
+            }))?;
+        }
+    }
+
+    writeln!(output, "
+// the estimated position of the key and the largest distance from it to the
+// position of a training key that reaches the same last layer model
+pub fn lookup(key: u64) -> (u64, u64) {{")?;
+    if let Some(transform) = trained.key_transform {
+        writeln!(output, "    let key = key.saturating_sub({}) / {};",
+                 transform.offset, transform.scale)?;
+    }
+
+    for (layer_idx, layer) in rmi.iter().enumerate() {
+        let num_params = layer[0].params().len();
+        let args: Vec<String> = (0..num_params).map(|p| if layer_idx == 0 {
+            format!("L0_PARAMETERS[{}]", p)
+        } else {
+            format!("L{}_PARAMETERS[{} * model_index + {}]", layer_idx, num_params, p)
+        }).collect();
+        writeln!(output, "    let pred = {}({}, key);", layer[0].function_name(), args.join(", "))?;
+
+        if layer_idx + 1 < rmi.len() {
+            let next_layer_size = match &trained.leaf_map {
+                Some(map) if layer_idx + 2 == rmi.len() => map.len(),
+                _ => rmi[layer_idx + 1].len()
+            };
+            writeln!(output,
+                     "    let model_index = if pred > {0} {{ {0} }} else {{ pred as usize }};",
+                     next_layer_size - 1)?;
+        }
+        if layer_idx + 2 == rmi.len() && trained.leaf_map.is_some() {
+            writeln!(output, "    let model_index = LEAF_MAP[model_index] as usize;")?;
+        }
+    }
+
+    writeln!(output, "    let pos = if pred > {0} {{ {0} }} else {{ pred }};", total_rows - 1)?;
+    let pos = match trained.position_unit {
+        PositionUnit::Element => "pos",
+        PositionUnit::Offset(_) => "pos - pos % RECORD_SIZE"
     };
+    let leaf = if rmi.len() > 1 { "model_index" } else { "0" };
+    writeln!(output, "    ({}, LAST_LAYER_ERRORS[{}])", pos, leaf)?;
+    writeln!(output, "}}")?;
 
-    
-    return generate_code(
-        &mut bw1,
-        &mut bw2,
-        &mut bw3,
-        namespace,
-        num_rows,
-        trained_model.rmi,
-        lle,
-        conf,
-        build_time,
-    );
-        
-    
+    return Ok(());
+}
+
+// the RMI in fixed point, and the largest error of each of its last layer
+// models over the data (clamped and rounded to a record like lookup)
+fn measure_fixed_point(mut trained_model: TrainedRMI, data: &ModelDataWrapper,
+                       num_rows: usize) -> (RuntimeRMI, Vec<u64>) {
+    assert!(trained_model.pruned.is_none(), "no_std Rust code does not support pruned leaves");
+    trained_model.rmi = fixed_point_rmi(std::mem::take(&mut trained_model.rmi));
+
+    let runtime = RuntimeRMI::new(trained_model);
+    let position_unit = runtime.trained().position_unit;
+    let mut errors = vec![0; runtime.trained().rmi.last().unwrap().len()];
+    for (key, pos) in data.iter_int_int() {
+        let pred = u64::min(runtime.predict(key.into()), num_rows as u64 - 1);
+        let pred = match position_unit {
+            PositionUnit::Element => pred,
+            PositionUnit::Offset(record_size) => pred - pred % record_size
+        };
+        let leaf = runtime.route(key.into());
+        errors[leaf] = u64::max(errors[leaf], u64::max(pred, pos) - u64::min(pred, pos));
+    }
+
+    return (runtime, errors);
+}
+
+// emits the RMI as Rust for #![no_std] crates (namespace.rs), such as
+// firmware for microcontrollers without an FPU: the parameters are static
+// arrays, and the models are evaluated in fixed point (see FixedPointLine),
+// so only lines and radix models are supported. Its lookup(key) returns the
+// position and an error, measured here over the data with the fixed-point
+// models, since their predictions can differ from the trained ones. The data
+// must be the (untransformed) data the RMI was trained on.
+pub fn output_rust_no_std(namespace: &str,
+                          trained_model: TrainedRMI,
+                          data: &ModelDataWrapper,
+                          num_rows: usize,
+                          build_time: u128) -> Result<(), std::io::Error> {
+    let (runtime, errors) = measure_fixed_point(trained_model, data, num_rows);
+    let f = File::create(format!("{}.rs", namespace)).expect("Could not write RMI Rust file");
+    let mut bw = BufWriter::new(f);
+    return generate_rust_no_std(&mut bw, namespace, num_rows, runtime.trained(), &errors,
+                                build_time);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train::{train, train_with_options, TrainingOptions};
+
+    // writes the files to dir, builds the generated code (the first file)
+    // and the test's main (the last) with the C++ compiler and the given
+    // flags, and runs the program with dir as its argument. The files and
+    // the program are removed again. Returns None without the
+    // codegen-roundtrip feature, so plain test runs need no C++ compiler.
+    fn compile_and_run<T: AsRef<[u8]>>(dir: &Path, files: &[(&str, T)],
+                                       flags: &[&str]) -> Option<std::process::Output> {
+        if !cfg!(feature = "codegen-roundtrip") {
+            return None;
+        }
+        for (file, contents) in files.iter() {
+            std::fs::write(dir.join(file), contents).unwrap();
+        }
+
+        let (code, main) = (files[0].0, files[files.len() - 1].0);
+        let binary = dir.join(main.trim_end_matches(".cpp"));
+        let compiled = std::process::Command::new("c++")
+            .arg("-std=c++17")
+            .args(flags)
+            .arg("-o")
+            .arg(&binary)
+            .arg(dir.join(code))
+            .arg(dir.join(main))
+            .output()
+            .expect("the codegen-roundtrip feature needs a C++ compiler (c++)");
+        assert!(compiled.status.success(), "{}", String::from_utf8_lossy(&compiled.stderr));
+        let output = std::process::Command::new(&binary).arg(dir).output().unwrap();
+        std::fs::remove_file(&binary).unwrap();
+        for (file, _) in files.iter() {
+            std::fs::remove_file(dir.join(file)).unwrap();
+        }
+        return Some(output);
+    }
+
+    // writes the files to dir and compiles the generated code (the first
+    // file) to an object with the given compiler and flags, which must
+    // succeed. The files and the object are removed again. Does nothing
+    // without the codegen-roundtrip feature.
+    fn compile_only<T: AsRef<[u8]>>(compiler: &str, flags: &[&str], dir: &Path,
+                                    files: &[(String, T)]) {
+        if !cfg!(feature = "codegen-roundtrip") {
+            return;
+        }
+        for (file, contents) in files.iter() {
+            std::fs::write(dir.join(file), contents).unwrap();
+        }
+
+        let object = dir.join(format!("{}.o", files[0].0));
+        let compiled = std::process::Command::new(compiler)
+            .args(flags)
+            .arg("-c")
+            .arg("-o")
+            .arg(&object)
+            .arg(dir.join(&files[0].0))
+            .output()
+            .unwrap_or_else(|e| panic!("the codegen-roundtrip feature needs {}: {}", compiler, e));
+        for (file, _) in files.iter() {
+            std::fs::remove_file(dir.join(file)).unwrap();
+        }
+        assert!(compiled.status.success(), "{}", String::from_utf8_lossy(&compiled.stderr));
+        std::fs::remove_file(&object).unwrap();
+    }
+
+    #[test]
+    fn test_bundle() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let rmis: Vec<NamedRMI> = ["first", "second"].iter().map(|ns| {
+            let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+            NamedRMI::new(ns, trained, data.len(), 0)
+        }).collect();
+        let expected_size = rmi_size(&rmis[0].trained_model.rmi, false);
+
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        let sizes = generate_bundle(&mut code, &mut data_h, &mut header, "bundle", rmis,
+                                    &CodegenOptions::default()).unwrap();
+        assert_eq!(sizes, vec![expected_size, expected_size]);
+
+        let code = String::from_utf8(code).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        let data_h = String::from_utf8(data_h).unwrap();
+        for ns in &["first", "second"] {
+            let open = format!("namespace {} {{", ns);
+            assert!(code.contains(&open));
+            assert!(header.contains(&open));
+            assert!(data_h.contains(&open));
+        }
+        assert!(code.contains("#include \"bundle.h\""));
+        assert_eq!(header.matches("#pragma once").count(), 1);
+        assert_eq!(header.matches("const char NAME[]").count(), 2);
+
+        // num_layers, branching_factor, size_bytes, max_error
+        assert_eq!(header.matches("RmiInfo rmi_info(void);").count(), 2);
+        assert_eq!(header.matches("uint64_t rmi_count(void);").count(), 2);
+        assert_eq!(code.matches(&format!("  return {}ULL;", data.len())).count(), 2);
+        let info = format!("RmiInfo info = {{ 2ULL, 8ULL, {}ULL, ", expected_size);
+        assert_eq!(code.matches(&info).count(), 2);
+    }
+
+    #[test]
+    fn test_bundle_extern_lookup() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let generate = |namespaces: &[&str]| {
+            let rmis: Vec<NamedRMI> = namespaces.iter().map(|ns| {
+                let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8)
+                    .unwrap();
+                NamedRMI::new(ns, trained, data.len(), 0)
+            }).collect();
+            let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+            generate_bundle(&mut code, &mut data_h, &mut header, "rmi_extern", rmis,
+                            &CodegenOptions::default()).unwrap();
+            return (String::from_utf8(code).unwrap(), String::from_utf8(data_h).unwrap(),
+                    String::from_utf8(header).unwrap());
+        };
+
+        // a single RMI keeps the plain symbol
+        let (code, _, header) = generate(&["single"]);
+        assert!(header.contains("extern \"C\" uint64_t lookup(uint64_t key);"));
+        assert!(!code.contains("single_lookup"));
+
+        // but the symbols of several would collide
+        let (code, data_h, header) = generate(&["first", "second"]);
+        assert!(!header.contains("extern \"C\" uint64_t lookup("));
+        for ns in &["first", "second"] {
+            let decl = format!("extern \"C\" uint64_t {}_lookup(uint64_t key);", ns);
+            assert_eq!(header.matches(&decl).count(), 1);
+        }
+
+        let dir = std::env::temp_dir();
+        // declared at global scope, as a C caller would
+        let main = "#include \"rmi_extern.h\"
+extern \"C\" uint64_t first_lookup(uint64_t key);
+extern \"C\" uint64_t second_lookup(uint64_t key);
+int main() {
+  for (uint64_t i = 0; i < 1000; i++) {
+    if (first_lookup(i * i) != first::lookup(i * i)) return 1;
+    if (second_lookup(i * i) != second::lookup(i * i)) return 2;
+  }
+  return 0;
+}";
+        let files = [("rmi_extern.cpp", code), ("rmi_extern.h", header),
+                     ("rmi_extern_data.h", data_h), ("rmi_extern_main.cpp", String::from(main))];
+        if let Some(output) = compile_and_run(&dir, &files, &["-DEXTERN_RMI_LOOKUP"]) {
+            assert_eq!(output.status.code(), Some(0));
+        }
+    }
+
+    #[test]
+    fn test_downsampled_count() {
+        // every fourth of 1000 rows, which keep their positions
+        let data = ModelData::IntKeyToIntPos((0..1000).step_by(4).map(|i| (i * i, i)).collect());
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+        assert_eq!(trained.num_keys, 250);
+
+        // rmi_count is the rows the RMI is emitted for, not the sample
+        let named = NamedRMI::new("sampled", trained, 1000, 0);
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_bundle(&mut code, &mut data_h, &mut header, "sampled", vec![named],
+                        &CodegenOptions::default()).unwrap();
+        let code = String::from_utf8(code).unwrap();
+        assert!(code.contains("rmi_count(void) {\n  return 1000ULL;"), "{}", code);
+
+        // as does RuntimeRMI::len, once the dataset size is set (as main does)
+        let mut trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+        trained.num_keys = 1000;
+        assert_eq!(RuntimeRMI::new(trained).len(), 1000);
+    }
+
+    #[test]
+    fn test_cstring_include() {
+        // only float keys and the models that store doubles as their bits
+        // copy them with std::memcpy
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let generate = |models: &str| {
+            let trained = train::train_single_layer(&mut ModelDataWrapper::new(&data), models)
+                .unwrap();
+            let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+            generate_code(&mut code, &mut data_h, &mut header, "cstr", "cstr", data.len(),
+                          trained.rmi, None, StorageConf::Embed, 0, LookupFeatures::default(),
+                          &CodegenOptions::default()).unwrap();
+            return String::from_utf8(code).unwrap();
+        };
+
+        assert!(!generate("linear").contains("#include <cstring>"));
+        assert!(!generate("plr").contains("#include <cstring>"));
+        assert!(generate("plr_aos").contains("#include <cstring>"));
+    }
+
+    #[test]
+    fn test_pure_hint() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let generate = |pure_hint: bool, storage: StorageConf, lle: Option<Vec<u64>>| {
+            let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+            let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+            generate_code(&mut code, &mut data_h, &mut header, "pure", "pure", data.len(),
+                          trained.rmi, lle, storage, 0, LookupFeatures::default(),
+                          &CodegenOptions { pure_hint, ..Default::default() }).unwrap();
+            return (String::from_utf8(code).unwrap(), String::from_utf8(header).unwrap());
+        };
+
+        let (code, header) = generate(true, StorageConf::Embed, None);
+        assert!(header.contains("#define RMI_PURE __attribute__((pure))"));
+        assert!(header.contains("RMI_PURE uint64_t lookup(uint64_t key);"));
+        assert!(code.contains("RMI_PURE uint64_t lookup(uint64_t key) {"));
+
+        // loaded parameters and error pointers are not side-effect free
+        let tmp = std::env::temp_dir().to_str().unwrap().to_string();
+        let (code, header) = generate(true, StorageConf::Disk(tmp.clone(), false),
+                                      Some(vec![0; 8]));
+        assert!(!header.contains("RMI_PURE") && !code.contains("RMI_PURE"));
+
+        let (code, header) = generate(true, StorageConf::Disk(tmp, false), None);
+        assert!(!header.contains("RMI_PURE") && !code.contains("RMI_PURE"));
+
+        let (code, header) = generate(false, StorageConf::Embed, None);
+        assert!(!header.contains("RMI_PURE") && !code.contains("RMI_PURE"));
+    }
+
+    #[test]
+    fn test_offsets() {
+        let mut data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        data.positions_to_offsets(24);
+        let mut md = ModelDataWrapper::new(&data);
+        md.set_position_unit(PositionUnit::Offset(24));
+        let trained = train(&mut md, "linear,linear", 8).unwrap();
+        assert_eq!(trained.position_unit, PositionUnit::Offset(24));
+
+        let tmp = std::env::temp_dir().to_str().unwrap().to_string();
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        let lle = trained.last_layer_max_l1s.clone();
+        generate_code(&mut code, &mut data_h, &mut header, "offsets", "offsets", md.num_positions(),
+                      trained.rmi, Some(lle), StorageConf::Disk(tmp, false), 0,
+                      LookupFeatures { position_unit: trained.position_unit, ..Default::default() },
+                      &CodegenOptions::default()).unwrap();
+
+        let code = String::from_utf8(code).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.contains("const uint64_t RECORD_SIZE = 24;"));
+        assert!(code.contains("return pos - pos % RECORD_SIZE;"));
+        assert!(code.contains("FCLAMP(fpred, 24000.0 - 1.0)"));
+    }
+
+    #[test]
+    fn test_exact_lookup() {
+        let keys: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+        let lle = trained.last_layer_max_l1s.clone();
+
+        let tmp = std::env::temp_dir();
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "exact", "exact", data.len(),
+                      trained.rmi, Some(lle),
+                      StorageConf::Disk(tmp.to_str().unwrap().to_string(), false), 0,
+                      LookupFeatures { exact_keys: Some(&keys), ..Default::default() },
+                      &CodegenOptions::default()).unwrap();
+
+        let code = String::from_utf8(code).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.contains("int lookup_exact(uint64_t key, uint64_t* pos);"));
+        assert!(header.contains("const size_t NUM_KEYS = 1000;"));
+        assert!(header.contains("uint64_t lookup(uint64_t key, size_t* err);"));
+        assert!(code.contains("KEYS = (uint64_t*) malloc(8000);"));
+        assert!(code.contains("free(KEYS);"));
+
+        let key_file = tmp.join("exact_KEYS");
+        let written = std::fs::read(&key_file).unwrap();
+        std::fs::remove_file(&key_file).unwrap();
+        assert_eq!(written.len(), 8000);
+        assert_eq!(&written[8..16], &1u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_struct_result() {
+        let keys: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+        let lle = trained.last_layer_max_l1s.clone();
+        let samples = selftest_samples(&data, 10);
+
+        let dir = std::env::temp_dir().join("rmi_struct_result");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "rmi_struct", "rmi_struct", data.len(),
+                      trained.rmi, Some(lle),
+                      StorageConf::Disk(dir.to_str().unwrap().to_string(), false), 0,
+                      LookupFeatures {
+                          exact_keys: Some(&keys), hinted_lookup: true, float_keys: true,
+                          selftest: Some((&samples, 0)), ..Default::default()
+                      },
+                      &CodegenOptions { struct_result: true, ..Default::default() }).unwrap();
+
+        let code = String::from_utf8(code).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.contains("struct RmiResult {\n  uint64_t pos;\n  uint64_t err;\n};"));
+        assert!(header.contains("RmiResult lookup(uint64_t key);"));
+        assert!(header.contains("RmiResult lookup_float(double key);"));
+        assert!(header.contains("uint64_t lookup_hinted(uint64_t key, uint64_t hint);"));
+        assert!(!code.contains("size_t* err"));
+        assert!(code.contains("  return res;"));
+
+        let main = "#include \"rmi_struct.h\"
+int main(int, char** argv) {
+  if (!rmi_struct::load(argv[1])) return 1;
+  for (uint64_t i = 0; i < 1000; i++) {
+    rmi_struct::RmiResult res = rmi_struct::lookup(i * i);
+    if ((res.pos > i ? res.pos - i : i - res.pos) > res.err) return 2;
+    uint64_t pos;
+    if (!rmi_struct::lookup_exact(i * i, &pos) || pos != i) return 3;
+  }
+  if (rmi_struct::rmi_selftest() != 0) return 4;
+  rmi_struct::cleanup();
+  return 0;
+}";
+        let files = [("rmi_struct.cpp", code), ("rmi_struct.h", header),
+                     ("rmi_struct_data.h", String::from_utf8(data_h).unwrap()),
+                     ("rmi_struct_main.cpp", String::from(main))];
+        let output = compile_and_run(&dir, &files, &[]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        if let Some(output) = output {
+            assert_eq!(output.status.code(), Some(0));
+        }
+    }
+
+    #[test]
+    fn test_simd_batch() {
+        // keys up to 1.25 * 10^19, so most need rounding to become doubles
+        let keys: Vec<u64> = (0..5000u64).map(|i| i * i * i * 100_000_000 + i % 13).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let queries: Vec<String> = keys.iter().step_by(9)
+            .chain([0, 1 << 53, (1 << 53) + 1, u64::MAX - 1, u64::MAX].iter())
+            .map(|k| format!("{}UL", k))
+            .collect();
+        assert_ne!(queries.len() % 4, 0);
+
+        // the compiled code is run with and without AVX2, if the CPU has it
+        let mut flag_sets: Vec<&[&str]> = vec![&["-O2"]];
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+                flag_sets.push(&["-O2", "-mavx2", "-mfma"]);
+            }
+        }
+
+        // (layers, last level errors, struct result)
+        for (models, errors, struct_result) in &[("linear,linear", false, false),
+                                                 ("radix,linear", true, false),
+                                                 ("linear,linear", true, true),
+                                                 ("cubic,linear", false, false)] {
+            let trained = train(&mut ModelDataWrapper::new(&data), models, 64).unwrap();
+            let lle = if *errors { Some(trained.last_layer_max_l1s.clone()) } else { None };
+            let dir = std::env::temp_dir().join("rmi_simd_batch");
+            std::fs::create_dir_all(&dir).unwrap();
+            let storage = if *errors {
+                StorageConf::Disk(dir.to_str().unwrap().to_string(), false)
+            } else {
+                StorageConf::Embed
+            };
+            let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+            generate_code(&mut code, &mut data_h, &mut header, "batch", "batch", data.len(),
+                          trained.rmi, lle, storage, 0, LookupFeatures::default(),
+                          &CodegenOptions {
+                              struct_result: *struct_result, simd_batch: true, ..Default::default()
+                          }).unwrap();
+            let code = String::from_utf8(code).unwrap();
+            let header = String::from_utf8(header).unwrap();
+
+            let errs_param = if *errors { ", size_t* errs" } else { "" };
+            assert!(header.contains(&format!(
+                "void lookup_batch(const uint64_t* keys, size_t n, uint64_t* out{});", errs_param
+            )), "{}", header);
+            let vectorized = models.starts_with("linear") || models.starts_with("radix");
+            assert_eq!(code.contains("lookup_from_top"), vectorized);
+            if models.starts_with("linear") {
+                assert!(code.contains("_mm256_storeu_pd(preds, linear_avx2(L0_PARAMETER0, \
+                                       L0_PARAMETER1, _mm256_loadu_si256("));
+            }
+
+            // each batch result is the same as lookup's
+            let (single, batch) = if *struct_result {
+                ("batch::RmiResult res = batch::lookup(QUERIES[i]);
+    pos = res.pos;
+    err = res.err;", "batch::lookup_batch(QUERIES, N, out, errs);")
+            } else if *errors {
+                ("pos = batch::lookup(QUERIES[i], &err);",
+                 "batch::lookup_batch(QUERIES, N, out, errs);")
+            } else {
+                ("pos = batch::lookup(QUERIES[i]);", "batch::lookup_batch(QUERIES, N, out);")
+            };
+            let main = format!("#include \"batch.h\"
+#include <cstdio>
+const size_t N = {n};
+const uint64_t QUERIES[] = {{ {queries} }};
+uint64_t out[N];
+size_t errs[N];
+int main(int argc, char** argv) {{
+  if (argc != 2) return 1;
+  {load}
+#if defined(__AVX2__) && defined(__FMA__)
+  std::puts(\"simd\");
+#else
+  std::puts(\"scalar\");
+#endif
+  {batch}
+  for (size_t i = 0; i < N; i++) {{
+    uint64_t pos;
+    size_t err = 0;
+    {single}
+    if (out[i] != pos || ({errors} && errs[i] != err)) return 2;
+  }}
+  return 0;
+}}", n = queries.len(), queries = queries.join(", "), batch = batch, single = single,
+                               errors = if *errors { 1 } else { 0 },
+                               load = if *errors { "if (!batch::load(argv[1])) return 1;" }
+                                      else { "" });
+
+            let files = [("batch.cpp", code), ("batch.h", header),
+                         ("batch_data.h", String::from_utf8(data_h).unwrap()),
+                         ("batch_main.cpp", main)];
+            let outputs: Vec<std::process::Output> = flag_sets.iter()
+                .filter_map(|flags| compile_and_run(&dir, &files, flags))
+                .collect();
+            std::fs::remove_dir_all(&dir).unwrap();
+
+            for (output, mode) in outputs.iter().zip(&["scalar\n", "simd\n"]) {
+                assert_eq!(output.status.code(), Some(0), "{} {}", models, mode);
+                assert_eq!(String::from_utf8_lossy(&output.stdout), *mode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_array_elements() {
+        let keys: Vec<u64> = (0..20_000).map(|i| i * i + (i % 7) * 3).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let generate_with = |models: &str, max_array_elements: Option<usize>| {
+            let trained = train(&mut ModelDataWrapper::new(&data), models, 1000).unwrap();
+            let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+            generate_code(&mut code, &mut data_h, &mut header, "chunked", "chunked", data.len(),
+                          trained.rmi, None, StorageConf::Embed, 0, LookupFeatures::default(),
+                          &CodegenOptions { max_array_elements, ..Default::default() }).unwrap();
+            return (String::from_utf8(code).unwrap(), String::from_utf8(header).unwrap(),
+                    String::from_utf8(data_h).unwrap());
+        };
+        let generate = |max_array_elements| generate_with("linear,linear", max_array_elements);
+
+        let (code, _, data_h) = generate(None);
+        assert!(data_h.contains("const double L1_PARAMETERS[] = {"));
+        assert!(code.contains("L1_PARAMETERS[2*modelIndex + 0]"));
+        assert_eq!(generate(Some(2000)).2, data_h);
+
+        // 150 models fit in 300 elements, rounded down to 128 models per
+        // array, so the 1000 models take 8 arrays
+        let (code, header, data_h) = generate(Some(300));
+        assert!(data_h.contains("const double L1_PARAMETERS_7[] = {"));
+        assert!(!data_h.contains("L1_PARAMETERS_8"));
+        assert!(data_h.contains("const double* const L1_PARAMETERS[] = { L1_PARAMETERS_0, \
+                                 L1_PARAMETERS_1, L1_PARAMETERS_2, L1_PARAMETERS_3, \
+                                 L1_PARAMETERS_4, L1_PARAMETERS_5, L1_PARAMETERS_6, \
+                                 L1_PARAMETERS_7 };"));
+        assert!(code.contains("L1_PARAMETERS[modelIndex / 128][2*(modelIndex % 128) + 1]"));
+        // the top model's two parameters are constants either way
+        assert!(data_h.contains("const double L0_PARAMETER0 = "));
+
+        // the single array of a radix2l top model is split into rows
+        let (radix_code, radix_header, radix_data_h) = generate_with("radix2l,linear", Some(300));
+        assert!(radix_data_h.contains("L0_PARAMETER0_ROWS[][300] = {{"));
+        assert!(radix_data_h.contains("* const L0_PARAMETER0 = L0_PARAMETER0_ROWS[0];"));
+
+        // both split lookups give the same positions as RuntimeRMI
+        for (models, code, header, data_h) in [("linear,linear", code, header, data_h),
+                                               ("radix2l,linear", radix_code, radix_header,
+                                                radix_data_h)].iter() {
+            check_chunked_lookups(models, &keys, &data, code, header, data_h);
+        }
+    }
+
+    fn check_chunked_lookups(models: &str, keys: &[u64], data: &ModelData,
+                             code: &str, header: &str, data_h: &str) {
+        let rmi = crate::runtime::RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(data), models, 1000).unwrap()
+        );
+        let (queries, expected): (Vec<String>, Vec<String>) = keys.iter().step_by(37)
+            .chain([u64::MAX].iter())
+            .map(|k| (format!("{}UL", k), format!("{}UL", u64::min(rmi.predict((*k).into()),
+                                                                    keys.len() as u64 - 1))))
+            .unzip();
+        let main = format!("#include \"chunked.h\"
+const uint64_t QUERIES[] = {{ {} }};
+const uint64_t EXPECTED[] = {{ {} }};
+int main() {{
+  for (size_t i = 0; i < {}; i++) {{
+    if (chunked::lookup(QUERIES[i]) != EXPECTED[i]) return 1;
+  }}
+  return 0;
+}}", queries.join(", "), expected.join(", "), queries.len());
+
+        let files = [("chunked.cpp", code), ("chunked.h", header), ("chunked_data.h", data_h),
+                     ("chunked_main.cpp", main.as_str())];
+        if let Some(output) = compile_and_run(&std::env::temp_dir(), &files, &[]) {
+            assert_eq!(output.status.code(), Some(0), "{}", models);
+        }
+    }
+
+    #[test]
+    fn test_single_model_array() {
+        let keys: Vec<u64> = (0..20_000).map(|i| i * i + (i % 7) * 3).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let rmi = crate::runtime::RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "radix2l,linear", 64).unwrap()
+        );
+        assert!(rmi.trained().rmi[0][0].params()[0].size() > 4096);
+
+        let dir = std::env::temp_dir().join("rmi_single_model_array");
+        std::fs::create_dir_all(&dir).unwrap();
+        let generate = |storage: StorageConf| {
+            let trained = train(&mut ModelDataWrapper::new(&data), "radix2l,linear", 64).unwrap();
+            let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+            generate_code(&mut code, &mut data_h, &mut header, "single", "single", data.len(),
+                          trained.rmi, None, storage, 0, LookupFeatures::default(),
+                          &CodegenOptions::default()).unwrap();
+            return (String::from_utf8(code).unwrap(), String::from_utf8(header).unwrap(),
+                    String::from_utf8(data_h).unwrap());
+        };
+
+        // the top model's table is written as the array itself when it is
+        // embedded, and to the data directory otherwise
+        let embedded = generate(StorageConf::Embed);
+        assert!(embedded.2.contains("L0_PARAMETERS[] = {"));
+        assert!(!embedded.2.contains("{{"));
+        let on_disk = generate(StorageConf::Disk(dir.to_str().unwrap().to_string(), false));
+        assert!(!on_disk.2.contains("L0_PARAMETERS[] = {"));
+        assert!(dir.join("single_L0_PARAMETERS").exists());
+
+        // and only the array loaded from there is freed by cleanup
+        assert!(!embedded.0.contains("free("));
+        assert!(on_disk.0.contains("free(L0_PARAMETERS);"));
+
+        let (queries, expected): (Vec<String>, Vec<String>) = keys.iter().step_by(37)
+            .chain([u64::MAX].iter())
+            .map(|k| (format!("{}UL", k), format!("{}UL", u64::min(rmi.predict((*k).into()),
+                                                                    keys.len() as u64 - 1))))
+            .unzip();
+        for (load, (code, header, data_h)) in [("", embedded),
+                                               ("if (!single::load(argv[1])) return 2;", on_disk)]
+            .iter() {
+            let main = format!("#include \"single.h\"
+const uint64_t QUERIES[] = {{ {} }};
+const uint64_t EXPECTED[] = {{ {} }};
+int main(int argc, char** argv) {{
+  {}
+  for (size_t i = 0; i < {}; i++) {{
+    if (single::lookup(QUERIES[i]) != EXPECTED[i]) return 1;
+  }}
+  return 0;
+}}", queries.join(", "), expected.join(", "), load, queries.len());
+            let files = [("single.cpp", code.as_str()), ("single.h", header),
+                         ("single_data.h", data_h), ("single_main.cpp", &main)];
+            if let Some(output) = compile_and_run(&dir, &files, &[]) {
+                assert_eq!(output.status.code(), Some(0), "{}", load);
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_alignment() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let generate = |storage: StorageConf, lle: bool, alignment: Option<usize>| {
+            let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+            let lle = if lle { Some(trained.last_layer_max_l1s.clone()) } else { None };
+            let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+            generate_code(&mut code, &mut data_h, &mut header, "aligned", "aligned", data.len(),
+                          trained.rmi, lle, storage, 0, LookupFeatures::default(),
+                          &CodegenOptions { alignment, ..Default::default() }).unwrap();
+            return (String::from_utf8(code).unwrap(), String::from_utf8(data_h).unwrap());
+        };
+
+        let (_, data_h) = generate(StorageConf::Embed, false, Some(64));
+        assert!(data_h.contains("alignas(64) const double L1_PARAMETERS[] = {"));
+        let (_, data_h) = generate(StorageConf::Embed, false, None);
+        assert!(!data_h.contains("alignas"));
+
+        // two doubles and an error per leaf model: 24 bytes, padded to 32
+        let tmp = std::env::temp_dir();
+        let param_file = tmp.join("aligned_L1_PARAMETERS");
+        let storage = || StorageConf::Disk(tmp.to_str().unwrap().to_string(), false);
+        let (code, _) = generate(storage(), true, Some(16));
+        assert!(code.contains("L1_PARAMETERS = (char*) aligned_alloc(16, 256);"));
+        assert!(code.contains("(modelIndex * 32)"));
+        assert_eq!(std::fs::metadata(&param_file).unwrap().len(), 8 * 32);
+
+        let (code, _) = generate(storage(), true, None);
+        assert!(code.contains("L1_PARAMETERS = (char*) malloc(192);"));
+        assert_eq!(std::fs::metadata(&param_file).unwrap().len(), 8 * 24);
+        std::fs::remove_file(&param_file).unwrap();
+    }
+
+    #[test]
+    fn test_composite_key() {
+        let layout = CompositeKey::new(32, 32);
+        let regions: Vec<u64> = (0..1000).map(|i| i / 100).collect();
+        let times: Vec<u64> = (0..1000).map(|i| (i % 100) * 60).collect();
+        let data = ModelData::from_composite_keys(&regions, &times, layout);
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "composite", "composite", data.len(),
+                      trained.rmi, None, StorageConf::Embed, 0,
+                      LookupFeatures { composite_key: Some(layout), ..Default::default() },
+                      &CodegenOptions::default()).unwrap();
+
+        let code = String::from_utf8(code).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.contains("uint64_t lookup(uint32_t first, uint32_t second);"));
+        assert!(header.contains("uint64_t lookup(uint64_t key);"));
+        assert!(code.contains("return lookup(((uint64_t) first << 32) | (uint64_t) second);"));
+    }
+
+    #[test]
+    fn test_float_keys() {
+        let keys: Vec<f64> = (0..1000).map(|i| (i as f64 - 500.0) * 0.75).collect();
+        let data = ModelData::from_float_keys(&keys);
+        let generate = |policy: NonFinitePolicy| {
+            let trained = train(&mut ModelDataWrapper::new(&data), "radix,linear", 8).unwrap();
+            let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+            generate_code(&mut code, &mut data_h, &mut header, "floats", "floats", data.len(),
+                          trained.rmi, None, StorageConf::Embed, 0,
+                          LookupFeatures {
+                              float_keys: true, non_finite_keys: policy, ..Default::default()
+                          },
+                          &CodegenOptions::default()).unwrap();
+            return (String::from_utf8(code).unwrap(), String::from_utf8(header).unwrap(),
+                    String::from_utf8(data_h).unwrap());
+        };
+
+        let (code, header, _) = generate(NonFinitePolicy::default());
+        assert!(header.contains("uint64_t lookup_float(double key);"));
+        assert!(header.contains("uint64_t lookup(uint64_t key);"));
+        assert!(code.contains("#include <cstring>"));
+        assert!(code.contains("uint64_t lookup_float(double key) {"));
+        assert!(code.contains("  if (!std::isfinite(key)) {\n    return 0ULL;\n  }"));
+        assert!(code.contains("  return lookup(bits);"));
+
+        // each policy's positions for NaN, infinity, and negative infinity,
+        // which must match RuntimeRMI::lookup_float's (UINT64_MAX for none)
+        let mapped: Vec<u64> = data.iter_int_int().map(|(k, _)| k).collect();
+        let rmi = crate::runtime::RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "radix,linear", 8).unwrap()
+        );
+        let policies = [(NonFinitePolicy::Sentinel(0), [0, 0, 0]),
+                        (NonFinitePolicy::Sentinel(12345), [12345, 12345, 12345]),
+                        (NonFinitePolicy::Clamp, [0, 999, 0]),
+                        (NonFinitePolicy::Error, [u64::MAX, u64::MAX, u64::MAX])];
+        let dir = std::env::temp_dir().join("rmi_float_keys");
+        std::fs::create_dir_all(&dir).unwrap();
+        for (policy, expected) in policies.iter() {
+            for (key, pos) in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY].iter().zip(expected) {
+                let runtime_pos = match rmi.lookup_float(&mapped, *key, *policy) {
+                    Ok((pos, _)) => pos as u64,
+                    Err(e) => e.pos.map_or(u64::MAX, |pos| pos as u64)
+                };
+                assert_eq!(runtime_pos, *pos, "{:?} {}", policy, key);
+            }
+
+            let (code, header, data_h) = generate(*policy);
+            let main = format!("#include \"floats.h\"
+#include <cmath>
+int main() {{
+  if (floats::lookup_float(NAN) != {}UL) return 1;
+  if (floats::lookup_float(INFINITY) != {}UL) return 2;
+  if (floats::lookup_float(-INFINITY) != {}UL) return 3;
+  if (floats::lookup_float(0.75) > 501 + 8) return 4;
+  return 0;
+}}", expected[0], expected[1], expected[2]);
+            let files = [("floats.cpp", code), ("floats.h", header), ("floats_data.h", data_h),
+                         ("floats_main.cpp", main)];
+            if let Some(output) = compile_and_run(&dir, &files, &[]) {
+                assert_eq!(output.status.code(), Some(0), "{:?}", policy);
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_string_keys() {
+        let strings: Vec<String> = (0..1000).map(|i| format!("user/{:06}", i * 13)).collect();
+        let data = ModelData::from_string_keys(&strings);
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+        let rmi = crate::runtime::RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap()
+        );
+
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "strings", "strings", data.len(),
+                      trained.rmi, None, StorageConf::Embed, 0,
+                      LookupFeatures { string_keys: true, ..Default::default() },
+                      &CodegenOptions::default()).unwrap();
+        let code = String::from_utf8(code).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.contains("uint64_t lookup_string(const char* key, size_t len);"));
+        assert!(header.contains("// with the same 8 byte prefix."));
+        assert!(code.contains("  return lookup(prefix);"));
+
+        // the packed keys give the same predictions as in Rust, including
+        // for strings shorter and longer than 8 bytes
+        let queries = ["", "u", "user/", "user/000", "user/0001", "user/012974", "zzzzzzzzzz"];
+        let checks: Vec<String> = queries.iter().enumerate().map(|(i, q)| {
+            let expected = u64::min(rmi.predict(string_to_monotonic_u64(q.as_bytes()).into()),
+                                    data.len() as u64 - 1);
+            format!("  if (strings::lookup_string(\"{}\", {}) != {}UL) return {};",
+                    q, q.len(), expected, i + 1)
+        }).collect();
+        let main = format!("#include \"strings.h\"\nint main() {{\n{}\n  return 0;\n}}",
+                           checks.join("\n"));
+
+        let dir = std::env::temp_dir().join("rmi_string_keys");
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [("strings.cpp", code), ("strings.h", header),
+                     ("strings_data.h", String::from_utf8(data_h).unwrap()),
+                     ("strings_main.cpp", main)];
+        let output = compile_and_run(&dir, &files, &[]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        if let Some(output) = output {
+            assert_eq!(output.status.code(), Some(0));
+        }
+    }
+
+    #[test]
+    fn test_quantized_keys() {
+        let keys: Vec<f64> = (0..1000).map(|i| i as f64 * 0.37 + 0.001).collect();
+        let data = ModelData::FloatKeyToIntPos(
+            keys.iter().enumerate().map(|(i, k)| (*k, i as u64)).collect()
+        ).quantize_to_int(100.0, 1.0);
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+        let rmi = crate::runtime::RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap()
+        );
+
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "quantized", "quantized", data.len(),
+                      trained.rmi, None, StorageConf::Embed, 0,
+                      LookupFeatures { quantized_keys: Some(100.0), ..Default::default() },
+                      &CodegenOptions::default()).unwrap();
+        let code = String::from_utf8(code).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.contains("uint64_t lookup_quantized(double key);"));
+        assert!(code.contains("  double scaled = std::floor(key * 100.0);"));
+
+        // the keys are scaled as quantize_to_int scales them, including
+        // the keys it saturates
+        let queries = [("-5.0", -5.0), ("NAN", f64::NAN), ("0.0", 0.0), ("3.14159", 3.14159),
+                       ("123.456", 123.456), ("1e30", 1e30), ("INFINITY", f64::INFINITY)];
+        let checks: Vec<String> = queries.iter().enumerate().map(|(i, (text, key))| {
+            let quantized = (key * 100.0).floor() as u64;
+            let expected = u64::min(rmi.predict(quantized.into()), data.len() as u64 - 1);
+            format!("  if (quantized::lookup_quantized({}) != {}UL) return {};",
+                    text, expected, i + 1)
+        }).collect();
+        let main = format!("#include \"quantized.h\"\n#include <cmath>\nint main() {{\n{}\n  \
+                            return 0;\n}}", checks.join("\n"));
+
+        let dir = std::env::temp_dir().join("rmi_quantized_keys");
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [("quantized.cpp", code), ("quantized.h", header),
+                     ("quantized_data.h", String::from_utf8(data_h).unwrap()),
+                     ("quantized_main.cpp", main)];
+        let output = compile_and_run(&dir, &files, &[]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        if let Some(output) = output {
+            assert_eq!(output.status.code(), Some(0));
+        }
+    }
+
+    #[test]
+    fn test_pruned_leaves() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i, i)).collect());
+        let opts = TrainingOptions { prune_leaves: true, ..Default::default() };
+        let trained = train_with_options(&mut ModelDataWrapper::new(&data),
+                                         "linear,linear", 8, &opts).unwrap();
+        let pruned = trained.pruned.clone().unwrap();
+
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "pruned", "pruned", data.len(),
+                      trained.rmi, None, StorageConf::Embed, 0,
+                      LookupFeatures { pruned: Some(&pruned), ..Default::default() },
+                      &CodegenOptions::default()).unwrap();
+
+        let code = String::from_utf8(code).unwrap();
+        let data_h = String::from_utf8(data_h).unwrap();
+        let bits: u64 = (0..8).filter(|i| pruned.is_pruned(*i)).map(|i| 1 << i).sum();
+        assert!(bits > 0);
+        assert!(data_h.contains(&format!("const uint64_t PRUNED[] = {{ {}ULL }};", bits)));
+        assert!(code.contains("if ((PRUNED[modelIndex / 64] >> (modelIndex % 64)) & 1) {"));
+        assert!(code.contains("fpred = fpred * 125.0;"));
+    }
+
+    #[test]
+    fn test_restrict_pointers() {
+        let keys: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let tmp = std::env::temp_dir();
+        let generate = |restrict: bool| {
+            let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+            let lle = trained.last_layer_max_l1s.clone();
+            let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+            generate_code(&mut code, &mut data_h, &mut header, "restrict", "restrict", data.len(),
+                          trained.rmi, Some(lle),
+                          StorageConf::Disk(tmp.to_str().unwrap().to_string(), false), 0,
+                          LookupFeatures { exact_keys: Some(&keys), ..Default::default() },
+                          &CodegenOptions {
+                              restrict_pointers: restrict, ..Default::default()
+                          }).unwrap();
+            return (String::from_utf8(code).unwrap(), String::from_utf8(data_h).unwrap(),
+                    String::from_utf8(header).unwrap());
+        };
+
+        let (code, data_h, header) = generate(true);
+        assert!(header.contains("#define RMI_RESTRICT __restrict"));
+        assert!(header.contains("uint64_t lookup(uint64_t key, size_t* RMI_RESTRICT err);"));
+        assert!(header.contains("int lookup_exact(uint64_t key, uint64_t* RMI_RESTRICT pos);"));
+        assert!(code.contains("uint64_t lookup(uint64_t key, size_t* RMI_RESTRICT err) {"));
+        assert!(data_h.contains("char* RMI_RESTRICT L1_PARAMETERS;"));
+        assert!(data_h.contains("uint64_t* RMI_RESTRICT KEYS;"));
+
+        let (code, data_h, header) = generate(false);
+        assert!(!code.contains("RMI_RESTRICT") && !data_h.contains("RMI_RESTRICT")
+                && !header.contains("RMI_RESTRICT"));
+        assert!(header.contains("uint64_t lookup(uint64_t key, size_t* err);"));
+
+        for name in &["restrict_L1_PARAMETERS", "restrict_KEYS"] {
+            std::fs::remove_file(tmp.join(name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_hinted_lookup() {
+        let keys: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+        let lle = trained.last_layer_max_l1s.clone();
+
+        let tmp = std::env::temp_dir();
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "hinted", "hinted", data.len(),
+                      trained.rmi, Some(lle),
+                      StorageConf::Disk(tmp.to_str().unwrap().to_string(), false), 0,
+                      LookupFeatures {
+                          exact_keys: Some(&keys), hinted_lookup: true, ..Default::default()
+                      },
+                      &CodegenOptions::default()).unwrap();
+        std::fs::remove_file(tmp.join("hinted_KEYS")).unwrap();
+        std::fs::remove_file(tmp.join("hinted_L1_PARAMETERS")).unwrap();
+
+        let code = String::from_utf8(code).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.contains("uint64_t lookup_hinted(uint64_t key, uint64_t hint);"));
+        assert!(code.contains("uint64_t lookup_hinted(uint64_t key, uint64_t hint) {"));
+        assert!(code.contains("} else if (hint >= lo && hint < hi) {"));
+        assert!(header.contains("uint64_t lookup_hinted_window(uint64_t key, uint64_t hint, \
+                                 uint64_t hint_err, size_t* window);"));
+        assert!(code.contains("  *window = (hi - lo + 1) / 2;"));
+    }
+
+    #[test]
+    fn test_branch_hints() {
+        let keys: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let dir = std::env::temp_dir();
+        let generate = |hints: bool| {
+            let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+            let lle = trained.last_layer_max_l1s.clone();
+            let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+            generate_code(&mut code, &mut data_h, &mut header, "rmi_hints", "rmi_hints", data.len(),
+                          trained.rmi, Some(lle),
+                          StorageConf::Disk(dir.to_str().unwrap().to_string(), false), 0,
+                          LookupFeatures {
+                              exact_keys: Some(&keys), hinted_lookup: true, ..Default::default()
+                          },
+                          &CodegenOptions { branch_hints: hints, ..Default::default() }).unwrap();
+            for name in &["rmi_hints_L1_PARAMETERS", "rmi_hints_KEYS"] {
+                std::fs::remove_file(dir.join(name)).unwrap();
+            }
+            return (String::from_utf8(code).unwrap(), String::from_utf8(data_h).unwrap(),
+                    String::from_utf8(header).unwrap());
+        };
+
+        let (code, _, _) = generate(false);
+        assert!(!code.contains("RMI_UNLIKELY") && !code.contains("RMI_LIKELY"));
+        assert!(code.contains("  if (inp < 0.0) return 0;"));
+
+        let (code, data_h, header) = generate(true);
+        assert!(code.contains("#define RMI_UNLIKELY(x) __builtin_expect(!!(x), 0)"));
+        assert!(code.contains("  if (RMI_UNLIKELY(inp < 0.0)) return 0;"));
+        assert!(code.contains("  return (RMI_UNLIKELY(inp > bound) ? bound : (size_t)inp);"));
+        assert_eq!(model_index_from_output!(ModelDataType::Int, 8, true, Dialect::Cpp, true),
+                   "(RMI_UNLIKELY(ipred > 8 - 1) ? 8 - 1 : ipred)");
+        assert!(code.contains("  if (RMI_UNLIKELY(lo > 0 && KEYS[lo - 1] >= key)) {"));
+        assert!(code.contains(
+            "  } else if (RMI_UNLIKELY(hi < NUM_KEYS && KEYS[hi - 1] < key)) {"));
+        // the searches are left alone
+        assert!(code.contains("    if (KEYS[mid] < key) lo = mid + 1;"));
+        assert!(code.contains("  if (lo < NUM_KEYS && KEYS[lo] == key) {"));
+
+        let files = [(String::from("rmi_hints.cpp"), code),
+                     (String::from("rmi_hints.h"), header),
+                     (String::from("rmi_hints_data.h"), data_h)];
+        compile_only("c++", &["-std=c++17", "-Wall", "-Werror"], &dir, &files);
+    }
+
+    #[test]
+    fn test_c89() {
+        let dir = std::env::temp_dir();
+        let mut data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let configs = [("c89_linear", "linear,linear", PositionUnit::Element),
+                       ("c89_cubic", "cubic,linear", PositionUnit::Element),
+                       ("c89_radix", "radix,linear", PositionUnit::Element),
+                       ("c89_offsets", "linear,linear", PositionUnit::Offset(24))];
+
+        for (name, models, unit) in configs.iter() {
+            if let PositionUnit::Offset(record_size) = unit {
+                data.positions_to_offsets(*record_size);
+            }
+            let mut md = ModelDataWrapper::new(&data);
+            md.set_position_unit(*unit);
+            let trained = train(&mut md, models, 8).unwrap();
+            let info = RmiInfo::from_trained(&trained, md.num_positions());
+            let num_keys = info.num_keys;
+
+            let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+            generate_code(&mut code, &mut data_h, &mut header, name, name, md.num_positions(),
+                          trained.rmi, None, StorageConf::Embed, 0,
+                          LookupFeatures {
+                              position_unit: *unit, info: Some(info), ..Default::default()
+                          },
+                          &CodegenOptions {
+                              dialect: Dialect::C89, ..Default::default()
+                          }).unwrap();
+
+            let code = String::from_utf8(code).unwrap();
+            let data_h = String::from_utf8(data_h).unwrap();
+            let header = String::from_utf8(header).unwrap();
+            assert!(header.contains(&format!("rmi_u64 {}_lookup(rmi_u64 key);", name)));
+            assert!(header.contains(&format!("struct {0}_RmiInfo {0}_rmi_info(void);", name)));
+            assert!(header.contains(&format!("rmi_u64 {}_rmi_count(void);", name)));
+            assert!(code.contains(&format!("rmi_u64 {}_lookup(rmi_u64 key) {{", name)));
+            for text in &[&code, &data_h, &header] {
+                assert!(!text.contains("//") && !text.contains("inline")
+                        && !text.contains("namespace") && !text.contains("std::")
+                        && !text.contains("uint64_t") && !text.contains("ULL"));
+            }
+            assert!(code.contains(&format!("  return {}UL;", num_keys)));
+
+            // a smoke test with the system C compiler
+            let files = [(format!("{}.c", name), code), (format!("{}.h", name), header),
+                         (format!("{}_data.h", name), data_h)];
+            compile_only("cc", &["-std=c89", "-pedantic-errors", "-Wall", "-Werror"], &dir,
+                         &files);
+        }
+    }
+
+    #[test]
+    fn test_fixed_point() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i + 7 * i, i)).collect());
+        let train_rmi = || train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+
+        // the predictions of the same RMI converted to fixed point
+        let fixed: Vec<Vec<Box<dyn Model>>> = train_rmi().rmi.into_iter()
+            .map(|layer| layer.into_iter().map(|m| to_fixed_point(m).unwrap()).collect())
+            .collect();
+        let keys: Vec<u64> = (0..1000).step_by(37).map(|i| i * i + 7 * i).collect();
+        let expected: Vec<u64> = keys.iter().map(|key| {
+            let idx = u64::min(fixed[0][0].predict_to_int((*key).into()), 7) as usize;
+            u64::min(fixed[1][idx].predict_to_int((*key).into()), 999)
+        }).collect();
+
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "rmi_fixed", "rmi_fixed", data.len(),
+                      train_rmi().rmi, None, StorageConf::Embed, 0, LookupFeatures::default(),
+                      &CodegenOptions { fixed_point: true, ..Default::default() }).unwrap();
+        let code = String::from_utf8(code).unwrap();
+        let lookup = &code[code.find("uint64_t lookup(uint64_t key) {").unwrap()..];
+        assert!(lookup.contains("ipred = linear_fx("));
+        assert!(!lookup.contains("fpred") && !code.contains("double"));
+
+        let dir = std::env::temp_dir().join("rmi_fixed_point");
+        std::fs::create_dir_all(&dir).unwrap();
+        let list = |v: &[u64]| v.iter().map(|x| format!("{}UL", x)).collect::<Vec<String>>()
+            .join(", ");
+        let main = format!("#include \"rmi_fixed.h\"
+int main() {{
+  const uint64_t keys[] = {{ {} }};
+  const uint64_t expected[] = {{ {} }};
+  for (size_t i = 0; i < {}; i++) {{
+    if (rmi_fixed::lookup(keys[i]) != expected[i]) return 1;
+  }}
+  return 0;
+}}", list(&keys), list(&expected), keys.len());
+        let files = [("rmi_fixed.cpp", code), ("rmi_fixed.h", String::from_utf8(header).unwrap()),
+                     ("rmi_fixed_data.h", String::from_utf8(data_h).unwrap()),
+                     ("rmi_fixed_main.cpp", main)];
+        // the generated code computes exactly the same
+        let output = compile_and_run(&dir, &files, &[]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        if let Some(output) = output {
+            assert_eq!(output.status.code(), Some(0));
+        }
+
+        // radix is already integer only, and C89 works too
+        let trained = train(&mut ModelDataWrapper::new(&data), "radix,linear", 8).unwrap();
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "fixed_c89", "fixed_c89", data.len(),
+                      trained.rmi, None, StorageConf::Embed, 0, LookupFeatures::default(),
+                      &CodegenOptions {
+                          dialect: Dialect::C89, fixed_point: true, ..Default::default()
+                      }).unwrap();
+        let code = String::from_utf8(code).unwrap();
+        assert!(code.contains("static rmi_u64 linear_fx(") && !code.contains("FCLAMP"));
+
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [(String::from("fixed_c89.c"), code),
+                     (String::from("fixed_c89.h"), String::from_utf8(header).unwrap()),
+                     (String::from("fixed_c89_data.h"), String::from_utf8(data_h).unwrap())];
+        compile_only("cc", &["-std=c89", "-pedantic-errors", "-Wall", "-Werror"], &dir, &files);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "The cubic model cannot be evaluated in fixed point")]
+    fn test_fixed_point_unsupported() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let trained = train(&mut ModelDataWrapper::new(&data), "cubic,linear", 8).unwrap();
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "cubic", "cubic", data.len(),
+                      trained.rmi, None, StorageConf::Embed, 0, LookupFeatures::default(),
+                      &CodegenOptions { fixed_point: true, ..Default::default() }).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "The linear_exceptions model cannot be emitted as C89")]
+    fn test_c89_unsupported() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear_exceptions,linear", 8)
+            .unwrap();
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "exceptions", "exceptions",
+                      data.len(), trained.rmi, None, StorageConf::Embed, 0,
+                      LookupFeatures::default(),
+                      &CodegenOptions { dialect: Dialect::C89, ..Default::default() }).unwrap();
+    }
+
+    #[test]
+    fn test_selftest() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let samples = selftest_samples(&data, 10);
+        assert_eq!(samples.len(), 10);
+        assert_eq!(samples[0], (0, 0));
+        assert_eq!(samples[9], (999 * 999, 999));
+        assert_eq!(selftest_samples(&data, 5000).len(), 1000);
+
+        // a bundle with the right positions, and one with a wrong position
+        // for the third sample
+        let mut wrong = samples.clone();
+        wrong[2].1 += 500;
+        let rmis: Vec<NamedRMI> = [("good", samples), ("bad", wrong)].iter()
+            .map(|(ns, samples)| {
+                let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8)
+                    .unwrap();
+                NamedRMI {
+                    selftest_samples: Some(samples.clone()),
+                    ..NamedRMI::new(ns, trained, data.len(), 0)
+                }
+            }).collect();
+
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_bundle(&mut code, &mut data_h, &mut header, "rmi_selftest_bundle", rmis,
+                        &CodegenOptions::default()).unwrap();
+        let code = String::from_utf8(code).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        assert_eq!(header.matches("int rmi_selftest(void);").count(), 2);
+        assert!(code.contains(&format!("{}UL", 999 * 999)));
+
+        let dir = std::env::temp_dir();
+        let main = "#include \"rmi_selftest_bundle.h\"
+int main() {
+  if (good::rmi_info().num_layers != 2 || good::rmi_info().branching_factor != 8) return 100;
+  if (good::rmi_count() != 1000) return 101;
+  return good::rmi_selftest() * 10 + bad::rmi_selftest();
+}";
+        let files = [("rmi_selftest_bundle.cpp", code), ("rmi_selftest_bundle.h", header),
+                     ("rmi_selftest_bundle_data.h", String::from_utf8(data_h).unwrap()),
+                     ("rmi_selftest_main.cpp", String::from(main))];
+        if let Some(output) = compile_and_run(&dir, &files, &[]) {
+            assert_eq!(output.status.code(), Some(3));
+        }
+    }
+
+    #[test]
+    fn test_log2_errors() {
+        assert_eq!([0, 1, 2, 3, 4, 7, 8, 1000, u64::MAX].iter()
+                   .map(|e| train::log2_error_bits(*e))
+                   .collect::<Vec<u64>>(), vec![0, 1, 2, 2, 3, 3, 4, 10, 64]);
+        // one less than the rounded up log2 errors reported by training
+        for err in [0, 1, 2, 3, 4, 7, 8, 1000, 1 << 40].iter() {
+            assert_eq!(train::log2_error_bits(*err) + 1,
+                       train::log2_window(*err).ceil() as u64, "{}", err);
+        }
+
+        let keys: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+        let lle = trained.last_layer_max_l1s.clone();
+        let max_error = *lle.iter().max().unwrap();
+        assert!(max_error > 1, "{}", max_error);
+        let samples = selftest_samples(&data, 1000);
+
+        let dir = std::env::temp_dir().join("rmi_log2_errors");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "rmi_log2", "rmi_log2", data.len(),
+                      trained.rmi, Some(lle),
+                      StorageConf::Disk(dir.to_str().unwrap().to_string(), false), 0,
+                      LookupFeatures {
+                          exact_keys: Some(&keys), hinted_lookup: true,
+                          selftest: Some((&samples, 0)), ..Default::default()
+                      },
+                      &CodegenOptions { log2_errors: true, ..Default::default() }).unwrap();
+
+        let code = String::from_utf8(code).unwrap();
+        assert!(code.contains(&format!("    if (diff > {}) return (int) i + 1;",
+                                       LOG2_ERROR_TO_ABSOLUTE)));
+        assert_eq!(code.matches(&format!("  err = {};", LOG2_ERROR_TO_ABSOLUTE)).count(), 3);
+
+        // each reported error is the bits of an absolute error, which still
+        // bounds the lookup once converted back
+        let main = format!("#include \"rmi_log2.h\"
+int main(int, char** argv) {{
+  if (!rmi_log2::load(argv[1])) return 1;
+  for (uint64_t i = 0; i < 1000; i++) {{
+    size_t err;
+    uint64_t guess = rmi_log2::lookup(i * i, &err);
+    if (err > {bits}) return 2;
+    if ((guess > i ? guess - i : i - guess) > {absolute}) return 3;
+    uint64_t pos;
+    if (!rmi_log2::lookup_exact(i * i, &pos) || pos != i) return 4;
+    if (rmi_log2::lookup_hinted(i * i, i + 1) != i) return 5;
+    size_t window;
+    guess = rmi_log2::lookup_hinted_window(i * i, i + 1, 1, &window);
+    if (window > 1 || (guess > i ? guess - i : i - guess) > window) return 7;
+  }}
+  if (rmi_log2::rmi_selftest() != 0) return 6;
+  rmi_log2::cleanup();
+  return 0;
+}}", bits = train::log2_error_bits(max_error), absolute = LOG2_ERROR_TO_ABSOLUTE);
+        let files = [("rmi_log2.cpp", code), ("rmi_log2.h", String::from_utf8(header).unwrap()),
+                     ("rmi_log2_data.h", String::from_utf8(data_h).unwrap()),
+                     ("rmi_log2_main.cpp", main)];
+        let output = compile_and_run(&dir, &files, &[]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        if let Some(output) = output {
+            assert_eq!(output.status.code(), Some(0));
+        }
+    }
+
+    #[test]
+    fn test_leaf_map() {
+        // sparse keys: most of the 256 buckets are empty
+        let data = ModelData::IntKeyToIntPos((0..1000u64).map(|i| (i, i))
+                                             .chain((0..1000).map(|i| ((1 << 40) + i * 7,
+                                                                       1000 + i)))
+                                             .collect());
+        let options = TrainingOptions { max_leaf_models: Some(12), ..Default::default() };
+        let trained = train_with_options(&mut ModelDataWrapper::new(&data), "linear,linear", 256,
+                                         &options).unwrap();
+        assert_eq!(trained.rmi[1].len(), 12);
+        let samples = selftest_samples(&data, 200);
+        let rmis = vec![NamedRMI {
+            selftest_samples: Some(samples),
+            ..NamedRMI::new("merged", trained, data.len(), 0)
+        }];
+
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_bundle(&mut code, &mut data_h, &mut header, "rmi_leaf_map", rmis,
+                        &CodegenOptions::default()).unwrap();
+        let code = String::from_utf8(code).unwrap();
+        let data_h = String::from_utf8(data_h).unwrap();
+        assert!(code.contains("modelIndex = LEAF_MAP[modelIndex];"));
+        assert!(code.contains("FCLAMP(fpred, 256.0 - 1.0)"));
+        assert!(data_h.contains("const short LEAF_MAP[] = { 0, "));
+
+        let dir = std::env::temp_dir();
+        let main = "#include \"rmi_leaf_map.h\"
+int main() { return merged::rmi_selftest(); }";
+        let files = [("rmi_leaf_map.cpp", code),
+                     ("rmi_leaf_map.h", String::from_utf8(header).unwrap()),
+                     ("rmi_leaf_map_data.h", data_h),
+                     ("rmi_leaf_map_main.cpp", String::from(main))];
+        if let Some(output) = compile_and_run(&dir, &files, &[]) {
+            assert_eq!(output.status.code(), Some(0));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "codegen-roundtrip")]
+    fn test_compiled_roundtrip() {
+        let compiler = "c++";
+
+        let keys: Vec<u64> = (0..2000u64).map(|i| i * i * 31 + (i % 7) * 1000).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        // training keys, keys between them, and keys past either end
+        let mut queries: Vec<u64> = keys.iter().step_by(3)
+            .flat_map(|k| vec![*k, k + 5]).collect();
+        queries.extend_from_slice(&[0, 1 << 40, u64::MAX]);
+
+        let dir = std::env::temp_dir();
+        let check = |models: &str, options: &TrainingOptions, unit: PositionUnit| {
+            let mut data = data.clone();
+            if let PositionUnit::Offset(record_size) = unit {
+                data.positions_to_offsets(record_size);
+            }
+            let mut md = ModelDataWrapper::new(&data);
+            md.set_position_unit(unit);
+            let trained = train_with_options(&mut md, models, 64, options).unwrap();
+            let result = verify_compiled(trained, md.num_positions(), &queries, compiler, &dir);
+            assert!(result.is_ok(), "{}: {}", models, result.unwrap_err());
+        };
+
+        let plain = TrainingOptions::default();
+        for models in &["linear,linear", "cubic,linear", "radix,linear", "linear,cubic",
+                        "robust_linear,linear_spline", "linear,linear,linear",
+                        "linear,adaptive4", "linear,adaptive6", "linear,isotonic16",
+                        "sampled_cdf,linear", "rank_transform,linear"] {
+            check(models, &plain, PositionUnit::Element);
+        }
+        check("linear,linear", &plain, PositionUnit::Offset(24));
+        check("linear,linear", &TrainingOptions { prune_leaves: true, ..Default::default() },
+              PositionUnit::Element);
+        check("linear,linear", &TrainingOptions { max_leaf_models: Some(8),
+                                                  ..Default::default() },
+              PositionUnit::Element);
+        check("linear,linear", &TrainingOptions {
+            key_transform: Some(KeyTransform::new(1000, 3)),
+            ..Default::default()
+        }, PositionUnit::Element);
+
+        // keys inside the gaps of gapped_linear are clamped the same way
+        let gapped = ModelData::IntKeyToIntPos((0..1000).chain(50_000..51_000).enumerate()
+                                               .map(|(i, k)| (k, i as u64)).collect());
+        let trained = train(&mut ModelDataWrapper::new(&gapped), "gapped_linear,linear", 16)
+            .unwrap();
+        let queries: Vec<u64> = (0..60_000).step_by(7).collect();
+        let result = verify_compiled(trained, gapped.len(), &queries, compiler, &dir);
+        assert!(result.is_ok(), "gapped_linear: {}", result.unwrap_err());
+    }
+
+    // a top model whose generated code routes every key one model further
+    // than its Rust prediction
+    struct OffByOne;
+
+    impl Model for OffByOne {
+        fn predict_to_int(&self, inp: ModelInput) -> u64 {
+            return match inp {
+                ModelInput::Int(key) => key / 100_000,
+                ModelInput::Float(key) => key as u64 / 100_000
+            };
+        }
+        fn input_type(&self) -> ModelDataType {
+            return ModelDataType::Int;
+        }
+        fn output_type(&self) -> ModelDataType {
+            return ModelDataType::Int;
+        }
+        fn params(&self) -> Vec<ModelParam> {
+            return vec![ModelParam::Int(100_000)];
+        }
+        fn code(&self) -> String {
+            return String::from("
+inline uint64_t off_by_one(uint64_t divisor, uint64_t inp) {
+  return inp / divisor + 1;
+}");
+        }
+        fn function_name(&self) -> String {
+            return String::from("off_by_one");
+        }
+    }
+
+    #[test]
+    fn test_compiled_roundtrip_mismatch() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let trained = train(&mut ModelDataWrapper::new(&data), "linear,linear", 10).unwrap();
+        let result = verify_compiled(trained, data.len(), &[30], "no-such-compiler",
+                                     &std::env::temp_dir());
+        assert!(result.unwrap_err().starts_with("could not run no-such-compiler"));
+
+        if cfg!(feature = "codegen-roundtrip") {
+            let mut trained = train(&mut ModelDataWrapper::new(&data), "linear,linear",
+                                    10).unwrap();
+            trained.rmi[0][0] = Box::new(OffByOne);
+            let result = verify_compiled(trained, data.len(), &[40_000, 90_000], "c++",
+                                         &std::env::temp_dir());
+            assert!(result.unwrap_err().starts_with("key 40000: the compiled lookup returned"));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "codegen-roundtrip")]
+    fn test_compiled_roundtrip_concurrent() {
+        let compiler = "c++";
+
+        // different models built in the same directory at the same time
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let threads: Vec<_> = ["linear,linear", "radix,linear", "cubic,linear", "linear,cubic"]
+            .iter()
+            .map(|models| {
+                let trained = train(&mut ModelDataWrapper::new(&data), models, 10).unwrap();
+                let num_rows = data.len();
+                std::thread::spawn(move || {
+                    let keys: Vec<u64> = (0..1000).map(|i| i * i).collect();
+                    verify_compiled(trained, num_rows, &keys, compiler, &std::env::temp_dir())
+                })
+            })
+            .collect();
+        for thread in threads {
+            let result = thread.join().unwrap();
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+        }
+    }
+
+    #[test]
+    fn test_key_transform() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (5000 + i * 60, i)).collect());
+        let options = TrainingOptions {
+            key_transform: Some(KeyTransform::new(5000, 60)),
+            ..Default::default()
+        };
+        let trained = train_with_options(&mut ModelDataWrapper::new(&data), "linear,linear", 8,
+                                         &options).unwrap();
+        assert_eq!(trained.key_transform, options.key_transform);
+
+        let (mut code, mut data_h, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data_h, &mut header, "transform", "transform", data.len(),
+                      trained.rmi, None, StorageConf::Embed, 0,
+                      LookupFeatures { key_transform: trained.key_transform, ..Default::default() },
+                      &CodegenOptions::default()).unwrap();
+
+        let code = String::from_utf8(code).unwrap();
+        let lookup = &code[code.find("uint64_t lookup(uint64_t key) {").unwrap()..];
+        let transform = lookup.find("  key = (key > 5000ULL ? key - 5000ULL : 0) / 60ULL;")
+            .unwrap();
+        assert!(transform < lookup.find("fpred = linear(").unwrap());
+    }
+
+    #[test]
+    fn test_rust_no_std() {
+        let pairs: Vec<(u64, u64)> = (0..5000u64).map(|i| (i * i + 7 * i, i)).collect();
+        let data = ModelData::IntKeyToIntPos(pairs.clone());
+        let configs = [("linear,linear", 64, None), ("radix,linear", 64, None),
+                       ("linear,linear,linear", 8, None), ("linear,linear", 64, Some(20))];
+        let dir = std::env::temp_dir().join("rmi_rust_no_std");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for (spec, branching_factor, max_leaf_models) in configs.iter() {
+            let options = TrainingOptions { max_leaf_models: *max_leaf_models,
+                                            ..Default::default() };
+            let trained = train_with_options(&mut ModelDataWrapper::new(&data), spec,
+                                             *branching_factor, &options).unwrap();
+            let (runtime, errors) = measure_fixed_point(trained, &ModelDataWrapper::new(&data),
+                                                        data.len());
+            let mut code = Vec::new();
+            generate_rust_no_std(&mut code, "nostd", data.len(), runtime.trained(), &errors, 0)
+                .unwrap();
+            let code = String::from_utf8(code).unwrap();
+            assert!(!code.contains("f64") && !code.contains("std::"), "{}", code);
+            assert!(code.contains("pub const fn rmi_count() -> u64 {\n    5000\n}"));
+            assert_eq!(code.contains("LEAF_MAP[model_index]"), max_leaf_models.is_some());
+
+            // lookup returns the fixed-point prediction, and its error covers
+            // every training key
+            let (queries, expected): (Vec<String>, Vec<String>) = pairs.iter().step_by(7)
+                .map(|(k, _)| *k)
+                .chain(vec![0, 12_345, u64::MAX])
+                .map(|k| (k.to_string(),
+                          u64::min(runtime.predict(k.into()), data.len() as u64 - 1).to_string()))
+                .unzip();
+            let training: Vec<String> = pairs.iter().map(|(k, p)| format!("({}, {})", k, p))
+                .collect();
+            let main = format!("#[path = \"nostd.rs\"]
+mod nostd;
+const QUERIES: [u64; {}] = [{}];
+const EXPECTED: [u64; {}] = [{}];
+const TRAINING: [(u64, u64); {}] = [{}];
+fn main() {{
+    for (key, expected) in QUERIES.iter().zip(EXPECTED.iter()) {{
+        assert_eq!(nostd::lookup(*key).0, *expected, \"key {{}}\", key);
+    }}
+    for (key, pos) in TRAINING.iter() {{
+        let (pred, err) = nostd::lookup(*key);
+        assert!(pred.abs_diff(*pos) <= err, \"key {{}}\", key);
+    }}
+}}", queries.len(), queries.join(", "), expected.len(), expected.join(", "),
+                               training.len(), training.join(", "));
+            let lib = "#![no_std]
+#[path = \"nostd.rs\"]
+mod nostd;
+pub fn lookup(key: u64) -> (u64, u64) {
+    nostd::lookup(key)
+}";
+            for (file, text) in &[("nostd.rs", code.as_str()), ("main.rs", main.as_str()),
+                                  ("lib.rs", lib)] {
+                std::fs::write(dir.join(file), text).unwrap();
+            }
+
+            // build it as part of a no_std library, and run it (with
+            // overflow checks), if there is a Rust compiler
+            let rustc = |args: &[&str], file: &str| {
+                return std::process::Command::new("rustc")
+                    .args(&["--edition", "2018", "--out-dir"])
+                    .arg(&dir)
+                    .args(args)
+                    .arg(dir.join(file))
+                    .output();
+            };
+            let library = match rustc(&["--crate-type", "rlib", "-D", "warnings"], "lib.rs") {
+                Ok(output) => output,
+                Err(_) => break
+            };
+            assert!(library.status.success(), "{}", String::from_utf8_lossy(&library.stderr));
+            let binary = rustc(&["-C", "debug-assertions"], "main.rs").unwrap();
+            assert!(binary.status.success(), "{}", String::from_utf8_lossy(&binary.stderr));
+            let run = std::process::Command::new(dir.join("main")).output().unwrap();
+            assert!(run.status.success(), "{}: {}", spec, String::from_utf8_lossy(&run.stderr));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
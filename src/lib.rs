@@ -0,0 +1,24 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+ 
+
+// the training, code generation, and in-process lookups behind the rmi
+// binary, for programs that build RMIs themselves (for example, with their
+// own models added by train::register_model)
+
+#![allow(clippy::needless_return)]
+#![allow(clippy::len_without_is_empty)]
+
+pub mod codegen;
+pub mod load;
+pub mod models;
+pub mod train;
+pub mod optimizer;
+pub mod runtime;
+pub mod bench;
+pub mod artifact;
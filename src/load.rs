@@ -7,19 +7,19 @@
  
  
 
-use crate::models::ModelData;
+use crate::models::{float_to_monotonic_u64, ModelData};
 use byteorder::{LittleEndian, ReadBytesExt};
 use log::debug;
 use log::*;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
 
 pub enum DataType {
     UINT64,
     UINT32,
 }
 
-pub fn load_data(filepath: &str, dt: DataType, downsample: usize) -> (usize, ModelData) {
+pub fn load_data(filepath: &str, dt: DataType, downsample: usize) -> (usize, ModelData<'static>) {
     let fd = File::open(filepath).unwrap_or_else(|_| {
         panic!("Unable to open data file at {}", filepath)
     });
@@ -53,16 +53,155 @@ pub fn load_data(filepath: &str, dt: DataType, downsample: usize) -> (usize, Mod
     }
 
     let orig_size = init_data.len();
+    return (orig_size, ModelData::IntKeyToIntPos(downsample_data(init_data, downsample)));
+}
+
+// loads a text file with one key per line, in sorted order. If every key is
+// an integer, the keys are loaded as integers; otherwise, they are loaded
+// as floats, so that a key with a fractional part is never truncated.
+// Panics if a line is not a number or the keys are not sorted.
+pub fn load_text_data(filepath: &str, downsample: usize) -> (usize, ModelData<'static>) {
+    let data = read_text_keys(filepath);
+
+    let orig_size = data.len();
+    let data = match data {
+        ModelData::IntKeyToIntPos(data) => {
+            info!("All keys in {} are integers, using integer keys", filepath);
+            ModelData::IntKeyToIntPos(downsample_data(data, downsample))
+        },
+        ModelData::FloatKeyToIntPos(data) => {
+            info!("Some keys in {} are not integers, using float keys", filepath);
+            ModelData::FloatKeyToIntPos(downsample_data(data, downsample))
+        },
+        _ => unreachable!()
+    };
+
+    return (orig_size, data);
+}
+
+// loads a text file like load_text_data, but always as float keys mapped by
+// float_to_monotonic_u64 (as in ModelData::from_float_keys), for an RMI
+// with a lookup_float. Integer keys are converted to the nearest float.
+pub fn load_float_text_data(filepath: &str, downsample: usize) -> (usize, ModelData<'static>) {
+    let mut data: Vec<(u64, u64)> = match read_text_keys(filepath) {
+        ModelData::IntKeyToIntPos(data) => data.into_iter()
+            .map(|(k, pos)| (float_to_monotonic_u64(k as f64), pos)).collect(),
+        ModelData::FloatKeyToIntPos(data) => data.into_iter()
+            .map(|(k, pos)| (float_to_monotonic_u64(k), pos)).collect(),
+        _ => unreachable!()
+    };
+    // integers that round to the same float become duplicates
+    resolve_dup_keys(&mut data);
+
+    let orig_size = data.len();
+    return (orig_size, ModelData::IntKeyToIntPos(downsample_data(data, downsample)));
+}
+
+// loads a text file like load_text_data, with its keys quantized to
+// integers by ModelData::quantize_to_int (positions are not scaled), for an
+// RMI with a lookup_quantized using the same key_scale
+pub fn load_quantized_text_data(filepath: &str, key_scale: f64,
+                                downsample: usize) -> (usize, ModelData<'static>) {
+    let mut data = match read_text_keys(filepath).quantize_to_int(key_scale, 1.0) {
+        ModelData::IntKeyToIntPos(data) => data,
+        _ => unreachable!()
+    };
+    // keys closer together than 1 / key_scale become duplicates
+    resolve_dup_keys(&mut data);
+
+    let orig_size = data.len();
+    return (orig_size, ModelData::IntKeyToIntPos(downsample_data(data, downsample)));
+}
+
+// loads a text file with one sorted string key per line (bytes compared as
+// is), mapped by string_to_monotonic_u64 to its 8 byte prefix (as in
+// ModelData::from_string_keys), for an RMI with a lookup_string. Strings with
+// the same prefix become one key, at the position of the first of them.
+pub fn load_string_text_data(filepath: &str, downsample: usize) -> (usize, ModelData<'static>) {
+    let mut contents = String::new();
+    File::open(filepath)
+        .and_then(|mut fd| fd.read_to_string(&mut contents))
+        .unwrap_or_else(|_| panic!("Unable to read data file at {}", filepath));
+
+    let lines: Vec<(usize, &str)> = contents.lines().enumerate().collect();
+    let keys: Vec<&str> = lines.iter().map(|(_, l)| *l).collect();
+    check_sorted(&keys, &lines)
+        .unwrap_or_else(|e| panic!("Invalid data file {}: {}", filepath, e));
+
+    let mut data = match ModelData::from_string_keys(&keys) {
+        ModelData::IntKeyToIntPos(data) => data,
+        _ => unreachable!()
+    };
+    resolve_dup_keys(&mut data);
+
+    let orig_size = data.len();
+    return (orig_size, ModelData::IntKeyToIntPos(downsample_data(data, downsample)));
+}
+
+fn read_text_keys(filepath: &str) -> ModelData<'static> {
+    let mut contents = String::new();
+    File::open(filepath)
+        .and_then(|mut fd| fd.read_to_string(&mut contents))
+        .unwrap_or_else(|_| panic!("Unable to read data file at {}", filepath));
+
+    return parse_text_keys(&contents)
+        .unwrap_or_else(|e| panic!("Invalid data file {}: {}", filepath, e));
+}
+
+fn parse_text_keys(contents: &str) -> Result<ModelData<'static>, String> {
+    let lines: Vec<(usize, &str)> = contents.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, l)| !l.is_empty())
+        .collect();
+
+    let int_keys: Option<Vec<u64>> = lines.iter().map(|(_, l)| l.parse().ok()).collect();
+    if let Some(keys) = int_keys {
+        check_sorted(&keys, &lines)?;
+        let mut data: Vec<(u64, u64)> = keys.into_iter()
+            .enumerate().map(|(idx, k)| (k, idx as u64)).collect();
+        resolve_dup_keys(&mut data);
+        return Ok(ModelData::IntKeyToIntPos(data));
+    }
 
+    let mut keys = Vec::with_capacity(lines.len());
+    for (line_num, l) in lines.iter() {
+        match l.parse::<f64>() {
+            Ok(k) if k.is_finite() => keys.push(k),
+            _ => return Err(format!("line {} is not a number: {}", line_num + 1, l))
+        }
+    }
+    check_sorted(&keys, &lines)?;
+
+    let mut data: Vec<(f64, u64)> = keys.into_iter()
+        .enumerate().map(|(idx, k)| (k, idx as u64)).collect();
+    resolve_dup_keys(&mut data);
+    return Ok(ModelData::FloatKeyToIntPos(data));
+}
+
+fn check_sorted<T: PartialOrd>(keys: &[T], lines: &[(usize, &str)]) -> Result<(), String> {
+    return match keys.windows(2).position(|w| w[1] < w[0]) {
+        Some(idx) => {
+            let (line_num, l) = lines[idx + 1];
+            Err(format!("keys are not sorted: line {} ({}) is smaller than the key before it",
+                        line_num + 1, l))
+        },
+        None => Ok(())
+    };
+}
+
+fn downsample_data<T>(data: Vec<T>, downsample: usize) -> Vec<T> {
     // it is critical that we downsample AFTER assigning indexes and
     // not before.
-    if downsample > 1 {
-        trace!("Downsampling by a factor of {}", downsample);
-        init_data = init_data.into_iter().step_by(downsample).collect();
-        info!("Downsampled from {} to {}", orig_size, init_data.len());
+    if downsample <= 1 {
+        return data;
     }
 
-    return (orig_size, ModelData::IntKeyToIntPos(init_data));
+    let orig_size = data.len();
+    trace!("Downsampling by a factor of {}", downsample);
+    let data: Vec<T> = data.into_iter().step_by(downsample).collect();
+    info!("Downsampled from {} to {}", orig_size, data.len());
+    return data;
 }
 
 fn has_duplicates<T: PartialEq>(data: &[T]) -> bool {
@@ -81,7 +220,7 @@ fn has_duplicates<T: PartialEq>(data: &[T]) -> bool {
     return false;
 }
 
-fn resolve_dup_keys(data: &mut [(u64, u64)]) {
+fn resolve_dup_keys<T: PartialEq + Copy>(data: &mut [(T, u64)]) {
     if data.len() <= 1 {
         return;
     }
@@ -95,3 +234,83 @@ fn resolve_dup_keys(data: &mut [(u64, u64)]) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::string_to_monotonic_u64;
+
+    #[test]
+    fn test_parse_text_keys() {
+        let data = parse_text_keys("1\n5\n5\n\n9\n").unwrap();
+        assert!(matches!(&data, ModelData::IntKeyToIntPos(d)
+                         if d == &vec![(1, 0), (5, 1), (5, 1), (9, 3)]));
+
+        // one fractional key makes every key a float
+        let data = parse_text_keys("1\n2.5\n3\n").unwrap();
+        assert!(matches!(&data, ModelData::FloatKeyToIntPos(d)
+                         if d == &vec![(1.0, 0), (2.5, 1), (3.0, 2)]));
+
+        assert!(parse_text_keys("1\n3\n2\n").err().unwrap().contains("line 3"));
+        assert!(parse_text_keys("1.5\n0.5\n").err().unwrap().contains("not sorted"));
+        assert!(parse_text_keys("1\nabc\n").err().unwrap().contains("line 2"));
+        assert!(parse_text_keys("1\nNaN\n").is_err());
+    }
+
+    #[test]
+    fn test_load_text_data() {
+        let path = std::env::temp_dir().join("rmi_test_load_text_data.txt");
+        std::fs::write(&path, "0.5\n1.5\n2.5\n3.5\n").unwrap();
+        let (num_rows, data) = load_text_data(path.to_str().unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(num_rows, 4);
+        assert!(matches!(&data, ModelData::FloatKeyToIntPos(d)
+                         if d == &vec![(0.5, 0), (2.5, 2)]));
+    }
+
+    #[test]
+    fn test_load_float_text_data() {
+        let path = std::env::temp_dir().join("rmi_test_load_float_text_data.txt");
+        std::fs::write(&path, "0\n5\n9007199254740992\n9007199254740993\n").unwrap();
+        let (num_rows, data) = load_float_text_data(path.to_str().unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+
+        // the last two integers are the same float, so the same key
+        let big = float_to_monotonic_u64(9007199254740992.0);
+        assert_eq!(num_rows, 4);
+        assert!(matches!(&data, ModelData::IntKeyToIntPos(d)
+                         if d == &vec![(float_to_monotonic_u64(0.0), 0),
+                                       (float_to_monotonic_u64(5.0), 1), (big, 2), (big, 2)]));
+    }
+
+    #[test]
+    fn test_load_quantized_text_data() {
+        let path = std::env::temp_dir().join("rmi_test_load_quantized_text_data.txt");
+        std::fs::write(&path, "-1\n0.25\n0.29\n2.75\n").unwrap();
+        let (num_rows, data) = load_quantized_text_data(path.to_str().unwrap(), 10.0, 1);
+        std::fs::remove_file(&path).unwrap();
+
+        // the negative key saturates, and 0.25 and 0.29 become the same key
+        assert_eq!(num_rows, 4);
+        assert!(matches!(&data, ModelData::IntKeyToIntPos(d)
+                         if d == &vec![(0, 0), (2, 1), (2, 1), (27, 3)]));
+    }
+
+    #[test]
+    fn test_load_string_text_data() {
+        let path = std::env::temp_dir().join("rmi_test_load_string_text_data.txt");
+        std::fs::write(&path, "apple\nbanana\nbananarama1\nbananarama2\ncherry\n").unwrap();
+        let (num_rows, data) = load_string_text_data(path.to_str().unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+
+        // the two strings starting with "bananara" collide
+        let long = string_to_monotonic_u64(b"bananara");
+        assert_eq!(num_rows, 5);
+        assert!(matches!(&data, ModelData::IntKeyToIntPos(d)
+                         if d == &vec![(string_to_monotonic_u64(b"apple"), 0),
+                                       (string_to_monotonic_u64(b"banana"), 1),
+                                       (long, 2), (long, 2),
+                                       (string_to_monotonic_u64(b"cherry"), 4)]));
+    }
+}
@@ -9,15 +9,12 @@
 
 #![allow(clippy::needless_return)]
 
-mod codegen;
-mod load;
-mod models;
-mod train;
-mod optimizer;
-
-use load::{load_data, DataType};
-use models::ModelDataWrapper;
-use train::train;
+use rmi::{artifact, codegen, optimizer};
+use rmi::codegen::{CodegenOptions, Dialect, NamedRMI};
+use rmi::load::{load_data, load_float_text_data, load_quantized_text_data, load_string_text_data,
+           load_text_data, DataType};
+use rmi::models::{KeyTransform, ModelDataWrapper, NonFinitePolicy, PositionUnit};
+use rmi::train::{train_with_options, TrainingOptions, ErrorSlack, Diagnostics};
 
 use json::*;
 use log::*;
@@ -25,7 +22,7 @@ use std::f64;
 use std::fs::File;
 use std::io::BufWriter;
 use std::io::Write;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::fs;
 use std::path::Path;
 use rayon::prelude::*;
@@ -79,11 +76,227 @@ fn main() {
              .long("param-grid")
              .value_name("file")
              .help("train the RMIs specified in the JSON file and report their errors"))
+        .arg(Arg::with_name("float-keys")
+             .long("float-keys")
+             .help("load the keys of a text file as floats mapped by float_to_monotonic_u64, \
+                    and also generate lookup_float(double key), which maps its key the same way"))
+        .arg(Arg::with_name("non-finite-keys")
+             .long("non-finite-keys")
+             .value_name("policy")
+             .requires("float-keys")
+             .help("what lookup_float returns for NaN and infinite keys: sentinel:<pos> (the \
+                    default, sentinel:0), clamp (the first or last position for infinities, and \
+                    0 for NaN), or error (UINT64_MAX)"))
+        .arg(Arg::with_name("quantize-keys")
+             .long("quantize-keys")
+             .value_name("scale")
+             .conflicts_with("float-keys")
+             .help("load the keys of a text file as floor(key * scale), and also generate \
+                    lookup_quantized(double key), which scales its key the same way"))
+        .arg(Arg::with_name("string-keys")
+             .long("string-keys")
+             .conflicts_with("float-keys")
+             .conflicts_with("quantize-keys")
+             .help("load each line of a text file as a string key mapped by \
+                    string_to_monotonic_u64 (its first 8 bytes), and also generate \
+                    lookup_string(const char* key, size_t len), which maps its key the same way"))
+        .arg(Arg::with_name("bundle")
+             .long("bundle")
+             .value_name("name")
+             .requires("param-grid")
+             .help("with --param-grid, write the RMIs of all configs with a namespace into one \
+                    set of files (name.cpp, name.h, and name_data.h) instead of one set per \
+                    namespace. Their \"binary\" settings must agree"))
         .arg(Arg::with_name("data-path")
              .long("data-path")
              .short("d")
              .value_name("dir")
              .help("exports parameters to files in this directory instead of embedding them"))
+        .arg(Arg::with_name("compress-data")
+             .long("compress-data")
+             .requires("data-path")
+             .help("zstd compress the exported parameter files (requires the zstd feature)"))
+        .arg(Arg::with_name("pure-lookup")
+             .long("pure-lookup")
+             .help("mark the generated lookup function as pure (GCC/Clang), so repeated lookups of \
+                    the same key can be combined. Only applies to embedded parameters without -e."))
+        .arg(Arg::with_name("restrict-pointers")
+             .long("restrict-pointers")
+             .help("declare the error output pointer and loaded parameter pointers of the generated \
+                    code restrict (__restrict in C++), so the compiler may assume they do not alias"))
+        .arg(Arg::with_name("branch-hints")
+             .long("branch-hints")
+             .help("mark the branches of the generated code taken only for keys outside of the \
+                    trained range or the error window as unlikely (__builtin_expect on GCC and \
+                    Clang, a no-op elsewhere)"))
+        .arg(Arg::with_name("struct-result")
+             .long("struct-result")
+             .requires("last-layer-errors")
+             .help("return the position and error from lookup by value, as a struct RmiResult \
+                    { uint64_t pos; uint64_t err; }, instead of writing the error through a pointer"))
+        .arg(Arg::with_name("log2-errors")
+             .long("log2-errors")
+             .requires("last-layer-errors")
+             .help("report the last level error of a lookup as ceil(log2(err + 1)), the number of \
+                    bits of the absolute error err, instead of err itself"))
+        .arg(Arg::with_name("fixed-point")
+             .long("fixed-point")
+             .conflicts_with("last-layer-errors")
+             .conflicts_with("prune-leaves")
+             .conflicts_with("param-grid")
+             .help("evaluate the models with integer (fixed-point) arithmetic only, for targets \
+                    without an FPU. Only linear, linear_spline, linear_minimax, linear_centered, \
+                    linear_anchored, robust_linear, and radix layers are supported. Predictions \
+                    can differ from the floating point ones by a position, so the measured \
+                    errors are not guaranteed."))
+        .arg(Arg::with_name("align")
+             .long("align")
+             .value_name("bytes")
+             .help("align parameter arrays to this many bytes (a power of two, e.g. 16 for SIMD \
+                    loads or 64 for cache lines), padding each model's parameters in mixed arrays"))
+        .arg(Arg::with_name("max-array-elements")
+             .long("max-array-elements")
+             .value_name("count")
+             .conflicts_with("data-path")
+             .help("split embedded parameter arrays with more than this many elements into \
+                    smaller arrays of whole models, reached through an array of pointers. This \
+                    bounds the size of each C initializer, which keeps huge tables from slowing \
+                    down (or exhausting the memory of) the C compiler, at the cost of one more \
+                    load per lookup."))
+        .arg(Arg::with_name("simd-batch")
+             .long("simd-batch")
+             .help("also generate lookup_batch, which looks up an array of keys. When the code \
+                    is compiled with AVX2 and FMA (e.g. -mavx2 -mfma) and the top model is \
+                    linear or radix, it evaluates the top model on four keys at a time with \
+                    intrinsics; otherwise it calls lookup for each key. Both give the same \
+                    positions as lookup."))
+        .arg(Arg::with_name("c89")
+             .long("c89")
+             .conflicts_with("data-path")
+             .conflicts_with("last-layer-errors")
+             .conflicts_with("pure-lookup")
+             .conflicts_with("restrict-pointers")
+             .conflicts_with("struct-result")
+             .conflicts_with("align")
+             .conflicts_with("simd-batch")
+             .help("generate C89 (file.c) instead of C++, for toolchains without <cstdint> or \
+                    inline functions. Names are prefixed by the namespace, and only the plain \
+                    lookup with embedded parameters of linear, linear_spline, robust_linear, \
+                    cubic, and radix models is supported."))
+        .arg(Arg::with_name("rust-no-std")
+             .long("rust-no-std")
+             .conflicts_with("c89")
+             .conflicts_with("data-path")
+             .conflicts_with("prune-leaves")
+             .conflicts_with("param-grid")
+             .conflicts_with("exact-lookup")
+             .conflicts_with("selftest")
+             .conflicts_with("log2-errors")
+             .conflicts_with("simd-batch")
+             .help("generate Rust for #![no_std] crates (namespace.rs) instead of C++, with a \
+                    lookup(key) -> (pos, err) over static parameter arrays. The models are \
+                    evaluated in fixed point, as with --fixed-point, and the errors are measured \
+                    with the fixed-point models."))
+        .arg(Arg::with_name("artifact")
+             .long("artifact")
+             .conflicts_with("c89")
+             .conflicts_with("rust-no-std")
+             .conflicts_with("data-path")
+             .conflicts_with("prune-leaves")
+             .conflicts_with("max-leaf-models")
+             .conflicts_with("key-offset")
+             .conflicts_with("key-scale")
+             .conflicts_with("param-grid")
+             .conflicts_with("exact-lookup")
+             .conflicts_with("selftest")
+             .help("write the layers and parameters as an artifact (namespace.rmia) instead of \
+                    generating C++, with its schema (namespace.rmia.txt), for programs that \
+                    evaluate the RMI themselves. Only linear, cubic, radix, and \
+                    connected_spline models are supported."))
+        .arg(Arg::with_name("exact-lookup")
+             .long("exact-lookup")
+             .requires("data-path")
+             .requires("last-layer-errors")
+             .conflicts_with("record-size")
+             .conflicts_with("param-grid")
+             .help("also generate lookup_exact, which reports whether the key exists and its exact \
+                    position. The keys are written to the data directory and read by load."))
+        .arg(Arg::with_name("hinted-lookup")
+             .long("hinted-lookup")
+             .requires("exact-lookup")
+             .help("also generate lookup_hinted, which returns the position of a key given a position \
+                    it is expected to be near, searching outwards from it when it is within the \
+                    error window, and lookup_hinted_window, which narrows the error window to a \
+                    window around the hint"))
+        .arg(Arg::with_name("selftest")
+             .long("selftest")
+             .value_name("samples")
+             .conflicts_with("param-grid")
+             .help("embed this many (key, position) pairs sampled from the data, and generate \
+                    rmi_selftest, which returns 0 if the lookup of each is within the error bound"))
+        .arg(Arg::with_name("record-size")
+             .long("record-size")
+             .value_name("bytes")
+             .conflicts_with("optimize")
+             .help("treat positions as byte offsets of records of this size. Lookups return \
+                    (and -e errors are given as) byte offsets aligned to a record."))
+        .arg(Arg::with_name("error-slack")
+             .long("error-slack")
+             .value_name("slack")
+             .help("widen each stored last layer error by a number of positions (e.g. 16) or a percentage (e.g. 5%), \
+                    so lookups stay correct after that many inserts. Only a fixed number of positions \
+                    guarantees correctness, and only up to that many inserted keys."))
+        .arg(Arg::with_name("diagnostics")
+             .long("diagnostics")
+             .value_name("mode")
+             .possible_values(&["warn", "strict"])
+             .help("check each last layer model against always predicting the midpoint of its positions, \
+                    and warn (or with strict, fail) when the model's max error is worse"))
+        .arg(Arg::with_name("prune-leaves")
+             .long("prune-leaves")
+             .help("replace each last layer model that is no more accurate than the top model \
+                    with the top model's own prediction (two layer RMIs only)"))
+        .arg(Arg::with_name("max-leaf-models")
+             .long("max-leaf-models")
+             .value_name("count")
+             .conflicts_with("prune-leaves")
+             .help("store at most this many last layer models, by merging adjacent ones (the \
+                    emptiest first) and mapping the top model's output to them through a table \
+                    (two layer RMIs only)"))
+        .arg(Arg::with_name("detect-dense-ranges")
+             .long("detect-dense-ranges")
+             .help("if the keys are consecutive integers, replace the requested models with a \
+                    single dense_range model, which computes each position exactly"))
+        .arg(Arg::with_name("key-offset")
+             .long("key-offset")
+             .value_name("offset")
+             .conflicts_with("exact-lookup")
+             .conflicts_with("optimize")
+             .help("subtract this from every key (e.g. an epoch) before training, and in the \
+                    generated lookup. Keys below it are treated as the offset."))
+        .arg(Arg::with_name("key-scale")
+             .long("key-scale")
+             .value_name("scale")
+             .conflicts_with("exact-lookup")
+             .conflicts_with("optimize")
+             .help("divide every key (after subtracting --key-offset) by this (e.g. a \
+                    granularity) before training, and in the generated lookup"))
+        .arg(Arg::with_name("position-divisor")
+             .long("position-divisor")
+             .value_name("divisor")
+             .conflicts_with("param-grid")
+             .conflicts_with("optimize")
+             .conflicts_with("exact-lookup")
+             .conflicts_with("selftest")
+             .conflicts_with("rust-no-std")
+             .help("divide every position by this (e.g. the records, or with --record-size the \
+                    bytes, per page) before training, so the generated lookup returns the index \
+                    of the page holding the key, and the errors are in pages"))
+        .arg(Arg::with_name("lookup-window-errors")
+             .long("lookup-window-errors")
+             .help("measure the errors from the position the generated lookup returns (clamped \
+                    and rounded down to a record) instead of the raw prediction, so the reported \
+                    errors are exactly the search windows the code needs"))
         .arg(Arg::with_name("threads")
              .long("threads")
              .short("t")
@@ -96,6 +309,61 @@ fn main() {
              .long("optimize")
              .value_name("file")
              .help("Search for Pareto efficient RMI configurations. Specify the name of the output file."))
+        .arg(Arg::with_name("verbose")
+             .long("verbose")
+             .short("v")
+             .help("with --optimize, also report percentiles of the per-key error and the \
+                    fraction of keys predicted exactly (which training a single RMI also logs)"))
+        .arg(Arg::with_name("csv")
+             .long("csv")
+             .value_name("file")
+             .requires("optimize")
+             .help("with --optimize, also write the statistics of every measured config to a CSV file"))
+        .arg(Arg::with_name("max-concurrent")
+             .long("max-concurrent")
+             .value_name("count")
+             .requires("optimize")
+             .help("with --optimize, train at most this many RMIs at a time, to bound memory use \
+                    (default: as many as there are threads)"))
+        .arg(Arg::with_name("cache")
+             .long("cache")
+             .value_name("file")
+             .requires("optimize")
+             .help("with --optimize, reuse the configs measured by earlier runs on the same data \
+                    from this file, and add new measurements to it"))
+        .arg(Arg::with_name("first-phase-only")
+             .long("first-phase-only")
+             .requires("optimize")
+             .help("with --optimize, skip the second phase (the sweep over every branching \
+                    factor) for a faster, coarser search"))
+        .arg(Arg::with_name("time-budget")
+             .long("time-budget")
+             .value_name("seconds")
+             .requires("optimize")
+             .help("with --optimize, start no new measurements after this many seconds, and \
+                    pick the Pareto front from the configs measured by then"))
+        .arg(Arg::with_name("pin")
+             .long("pin")
+             .value_name("models:branching factor")
+             .multiple(true)
+             .requires("optimize")
+             .help("with --optimize, always measure and report this config (e.g. \
+                    linear,linear:4096), such as one already in production, even if others \
+                    beat it. Can be given more than once"))
+        .arg(Arg::with_name("narrow-bias")
+             .long("narrow-bias")
+             .value_name("bias")
+             .requires("optimize")
+             .help("with --optimize, a value between 0 (default) and 1. Higher values keep more of \
+                    the most accurate configs when picking which to report, instead of spreading \
+                    them evenly over sizes"))
+        .arg(Arg::with_name("objective")
+             .long("objective")
+             .value_name("error")
+             .possible_values(&["avg", "max", "composite"])
+             .help("with --optimize, the error to trade off against size: average (default), \
+                    maximum, or composite (a config must be beaten on size and both errors to \
+                    be dropped)"))
         .get_matches();
 
     // set the max number of threads to 4 by default, otherwise Rayon goes
@@ -111,7 +379,64 @@ fn main() {
         .map(|x| x.parse::<usize>().unwrap())
         .unwrap_or(1);
 
-    let data_dir = matches.value_of("data-path");
+    let train_options = TrainingOptions {
+        error_slack: matches.value_of("error-slack").map(|s| {
+            ErrorSlack::parse(s).unwrap_or_else(|| panic!("Invalid error slack: {}", s))
+        }),
+        diagnostics: match matches.value_of("diagnostics") {
+            Some("warn") => Diagnostics::Warn,
+            Some("strict") => Diagnostics::Strict,
+            _ => Diagnostics::Off
+        },
+        prune_leaves: matches.is_present("prune-leaves"),
+        max_leaf_models: matches.value_of("max-leaf-models").map(|s| {
+            s.parse::<usize>().ok().filter(|c| *c > 0)
+                .unwrap_or_else(|| panic!("Invalid max leaf models: {}", s))
+        }),
+        detect_dense_ranges: matches.is_present("detect-dense-ranges"),
+        key_transform: if matches.is_present("key-offset") || matches.is_present("key-scale") {
+            let offset = matches.value_of("key-offset").map(|s| {
+                s.parse::<u64>().unwrap_or_else(|_| panic!("Invalid key offset: {}", s))
+            }).unwrap_or(0);
+            let scale = matches.value_of("key-scale").map(|s| {
+                s.parse::<u64>().ok().filter(|s| *s > 0)
+                    .unwrap_or_else(|| panic!("Invalid key scale: {}", s))
+            }).unwrap_or(1);
+            Some(KeyTransform::new(offset, scale))
+        } else {
+            None
+        },
+        position_divisor: matches.value_of("position-divisor").map(|s| {
+            s.parse::<u64>().ok().filter(|d| *d > 0)
+                .unwrap_or_else(|| panic!("Invalid position divisor: {}", s))
+        }),
+        lookup_window_errors: matches.is_present("lookup-window-errors"),
+        ..Default::default()
+    };
+    let codegen_options = CodegenOptions {
+        data_dir: matches.value_of("data-path").map(String::from),
+        compress_data: matches.is_present("compress-data"),
+        pure_hint: matches.is_present("pure-lookup"),
+        alignment: matches.value_of("align").map(|s| {
+            s.parse::<usize>().ok().filter(|a| a.is_power_of_two())
+                .unwrap_or_else(|| panic!("Invalid alignment: {}", s))
+        }),
+        restrict_pointers: matches.is_present("restrict-pointers"),
+        dialect: if matches.is_present("c89") { Dialect::C89 } else { Dialect::Cpp },
+        struct_result: matches.is_present("struct-result"),
+        fixed_point: matches.is_present("fixed-point"),
+        branch_hints: matches.is_present("branch-hints"),
+        log2_errors: matches.is_present("log2-errors"),
+        max_array_elements: matches.value_of("max-array-elements").map(|s| {
+            s.parse::<usize>().ok().filter(|n| *n > 0)
+                .unwrap_or_else(|| panic!("Invalid maximum array elements: {}", s))
+        }),
+        simd_batch: matches.is_present("simd-batch"),
+        ..Default::default()
+    };
+    if codegen_options.compress_data && !cfg!(feature = "zstd") {
+        panic!("--compress-data requires building with the zstd feature");
+    }
     
     if matches.value_of("namespace").is_some() && matches.value_of("param-grid").is_some() {
         panic!("Can only specify one of namespace or param-grid");
@@ -119,15 +444,97 @@ fn main() {
     
     info!("Reading {}...", fp);
 
-    let (num_rows, data) = if fp.contains("uint64") {
+    let float_keys = matches.is_present("float-keys");
+    let non_finite_keys = matches.value_of("non-finite-keys").map(|s| {
+        NonFinitePolicy::parse(s).unwrap_or_else(|| panic!("Invalid non-finite key policy: {}", s))
+    }).unwrap_or_default();
+    let quantized_keys = matches.value_of("quantize-keys").map(|s| {
+        s.parse::<f64>().ok().filter(|scale| *scale > 0.0)
+            .unwrap_or_else(|| panic!("Invalid key scale: {}", s))
+    });
+    let string_keys = matches.is_present("string-keys");
+    let (num_rows, mut data) = if float_keys {
+        assert!(fp.ends_with(".txt"), "--float-keys requires a text data file");
+        load_float_text_data(fp, downsample)
+    } else if let Some(key_scale) = quantized_keys {
+        assert!(fp.ends_with(".txt"), "--quantize-keys requires a text data file");
+        load_quantized_text_data(fp, key_scale, downsample)
+    } else if string_keys {
+        assert!(fp.ends_with(".txt"), "--string-keys requires a text data file");
+        load_string_text_data(fp, downsample)
+    } else if fp.ends_with(".txt") {
+        load_text_data(fp, downsample)
+    } else if fp.contains("uint64") {
         load_data(&fp, DataType::UINT64, downsample)
     } else {
         load_data(&fp, DataType::UINT32, downsample)
     };
 
+    let position_unit = match matches.value_of("record-size") {
+        Some(s) => {
+            let record_size = s.parse::<u64>().ok().filter(|rs| *rs > 0)
+                .unwrap_or_else(|| panic!("Invalid record size: {}", s));
+            data.positions_to_offsets(record_size);
+            PositionUnit::Offset(record_size)
+        },
+        None => PositionUnit::Element
+    };
+    let num_positions = match position_unit {
+        PositionUnit::Element => num_rows,
+        PositionUnit::Offset(record_size) => num_rows * record_size as usize
+    };
+
     if matches.is_present("optimize") {
-        let results = optimizer::find_pareto_efficient_configs(&data, 10);
-        optimizer::RMIStatistics::display_table(&results);
+        let verbose = matches.is_present("verbose");
+        let objective = match matches.value_of("objective") {
+            Some("max") => optimizer::Objective::MaxError,
+            Some("composite") => optimizer::Objective::Composite,
+            _ => optimizer::Objective::AvgError
+        };
+        let narrow_bias = matches.value_of("narrow-bias").map(|s| {
+            s.parse::<f64>().ok().filter(|b| (0.0..=1.0).contains(b))
+                .unwrap_or_else(|| panic!("Invalid narrowing bias: {}", s))
+        }).unwrap_or(0.0);
+        let max_concurrent = matches.value_of("max-concurrent").map(|s| {
+            s.parse::<usize>().ok().filter(|c| *c > 0)
+                .unwrap_or_else(|| panic!("Invalid max concurrent RMIs: {}", s))
+        });
+        let phases = if matches.is_present("first-phase-only") {
+            optimizer::Phases::FirstOnly
+        } else {
+            optimizer::Phases::Both
+        };
+        let time_budget = matches.value_of("time-budget").map(|s| {
+            s.parse::<f64>().ok().filter(|t| *t > 0.0 && t.is_finite())
+                .map(Duration::from_secs_f64)
+                .unwrap_or_else(|| panic!("Invalid time budget: {}", s))
+        });
+        let pinned: Vec<(String, u64)> = matches.values_of("pin").into_iter().flatten()
+            .map(|s| s.rsplit_once(':')
+                 .and_then(|(models, bf)| Some((models.to_string(), bf.parse::<u64>().ok()?)))
+                 .unwrap_or_else(|| panic!("Invalid pinned config: {}", s)))
+            .collect();
+        let (report, all_results) = optimizer::search_configs(&data, 10, verbose,
+                                                               objective, max_concurrent,
+                                                               narrow_bias,
+                                                               matches.value_of("cache"),
+                                                               phases, None, time_budget,
+                                                               &pinned)
+            .unwrap_or_else(|e| panic!("Could not optimize: {}", e));
+        if report.budget_exhausted {
+            warn!("The time budget ran out after measuring {} of {} planned configs",
+                  report.measured_count, report.planned_count);
+        }
+        info!("Measured {} configs ({} in {:?}, then {} in {:?}), {} on the Pareto front",
+              report.measured_count, report.phase1_count, report.phase1_elapsed,
+              report.phase2_count, report.phase2_elapsed, report.front_size);
+        let results = report.selected;
+        if let Some(csv_fp) = matches.value_of("csv") {
+            let f = File::create(csv_fp).expect("Could not write CSV file");
+            let mut bw = BufWriter::new(f);
+            optimizer::RMIStatistics::write_csv(&all_results, &mut bw).unwrap();
+        }
+        optimizer::RMIStatistics::display_table(&results, verbose);
 
         let nmspc_prefix = if matches.value_of("namespace").is_some() {
             matches.value_of("namespace").unwrap()
@@ -153,6 +560,7 @@ fn main() {
     }
     
     if let Some(param_grid) = matches.value_of("param-grid").map(|x| x.to_string()) {
+        let bundle = matches.value_of("bundle");
         let pg = {
             let raw_json = fs::read_to_string(param_grid.clone()).unwrap();
             let mut as_json = json::parse(raw_json.as_str()).unwrap();
@@ -188,9 +596,12 @@ fn main() {
                     trace!("Training RMI {} with branching factor {}",
                            models, *branch_factor);
                     let mut md_container = ModelDataWrapper::new(&data);
+                    md_container.set_position_unit(position_unit);
                     
                     let start_time = SystemTime::now();
-                    let trained_model = train(&mut md_container, models, *branch_factor);
+                    let trained_model = train_with_options(&mut md_container, models,
+                                                           *branch_factor, &train_options)
+                        .unwrap_or_else(|e| panic!("Could not train RMI {}: {}", models, e));
                     let build_time = SystemTime::now()
                         .duration_since(start_time)
                         .map(|d| d.as_nanos())
@@ -204,12 +615,12 @@ fn main() {
                         "branching factor" => *branch_factor,
                         "average error" => trained_model.model_avg_error as f64,
                         "average error %" => trained_model.model_max_error as f64
-                            / num_rows as f64 * 100.0,
+                            / num_positions as f64 * 100.0,
                         "average l2 error" => trained_model.model_avg_l2_error as f64,
                         "average log2 error" => trained_model.model_avg_log2_error,
                         "max error" => trained_model.model_max_error,
                         "max error %" => trained_model.model_max_error as f64
-                            / num_rows as f64 * 100.0,
+                            / num_positions as f64 * 100.0,
                         "max log2 error" => trained_model.model_max_log2_error,
                         "size binary search" => size_bs,
                         "size linear search" => size_ls,
@@ -217,29 +628,51 @@ fn main() {
                         "binary" => *bsearch
                     };
                     
+                    // bundled RMIs are written once all of them are trained
+                    let mut to_bundle = None;
                     if let Some(nmspc) = namespace {
-                        codegen::output_rmi(
-                            &nmspc,
-                            *bsearch,
-                            trained_model,
-                            num_rows,
-                            build_time,
-                            data_dir).unwrap();
-                        
+                        let named = NamedRMI {
+                            float_keys, non_finite_keys, quantized_keys, string_keys,
+                            ..NamedRMI::new(&nmspc, trained_model, num_positions, build_time)
+                        };
+                        if bundle.is_some() {
+                            to_bundle = Some((named, *bsearch));
+                        } else {
+                            let options = CodegenOptions {
+                                last_layer_errors: *bsearch,
+                                fixed_point: false,
+                                ..codegen_options.clone()
+                            };
+                            codegen::output_rmi(named, &options).unwrap();
+                        }
                     }
                     
                     pbar.inc(1);
-                    return result_obj;
+                    return (result_obj, to_bundle);
                 };
 
-            let results: Vec<JsonValue> =
+            let (results, to_bundle): (Vec<JsonValue>, Vec<Option<(NamedRMI, bool)>>) =
                 if matches.is_present("disable-parallel-training") {
                     trace!("Training models sequentially");
-                    to_test.iter().map(train_func).collect()
+                    to_test.iter().map(train_func).unzip()
                 } else {
                     trace!("Training models in parallel");
-                    to_test.par_iter().map(train_func).collect()
+                    to_test.par_iter().map(train_func).unzip()
                 };
+
+            if let Some(file_name) = bundle {
+                let (rmis, bsearch): (Vec<NamedRMI>, Vec<bool>) =
+                    to_bundle.into_iter().flatten().unzip();
+                assert!(!rmis.is_empty(), "--bundle requires configs with a namespace");
+                assert!(bsearch.iter().all(|b| *b == bsearch[0]),
+                        "Bundled configs must agree on \"binary\"");
+                let options = CodegenOptions {
+                    last_layer_errors: bsearch[0],
+                    fixed_point: false,
+                    ..codegen_options.clone()
+                };
+                codegen::output_rmis(file_name, rmis, &options).unwrap();
+            }
             
             //let results: Vec<JsonValue> = to_test
             //.par_iter().map(
@@ -264,19 +697,26 @@ fn main() {
             .unwrap();
         let last_layer_errors = matches.is_present("last-layer-errors");
         let mut md_container = ModelDataWrapper::new(&data);
+        md_container.set_position_unit(position_unit);
 
         let start_time = SystemTime::now();
-        let trained_model = train(&mut md_container, models, branch_factor);
+        let mut trained_model = train_with_options(&mut md_container, models,
+                                                   branch_factor, &train_options)
+            .unwrap_or_else(|e| panic!("Could not train RMI {}: {}", models, e));
+        // a downsampled RMI still indexes every row
+        trained_model.num_keys = num_rows;
         let build_time = SystemTime::now()
             .duration_since(start_time)
             .map(|d| d.as_nanos())
             .unwrap_or(std::u128::MAX);
         info!("Model build time: {} ms", build_time / 1_000_000);
+        // with a position divisor, the errors are in pages
+        let lookup_positions = trained_model.num_lookup_positions(num_positions);
 
         info!(
             "Average model error: {} ({}%)",
             trained_model.model_avg_error as f64,
-            trained_model.model_avg_error / num_rows as f64 * 100.0
+            trained_model.model_avg_error / lookup_positions as f64 * 100.0
         );
         info!(
             "Average model L2 error: {}",
@@ -294,8 +734,20 @@ fn main() {
             "Max model error on model {}: {} ({}%)",
             trained_model.model_max_error_idx,
             trained_model.model_max_error,
-            trained_model.model_max_error as f64 / num_rows as f64 * 100.0
+            trained_model.model_max_error as f64 / lookup_positions as f64 * 100.0
         );
+        if matches.is_present("verbose") {
+            info!(
+                "Keys predicted exactly: {}%",
+                trained_model.exact_fraction * 100.0
+            );
+        }
+        if trained_model.rmi[trained_model.rmi.len() - 1][0].chosen_type().is_some() {
+            let mix: Vec<String> = trained_model.leaf_type_mix().iter()
+                .map(|(name, count)| format!("{} {}", count, name))
+                .collect();
+            info!("Last layer model types: {}", mix.join(", "));
+        }
         
         match matches.value_of("stats-file") {
             None => {}
@@ -322,13 +774,39 @@ fn main() {
         }
         
         if !matches.is_present("no-code") {
-            codegen::output_rmi(
-                &namespace,
-                last_layer_errors,
-                trained_model,
-                num_rows,
-                build_time,
-                data_dir).unwrap();
+            let exact_keys = if matches.is_present("exact-lookup") {
+                assert_eq!(downsample, 1, "--exact-lookup cannot be used with downsampling");
+                Some(ModelDataWrapper::new(&data).iter_int_int().map(|(k, _)| k).collect())
+            } else {
+                None
+            };
+            let hinted_lookup = matches.is_present("hinted-lookup");
+            let selftest_samples = matches.value_of("selftest").map(|s| {
+                let count = s.parse::<usize>().ok().filter(|c| *c > 0)
+                    .unwrap_or_else(|| panic!("Invalid number of self-test samples: {}", s));
+                codegen::selftest_samples(&data, count)
+            });
+
+            if matches.is_present("rust-no-std") {
+                codegen::output_rust_no_std(&namespace, trained_model,
+                                            &ModelDataWrapper::new(&data), num_positions,
+                                            build_time).unwrap();
+                return;
+            }
+
+            if matches.is_present("artifact") {
+                artifact::output_artifact(&namespace, &trained_model, num_positions,
+                                          last_layer_errors).unwrap();
+                return;
+            }
+
+            let named = NamedRMI {
+                exact_keys, hinted_lookup, float_keys, non_finite_keys, quantized_keys,
+                string_keys, selftest_samples,
+                ..NamedRMI::new(&namespace, trained_model, num_positions, build_time)
+            };
+            let options = CodegenOptions { last_layer_errors, ..codegen_options };
+            codegen::output_rmi(named, &options).unwrap();
         } else {
             trace!("Skipping code generation due to CLI flag");
         }
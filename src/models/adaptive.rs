@@ -0,0 +1,240 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+
+// A model that picks its own type from the data it is trained on: a line,
+// a cubic, or a connected spline with two segments, whichever has the
+// lowest max error among those that fit in its parameter slots. Every
+// model of a layer stores the same number of slots (so codegen can lay
+// them out as arrays), plus a tag with the type it chose, which the
+// generated code switches on. On mixed data, a layer of these can beat
+// any layer of a single type.
+
+// the types an adaptive model can choose, by their tag
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AdaptiveChoice {
+    Line = 0,
+    Cubic = 1,
+    Spline = 2
+}
+
+impl AdaptiveChoice {
+    // the number of parameter slots the type uses
+    fn slots(self) -> usize {
+        return match self {
+            AdaptiveChoice::Line => 2,
+            AdaptiveChoice::Cubic => 4,
+            AdaptiveChoice::Spline => 6
+        };
+    }
+
+    pub fn name(self) -> &'static str {
+        return match self {
+            AdaptiveChoice::Line => "line",
+            AdaptiveChoice::Cubic => "cubic",
+            AdaptiveChoice::Spline => "spline"
+        };
+    }
+}
+
+pub struct AdaptiveModel {
+    choice: AdaptiveChoice,
+    // the parameters of the chosen type, padded with zeros to max_slots
+    params: Vec<f64>,
+    max_slots: usize
+}
+
+fn float_params(model: &dyn Model) -> Vec<f64> {
+    return model.params().iter().flat_map(|p| match p {
+        ModelParam::Float(v) => vec![*v],
+        ModelParam::FloatArray(a) => a.clone(),
+        _ => panic!("adaptive candidates only have float parameters")
+    }).collect();
+}
+
+impl AdaptiveModel {
+    // max_slots is 4 (lines and cubics) or 6 (all types)
+    pub fn new(data: &ModelDataWrapper, max_slots: usize) -> AdaptiveModel {
+        assert!(max_slots == 4 || max_slots == 6, "adaptive models have 4 or 6 parameter slots");
+
+        let mut choices = vec![AdaptiveChoice::Line, AdaptiveChoice::Line, AdaptiveChoice::Cubic];
+        let mut candidates: Vec<Box<dyn Model>> = vec![
+            Box::new(LinearModel::new(data)),
+            Box::new(LinearSplineModel::new(data)),
+            Box::new(CubicSplineModel::new(data))
+        ];
+        if max_slots >= AdaptiveChoice::Spline.slots() {
+            choices.push(AdaptiveChoice::Spline);
+            candidates.push(Box::new(ConnectedSplineModel::new(data, 2)));
+        }
+
+        let best = most_accurate(&candidates, data);
+        let choice = choices[best];
+        let mut params = float_params(candidates[best].as_ref());
+        if choice == AdaptiveChoice::Spline {
+            // knots at duplicate keys are dropped, so repeat the last knot
+            // to fill all three, which predicts the same for every key
+            while params.len() < choice.slots() {
+                let knot = (params[params.len() - 2], params[params.len() - 1]);
+                params.push(knot.0);
+                params.push(knot.1);
+            }
+        }
+        params.resize(max_slots, 0.0);
+
+        return AdaptiveModel { choice, params, max_slots };
+    }
+
+    pub fn choice(&self) -> AdaptiveChoice {
+        return self.choice;
+    }
+}
+
+impl Model for AdaptiveModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        let x = inp.as_float();
+        let p = &self.params;
+        return match self.choice {
+            AdaptiveChoice::Line => p[1].mul_add(x, p[0]),
+            AdaptiveChoice::Cubic => p[0].mul_add(x, p[1]).mul_add(x, p[2]).mul_add(x, p[3]),
+            AdaptiveChoice::Spline => {
+                if x <= p[0] {
+                    p[1]
+                } else if x >= p[4] {
+                    p[5]
+                } else {
+                    // the segment starting at the last knot at or before x
+                    let a = if x < p[2] { &p[0..4] } else { &p[2..6] };
+                    a[1] + (a[3] - a[1]) * ((x - a[0]) / (a[2] - a[0]))
+                }
+            }
+        };
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        // the tag is a double too, since codegen can only lay out the
+        // parameters of a layer as one array if they have the same type
+        let mut params = vec![ModelParam::Float(self.choice as u64 as f64)];
+        params.extend(self.params.iter().map(|p| ModelParam::Float(*p)));
+        return params;
+    }
+
+    fn code(&self) -> String {
+        let slots: Vec<String> = (0..self.max_slots).map(|i| format!("double p{}", i)).collect();
+        let spline = if self.max_slots >= AdaptiveChoice::Spline.slots() {
+            "
+    case 2:
+        if (inp <= p0) return p1;
+        if (inp >= p4) return p5;
+        if (inp < p2) return p1 + (p3 - p1) * ((inp - p0) / (p2 - p0));
+        return p3 + (p5 - p3) * ((inp - p2) / (p4 - p2));"
+        } else {
+            ""
+        };
+        return format!(
+            "
+inline double {name}(double type, {slots}, double inp) {{
+    switch ((int) type) {{
+    case 1:
+        return std::fma(std::fma(std::fma(p0, inp, p1), inp, p2), inp, p3);{spline}
+    default:
+        return std::fma(p1, inp, p0);
+    }}
+}}",
+            name = self.function_name(),
+            slots = slots.join(", "),
+            spline = spline
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return format!("adaptive{}", self.max_slots);
+    }
+
+    fn chosen_type(&self) -> Option<&'static str> {
+        return Some(self.choice.name());
+    }
+
+    fn set_to_constant_model(&mut self, constant: u64) -> bool {
+        self.choice = AdaptiveChoice::Line;
+        self.params = vec![0.0; self.max_slots];
+        self.params[0] = constant as f64;
+        return true;
+    }
+
+    fn is_constant(&self) -> bool {
+        let p = &self.params;
+        return match self.choice {
+            AdaptiveChoice::Line => p[1] == 0.0,
+            AdaptiveChoice::Cubic => p[0] == 0.0 && p[1] == 0.0 && p[2] == 0.0,
+            AdaptiveChoice::Spline => p[1] == p[5]
+        };
+    }
+
+    fn is_monotone(&self) -> bool {
+        let p = &self.params;
+        return match self.choice {
+            // fma rounds the exact (non-decreasing) value once
+            AdaptiveChoice::Line => p[1] >= 0.0,
+            AdaptiveChoice::Cubic => false,
+            AdaptiveChoice::Spline => p[1] <= p[3] && p[3] <= p[5]
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choices() {
+        // a line is exact on linear data, and chosen over the larger types
+        let md = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * 3, i)).collect());
+        let model = AdaptiveModel::new(&ModelDataWrapper::new(&md), 6);
+        assert_eq!(model.choice(), AdaptiveChoice::Line);
+        assert_eq!(model.params().len(), 7);
+
+        // two lines with different slopes need the spline
+        let md = ModelData::IntKeyToIntPos((0..1000)
+            .map(|i| (if i < 500 { i } else { 500 + (i - 500) * 100 }, i)).collect());
+        let container = ModelDataWrapper::new(&md);
+        let model = AdaptiveModel::new(&container, 6);
+        assert_eq!(model.choice(), AdaptiveChoice::Spline);
+        assert!(max_error(&model, &container) <= 1);
+
+        // but it does not fit in four slots
+        let model = AdaptiveModel::new(&container, 4);
+        assert_ne!(model.choice(), AdaptiveChoice::Spline);
+        assert_eq!(model.params().len(), 5);
+
+        // float keys are measured as floats, not truncated
+        let md = ModelData::FloatKeyToIntPos((0..1000).map(|i| (i as f64 / 16.0, i)).collect());
+        let container = ModelDataWrapper::new(&md);
+        let model = AdaptiveModel::new(&container, 6);
+        assert_eq!(model.choice(), AdaptiveChoice::Line);
+        assert_eq!(max_error(&model, &container), 0);
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let md = ModelData::IntKeyToIntPos((0..100).map(|i| (7, i)).collect());
+        let model = AdaptiveModel::new(&ModelDataWrapper::new(&md), 6);
+        assert_eq!(model.params().len(), 7);
+        assert!(model.predict_to_float(7.into()).is_finite());
+        assert!(model.predict_to_float(0.into()).is_finite());
+        assert!(model.predict_to_float(100.into()).is_finite());
+    }
+}
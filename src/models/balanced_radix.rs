@@ -92,6 +92,13 @@ impl BalancedRadixModel {
         }
 
         let largest_value = data.iter_int_int().map(|(_x, y)| y).max().unwrap();
+        if largest_value < 3 {
+            // too few targets to balance, always predict 0
+            return BalancedRadixModel {
+                params: (0, 0, 0),
+                high: true,
+            };
+        }
 
         return bradix(data, largest_value);
     }
@@ -100,6 +107,9 @@ impl BalancedRadixModel {
 impl Model for BalancedRadixModel {
     fn predict_to_int(&self, inp: ModelInput) -> u64 {
         let (left_shift, num_bits, clamp) = self.params;
+        if num_bits == 0 {
+            return 0;
+        }
 
         let as_int: u64 = inp.as_int();
         let res = (as_int << left_shift) >> (64 - num_bits);
@@ -127,6 +137,19 @@ impl Model for BalancedRadixModel {
     }
 
     fn code(&self) -> String {
+        // with no bits, every key goes to the first model. This is known
+        // when the code is generated, so lookups do not check for it.
+        if self.params.1 == 0 {
+            return format!(
+                "
+inline uint64_t {}(uint64_t, uint64_t, uint64_t, uint64_t) {{
+    return 0;
+}}
+",
+                self.function_name()
+            );
+        }
+
         if self.high {
             return String::from(
                 "
@@ -176,4 +199,13 @@ mod tests {
         BalancedRadixModel::new(&ModelData::empty());
     }
 
+    #[test]
+    fn test_single() {
+        let md = ModelData::IntKeyToIntPos(vec![(5, 0)]);
+        let model = BalancedRadixModel::new(&ModelDataWrapper::new(&md));
+        assert_eq!(model.predict_to_int(5.into()), 0);
+        assert!(model.code().contains("    return 0;"));
+        assert!(!model.code().contains("bits == 0") && !model.code().contains("64 - bits"));
+    }
+
 }
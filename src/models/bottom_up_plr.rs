@@ -32,21 +32,59 @@ fn bottom_up_plr(data: &ModelDataWrapper) -> (Vec<u64>, Vec<f64>) {
     return (points, coeffs);
 }
 
+// how the segments of a BottomUpPLR are laid out in the generated code.
+// StructOfArrays is the default (`plr`); ArrayOfStructs (`plr_aos`) is
+// opt-in, since bench_segment_layouts has not shown it to be faster.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SegmentLayout {
+    // one array of (first key, slope, intercept) records, with the floats
+    // stored as their bits. Once the search has found a segment, its slope
+    // and intercept are next to its key, so the last step touches one cache
+    // line (two for the quarter of the 24 byte records that straddle a line
+    // boundary).
+    ArrayOfStructs,
+
+    // an array of first keys and a separate array of (slope, intercept)
+    // pairs, so the last step touches a line of each (or three lines, for
+    // the pairs that straddle a boundary). The keys are packed closer, so
+    // the last few probes of the search share more lines.
+    StructOfArrays
+}
+
+impl Default for SegmentLayout {
+    fn default() -> SegmentLayout {
+        return SegmentLayout::StructOfArrays;
+    }
+}
+
 pub struct BottomUpPLR {
     radix: Vec<u64>,
     points: Vec<u64>,
-    coeffs: Vec<f64>
-       
+    coeffs: Vec<f64>,
+    layout: SegmentLayout
 }
 
 impl BottomUpPLR {
     pub fn new(data: &ModelDataWrapper) -> BottomUpPLR {
+        return BottomUpPLR::with_layout(data, SegmentLayout::default());
+    }
+
+    pub fn with_layout(data: &ModelDataWrapper, layout: SegmentLayout) -> BottomUpPLR {
         let (points, coeffs) = bottom_up_plr(data);
         let radix = radix_index(&points, NUM_RADIX_BITS);
         return BottomUpPLR {
-            radix, points, coeffs
+            radix, points, coeffs, layout
         };
     }
+
+    // the (first key, slope bits, intercept bits) records of the
+    // ArrayOfStructs layout
+    fn segment_records(&self) -> Vec<u64> {
+        return self.points.iter().enumerate()
+            .flat_map(|(idx, key)| vec![*key, self.coeffs[2 * idx].to_bits(),
+                                        self.coeffs[2 * idx + 1].to_bits()])
+            .collect();
+    }
 }
 
 impl Model for BottomUpPLR {
@@ -66,10 +104,12 @@ impl Model for BottomUpPLR {
                 line_index, self.points[line_index-1], val); 
         assert!(line_index == self.points.len() - 1 || self.points[line_index + 1] > val);
         
-        // verify that the radix table would have given valid bounds
+        // verify that the radix table would have given valid bounds: the
+        // search covers the segments starting in the key's bucket, and the
+        // segment before them
         let radix_hint = val >> (64 - NUM_RADIX_BITS);
-        let radix_ub = self.radix[radix_hint as usize] as usize;
-        let radix_lb = if radix_hint == 0 { 0 } else { self.radix[radix_hint as usize - 1] as usize - 1 };
+        let radix_ub = self.radix[radix_hint as usize + 1] as usize;
+        let radix_lb = (self.radix[radix_hint as usize] as usize).saturating_sub(1);
         assert!(radix_lb <= line_index,
                 "radix key: {} radix lb: {}, radix ub: {}, correct: {}, key: {}, value: {}",
                 radix_hint, radix_lb, radix_ub, line_index, val, self.points[line_index]);
@@ -88,20 +128,54 @@ impl Model for BottomUpPLR {
     fn input_type(&self) -> ModelDataType { return ModelDataType::Int; }
     fn output_type(&self) -> ModelDataType { return ModelDataType::Float; }
 
-    fn params(&self) -> Vec<ModelParam> { return vec![self.points.len().into(),
-                                                      self.radix.clone().into(),
-                                                      self.points.clone().into(),
-                                                      self.coeffs.clone().into()]; }
+    fn params(&self) -> Vec<ModelParam> {
+        if self.layout == SegmentLayout::ArrayOfStructs {
+            return vec![self.points.len().into(),
+                        self.radix.clone().into(),
+                        self.segment_records().into()];
+        }
+
+        return vec![self.points.len().into(),
+                    self.radix.clone().into(),
+                    self.points.clone().into(),
+                    self.coeffs.clone().into()];
+    }
     
     fn code(&self) -> String {
+        if self.layout == SegmentLayout::ArrayOfStructs {
+            return format!("
+inline double plr_aos(const uint64_t size, const uint64_t radix[],
+                      const uint64_t segments[], uint64_t key) {{
+    uint64_t key_radix = key >> (64 - {});
+    uint64_t lo = radix[key_radix];
+    uint64_t count = radix[key_radix + 1] - lo;
+
+    // upper bound of the key among the records' first keys
+    while (count > 0) {{
+        uint64_t half = count / 2;
+        if (segments[3 * (lo + half)] <= key) {{
+            lo += half + 1;
+            count -= half + 1;
+        }} else {{
+            count = half;
+        }}
+    }}
+
+    const uint64_t* segment = segments + 3 * (lo == 0 ? 0 : lo - 1);
+    return double_from_bits(segment[1]) * (double)key + double_from_bits(segment[2]);
+}}
+", NUM_RADIX_BITS);
+        }
+
         return format!("
 inline uint64_t plr(const uint64_t size, 
                     const uint64_t radix[],
                     const uint64_t pivots[], const double coeffs[], uint64_t key) {{
     uint64_t key_radix = key >> (64 - {});
-    unsigned int radix_ub = radix[key_radix];
-    unsigned int radix_lb = (key_radix == 0 ? 0 : radix[key_radix - 1] - 1);
-    uint64_t li = bs_upper_bound(pivots + radix_lb, radix_ub - radix_lb, key) + radix_lb - 1;
+    uint64_t radix_lb = radix[key_radix];
+    uint64_t radix_ub = radix[key_radix + 1];
+    uint64_t li = bs_upper_bound(pivots + radix_lb, radix_ub - radix_lb, key) + radix_lb;
+    li = (li == 0 ? 0 : li - 1);
 
     double alpha = coeffs[2*li];
     double beta = coeffs[2*li + 1];
@@ -113,11 +187,488 @@ inline uint64_t plr(const uint64_t size,
 
     fn standard_functions(&self) -> HashSet<StdFunctions> {
         let mut to_r = HashSet::new();
-        to_r.insert(StdFunctions::BinarySearch);
+        to_r.insert(match self.layout {
+            SegmentLayout::ArrayOfStructs => StdFunctions::DoubleFromBits,
+            SegmentLayout::StructOfArrays => StdFunctions::BinarySearch
+        });
         return to_r;
     }
     
-    fn function_name(&self) -> String { return String::from("plr"); }
+    fn function_name(&self) -> String {
+        return String::from(match self.layout {
+            SegmentLayout::ArrayOfStructs => "plr_aos",
+            SegmentLayout::StructOfArrays => "plr"
+        });
+    }
+    fn restriction(&self) -> ModelRestriction { return ModelRestriction::MustBeBottom; }
+}
+
+
+// clusters the values into at most k groups (1D k-means, starting from
+// evenly spaced quantiles) and returns the sorted group means
+fn kmeans_1d(values: &[f64], k: usize) -> Vec<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    sorted.dedup();
+    if sorted.len() <= k {
+        return sorted;
+    }
+
+    let mut centroids: Vec<f64> = (0..k)
+        .map(|i| sorted[(2 * i + 1) * sorted.len() / (2 * k)])
+        .collect();
+
+    for _ in 0..100 {
+        let mut sums = vec![0.0; centroids.len()];
+        let mut counts = vec![0; centroids.len()];
+        for v in values {
+            let idx = nearest(&centroids, *v);
+            sums[idx] += v;
+            counts[idx] += 1;
+        }
+
+        let next: Vec<f64> = centroids.iter().enumerate()
+            .map(|(idx, c)| if counts[idx] == 0 { *c } else { sums[idx] / counts[idx] as f64 })
+            .collect();
+        if next == centroids {
+            break;
+        }
+        centroids = next;
+    }
+
+    centroids.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    centroids.dedup();
+    return centroids;
+}
+
+fn nearest(codebook: &[f64], v: f64) -> usize {
+    let mut best = 0;
+    for (idx, c) in codebook.iter().enumerate() {
+        if (c - v).abs() < (codebook[best] - v).abs() {
+            best = idx;
+        }
+    }
+    return best;
+}
+
+// The segments of BottomUpPLR, with each segment's slope replaced by the
+// nearest entry of a small codebook (found by k-means over all of the
+// slopes), so that a segment stores a short codebook index instead of its
+// own slope. Each segment is anchored at its first key, and its offset is
+// refit to the quantized slope, so the error only grows by how much the
+// quantized slope bends the segment. The error bound is measured after
+// quantization.
+pub struct QuantizedPLRModel {
+    points: Vec<u64>,
+    codebook: Vec<f64>,
+    slope_indexes: Vec<u16>,
+    offsets: Vec<f64>,
+    max_error: u64
+}
+
+impl QuantizedPLRModel {
+    pub fn new(data: &ModelDataWrapper, codebook_size: usize) -> QuantizedPLRModel {
+        assert!(codebook_size >= 1 && codebook_size <= i16::MAX as usize,
+                "the codebook needs between 1 and {} slopes", i16::MAX);
+        let (points, coeffs) = bottom_up_plr(data);
+        if points.is_empty() {
+            return QuantizedPLRModel {
+                points: vec![0], codebook: vec![0.0], slope_indexes: vec![0], offsets: vec![0.0],
+                max_error: 0
+            };
+        }
+
+        let slopes: Vec<f64> = coeffs.iter().step_by(2).copied().collect();
+        let codebook = kmeans_1d(&slopes, codebook_size);
+        let slope_indexes: Vec<u16> = slopes.iter()
+            .map(|s| nearest(&codebook, *s) as u16)
+            .collect();
+
+        // the range of (position - slope * distance from the segment start)
+        // over each segment's keys. The offset is its midpoint.
+        let mut ranges = vec![(f64::INFINITY, f64::NEG_INFINITY); points.len()];
+        for (x, y) in data.iter_int_int() {
+            let seg = points.upper_bound(&x) - 1;
+            let slope = codebook[slope_indexes[seg] as usize];
+            let offset = y as f64 - slope * (x as f64 - points[seg] as f64);
+            ranges[seg] = (f64::min(ranges[seg].0, offset), f64::max(ranges[seg].1, offset));
+        }
+        let offsets = ranges.iter().enumerate()
+            .map(|(seg, (lo, hi))| {
+                if lo.is_finite() {
+                    (lo + hi) / 2.0
+                } else {
+                    // no keys start after this segment's first key (a
+                    // duplicate start), so keep its original line
+                    coeffs[2 * seg].mul_add(points[seg] as f64, coeffs[2 * seg + 1])
+                }
+            })
+            .collect();
+
+        let mut model = QuantizedPLRModel { points, codebook, slope_indexes, offsets, max_error: 0 };
+        model.max_error = data.iter_int_int()
+            .map(|(x, y)| {
+                let pred = model.predict_to_int(x.into());
+                u64::max(pred, y) - u64::min(pred, y)
+            })
+            .max().unwrap_or(0);
+
+        return model;
+    }
+}
+
+impl Model for QuantizedPLRModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        let val = inp.as_int();
+        let seg = self.points.upper_bound(&val).saturating_sub(1);
+        let slope = self.codebook[self.slope_indexes[seg] as usize];
+        return slope.mul_add(val as f64 - self.points[seg] as f64, self.offsets[seg]);
+    }
+
+    fn input_type(&self) -> ModelDataType { return ModelDataType::Int; }
+    fn output_type(&self) -> ModelDataType { return ModelDataType::Float; }
+
+    fn params(&self) -> Vec<ModelParam> {
+        return vec![self.points.len().into(),
+                    self.points.clone().into(),
+                    self.codebook.clone().into(),
+                    ModelParam::ShortArray(self.slope_indexes.clone()),
+                    self.offsets.clone().into()];
+    }
+
+    fn code(&self) -> String {
+        return String::from("
+inline double plr_q(const uint64_t size, const uint64_t pivots[], const double codebook[],
+                    const short slope_indexes[], const double offsets[], uint64_t key) {
+    uint64_t li = bs_upper_bound(pivots, size, key);
+    li = (li == 0 ? 0 : li - 1);
+    return std::fma(codebook[slope_indexes[li]], (double)key - (double)pivots[li], offsets[li]);
+}
+");
+    }
+
+    fn standard_functions(&self) -> HashSet<StdFunctions> {
+        let mut to_r = HashSet::new();
+        to_r.insert(StdFunctions::BinarySearch);
+        return to_r;
+    }
+
+    fn function_name(&self) -> String { return String::from("plr_q"); }
     fn restriction(&self) -> ModelRestriction { return ModelRestriction::MustBeBottom; }
+
+    fn error_bound(&self) -> Option<u64> {
+        return Some(self.max_error);
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "codegen-roundtrip")]
+    use byteorder::{WriteBytesExt, LittleEndian};
+    #[cfg(feature = "codegen-roundtrip")]
+    use std::convert::TryFrom;
+
+    // blocks of 100 keys, each spaced by one of three gaps, so the PLR
+    // segments have three distinct slopes
+    fn clustered_slopes() -> ModelData<'static> {
+        let mut key = 0;
+        let mut pairs = Vec::new();
+        for i in 0..20_000 {
+            key += [1, 4, 9][(i / 100) % 3];
+            pairs.push((key, i as u64));
+        }
+        return ModelData::IntKeyToIntPos(pairs);
+    }
+
+    #[test]
+    fn test_segment_layouts() {
+        let data = clustered_slopes();
+        let container = ModelDataWrapper::new(&data);
+        let aos = BottomUpPLR::with_layout(&container, SegmentLayout::ArrayOfStructs);
+        let soa = BottomUpPLR::new(&container);
+        assert_eq!(aos.function_name(), "plr_aos");
+        assert_eq!(soa.function_name(), "plr");
+
+        // the same segments, and so the same predictions, either way
+        let records = aos.segment_records();
+        assert_eq!(records.len(), 3 * aos.points.len());
+        for (idx, key) in aos.points.iter().enumerate() {
+            assert_eq!(records[3 * idx], *key);
+            assert_eq!(f64::from_bits(records[3 * idx + 1]), aos.coeffs[2 * idx]);
+            assert_eq!(f64::from_bits(records[3 * idx + 2]), aos.coeffs[2 * idx + 1]);
+        }
+        let params_size = |m: &BottomUpPLR| m.params().iter().map(|p| p.size()).sum::<usize>();
+        assert_eq!(params_size(&aos), params_size(&soa));
+    }
+
+    #[test]
+    #[cfg(feature = "codegen-roundtrip")]
+    fn test_segment_records_code() {
+        // keys over the whole range, which the radix index assumes
+        let data = ModelData::IntKeyToIntPos((0..20_000u64)
+                                             .map(|i| (i * (u64::MAX / 20_000) + (i % 13) * 7, i))
+                                             .collect());
+        let model = BottomUpPLR::with_layout(&ModelDataWrapper::new(&data),
+                                             SegmentLayout::ArrayOfStructs);
+        let keys: Vec<u64> = data.iter_int_int().map(|(k, _)| k).step_by(7).collect();
+
+        // the radix index is too large for a literal, so the parameters and
+        // the keys are read from a file: each array is preceded by its length
+        let dir = std::env::temp_dir();
+        let input = dir.join("rmi_plr_aos_input.bin");
+        let source = dir.join("rmi_plr_aos.cpp");
+        let binary = dir.join("rmi_plr_aos");
+        let mut bytes = Vec::new();
+        for array in &[model.radix.clone(), model.segment_records(), keys.clone()] {
+            bytes.write_u64::<LittleEndian>(array.len() as u64).unwrap();
+            for v in array {
+                bytes.write_u64::<LittleEndian>(*v).unwrap();
+            }
+        }
+        std::fs::write(&input, bytes).unwrap();
+
+        let main = format!("#include <cstdint>
+#include <cstdio>
+#include <cstring>
+#include <vector>
+{}{}
+static std::vector<uint64_t> read_array(FILE* f) {{
+    uint64_t len;
+    if (fread(&len, 8, 1, f) != 1) return std::vector<uint64_t>();
+    std::vector<uint64_t> v(len);
+    if (fread(v.data(), 8, len, f) != len) v.clear();
+    return v;
+}}
+int main(int argc, char** argv) {{
+    FILE* f = fopen(argv[1], \"rb\");
+    std::vector<uint64_t> radix = read_array(f), segments = read_array(f), keys = read_array(f);
+    fclose(f);
+    for (uint64_t key : keys) {{
+        double pred = plr_aos(segments.size() / 3, radix.data(), segments.data(), key);
+        fwrite(&pred, 8, 1, stdout);
+    }}
+    return 0;
+}}", StdFunctions::DoubleFromBits.code(), model.code());
+        std::fs::write(&source, main).unwrap();
+
+        let compiled = std::process::Command::new("c++")
+            .args(&["-std=c++17", "-o"])
+            .arg(&binary)
+            .arg(&source)
+            .output()
+            .unwrap();
+        std::fs::remove_file(&source).unwrap();
+        assert!(compiled.status.success(), "{}", String::from_utf8_lossy(&compiled.stderr));
+        let output = std::process::Command::new(&binary).arg(&input).output().unwrap();
+        std::fs::remove_file(&binary).unwrap();
+        std::fs::remove_file(&input).unwrap();
+        assert!(output.status.success());
+
+        let preds: Vec<f64> = output.stdout.chunks(8)
+            .map(|c| f64::from_le_bytes(<[u8; 8]>::try_from(c).unwrap()))
+            .collect();
+        assert_eq!(preds.len(), keys.len());
+        for (key, pred) in keys.iter().zip(preds.iter()) {
+            assert_eq!(*pred, model.predict_to_float((*key).into()), "key {}", key);
+        }
+    }
+
+    // compares the cache misses and lookup times of the two layouts on a
+    // model with too many segments for the L2 cache. It needs a C++ compiler
+    // and hardware counters (perf_event_open), and prints its measurements:
+    //   cargo test --release --features codegen-roundtrip bench_segment_layouts \
+    //       -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    #[cfg(feature = "codegen-roundtrip")]
+    fn bench_segment_layouts() {
+        // gaps from 1 to 2^43, so the segments are short
+        let mut state: u64 = 42;
+        let mut key = 0;
+        let data = ModelData::IntKeyToIntPos((0..4_000_000u64).map(|i| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            key += 1 << ((state >> 33) % 44);
+            return (key, i);
+        }).collect());
+        let container = ModelDataWrapper::new(&data);
+        let aos = BottomUpPLR::with_layout(&container, SegmentLayout::ArrayOfStructs);
+        let soa = BottomUpPLR::new(&container);
+        println!("{} segments", aos.points.len());
+
+        // every key once, in a random order
+        let mut keys: Vec<u64> = data.iter_int_int().map(|(k, _)| k).collect();
+        for idx in (1..keys.len()).rev() {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            keys.swap(idx, (state >> 33) as usize % (idx + 1));
+        }
+
+        let dir = std::env::temp_dir();
+        let input = dir.join("rmi_plr_bench_input.bin");
+        let source = dir.join("rmi_plr_bench.cpp");
+        let binary = dir.join("rmi_plr_bench");
+        let coeff_bits: Vec<u64> = soa.coeffs.iter().map(|c| c.to_bits()).collect();
+        let mut bytes = Vec::new();
+        for array in &[aos.radix.clone(), aos.segment_records(), soa.points.clone(), coeff_bits,
+                       keys] {
+            bytes.write_u64::<LittleEndian>(array.len() as u64).unwrap();
+            for v in array {
+                bytes.write_u64::<LittleEndian>(*v).unwrap();
+            }
+        }
+        std::fs::write(&input, bytes).unwrap();
+
+        let main = format!("#include <chrono>
+#include <cstdint>
+#include <cstdio>
+#include <cstring>
+#include <vector>
+#include <linux/perf_event.h>
+#include <sys/ioctl.h>
+#include <sys/syscall.h>
+#include <unistd.h>
+{}{}{}{}
+static std::vector<uint64_t> read_array(FILE* f) {{
+    uint64_t len;
+    if (fread(&len, 8, 1, f) != 1) return std::vector<uint64_t>();
+    std::vector<uint64_t> v(len);
+    if (fread(v.data(), 8, len, f) != len) v.clear();
+    return v;
+}}
+static int open_counter(uint32_t type, uint64_t config) {{
+    perf_event_attr attr;
+    memset(&attr, 0, sizeof(attr));
+    attr.size = sizeof(attr);
+    attr.type = type;
+    attr.config = config;
+    attr.disabled = 1;
+    attr.exclude_kernel = 1;
+    return (int) syscall(SYS_perf_event_open, &attr, 0, -1, -1, 0);
+}}
+static volatile double sink;
+template <typename F> static void measure(const char* name, const std::vector<uint64_t>& keys,
+                                            F lookup) {{
+    int counters[2] = {{
+        open_counter(PERF_TYPE_HW_CACHE, PERF_COUNT_HW_CACHE_L1D
+                     | (PERF_COUNT_HW_CACHE_OP_READ << 8)
+                     | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)),
+        open_counter(PERF_TYPE_HARDWARE, PERF_COUNT_HW_CACHE_MISSES)
+    }};
+    double sum = 0;
+    for (uint64_t key : keys) sum += lookup(key);
+    for (int fd : counters) if (fd >= 0) ioctl(fd, PERF_EVENT_IOC_ENABLE, 0);
+    auto start = std::chrono::steady_clock::now();
+    for (uint64_t key : keys) sum += lookup(key);
+    auto end = std::chrono::steady_clock::now();
+    printf(\"%s: %.1f ns/lookup\", name,
+           std::chrono::duration<double, std::nano>(end - start).count() / keys.size());
+    const char* labels[2] = {{\"L1d misses\", \"LLC misses\"}};
+    for (int i = 0; i < 2; i++) {{
+        uint64_t count;
+        if (counters[i] < 0 || read(counters[i], &count, 8) != 8) {{
+            printf(\", %s: unavailable\", labels[i]);
+        }} else {{
+            printf(\", %s: %.2f/lookup\", labels[i], (double) count / keys.size());
+        }}
+    }}
+    printf(\"\\n\");
+    sink = sum;
+}}
+int main(int argc, char** argv) {{
+    FILE* f = fopen(argv[1], \"rb\");
+    std::vector<uint64_t> radix = read_array(f), segments = read_array(f);
+    std::vector<uint64_t> pivots = read_array(f), coeff_bits = read_array(f), keys = read_array(f);
+    fclose(f);
+    std::vector<double> coeffs(coeff_bits.size());
+    memcpy(coeffs.data(), coeff_bits.data(), 8 * coeffs.size());
+
+    measure(\"plr_aos\", keys, [&](uint64_t key) {{
+        return plr_aos(segments.size() / 3, radix.data(), segments.data(), key);
+    }});
+    measure(\"plr\", keys, [&](uint64_t key) {{
+        return (double) plr(pivots.size(), radix.data(), pivots.data(), coeffs.data(), key);
+    }});
+    return 0;
+}}", StdFunctions::DoubleFromBits.code(), StdFunctions::BinarySearch.code(), aos.code(),
+                           soa.code());
+        std::fs::write(&source, main).unwrap();
+
+        let compiled = std::process::Command::new("c++")
+            .args(&["-std=c++17", "-O3", "-o"])
+            .arg(&binary)
+            .arg(&source)
+            .output()
+            .unwrap();
+        std::fs::remove_file(&source).unwrap();
+        assert!(compiled.status.success(), "{}", String::from_utf8_lossy(&compiled.stderr));
+        let output = std::process::Command::new(&binary).arg(&input).output().unwrap();
+        std::fs::remove_file(&binary).unwrap();
+        std::fs::remove_file(&input).unwrap();
+        print!("{}", String::from_utf8_lossy(&output.stdout));
+        assert!(output.status.success());
+    }
+
+    #[test]
+    fn test_radix_bounds() {
+        // curved keys over the whole range, so the segments start in many
+        // radix buckets: radix[b] is where bucket b starts, so a key's segment
+        // is between that (or the segment before it) and the next bucket's
+        let data = ModelData::IntKeyToIntPos((0..20_000u64)
+                                             .map(|i| (i * i * (u64::MAX / 400_000_000), i))
+                                             .collect());
+        let container = ModelDataWrapper::new(&data);
+        let model = BottomUpPLR::new(&container);
+        let buckets = model.radix.windows(2).filter(|w| w[0] < w[1]).count();
+        assert!(buckets > 10, "{}", buckets);
+        for (key, _) in data.iter_int_int() {
+            model.predict_to_float(key.into());
+        }
+
+        if cfg!(feature = "codegen-roundtrip") {
+            let trained = crate::train::train_single_layer(&mut ModelDataWrapper::new(&data),
+                                                           "plr").unwrap();
+            let keys: Vec<u64> = data.iter_int_int().map(|(k, _)| k).step_by(7).collect();
+            let result = crate::codegen::verify_compiled(trained, data.len(), &keys, "c++",
+                                                         &std::env::temp_dir());
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+        }
+    }
+
+    #[test]
+    fn test_kmeans_1d() {
+        let values = [1.0, 1.1, 0.9, 5.0, 5.2, 4.8, 10.0];
+        assert_eq!(kmeans_1d(&values, 3), vec![1.0, 5.0, 10.0]);
+        assert_eq!(kmeans_1d(&values, 10).len(), 7);
+        assert_eq!(kmeans_1d(&[2.0, 2.0], 4), vec![2.0]);
+    }
+
+    #[test]
+    fn test_quantized_plr() {
+        let data = clustered_slopes();
+        let container = ModelDataWrapper::new(&data);
+        let quantized = QuantizedPLRModel::new(&container, 4);
+        assert!(quantized.points.len() > 100);
+        assert!(quantized.codebook.len() <= 4);
+
+        let bound = quantized.error_bound().unwrap();
+        for (x, y) in container.iter_int_int() {
+            let pred = quantized.predict_to_int(x.into());
+            assert!(u64::max(pred, y) - u64::min(pred, y) <= bound);
+        }
+
+        // the unquantized segments store a slope and an intercept each
+        // (not counting the radix table used to find them)
+        let plr = BottomUpPLR::new(&container);
+        let plr_size: usize = plr.params()[2..].iter().map(|p| p.size()).sum();
+        let quantized_size: usize = quantized.params().iter().map(|p| p.size()).sum();
+        assert!((quantized_size as f64) < 0.8 * plr_size as f64,
+                "{} vs {}", quantized_size, plr_size);
+    }
+
+    #[test]
+    fn test_quantized_plr_empty() {
+        let model = QuantizedPLRModel::new(&ModelDataWrapper::new(&ModelData::empty()), 8);
+        assert_eq!(model.predict_to_int(5.into()), 0);
+        assert_eq!(model.error_bound(), Some(0));
+    }
+}
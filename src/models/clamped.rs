@@ -0,0 +1,188 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+
+// the smallest and largest keys the model was trained on. Keys outside of
+// this range are clamped to it before evaluating the model, so out-of-range
+// lookups predict the first (or last) trained position instead of
+// extrapolating.
+fn key_range(data: &ModelDataWrapper) -> (f64, f64) {
+    if data.len() == 0 {
+        return (0.0, 0.0);
+    }
+
+    return (data.get(0).0, data.get(data.len() - 1).0);
+}
+
+fn clamp_key(inp: ModelInput, range: (f64, f64)) -> ModelInput {
+    let (min_key, max_key) = range;
+    return f64::min(f64::max(inp.as_float(), min_key), max_key).into();
+}
+
+pub struct ClampedLinearModel {
+    range: (f64, f64),
+    model: LinearModel
+}
+
+impl ClampedLinearModel {
+    pub fn new(data: &ModelDataWrapper) -> ClampedLinearModel {
+        return ClampedLinearModel {
+            range: key_range(data),
+            model: LinearModel::new(data)
+        };
+    }
+}
+
+impl Model for ClampedLinearModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        return self.model.predict_to_float(clamp_key(inp, self.range));
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut params = vec![self.range.0.into(), self.range.1.into()];
+        params.extend(self.model.params());
+        return params;
+    }
+
+    fn code(&self) -> String {
+        return String::from(
+            "
+inline double linear_clamped(double min_key, double max_key,
+                             double alpha, double beta, double inp) {
+    return std::fma(beta, fmin(fmax(inp, min_key), max_key), alpha);
+}",
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("linear_clamped");
+    }
+
+    fn set_to_constant_model(&mut self, constant: u64) -> bool {
+        return self.model.set_to_constant_model(constant);
+    }
+
+    fn is_constant(&self) -> bool {
+        return self.model.is_constant();
+    }
+}
+
+pub struct ClampedCubicModel {
+    range: (f64, f64),
+    model: CubicSplineModel
+}
+
+impl ClampedCubicModel {
+    pub fn new(data: &ModelDataWrapper) -> ClampedCubicModel {
+        return ClampedCubicModel {
+            range: key_range(data),
+            model: CubicSplineModel::new(data)
+        };
+    }
+}
+
+impl Model for ClampedCubicModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        return self.model.predict_to_float(clamp_key(inp, self.range));
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut params = vec![self.range.0.into(), self.range.1.into()];
+        params.extend(self.model.params());
+        return params;
+    }
+
+    fn code(&self) -> String {
+        return String::from(
+            "
+inline double cubic_clamped(double min_key, double max_key,
+                            double a, double b, double c, double d, double inp) {
+    auto x = fmin(fmax(inp, min_key), max_key);
+    auto v1 = std::fma(a, x, b);
+    auto v2 = std::fma(v1, x, c);
+    auto v3 = std::fma(v2, x, d);
+    return v3;
+}",
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("cubic_clamped");
+    }
+    fn needs_bounds_check(&self) -> bool {
+        return false;
+    }
+
+    fn set_to_constant_model(&mut self, constant: u64) -> bool {
+        return self.model.set_to_constant_model(constant);
+    }
+
+    fn is_constant(&self) -> bool {
+        return self.model.is_constant();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::*;
+
+    #[test]
+    fn test_linear_clamped() {
+        let md = ModelData::IntKeyToIntPos(vec![(10, 0), (20, 1), (30, 2), (40, 3)]);
+        let container = ModelDataWrapper::new(&md);
+
+        let unclamped = LinearModel::new(&container);
+        let clamped = ClampedLinearModel::new(&container);
+
+        // plain linear regression extrapolates below zero and past the end
+        assert!(unclamped.predict_to_float(0.into()) < 0.0);
+        assert!(unclamped.predict_to_float(100.into()) > 3.0);
+
+        assert_eq!(clamped.predict_to_int(0.into()), 0);
+        assert_eq!(clamped.predict_to_int(100.into()), 3);
+        assert_eq!(clamped.predict_to_int(20.into()), unclamped.predict_to_int(20.into()));
+    }
+
+    #[test]
+    fn test_cubic_clamped() {
+        let md = ModelData::IntKeyToIntPos(vec![(10, 0), (20, 1), (30, 8), (40, 20)]);
+        let container = ModelDataWrapper::new(&md);
+
+        let clamped = ClampedCubicModel::new(&container);
+        let lowest = clamped.predict_to_float(10.into());
+        let highest = clamped.predict_to_float(40.into());
+
+        assert_eq!(clamped.predict_to_float(0.into()), lowest);
+        assert_eq!(clamped.predict_to_float(1000.into()), highest);
+        assert_abs_diff_eq!(highest, 20.0, epsilon = 0.5);
+    }
+
+    #[test]
+    fn test_empty() {
+        let md = ModelData::empty();
+        let container = ModelDataWrapper::new(&md);
+        ClampedLinearModel::new(&container);
+        ClampedCubicModel::new(&container);
+    }
+}
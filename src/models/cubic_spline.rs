@@ -178,14 +178,26 @@ inline double cubic(double a, double b, double c, double d, double x) {
     fn function_name(&self) -> String {
         return String::from("cubic");
     }
-    fn needs_bounds_check(&self) -> bool {
-        return false;
-    }
 
+    fn c89_code(&self) -> Option<String> {
+        return Some(String::from(
+            "
+static double cubic(double a, double b, double c, double d, double x) {
+    double v1 = fma(a, x, b);
+    double v2 = fma(v1, x, c);
+    return fma(v2, x, d);
+}",
+        ));
+    }
     fn set_to_constant_model(&mut self, constant: u64) -> bool {
         self.params = (0.0, 0.0, 0.0, constant as f64);
         return true;
-    }    
+    }
+
+    fn is_constant(&self) -> bool {
+        let (a, b, c, _d) = self.params;
+        return a == 0.0 && b == 0.0 && c == 0.0;
+    }
 }
 
 #[cfg(test)]
@@ -246,4 +258,15 @@ mod tests {
         CubicSplineModel::new(&ModelData::empty());
     }
 
+    #[test]
+    fn test_bounds_check() {
+        // past the last training key, the spline keeps growing, so a larger
+        // key is predicted past the last position
+        let md = ModelData::IntKeyToIntPos((0..100u64)
+            .map(|i| (((i as f64).sqrt() * 1000.0) as u64, i)).collect());
+        let cubic_mod = CubicSplineModel::new(&ModelDataWrapper::new(&md));
+        assert!(cubic_mod.predict_to_int(100_000.into()) >= 100);
+        assert!(cubic_mod.needs_bounds_check());
+    }
+
 }
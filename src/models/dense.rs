@@ -0,0 +1,133 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::*;
+
+// true if the keys are consecutive integers (no gaps or duplicates) at
+// consecutive positions, so that each key's position is its offset from
+// the first key
+pub fn is_dense_range(data: &ModelDataWrapper) -> bool {
+    if data.len() == 0 {
+        return false;
+    }
+
+    let mut pairs = data.iter_int_int();
+    let mut last = pairs.next().unwrap();
+    for (x, y) in pairs {
+        if Some(x) != last.0.checked_add(1) || Some(y) != last.1.checked_add(1) {
+            return false;
+        }
+        last = (x, y);
+    }
+
+    return true;
+}
+
+// predicts the position of a key as its distance from the smallest key,
+// which is exact for a dense range of keys (see is_dense_range). Keys
+// below the range are predicted to be at its start.
+pub struct DenseRangeModel {
+    min_key: u64,
+    first_pos: u64,
+    max_error: u64
+}
+
+impl DenseRangeModel {
+    pub fn new(data: &ModelDataWrapper) -> DenseRangeModel {
+        if data.len() == 0 {
+            return DenseRangeModel { min_key: 0, first_pos: 0, max_error: 0 };
+        }
+
+        let (min_key, first_pos) = data.iter_int_int().next().unwrap();
+        let mut model = DenseRangeModel { min_key, first_pos, max_error: 0 };
+
+        // zero on a dense range, but the model may be trained on any data
+        model.max_error = data.iter_int_int()
+            .map(|(x, y)| {
+                let pred = model.predict_to_int(x.into());
+                u64::max(pred, y) - u64::min(pred, y)
+            })
+            .max().unwrap_or(0);
+
+        return model;
+    }
+}
+
+impl Model for DenseRangeModel {
+    fn predict_to_int(&self, inp: ModelInput) -> u64 {
+        let key = inp.as_int();
+        if key < self.min_key {
+            return self.first_pos;
+        }
+
+        return (key - self.min_key).saturating_add(self.first_pos);
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        return vec![self.min_key.into(), self.first_pos.into()];
+    }
+
+    fn code(&self) -> String {
+        return String::from(
+            "
+inline uint64_t dense_range(uint64_t min_key, uint64_t first_pos, uint64_t inp) {
+    if (inp < min_key) return first_pos;
+    return inp - min_key + first_pos;
+}",
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("dense_range");
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        // positions are predicted unscaled, so this cannot route keys to
+        // the models of another layer
+        return ModelRestriction::MustBeBottom;
+    }
+
+    fn error_bound(&self) -> Option<u64> {
+        return Some(self.max_error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dense_range() {
+        let md = ModelData::IntKeyToIntPos((0..1000).map(|i| (i + 5000, i)).collect());
+        let container = ModelDataWrapper::new(&md);
+        assert!(is_dense_range(&container));
+
+        let model = DenseRangeModel::new(&container);
+        assert_eq!(model.error_bound(), Some(0));
+        for (x, y) in container.iter_int_int() {
+            assert_eq!(model.predict_to_int(x.into()), y);
+        }
+        assert_eq!(model.predict_to_int(0.into()), 0);
+
+        // a gap, a duplicate, or no keys at all
+        let gap = ModelData::IntKeyToIntPos(vec![(1, 0), (2, 1), (4, 2)]);
+        assert!(!is_dense_range(&ModelDataWrapper::new(&gap)));
+        let dup = ModelData::IntKeyToIntPos(vec![(1, 0), (1, 1), (2, 2)]);
+        assert!(!is_dense_range(&ModelDataWrapper::new(&dup)));
+        assert!(!is_dense_range(&ModelDataWrapper::new(&ModelData::empty())));
+
+        assert_eq!(DenseRangeModel::new(&ModelDataWrapper::new(&gap)).error_bound(), Some(1));
+    }
+}
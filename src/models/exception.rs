@@ -0,0 +1,303 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+
+// A base model with a list of exceptions: the keys the base model misses by
+// more than a threshold are stored with their exact positions, and a lookup
+// binary searches them before falling back to the base model. The error
+// bound only covers the other keys, so a few outliers do not widen it for
+// every lookup.
+//
+// The parameters are one array of 64-bit words: the base model's
+// parameters (floats as their bits), then the exception keys, then their
+// positions. Only base models with scalar parameters can be code generated,
+// and not as C89 (the code uses inline functions and std::memcpy).
+pub struct ExceptionModel {
+    base: Box<dyn Model>,
+    exceptions: Vec<(u64, u64)>,
+    max_error: u64
+}
+
+impl ExceptionModel {
+    pub fn new(base: Box<dyn Model>, data: &ModelDataWrapper, threshold: u64) -> ExceptionModel {
+        let mut exceptions: Vec<(u64, u64)> = Vec::new();
+        for (x, y) in data.iter_input_int() {
+            let pred = base.predict_to_int(x);
+            let key = match exception_key(x) {
+                Some(key) => key,
+                None => continue
+            };
+            let is_new_key = exceptions.last().map(|(last, _)| *last < key).unwrap_or(true);
+
+            // a repeated key keeps the position of its first occurrence
+            if u64::max(pred, y) - u64::min(pred, y) > threshold && is_new_key {
+                exceptions.push((key, y));
+            }
+        }
+
+        let mut model = ExceptionModel { base, exceptions, max_error: 0 };
+        model.max_error = data.iter_input_int()
+            .map(|(x, y)| {
+                let pred = model.predict_to_int(x);
+                u64::max(pred, y) - u64::min(pred, y)
+            })
+            .max().unwrap_or(0);
+
+        return model;
+    }
+
+    // stores (about) the given fraction of the keys as exceptions: those the
+    // base model misses by the most
+    pub fn with_fraction(base: Box<dyn Model>, data: &ModelDataWrapper,
+                         fraction: f64) -> ExceptionModel {
+        assert!((0.0..=1.0).contains(&fraction), "the fraction must be in [0, 1]");
+        let mut residuals: Vec<u64> = data.iter_input_int()
+            .map(|(x, y)| {
+                let pred = base.predict_to_int(x);
+                u64::max(pred, y) - u64::min(pred, y)
+            })
+            .collect();
+        if residuals.is_empty() {
+            return ExceptionModel::new(base, data, 0);
+        }
+
+        // keys with a residual above the threshold are exceptions
+        residuals.sort_unstable();
+        let kept = ((1.0 - fraction) * residuals.len() as f64).ceil() as usize;
+        let threshold = residuals[usize::min(kept, residuals.len()).saturating_sub(1)];
+        return ExceptionModel::new(base, data, threshold);
+    }
+
+    pub fn exceptions(&self) -> &[(u64, u64)] {
+        return &self.exceptions;
+    }
+
+    fn exception_for(&self, inp: ModelInput) -> Option<u64> {
+        let key = exception_key(inp)?;
+        return self.exceptions.binary_search_by_key(&key, |(k, _)| *k).ok()
+            .map(|idx| self.exceptions[idx].1);
+    }
+}
+
+// the key an input is stored as if it is an exception. The generated code
+// takes integer keys, so a float key can only be one if it is integral
+// (others are covered by the error bound).
+fn exception_key(inp: ModelInput) -> Option<u64> {
+    return match inp {
+        ModelInput::Int(key) => Some(key),
+        ModelInput::Float(key) if key.fract() == 0.0 && key >= 0.0 && key < 2f64.powi(64) =>
+            Some(key as u64),
+        ModelInput::Float(_) => None
+    };
+}
+
+impl Model for ExceptionModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        return match self.exception_for(inp) {
+            Some(pos) => pos as f64,
+            None => self.base.predict_to_float(inp)
+        };
+    }
+
+    fn predict_to_int(&self, inp: ModelInput) -> u64 {
+        return match self.exception_for(inp) {
+            Some(pos) => pos,
+            None => self.base.predict_to_int(inp)
+        };
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return self.base.output_type();
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut words: Vec<u64> = self.base.params().iter().map(|p| match p {
+            ModelParam::Int(v) => *v,
+            ModelParam::Float(v) => v.to_bits(),
+            _ => panic!("The exceptions of a {} model cannot be stored: it has array parameters",
+                        self.base.function_name())
+        }).collect();
+        words.extend(self.exceptions.iter().map(|(key, _)| *key));
+        words.extend(self.exceptions.iter().map(|(_, pos)| *pos));
+        return vec![ModelParam::IntArray(words)];
+    }
+
+    fn code(&self) -> String {
+        let base_params = self.base.params();
+        let mut unpack = String::new();
+        let mut args = Vec::new();
+        for (idx, param) in base_params.iter().enumerate() {
+            match param {
+                ModelParam::Int(_) => args.push(format!("params[{}]", idx)),
+                ModelParam::Float(_) => {
+                    unpack += &format!("    double p{0} = double_from_bits(params[{0}]);\n",
+                                       idx);
+                    args.push(format!("p{}", idx));
+                },
+                _ => panic!("The exceptions of a {} model cannot be code generated: it has array \
+                             parameters", self.base.function_name())
+            }
+        }
+        args.push(format!("({})inp", self.base.input_type().c_type()));
+
+        return format!(
+            "{base_code}
+inline {out} {name}(const uint64_t* params, uint64_t inp) {{
+    const uint64_t count = {count};
+    const uint64_t* keys = params + {num_base};
+
+    // the first exception key >= inp
+    uint64_t lo = 0, hi = count;
+    while (lo < hi) {{
+        uint64_t mid = lo + (hi - lo) / 2;
+        if (keys[mid] < inp) lo = mid + 1;
+        else hi = mid;
+    }}
+    if (lo < count && keys[lo] == inp) return ({out})keys[count + lo];
+
+{unpack}    return {base}({args});
+}}",
+            base_code = self.base.code(),
+            out = self.base.output_type().c_type(),
+            name = self.function_name(),
+            count = self.exceptions.len(),
+            num_base = base_params.len(),
+            unpack = unpack,
+            base = self.base.function_name(),
+            args = args.join(", ")
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return format!("{}_exceptions", self.base.function_name());
+    }
+
+    fn standard_functions(&self) -> HashSet<StdFunctions> {
+        let mut to_r = self.base.standard_functions();
+        to_r.insert(StdFunctions::DoubleFromBits);
+        return to_r;
+    }
+
+    fn needs_bounds_check(&self) -> bool {
+        return self.base.needs_bounds_check();
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
+    }
+
+    fn error_bound(&self) -> Option<u64> {
+        return Some(self.max_error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exceptions() {
+        // evenly spaced keys, with a handful of outliers injected between
+        // them whose positions are far from their neighbors'
+        let outliers = [(1005, 700), (3005, 10), (5005, 900), (7005, 50), (9005, 200)];
+        let mut pairs: Vec<(u64, u64)> = (0..1000).map(|i| (i * 10, i)).collect();
+        pairs.extend_from_slice(&outliers);
+        pairs.sort_unstable();
+        let md = ModelData::IntKeyToIntPos(pairs.clone());
+        let container = ModelDataWrapper::new(&md);
+
+        let base = LinearModel::new(&container);
+        let base_error = pairs.iter()
+            .map(|(x, y)| {
+                let pred = base.predict_to_int((*x).into());
+                u64::max(pred, *y) - u64::min(pred, *y)
+            })
+            .max().unwrap();
+        assert!(base_error > 500, "{}", base_error);
+
+        let model = ExceptionModel::new(Box::new(base), &container, 10);
+        assert_eq!(model.exceptions(), &outliers);
+        for (key, pos) in outliers.iter() {
+            assert_eq!(model.predict_to_int((*key).into()), *pos);
+        }
+
+        // the bound only covers the other keys, which the line fits closely
+        let bound = model.error_bound().unwrap();
+        assert!(bound <= 10, "{}", bound);
+        for (x, y) in container.iter_int_int() {
+            let pred = model.predict_to_int(x.into());
+            assert!(u64::max(pred, y) - u64::min(pred, y) <= bound);
+        }
+
+        // the line's 2 parameters, then 5 keys and 5 positions
+        assert_eq!(model.params()[0].len(), 12);
+        assert_eq!(model.function_name(), "linear_exceptions");
+
+        // 5 of the 1005 keys are the worst fit
+        let model = ExceptionModel::with_fraction(Box::new(LinearModel::new(&container)),
+                                                  &container, 0.005);
+        assert_eq!(model.exceptions(), &outliers);
+        let model = ExceptionModel::with_fraction(Box::new(LinearModel::new(&container)),
+                                                  &container, 0.0);
+        assert!(model.exceptions().is_empty());
+        assert_eq!(model.error_bound(), Some(base_error));
+    }
+
+    #[test]
+    fn test_float_keys() {
+        // the fractional keys are measured as floats rather than truncated
+        // onto their integral neighbors, and cannot be exceptions
+        let mut pairs: Vec<(f64, u64)> = (0..1000).map(|i| (i as f64 * 10.0, i)).collect();
+        pairs.extend_from_slice(&[(1005.0, 700), (3005.5, 10)]);
+        pairs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        let md = ModelData::FloatKeyToIntPos(pairs.clone());
+        let container = ModelDataWrapper::new(&md);
+
+        let model = ExceptionModel::new(Box::new(LinearModel::new(&container)), &container, 10);
+        assert_eq!(model.exceptions(), &[(1005, 700)]);
+        assert_eq!(model.predict_to_int(1005.0.into()), 700);
+        let bound = model.error_bound().unwrap();
+        assert!(bound > 200, "{}", bound);
+        for (x, y) in container.iter_float_int() {
+            let pred = model.predict_to_int(x.into());
+            assert!(u64::max(pred, y) - u64::min(pred, y) <= bound);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "codegen-roundtrip")]
+    fn test_exceptions_code() {
+        let compiler = "c++";
+
+        let mut pairs: Vec<(u64, u64)> = (0..1000).map(|i| (i * i, i)).collect();
+        let clean = ModelData::IntKeyToIntPos(pairs.clone());
+        pairs.extend_from_slice(&[(1001, 900), (250_001, 3)]);
+        pairs.sort_unstable();
+        let md = ModelData::IntKeyToIntPos(pairs.clone());
+
+        // the base models only accept positions that never decrease
+        for base in &["linear", "cubic", "radix"] {
+            let mut trained = crate::train::train_single_layer(
+                &mut ModelDataWrapper::new(&clean), base).unwrap();
+            let model = trained.rmi[0].pop().unwrap();
+            let model = ExceptionModel::new(model, &ModelDataWrapper::new(&md), 20);
+            assert!(!model.exceptions().is_empty());
+            trained.rmi[0].push(Box::new(model));
+
+            let mut keys: Vec<u64> = pairs.iter().map(|(k, _)| *k).collect();
+            keys.extend_from_slice(&[1002, 5, u64::MAX]);
+            let result = crate::codegen::verify_compiled(trained, md.len(), &keys, compiler,
+                                                         &std::env::temp_dir());
+            assert!(result.is_ok(), "{}: {}", base, result.unwrap_err());
+        }
+    }
+}
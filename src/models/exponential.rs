@@ -0,0 +1,141 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::*;
+
+// same as exp_clamped in the generated code
+fn exp_clamped(x: f64) -> f64 {
+    return if x < -708.0 { 0.0 } else { x.exp() };
+}
+
+// the maximum likelihood fit of an exponential distribution starting at
+// the smallest key: the rate is one over the mean distance from it.
+fn ecdf(loc_data: &ModelDataWrapper) -> (f64, f64, f64) {
+    if loc_data.len() == 0 {
+        return (0.0, 1.0, 0.0);
+    }
+
+    let x0 = loc_data.get(0).0;
+    let n = loc_data.len() as f64;
+    let mut mean = 0.0;
+    let mut scale = -f64::INFINITY;
+    for (x, y) in loc_data.iter_float_float() {
+        mean += (x - x0) / n;
+        scale = f64::max(scale, y);
+    }
+
+    // a single distinct key has no spread; any rate will do
+    let lambda = if mean > 0.0 && (1.0 / mean).is_finite() { 1.0 / mean } else { 1.0 };
+    return (x0, lambda, scale);
+}
+
+pub struct ExponentialModel {
+    params: (f64, f64, f64),
+}
+
+impl ExponentialModel {
+    pub fn new(data: &ModelDataWrapper) -> ExponentialModel {
+        return ExponentialModel { params: ecdf(data) };
+    }
+}
+
+impl Model for ExponentialModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        let (x0, lambda, scale) = self.params;
+        let dx = inp.as_float() - x0;
+        if dx <= 0.0 {
+            return 0.0;
+        }
+        return (1.0 - exp_clamped(-lambda * dx)) * scale;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        return vec![
+            self.params.0.into(),
+            self.params.1.into(),
+            self.params.2.into(),
+        ];
+    }
+
+    fn code(&self) -> String {
+        return String::from(
+            "
+inline double ecdf(double x0, double lambda, double scale, double inp) {
+    const double dx = inp - x0;
+    if (dx <= 0.0) return 0.0;
+    return (1.0 - exp_clamped(-lambda * dx)) * scale;
+}",
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("ecdf");
+    }
+    fn standard_functions(&self) -> HashSet<StdFunctions> {
+        let mut to_r = HashSet::new();
+        to_r.insert(StdFunctions::ExpClamped);
+        return to_r;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_fit() {
+        // the quantiles of an exponential distribution with rate 1 / 5000
+        let n = 10_000;
+        let md = ModelData::IntKeyToIntPos((0..n).map(|i| {
+            let q = (i as f64 + 0.5) / n as f64;
+            ((-(1.0 - q).ln() * 5000.0) as u64 + 100, i)
+        }).collect());
+        let container = ModelDataWrapper::new(&md);
+        let model = ExponentialModel::new(&container);
+
+        let (x0, lambda, _) = model.params;
+        assert_eq!(x0, 100.0);
+        assert!((lambda * 5000.0 - 1.0).abs() < 0.01);
+
+        let max_err = container.iter_float_float()
+            .map(|(x, y)| (model.predict_to_float(x.into()) - y).abs())
+            .fold(0.0, f64::max);
+        assert!(max_err < n as f64 * 0.01, "max error {}", max_err);
+    }
+
+    #[test]
+    fn test_tails() {
+        let md = ModelData::IntKeyToIntPos(vec![(10, 0), (20, 1), (40, 2)]);
+        let model = ExponentialModel::new(&ModelDataWrapper::new(&md));
+
+        assert_eq!(model.predict_to_float(0.into()), 0.0);
+        assert_eq!(model.predict_to_float(10.into()), 0.0);
+        assert_eq!(model.predict_to_float(u64::MAX.into()), 2.0);
+    }
+
+    #[test]
+    fn test_empty() {
+        let model = ExponentialModel::new(&ModelDataWrapper::new(&ModelData::empty()));
+        assert_eq!(model.predict_to_int(5.into()), 0);
+    }
+
+    #[test]
+    fn test_single_key() {
+        let md = ModelData::IntKeyToIntPos(vec![(7, 0), (7, 0)]);
+        let model = ExponentialModel::new(&ModelDataWrapper::new(&md));
+        assert!(model.params.1.is_finite());
+        assert_eq!(model.predict_to_int(7.into()), 0);
+    }
+}
@@ -0,0 +1,238 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+use std::convert::TryFrom;
+
+// fractional bits of the intercept and of the sum before rounding down
+const FRACTION_BITS: u32 = 16;
+
+// products are saturated here, which is far beyond any position
+const SATURATION: u64 = 1 << 62;
+
+// the 128-bit product of a and b, shifted right and saturated
+fn mul_shift(a: u64, b: u64, shift: u32) -> u64 {
+    let product = (u128::from(a) * u128::from(b)) >> shift;
+    return u64::try_from(product).unwrap_or(SATURATION).min(SATURATION);
+}
+
+// A line (see Model::as_line) evaluated with integer arithmetic only, for
+// targets without an FPU. The intercept is stored in Q.16 fixed point, and
+// the slope as an integer numerator over 2^shift, with the shift chosen to
+// keep 62 significant bits of it. The key times the slope is computed as a
+// 128-bit product (from 32-bit halves in the generated code), so any key is
+// in range. The sum is rounded down to a position.
+//
+// Rounding the intercept to 2^-17 and the slope to 2^-62 of itself means a
+// prediction can be one position away from the floating point one, which
+// in an upper layer can pick the neighboring model.
+//
+// The negative numbers are stored in two's complement, since the
+// parameters are unsigned integers.
+pub struct FixedPointLine {
+    intercept: i64,
+    slope: i64,
+    shift: u32
+}
+
+impl FixedPointLine {
+    // None if the line does not fit: an intercept of 2^45 or more, or a
+    // slope of 2^45 or more positions per key
+    pub fn new(intercept: f64, slope: f64) -> Option<FixedPointLine> {
+        let limit = 2f64.powi(62 - FRACTION_BITS as i32 - 1);
+        if !intercept.is_finite() || !slope.is_finite()
+            || intercept.abs() >= limit || slope.abs() >= limit {
+            return None;
+        }
+
+        let intercept_q = (intercept * 2f64.powi(FRACTION_BITS as i32)).round() as i64;
+
+        // the largest shift (up to 127) that keeps the slope below 2^62
+        let mut shift = FRACTION_BITS;
+        while shift < 127 && (slope * 2f64.powi(shift as i32 + 1)).abs() < 2f64.powi(62) {
+            shift += 1;
+        }
+        let slope_q = (slope * 2f64.powi(shift as i32)).round() as i64;
+
+        return Some(FixedPointLine { intercept: intercept_q, slope: slope_q, shift });
+    }
+}
+
+// a fixed-point version of the model, if it has one: lines are converted,
+// and models that only use integer arithmetic are kept as they are
+pub fn to_fixed_point(model: Box<dyn Model>) -> Option<Box<dyn Model>> {
+    if model.integer_only() {
+        return Some(model);
+    }
+
+    let (intercept, slope) = model.as_line()?;
+    return FixedPointLine::new(intercept, slope).map(|m| Box::new(m) as Box<dyn Model>);
+}
+
+impl Model for FixedPointLine {
+    fn predict_to_int(&self, inp: ModelInput) -> u64 {
+        let term = mul_shift(self.slope.unsigned_abs(), inp.as_int(),
+                             self.shift - FRACTION_BITS) as i64;
+        let sum = self.intercept + if self.slope < 0 { -term } else { term };
+        if sum < 0 {
+            return 0;
+        }
+        return (sum as u64) >> FRACTION_BITS;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        return vec![ModelParam::Int(self.intercept as u64), ModelParam::Int(self.slope as u64),
+                    ModelParam::Int(u64::from(self.shift - FRACTION_BITS))];
+    }
+
+    fn code(&self) -> String {
+        return fixed_point_code("inline uint64_t", "uint64_t", "ULL");
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("linear_fx");
+    }
+
+    fn c89_code(&self) -> Option<String> {
+        return Some(fixed_point_code("static rmi_u64", "rmi_u64", "UL"));
+    }
+
+    fn rust_code(&self) -> Option<String> {
+        // u128 is in core, so the product needs no halves
+        return Some(format!(
+            "
+#[inline]
+fn linear_fx(intercept: u64, slope: u64, shift: u64, inp: u64) -> u64 {{
+    let (intercept, slope) = (intercept as i64, slope as i64);
+    let product = (u128::from(slope.unsigned_abs()) * u128::from(inp)) >> shift;
+    let term = product.min({sat}) as i64;
+    let sum = if slope < 0 {{ intercept - term }} else {{ intercept + term }};
+    if sum < 0 {{
+        return 0;
+    }}
+    (sum as u64) >> {frac}
+}}",
+            sat = SATURATION,
+            frac = FRACTION_BITS
+        ));
+    }
+
+    fn integer_only(&self) -> bool {
+        return true;
+    }
+}
+
+// unsigned arithmetic only, so the same code is valid C89 and C++. A value
+// is negative when its top bit is set. The suffix makes a literal u64_type.
+fn fixed_point_code(storage: &str, u64_type: &str, suffix: &str) -> String {
+    return format!(
+        "
+{storage} rmi_mul_shift({t} a, {t} b, {t} shift) {{
+    {t} a_lo = a & 0xFFFFFFFF{s}, a_hi = a >> 32;
+    {t} b_lo = b & 0xFFFFFFFF{s}, b_hi = b >> 32;
+    {t} lo_lo = a_lo * b_lo, hi_lo = a_hi * b_lo, lo_hi = a_lo * b_hi;
+    {t} cross = (lo_lo >> 32) + (hi_lo & 0xFFFFFFFF{s}) + lo_hi;
+    {t} lo = (cross << 32) | (lo_lo & 0xFFFFFFFF{s});
+    {t} hi = (hi_lo >> 32) + (cross >> 32) + a_hi * b_hi;
+    {t} res;
+    if (shift == 0) {{
+        if (hi != 0) return {sat}{s};
+        res = lo;
+    }} else if (shift < 64) {{
+        if ((hi >> shift) != 0) return {sat}{s};
+        res = (lo >> shift) | (hi << (64 - shift));
+    }} else {{
+        res = hi >> (shift - 64);
+    }}
+    return (res > {sat}{s} ? {sat}{s} : res);
+}}
+{storage} linear_fx({t} intercept, {t} slope, {t} shift, {t} inp) {{
+    {t} negative = slope >> 63;
+    {t} term = rmi_mul_shift(negative ? ~slope + 1 : slope, inp, shift);
+    {t} sum = intercept + (negative ? ~term + 1 : term);
+    if (sum >> 63) return 0;
+    return sum >> {frac};
+}}",
+        storage = storage,
+        t = u64_type,
+        s = suffix,
+        sat = SATURATION,
+        frac = FRACTION_BITS
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_point_line() {
+        let line = FixedPointLine::new(10.5, 0.25).unwrap();
+        assert_eq!(line.predict_to_int(0.into()), 10);
+        assert_eq!(line.predict_to_int(4.into()), 11);
+        assert_eq!(line.predict_to_int(u64::MAX.into()), (SATURATION >> FRACTION_BITS) + 10);
+
+        let line = FixedPointLine::new(100.0, -2.0).unwrap();
+        assert_eq!(line.predict_to_int(10.into()), 80);
+        assert_eq!(line.predict_to_int(60.into()), 0);
+
+        // a tiny slope over huge keys keeps its precision
+        let slope = 1e6 / 2f64.powi(63);
+        let line = FixedPointLine::new(-3.0, slope).unwrap();
+        for key in &[1u64 << 40, 1 << 62, 3 << 61, u64::MAX] {
+            let float = f64::max(0.0, slope.mul_add(*key as f64, -3.0).floor()) as i64;
+            let fixed = line.predict_to_int((*key).into()) as i64;
+            assert!((float - fixed).abs() <= 1, "{} vs {}", float, fixed);
+        }
+
+        assert!(FixedPointLine::new(1e15, 0.0).is_none());
+        assert!(FixedPointLine::new(0.0, f64::NAN).is_none());
+    }
+
+    #[test]
+    fn test_fixed_point_rmi_error() {
+        // the same RMI evaluated with floats and in fixed point
+        let data = ModelData::IntKeyToIntPos((0..10_000u64).map(|i| (i * i + 7 * i, i)).collect());
+        let trained = crate::train::train(&mut ModelDataWrapper::new(&data), "linear,linear", 64)
+            .unwrap();
+        let (top, bottom) = (&trained.rmi[0][0], &trained.rmi[1]);
+        let fixed_top = FixedPointLine::new(top.as_line().unwrap().0, top.as_line().unwrap().1)
+            .unwrap();
+        let fixed_bottom: Vec<FixedPointLine> = bottom.iter()
+            .map(|m| FixedPointLine::new(m.as_line().unwrap().0, m.as_line().unwrap().1).unwrap())
+            .collect();
+
+        let mut float_max = 0;
+        let mut fixed_max = 0;
+        let mut same = 0;
+        for (key, pos) in data.iter_int_int() {
+            let idx = u64::min(top.predict_to_int(key.into()), 63) as usize;
+            let float = bottom[idx].predict_to_int(key.into());
+            float_max = u64::max(float_max, u64::max(float, pos) - u64::min(float, pos));
+
+            let idx = u64::min(fixed_top.predict_to_int(key.into()), 63) as usize;
+            let fixed = fixed_bottom[idx].predict_to_int(key.into());
+            fixed_max = u64::max(fixed_max, u64::max(fixed, pos) - u64::min(fixed, pos));
+
+            if fixed == float {
+                same += 1;
+            }
+        }
+
+        // almost every prediction is the same, and the error barely moves
+        assert!(same >= data.len() * 99 / 100);
+        assert!(fixed_max <= float_max + 1, "{} vs {}", fixed_max, float_max);
+    }
+}
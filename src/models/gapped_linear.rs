@@ -0,0 +1,203 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+
+// a gap is a step between consecutive keys this many times larger than the
+// typical (median) step
+const GAP_FACTOR: u64 = 16;
+
+// A linear model over keys with large excluded ranges (e.g. deleted blocks
+// of IDs). The largest gaps between consecutive keys are stored as
+// (first key after the gap, keys skipped up to it) pairs. A lookup binary
+// searches the pairs for the last gap before the key, subtracts the keys
+// skipped up to there, and applies the line fit to the keys with the gaps
+// removed. Keys inside a gap follow the key before the gap, up to the
+// first key after it, so the predictions stay non-decreasing.
+//
+// The pairs start with (0, 0), so the search always finds one.
+pub struct GappedLinearModel {
+    linear: LinearModel,
+    gaps: Vec<(u64, u64)>,
+    max_error: u64
+}
+
+fn find_gaps(data: &ModelDataWrapper, max_gaps: usize) -> Vec<(u64, u64)> {
+    let keys: Vec<u64> = data.iter_int_int().map(|(k, _)| k).collect();
+    let mut steps: Vec<u64> = keys.windows(2).map(|w| w[1] - w[0]).filter(|s| *s > 0).collect();
+    if steps.is_empty() {
+        return vec![(0, 0)];
+    }
+
+    let mid = steps.len() / 2;
+    let median = *steps.select_nth_unstable(mid).1;
+
+    // (index of the key after the gap, size of the gap), largest first
+    let mut gaps: Vec<(usize, u64)> = keys.windows(2).enumerate()
+        .map(|(idx, w)| (idx + 1, w[1] - w[0]))
+        .filter(|(_, step)| *step > median.saturating_mul(GAP_FACTOR))
+        .collect();
+    gaps.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    gaps.truncate(max_gaps);
+    gaps.sort_by_key(|(idx, _)| *idx);
+
+    // each gap is shrunk to a typical step
+    let mut pairs = vec![(0, 0)];
+    let mut skipped = 0;
+    for (idx, step) in gaps {
+        skipped += step - median;
+        pairs.push((keys[idx], skipped));
+    }
+    return pairs;
+}
+
+// the key with the gaps before it removed. A key inside a gap is clamped to
+// the (compressed) first key after the gap.
+fn compress(gaps: &[(u64, u64)], key: u64) -> u64 {
+    let idx = gaps.partition_point(|(start, _)| *start <= key);
+    let compressed = key - gaps[idx - 1].1;
+    return match gaps.get(idx) {
+        Some((start, skipped)) => u64::min(compressed, start - skipped),
+        None => compressed
+    };
+}
+
+impl GappedLinearModel {
+    pub fn new(data: &ModelDataWrapper, max_gaps: usize) -> GappedLinearModel {
+        let gaps = find_gaps(data, max_gaps);
+
+        let compressed = ModelData::IntKeyToIntPos(
+            data.iter_int_int().map(|(k, p)| (compress(&gaps, k), p)).collect()
+        );
+        let linear = LinearModel::new(&ModelDataWrapper::new(&compressed));
+
+        let mut model = GappedLinearModel { linear, gaps, max_error: 0 };
+        model.max_error = data.iter_int_int()
+            .map(|(x, y)| {
+                let pred = model.predict_to_int(x.into());
+                u64::max(pred, y) - u64::min(pred, y)
+            })
+            .max().unwrap_or(0);
+
+        return model;
+    }
+}
+
+impl Model for GappedLinearModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        let key = inp.as_int();
+        return self.linear.predict_to_float(compress(&self.gaps, key).into());
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let gaps: Vec<u64> = self.gaps.iter().flat_map(|(start, skipped)| vec![*start, *skipped])
+            .collect();
+        let mut params = self.linear.params();
+        params.push(gaps.into());
+        return params;
+    }
+
+    fn code(&self) -> String {
+        return format!(
+            "
+inline double gapped_linear(double alpha, double beta, const uint64_t gaps[], uint64_t inp) {{
+    // the last (first key after a gap, keys skipped) pair with a key <= inp
+    size_t lo = 0, hi = {num_gaps};
+    while (hi - lo > 1) {{
+        size_t mid = (lo + hi) / 2;
+        if (gaps[2 * mid] <= inp) lo = mid;
+        else hi = mid;
+    }}
+    uint64_t key = inp - gaps[2 * lo + 1];
+    // inside the next gap, clamped to the first key after it
+    if (hi < {num_gaps} && key > gaps[2 * hi] - gaps[2 * hi + 1]) {{
+        key = gaps[2 * hi] - gaps[2 * hi + 1];
+    }}
+    return std::fma(beta, (double) key, alpha);
+}}",
+            num_gaps = self.gaps.len()
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("gapped_linear");
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
+    }
+
+    fn error_bound(&self) -> Option<u64> {
+        return Some(self.max_error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gapped_linear() {
+        // dense IDs with two deleted blocks
+        let keys: Vec<u64> = (0..1000).chain(50_000..51_000).chain(1_000_000..1_002_000)
+            .collect();
+        let md = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                           .map(|(i, k)| (*k, i as u64)).collect());
+        let container = ModelDataWrapper::new(&md);
+
+        let model = GappedLinearModel::new(&container, 8);
+        assert_eq!(model.gaps, vec![(0, 0), (50_000, 49_000), (1_000_000, 998_000)]);
+        assert_eq!(model.error_bound(), Some(0));
+        for (x, y) in container.iter_int_int() {
+            assert_eq!(model.predict_to_int(x.into()), y);
+        }
+
+        // keys inside a gap are predicted between the keys around it
+        let mut last = 0;
+        for key in (0..1_100_000).step_by(13) {
+            let pred = model.predict_to_int(key.into());
+            assert!(pred >= last, "{}", key);
+            if (1000..50_000).contains(&key) {
+                assert!(pred == 999 || pred == 1000, "{} {}", key, pred);
+            }
+            last = pred;
+        }
+
+        // the plain line is far off
+        let linear = LinearModel::new(&container);
+        assert!(container.iter_int_int().any(|(x, y)| {
+            let pred = linear.predict_to_int(x.into());
+            u64::max(pred, y) - u64::min(pred, y) > 100
+        }));
+
+        // only the largest gap is kept
+        let model = GappedLinearModel::new(&container, 1);
+        assert_eq!(model.gaps, vec![(0, 0), (1_000_000, 949_000)]);
+        assert_eq!(model.params().len(), 3);
+        assert_eq!(model.params()[2].len(), 4);
+    }
+
+    #[test]
+    fn test_gapped_linear_no_gaps() {
+        let md = ModelData::IntKeyToIntPos((0..100).map(|i| (i * 3, i)).collect());
+        let model = GappedLinearModel::new(&ModelDataWrapper::new(&md), 8);
+        assert_eq!(model.gaps, vec![(0, 0)]);
+        assert_eq!(model.predict_to_int(30.into()), 10);
+
+        let model = GappedLinearModel::new(&ModelDataWrapper::new(&ModelData::empty()), 8);
+        assert_eq!(model.gaps, vec![(0, 0)]);
+        assert_eq!(model.error_bound(), Some(0));
+    }
+}
@@ -113,6 +113,124 @@ inline uint64_t ed_histogram(const uint64_t length,
     fn needs_bounds_check(&self) -> bool { return false; }
 }
 
+// Buckets whose boundaries grow geometrically from the smallest to the
+// largest key, so that a heavy tail is covered by a few wide buckets while
+// the dense head gets many narrow ones. Each bucket stores the position of
+// its first key (empty buckets store the position of the next key), and a
+// key is predicted to be at the start of its bucket.
+//
+// Logarithms need positive keys, so when the smallest key is 0 all keys are
+// shifted up by one.
+pub struct LogHistogramModel {
+    shift: f64,
+    log_min: f64,
+    scale: f64,
+    starts: Vec<f64>,
+    max_population: u64
+}
+
+// the bucket of a key, as computed by the generated log_bucket
+fn log_bucket(inp: f64, shift: f64, log_min: f64, scale: f64, num_buckets: usize) -> usize {
+    let bucket = ((inp + shift).ln() - log_min) * scale;
+    if bucket.is_nan() || bucket <= 0.0 {
+        return 0;
+    }
+    return usize::min(num_buckets - 1, bucket as usize);
+}
+
+impl LogHistogramModel {
+    pub fn new(data: &ModelDataWrapper, num_buckets: usize) -> LogHistogramModel {
+        assert!(num_buckets >= 1);
+        if data.len() == 0 {
+            return LogHistogramModel {
+                shift: 0.0, log_min: 0.0, scale: 0.0,
+                starts: vec![0.0; num_buckets], max_population: 0
+            };
+        }
+
+        let (min, max) = (data.get(0).0, data.get(data.len() - 1).0);
+        let shift = if min <= 0.0 { 1.0 - min } else { 0.0 };
+        let log_min = (min + shift).ln();
+        let log_range = (max + shift).ln() - log_min;
+        let scale = if log_range > 0.0 { num_buckets as f64 / log_range } else { 0.0 };
+
+        let mut starts: Vec<Option<f64>> = vec![None; num_buckets];
+        let mut populations = vec![0; num_buckets];
+        for (x, y) in data.iter_float_float() {
+            let bucket = log_bucket(x, shift, log_min, scale, num_buckets);
+            starts[bucket].get_or_insert(y);
+            populations[bucket] += 1;
+        }
+
+        // an empty bucket predicts the position of the next key
+        let mut next = data.get(data.len() - 1).1;
+        let mut filled = vec![0.0; num_buckets];
+        for bucket in (0..num_buckets).rev() {
+            next = starts[bucket].unwrap_or(next);
+            filled[bucket] = next;
+        }
+
+        trace!("Log histogram with {} buckets, largest holds {} keys",
+               num_buckets, populations.iter().max().unwrap());
+
+        return LogHistogramModel {
+            shift, log_min, scale,
+            starts: filled,
+            max_population: *populations.iter().max().unwrap()
+        };
+    }
+}
+
+impl Model for LogHistogramModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        let bucket = log_bucket(inp.as_float(), self.shift, self.log_min,
+                                self.scale, self.starts.len());
+        return self.starts[bucket];
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        return vec![self.shift.into(), self.log_min.into(), self.scale.into(),
+                    self.starts.clone().into()];
+    }
+
+    fn code(&self) -> String {
+        return format!(
+            "
+inline double log_histogram(double shift, double log_min, double scale,
+                            const double starts[], double inp) {{
+    return starts[log_bucket(inp, shift, log_min, scale, {})];
+}}",
+            self.starts.len()
+        );
+    }
+
+    fn standard_functions(&self) -> HashSet<StdFunctions> {
+        let mut to_r = HashSet::new();
+        to_r.insert(StdFunctions::LogBucket);
+        return to_r;
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("log_histogram");
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
+    }
+
+    // every key of a bucket is at most this many positions past its start
+    fn error_bound(&self) -> Option<u64> {
+        return Some(self.max_population);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -141,4 +259,60 @@ mod tests {
         EquidepthHistogramModel::new(&ModelData::empty());
     }
 
+
+    // keys following a Pareto distribution: most keys are near the
+    // smallest, with a long tail of large keys
+    fn power_law_data() -> ModelData<'static> {
+        let n = 100_000;
+        return ModelData::IntKeyToIntPos((0..n).map(|i| {
+            let u = 1.0 - (i as f64 + 0.5) / n as f64;
+            let key = (1_000_000.0 * u.powf(-1.0 / 1.5)) as u64;
+            (key, i as u64)
+        }).collect());
+    }
+
+    #[test]
+    fn test_log_hist() {
+        let md = power_law_data();
+        let container = ModelDataWrapper::new(&md);
+        let model = LogHistogramModel::new(&container, 1024);
+
+        let bound = model.error_bound().unwrap();
+        for (x, y) in container.iter_int_int() {
+            let pred = model.predict_to_int(x.into());
+            assert!(pred <= y && y - pred <= bound);
+        }
+
+        // an equidepth histogram with buckets as full as the fullest log
+        // bucket is as accurate, but its radix table dominates its size
+        let pivots: Vec<u64> = (0..container.len()).step_by(bound as usize)
+            .map(|i| container.get_key(i))
+            .collect();
+        let radix = radix_index(&pivots, 20);
+        let ed_mod = EquidepthHistogramModel { params: pivots, radix };
+
+        let size = |params: Vec<ModelParam>| params.iter().map(|p| p.size()).sum::<usize>();
+        assert!(size(model.params()) * 100 < size(ed_mod.params()));
+    }
+
+    #[test]
+    fn test_log_hist_small() {
+        // a zero key is shifted
+        let md = ModelData::IntKeyToIntPos(vec![(0, 0), (10, 1), (100, 2), (1000, 3), (10000, 4)]);
+        let model = LogHistogramModel::new(&ModelDataWrapper::new(&md), 5);
+        assert_eq!(model.shift, 1.0);
+        for (x, y) in ModelDataWrapper::new(&md).iter_int_int() {
+            assert_eq!(model.predict_to_int(x.into()), y);
+        }
+        assert_eq!(model.predict_to_int(50000.into()), 4);
+        assert_eq!(model.error_bound(), Some(1));
+
+        // all duplicates
+        let md = ModelData::IntKeyToIntPos(vec![(7, 0), (7, 1), (7, 2)]);
+        let model = LogHistogramModel::new(&ModelDataWrapper::new(&md), 8);
+        assert_eq!(model.predict_to_int(7.into()), 0);
+        assert_eq!(model.error_bound(), Some(3));
+
+        LogHistogramModel::new(&ModelDataWrapper::new(&ModelData::empty()), 8);
+    }
 }
@@ -0,0 +1,324 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+
+// A non-decreasing step function from the key to the position, for noisy
+// positions that make other fits locally decrease. Pool-adjacent-violators
+// (PAVA) finds the best monotone fit under squared error: the positions of
+// each distinct key are averaged, and adjacent blocks whose means decrease
+// are merged into one level until none do. If that leaves more than k
+// levels, adjacent levels are pooled into k groups of about equal weight,
+// which keeps the fit monotone. Each level starts at a breakpoint key, and a
+// lookup binary searches the breakpoints for the last one at or below the
+// key; keys below the first breakpoint get the first level.
+//
+// The parameters are the breakpoints, then the levels. The top layer model
+// stores them as one array of any length. A model with slots stores exactly
+// that many levels as scalar parameters (repeating the last breakpoint and
+// level if it has fewer), so every model of a layer has the same parameters
+// and it can be used in any layer.
+pub struct IsotonicModel {
+    breakpoints: Vec<f64>,
+    levels: Vec<f64>,
+    max_error: u64,
+    slots: Option<usize>
+}
+
+// the first key, total weight, and sum of positions of a block of keys
+type Block = (f64, f64, f64);
+
+fn mean(block: &Block) -> f64 {
+    return block.2 / block.1;
+}
+
+// the level sets of the isotonic regression of the data
+fn pava(data: &ModelDataWrapper) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut pairs = data.iter_float_float().peekable();
+    while let Some((x, y)) = pairs.next() {
+        // all positions of a key are averaged, so the fit is a function
+        let mut block = (x, 1.0, y);
+        while let Some((_, y)) = pairs.next_if(|(next, _)| *next == x) {
+            block.1 += 1.0;
+            block.2 += y;
+        }
+
+        // merge with the previous blocks while they are not below this one
+        while let Some(prev) = blocks.last() {
+            if mean(prev) < mean(&block) {
+                break;
+            }
+            let prev = blocks.pop().unwrap();
+            block = (prev.0, prev.1 + block.1, prev.2 + block.2);
+        }
+        blocks.push(block);
+    }
+
+    return blocks;
+}
+
+impl IsotonicModel {
+    pub fn new(data: &ModelDataWrapper, k: usize) -> IsotonicModel {
+        assert!(k >= 1, "an isotonic model needs at least 1 level");
+        let blocks = pava(data);
+        if blocks.is_empty() {
+            return IsotonicModel {
+                breakpoints: vec![0.0], levels: vec![0.0], max_error: 0, slots: None
+            };
+        }
+
+        // pool the blocks into at most k groups by the weight before them
+        let total = data.len() as f64;
+        let mut groups: Vec<Block> = Vec::new();
+        let mut group_idx = usize::MAX;
+        let mut weight_before = 0.0;
+        for block in blocks {
+            let idx = (weight_before * k as f64 / total) as usize;
+            weight_before += block.1;
+            if idx == group_idx {
+                let group = groups.last_mut().unwrap();
+                group.1 += block.1;
+                group.2 += block.2;
+            } else {
+                groups.push(block);
+                group_idx = idx;
+            }
+        }
+
+        let mut model = IsotonicModel {
+            breakpoints: groups.iter().map(|g| g.0).collect(),
+            levels: groups.iter().map(mean).collect(),
+            max_error: 0,
+            slots: None
+        };
+        model.max_error = data.iter_int_int()
+            .map(|(x, y)| {
+                let pred = model.predict_to_int(x.into());
+                u64::max(pred, y) - u64::min(pred, y)
+            })
+            .max().unwrap_or(0);
+
+        return model;
+    }
+
+    // a model with exactly k levels in scalar parameters
+    pub fn with_slots(data: &ModelDataWrapper, k: usize) -> IsotonicModel {
+        let mut model = IsotonicModel::new(data, k);
+        // a repeated breakpoint is counted along with the last one, so the
+        // padding predicts the last level, as before
+        let (breakpoint, level) = (*model.breakpoints.last().unwrap(),
+                                   *model.levels.last().unwrap());
+        model.breakpoints.resize(k, breakpoint);
+        model.levels.resize(k, level);
+        model.slots = Some(k);
+        return model;
+    }
+}
+
+impl Model for IsotonicModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        // the number of breakpoints <= the key
+        let idx = self.breakpoints.partition_point(|b| *b <= inp.as_float());
+        return self.levels[idx.saturating_sub(1)];
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut values = self.breakpoints.clone();
+        values.extend_from_slice(&self.levels);
+        if self.slots.is_some() {
+            return values.into_iter().map(ModelParam::Float).collect();
+        }
+        return vec![values.into()];
+    }
+
+    fn code(&self) -> String {
+        if let Some(k) = self.slots {
+            let params: Vec<String> = (0..2 * k).map(|i| format!("double p{}", i)).collect();
+            let names: Vec<String> = (0..2 * k).map(|i| format!("p{}", i)).collect();
+            return format!(
+                "
+inline double {name}({params}, double inp) {{
+    const double breakpoints[] = {{{breakpoints}}};
+    const double levels[] = {{{levels}}};
+
+    // the number of breakpoints <= inp
+    size_t lo = 0, hi = {k};
+    while (lo < hi) {{
+        size_t mid = lo + (hi - lo) / 2;
+        if (breakpoints[mid] <= inp) lo = mid + 1;
+        else hi = mid;
+    }}
+
+    return levels[lo == 0 ? 0 : lo - 1];
+}}",
+                name = self.function_name(),
+                params = params.join(", "),
+                breakpoints = names[..k].join(", "),
+                levels = names[k..].join(", "),
+                k = k
+            );
+        }
+
+        return format!(
+            "
+inline double isotonic(const double* params, double inp) {{
+    const size_t k = {k};
+    const double* breakpoints = params;
+    const double* levels = params + k;
+
+    // the number of breakpoints <= inp
+    size_t lo = 0, hi = k;
+    while (lo < hi) {{
+        size_t mid = lo + (hi - lo) / 2;
+        if (breakpoints[mid] <= inp) lo = mid + 1;
+        else hi = mid;
+    }}
+
+    return levels[lo == 0 ? 0 : lo - 1];
+}}",
+            k = self.breakpoints.len()
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return match self.slots {
+            Some(k) => format!("isotonic{}", k),
+            None => String::from("isotonic")
+        };
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        if self.slots.is_some() {
+            return ModelRestriction::None;
+        }
+        return ModelRestriction::MustBeTop;
+    }
+
+    fn error_bound(&self) -> Option<u64> {
+        return Some(self.max_error);
+    }
+
+    fn is_monotone(&self) -> bool {
+        return self.levels.windows(2).all(|w| w[0] <= w[1]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // keys 0, 10, 20, ... with positions that wander up to 40 away from
+    // the key's rank (an LCG, so the test is deterministic)
+    fn noisy_data() -> ModelData<'static> {
+        let mut state: u64 = 42;
+        return ModelData::IntKeyToIntPos((0..5000u64).map(|i| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let noise = (state >> 33) % 81;
+            return (i * 10, (i + noise).saturating_sub(40));
+        }).collect());
+    }
+
+    #[test]
+    fn test_isotonic_monotone() {
+        let md = noisy_data();
+        let container = ModelDataWrapper::new(&md);
+
+        for k in &[1, 16, 256, 100_000] {
+            let model = IsotonicModel::new(&container, *k);
+            assert!(model.levels.len() <= *k);
+            assert!(model.is_monotone());
+
+            let mut last = 0.0;
+            for (x, _) in container.iter_float_float() {
+                let pred = model.predict_to_float(x.into());
+                assert!(pred >= last, "{} < {} at {}", pred, last, x);
+                last = pred;
+            }
+
+            let bound = model.error_bound().unwrap();
+            for (x, y) in container.iter_int_int() {
+                let pred = model.predict_to_int(x.into());
+                assert!(u64::max(pred, y) - u64::min(pred, y) <= bound);
+            }
+        }
+
+        // without a limit, the fit is within the noise
+        let model = IsotonicModel::new(&container, 100_000);
+        assert!(model.error_bound().unwrap() <= 80, "{}", model.error_bound().unwrap());
+    }
+
+    #[test]
+    fn test_pava() {
+        // the decreasing run 5, 3, 1 is pooled, as are the positions of the
+        // repeated key 4
+        let md = ModelData::IntKeyToIntPos(vec![(0, 0), (1, 5), (2, 3), (3, 1), (4, 6), (4, 8),
+                                                (5, 9)]);
+        let model = IsotonicModel::new(&ModelDataWrapper::new(&md), 10);
+        assert_eq!(model.breakpoints, vec![0.0, 1.0, 4.0, 5.0]);
+        assert_eq!(model.levels, vec![0.0, 3.0, 7.0, 9.0]);
+        assert_eq!(model.predict_to_int(2.into()), 3);
+        assert_eq!(model.predict_to_int(100.into()), 9);
+        assert_eq!(model.error_bound(), Some(2));
+
+        let model = IsotonicModel::new(&ModelDataWrapper::new(&ModelData::empty()), 8);
+        assert_eq!(model.predict_to_int(3.into()), 0);
+        assert_eq!(model.error_bound(), Some(0));
+    }
+
+    #[test]
+    fn test_isotonic_bottom() {
+        let md = noisy_data();
+        let container = ModelDataWrapper::new(&md);
+
+        // the padding predicts the same as the model without it
+        let top = IsotonicModel::new(&container, 16);
+        let model = IsotonicModel::with_slots(&container, 16);
+        assert!(top.levels.len() <= 16);
+        assert_eq!(model.params().len(), 32);
+        assert_eq!(model.restriction(), ModelRestriction::None);
+        for (x, _) in container.iter_float_float() {
+            assert_eq!(model.predict_to_float(x.into()), top.predict_to_float(x.into()));
+        }
+
+        let model = IsotonicModel::with_slots(&ModelDataWrapper::new(&ModelData::empty()), 8);
+        assert_eq!(model.params().len(), 16);
+        assert_eq!(model.predict_to_int(3.into()), 0);
+
+        // each bottom model has its own levels, which never decrease
+        let trained = crate::train::train(&mut ModelDataWrapper::new(&md), "linear,isotonic16",
+                                          64).unwrap();
+        for (leaf, bound) in trained.rmi[1].iter().zip(trained.last_layer_max_l1s.iter()) {
+            assert!(leaf.is_monotone());
+            assert!(leaf.error_bound().unwrap() <= *bound);
+        }
+        assert!(trained.model_max_error < top.error_bound().unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "codegen-roundtrip")]
+    fn test_isotonic_code() {
+        let compiler = "c++";
+
+        let md = noisy_data();
+        let trained = crate::train::train_single_layer(&mut ModelDataWrapper::new(&md),
+                                                       "isotonic").unwrap();
+        let mut keys: Vec<u64> = md.iter_int_int().map(|(k, _)| k).collect();
+        keys.extend_from_slice(&[5, 12_345, u64::MAX]);
+        let result = crate::codegen::verify_compiled(trained, md.len(), &keys, compiler,
+                                                     &std::env::temp_dir());
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+    }
+}
@@ -45,7 +45,9 @@ fn slr<T: Iterator<Item = (f64, f64)>>(loc_data: T) -> (f64, f64) {
 
     let cov = c / f64::from(n - 1);
     let var = m2 / f64::from(n - 1);
-    assert!(var >= 0.0);
+    // a NaN variance (from non-finite keys) is let through here and reported
+    // by the finiteness check after training
+    assert!(var >= 0.0 || var.is_nan());
 
     if var == 0.0 {
         // variance is zero. pick the mean (only) value.
@@ -58,6 +60,80 @@ fn slr<T: Iterator<Item = (f64, f64)>>(loc_data: T) -> (f64, f64) {
     return (alpha, beta);
 }
 
+// Neumaier's variant of Kahan summation
+struct CompensatedSum {
+    sum: f64,
+    correction: f64
+}
+
+impl CompensatedSum {
+    fn new() -> CompensatedSum {
+        return CompensatedSum { sum: 0.0, correction: 0.0 };
+    }
+
+    fn add(&mut self, v: f64) {
+        let t = self.sum + v;
+        if self.sum.abs() >= v.abs() {
+            self.correction += (self.sum - t) + v;
+        } else {
+            self.correction += (v - t) + self.sum;
+        }
+        self.sum = t;
+    }
+
+    fn value(&self) -> f64 {
+        return self.sum + self.correction;
+    }
+}
+
+// same as slr, but uses two passes with compensated sums. Slower, but much
+// more precise when the keys are large (beyond 2^53), where the rounding
+// error of the running means in slr adds up.
+fn compensated_slr(data: &ModelDataWrapper) -> (f64, f64) {
+    let n = data.len();
+    if n == 0 {
+        return (0.0, 0.0);
+    }
+
+    let mut sum_x = CompensatedSum::new();
+    let mut sum_y = CompensatedSum::new();
+    for (x, y) in data.iter_float_float() {
+        sum_x.add(x);
+        sum_y.add(y);
+    }
+
+    let mean_x = sum_x.value() / n as f64;
+    let mean_y = sum_y.value() / n as f64;
+
+    if n == 1 {
+        return (mean_y, 0.0);
+    }
+
+    let mut cov = CompensatedSum::new();
+    let mut var = CompensatedSum::new();
+    for (x, y) in data.iter_float_float() {
+        let dx = x - mean_x;
+        cov.add(dx * (y - mean_y));
+        var.add(dx * dx);
+    }
+
+    let var = var.value();
+    assert!(var >= 0.0 || var.is_nan());
+
+    if var == 0.0 {
+        // variance is zero. pick the mean (only) value.
+        return (mean_y, 0.0);
+    }
+
+    let beta = cov.value() / var;
+    let alpha = mean_y - beta * mean_x;
+
+    return (alpha, beta);
+}
+
+// keys above this can no longer all be represented exactly as doubles
+const MAX_EXACT_KEY: f64 = 9_007_199_254_740_992.0; // 2^53
+
 fn loglinear_slr(data: &ModelDataWrapper) -> (f64, f64) {
     // log all of the outputs, omit any item that doesn't have a valid log
     let transformed_data: Vec<(f64, f64)> = data
@@ -72,16 +148,211 @@ fn loglinear_slr(data: &ModelDataWrapper) -> (f64, f64) {
     return slr(new_data.iter_float_float());
 }
 
+// the line minimizing the maximum vertical distance to any point (the L-inf
+// or Chebyshev fit). The best line is halfway between two parallel lines
+// enclosing all of the points, one touching the upper convex hull and the
+// other the lower; its slope is the slope of one of the hull edges.
+fn minimax_slr(data: &ModelDataWrapper) -> (f64, f64) {
+    if data.len() == 0 {
+        return (0.0, 0.0);
+    }
+
+    // shift keys to start at zero, which keeps the hull arithmetic precise
+    let x0 = data.get(0).0;
+    let mut upper: Vec<(f64, f64)> = Vec::new();
+    let mut lower: Vec<(f64, f64)> = Vec::new();
+
+    // cross product of (b - a) and (c - a)
+    let cross = |a: (f64, f64), b: (f64, f64), c: (f64, f64)| {
+        (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0)
+    };
+
+    for (x, y) in data.iter_float_float() {
+        let pt = (x - x0, y);
+
+        // duplicate keys: only the highest (lowest) position matters for
+        // the upper (lower) hull
+        match upper.last_mut() {
+            Some(last) if last.0 == pt.0 => { last.1 = f64::max(last.1, pt.1); },
+            _ => {
+                while upper.len() >= 2
+                    && cross(upper[upper.len() - 2], upper[upper.len() - 1], pt) >= 0.0 {
+                    upper.pop();
+                }
+                upper.push(pt);
+            }
+        }
+
+        match lower.last_mut() {
+            Some(last) if last.0 == pt.0 => { last.1 = f64::min(last.1, pt.1); },
+            _ => {
+                while lower.len() >= 2
+                    && cross(lower[lower.len() - 2], lower[lower.len() - 1], pt) <= 0.0 {
+                    lower.pop();
+                }
+                lower.push(pt);
+            }
+        }
+    }
+
+    let edge_slopes = |hull: &[(f64, f64)]| -> Vec<f64> {
+        hull.windows(2).map(|w| (w[1].1 - w[0].1) / (w[1].0 - w[0].0)).collect()
+    };
+    let upper_slopes = edge_slopes(&upper); // decreasing
+    let lower_slopes = edge_slopes(&lower); // increasing
+
+    // the highest and lowest intercept of a line with the given slope
+    // through any point. Walking along a hull changes the intercept by
+    // (edge slope - slope) * dx, so the extremes are where the hull's edge
+    // slopes cross the given slope.
+    let intercepts = |slope: f64| {
+        let top = upper[upper_slopes.partition_point(|s| *s > slope)];
+        let bottom = lower[lower_slopes.partition_point(|s| *s < slope)];
+        (top.1 - slope * top.0, bottom.1 - slope * bottom.0)
+    };
+
+    let mut best_slope = 0.0;
+    let (top, bottom) = intercepts(0.0);
+    let mut best_width = top - bottom;
+    for slope in upper_slopes.iter().chain(lower_slopes.iter()) {
+        let (top, bottom) = intercepts(*slope);
+        if top - bottom < best_width {
+            best_width = top - bottom;
+            best_slope = *slope;
+        }
+    }
+
+    let (top, bottom) = intercepts(best_slope);
+    let alpha = (top + bottom) / 2.0 - best_slope * x0;
+    return (alpha, best_slope);
+}
+
+// the least squares line, shifted so that its largest over-prediction and
+// largest under-prediction are equal. The slope is unchanged, but when the
+// residuals are skewed (a few points far on one side) the error window is
+// centered on the line, so the maximum error is half the residual range
+// rather than the larger of its two ends.
+fn centered_slr(data: &ModelDataWrapper) -> (f64, f64) {
+    let (alpha, beta) = LinearModel::new(data).params;
+    if data.len() == 0 {
+        return (alpha, beta);
+    }
+
+    let (mut lowest, mut highest) = (f64::INFINITY, f64::NEG_INFINITY);
+    for (x, y) in data.iter_float_float() {
+        let residual = y - beta.mul_add(x, alpha);
+        lowest = f64::min(lowest, residual);
+        highest = f64::max(highest, residual);
+    }
+
+    return (alpha + (lowest + highest) / 2.0, beta);
+}
+
+// the least squares line through the first point (x0, y0): only the slope
+// is fit, as sum((x - x0)(y - y0)) / sum((x - x0)^2), so the first key is
+// predicted at its own position. For a top model, that is position 0,
+// where an unconstrained line can predict a negative position.
+fn anchored_slr(data: &ModelDataWrapper) -> (f64, f64) {
+    if data.len() == 0 {
+        return (0.0, 0.0);
+    }
+
+    let (x0, y0) = data.get(0);
+    let mut cov = CompensatedSum::new();
+    let mut var = CompensatedSum::new();
+    for (x, y) in data.iter_float_float() {
+        let dx = x - x0;
+        cov.add(dx * (y - y0));
+        var.add(dx * dx);
+    }
+
+    // every key is the first one
+    let beta = if var.value() == 0.0 { 0.0 } else { cov.value() / var.value() };
+
+    // rounding can leave the prediction for x0 just below y0, which would
+    // round down to the position before it
+    let mut alpha = beta.mul_add(-x0, y0);
+    while beta.mul_add(x0, alpha) < y0 {
+        alpha = if alpha > 0.0 {
+            f64::from_bits(alpha.to_bits() + 1)
+        } else if alpha < 0.0 {
+            f64::from_bits(alpha.to_bits() - 1)
+        } else {
+            f64::from_bits(1)
+        };
+    }
+
+    return (alpha, beta);
+}
+
+// the error a LinearModel's line is fit to minimize
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinearFit {
+    // the sum of squared errors (ordinary least squares)
+    LeastSquares,
+
+    // the maximum error, which bounds the last-mile search directly, at the
+    // cost of a (usually) higher average error
+    Minimax,
+
+    // the sum of squared errors, with the line then shifted to center the
+    // error window on it (see centered_slr)
+    Centered,
+
+    // the sum of squared errors of a line through the first point, which
+    // predicts the first key exactly (see anchored_slr)
+    Anchored
+}
+
 pub struct LinearModel {
     params: (f64, f64),
 }
 
 impl LinearModel {
+    // uses compensated summation automatically if any key exceeds 2^53
     pub fn new(data: &ModelDataWrapper) -> LinearModel {
-        return LinearModel { params: slr(data.iter_float_float()) };
+        let large_keys = data.len() > 0
+            && f64::max(data.get(0).0.abs(), data.get(data.len() - 1).0.abs()) > MAX_EXACT_KEY;
+        return LinearModel::with_compensation(data, large_keys);
+    }
+
+    pub fn with_compensation(data: &ModelDataWrapper, compensated: bool) -> LinearModel {
+        let params = if compensated {
+            compensated_slr(data)
+        } else {
+            slr(data.iter_float_float())
+        };
+
+        return LinearModel { params };
+    }
+
+    pub fn with_fit(data: &ModelDataWrapper, fit: LinearFit) -> LinearModel {
+        return match fit {
+            LinearFit::LeastSquares => LinearModel::new(data),
+            LinearFit::Minimax => LinearModel { params: minimax_slr(data) },
+            LinearFit::Centered => LinearModel { params: centered_slr(data) },
+            LinearFit::Anchored => LinearModel { params: anchored_slr(data) }
+        };
     }
 }
 
+// linear() on four keys at once (see Model::avx2_code), shared by every
+// model whose code() is linear(). AVX2 cannot convert a u64 to a double, so
+// each key's high and low 32 bits are placed in the mantissas of 2^84 and
+// 2^52, and the two are recombined with a single rounding, exactly as the
+// scalar (double) cast rounds.
+pub(crate) const LINEAR_AVX2_CODE: &str = "
+inline __m256d linear_avx2(double alpha, double beta, __m256i inp) {
+    __m256i hi = _mm256_or_si256(_mm256_srli_epi64(inp, 32),
+                                 _mm256_castpd_si256(_mm256_set1_pd(19342813113834066795298816.0)));
+    __m256i lo = _mm256_blend_epi32(inp, _mm256_castpd_si256(_mm256_set1_pd(4503599627370496.0)),
+                                    0xaa);
+    __m256d x = _mm256_add_pd(_mm256_sub_pd(_mm256_castsi256_pd(hi),
+                                            _mm256_set1_pd(19342813118337666422669312.0)),
+                              _mm256_castsi256_pd(lo));
+    return _mm256_fmadd_pd(_mm256_set1_pd(beta), x, _mm256_set1_pd(alpha));
+}";
+
 impl Model for LinearModel {
     fn predict_to_float(&self, inp: ModelInput) -> f64 {
         let (intercept, slope) = self.params;
@@ -112,10 +383,36 @@ inline double linear(double alpha, double beta, double inp) {
         return String::from("linear");
     }
 
+    fn c89_code(&self) -> Option<String> {
+        return Some(String::from(
+            "
+static double linear(double alpha, double beta, double inp) {
+    return fma(beta, inp, alpha);
+}",
+        ));
+    }
+
+    fn avx2_code(&self) -> Option<String> {
+        return Some(String::from(LINEAR_AVX2_CODE));
+    }
+
+    fn as_line(&self) -> Option<(f64, f64)> {
+        return Some(self.params);
+    }
+
     fn set_to_constant_model(&mut self, constant: u64) -> bool {
         self.params = (constant as f64, 0.0);
         return true;
     }
+
+    fn is_constant(&self) -> bool {
+        return self.params.1 == 0.0;
+    }
+
+    fn is_monotone(&self) -> bool {
+        // fma rounds the exact (non-decreasing) value once
+        return self.params.1 >= 0.0;
+    }
             
 }
 
@@ -131,6 +428,10 @@ mod tests {
 
         assert_eq!(lin_mod.predict_to_int(1.into()), 2);
         assert_eq!(lin_mod.predict_to_int(6.into()), 7);
+        assert!(lin_mod.is_monotone());
+
+        let md = ModelData::IntKeyToIntPos(vec![(1, 4), (2, 3), (3, 2)]);
+        assert!(!LinearModel::new(&ModelDataWrapper::new(&md)).is_monotone());
     }
 
     #[test]
@@ -147,6 +448,148 @@ mod tests {
         LinearModel::new(&ModelData::empty());
     }
 
+    #[test]
+    fn test_compensated_large_keys() {
+        let base = u64::MAX - 2_000_000_000;
+        let md = ModelData::IntKeyToIntPos((0..10_000).map(|i| (base + i * 12345, i)).collect());
+        let container = ModelDataWrapper::new(&md);
+
+        let max_err = |model: &LinearModel| container.iter_float_float()
+            .map(|(x, y)| (model.predict_to_float(x.into()) - y).abs())
+            .fold(0.0, f64::max);
+
+        let naive = LinearModel::with_compensation(&container, false);
+        let compensated = LinearModel::with_compensation(&container, true);
+        assert!(max_err(&naive) > 10.0);
+        assert!(max_err(&compensated) < 1.0);
+
+        // keys are beyond 2^53, so the default uses compensation
+        assert_eq!(LinearModel::new(&container).params, compensated.params);
+
+        // small keys give (nearly) the same fit either way
+        let md = ModelData::IntKeyToIntPos(vec![(1, 2), (2, 3), (3, 4), (7, 9)]);
+        let container = ModelDataWrapper::new(&md);
+        let (a1, b1) = LinearModel::with_compensation(&container, false).params;
+        let (a2, b2) = LinearModel::with_compensation(&container, true).params;
+        assert!((a1 - a2).abs() < 1e-9 && (b1 - b2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_minimax() {
+        // one far outlier pulls the least squares fit of every point with it
+        let mut pts: Vec<(u64, u64)> = (0..1000).map(|i| (i * 10, i)).collect();
+        pts[500].1 = 800;
+        pts[501].1 = 800;
+        let md = ModelData::IntKeyToIntPos(pts);
+        let container = ModelDataWrapper::new(&md);
+
+        let errors = |model: &LinearModel| {
+            let errs: Vec<f64> = container.iter_float_float()
+                .map(|(x, y)| (model.predict_to_float(x.into()) - y).abs())
+                .collect();
+            let max = errs.iter().cloned().fold(0.0, f64::max);
+            let avg = errs.iter().sum::<f64>() / errs.len() as f64;
+            return (max, avg);
+        };
+
+        let (l2_max, l2_avg) = errors(&LinearModel::with_fit(&container, LinearFit::LeastSquares));
+        let (linf_max, linf_avg) = errors(&LinearModel::with_fit(&container, LinearFit::Minimax));
+        assert!(linf_max < l2_max);
+        assert!(linf_avg > l2_avg);
+
+        // collinear points are fit exactly
+        let md = ModelData::IntKeyToIntPos((0..100).map(|i| (5 + i * 3, i)).collect());
+        let (alpha, beta) = LinearModel::with_fit(&ModelDataWrapper::new(&md),
+                                                  LinearFit::Minimax).params;
+        assert!((alpha + 5.0 / 3.0).abs() < 1e-9 && (beta - 1.0 / 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_minimax_small() {
+        let md = ModelData::empty();
+        assert_eq!(LinearModel::with_fit(&ModelDataWrapper::new(&md), LinearFit::Minimax).params,
+                   (0.0, 0.0));
+
+        let md = ModelData::IntKeyToIntPos(vec![(7, 3)]);
+        let model = LinearModel::with_fit(&ModelDataWrapper::new(&md), LinearFit::Minimax);
+        assert_eq!(model.predict_to_int(7.into()), 3);
+
+        // duplicate keys: the best line passes halfway between their positions
+        let md = ModelData::IntKeyToIntPos(vec![(1, 0), (1, 1), (2, 2), (2, 3)]);
+        let model = LinearModel::with_fit(&ModelDataWrapper::new(&md), LinearFit::Minimax);
+        assert_eq!(model.params, (-1.5, 2.0));
+    }
+
+    #[test]
+    fn test_centered() {
+        // positions are a line plus noise that is only ever positive, with a
+        // long tail, so the least squares line sits low in the error window
+        let pts: Vec<(u64, u64)> = (0..2000u64)
+            .map(|i| (i * 10, i * 4 + if i % 50 == 0 { 200 } else { (i * 7) % 5 }))
+            .collect();
+        let md = ModelData::IntKeyToIntPos(pts);
+        let container = ModelDataWrapper::new(&md);
+
+        let max_err = |model: &LinearModel| container.iter_int_int()
+            .map(|(x, y)| {
+                let pred = model.predict_to_int(x.into());
+                u64::max(pred, y) - u64::min(pred, y)
+            })
+            .max().unwrap();
+
+        let ols = LinearModel::with_fit(&container, LinearFit::LeastSquares);
+        let centered = LinearModel::with_fit(&container, LinearFit::Centered);
+        assert_eq!(ols.params.1, centered.params.1);
+        assert!(max_err(&centered) < max_err(&ols),
+                "{} vs {}", max_err(&centered), max_err(&ols));
+
+        // the largest over- and under-prediction are the same
+        let residuals: Vec<f64> = container.iter_float_float()
+            .map(|(x, y)| y - centered.predict_to_float(x.into()))
+            .collect();
+        let lowest = residuals.iter().cloned().fold(f64::INFINITY, f64::min);
+        let highest = residuals.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        assert!((lowest + highest).abs() < 1e-6, "{} {}", lowest, highest);
+
+        let md = ModelData::empty();
+        assert_eq!(LinearModel::with_fit(&ModelDataWrapper::new(&md), LinearFit::Centered).params,
+                   (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_anchored() {
+        // positions grow faster as the keys get denser, so the least squares
+        // line starts below the first position
+        let md = ModelData::IntKeyToIntPos((0..1000u64)
+            .map(|i| (1_000_000 + ((i as f64).sqrt() * 1000.0) as u64, i))
+            .collect());
+        let container = ModelDataWrapper::new(&md);
+        let ols = LinearModel::with_fit(&container, LinearFit::LeastSquares);
+        assert!(ols.predict_to_float(1_000_000.into()) < -10.0);
+
+        let anchored = LinearModel::with_fit(&container, LinearFit::Anchored);
+        assert_eq!(anchored.predict_to_int(1_000_000.into()), 0);
+        assert!(anchored.predict_to_float(1_000_000.into()) >= 0.0);
+        assert!(anchored.is_monotone());
+
+        // a bottom model's first key is predicted at its own position, even
+        // for keys beyond 2^53
+        for (x0, y0) in &[(123_456_789, 500), (1 << 60, 7), ((1 << 60) + 3, 12_345)] {
+            let md = ModelData::IntKeyToIntPos((0..100u64)
+                .map(|i| (x0 + i * 1_000_000_007, y0 + i / 3))
+                .collect());
+            let model = LinearModel::with_fit(&ModelDataWrapper::new(&md), LinearFit::Anchored);
+            assert_eq!(model.predict_to_int((*x0).into()), *y0);
+        }
+
+        let md = ModelData::IntKeyToIntPos(vec![(5, 2), (5, 3)]);
+        let model = LinearModel::with_fit(&ModelDataWrapper::new(&md), LinearFit::Anchored);
+        assert_eq!(model.params, (2.0, 0.0));
+        let md = ModelData::empty();
+        assert_eq!(LinearModel::with_fit(&ModelDataWrapper::new(&md), LinearFit::Anchored).params,
+                   (0.0, 0.0));
+    }
+
 }
 
 pub struct LogLinearModel {
@@ -245,7 +688,12 @@ impl RobustLinearModel {
         }
         
         let bnd = usize::max(1, ((total_items as f64) * 0.0001) as usize);
-        assert!(bnd*2+1 < data.len());
+        if bnd*2+1 >= data.len() {
+            // too few items to trim any outliers, use all of them
+            return RobustLinearModel {
+                params: slr(data.iter_float_float())
+            };
+        }
         
         let mut iter = data.iter_float_float();
         iter.bound(bnd, data.len() - bnd);
@@ -287,8 +735,34 @@ inline double linear(double alpha, double beta, double inp) {
         return String::from("linear");
     }
 
+    fn c89_code(&self) -> Option<String> {
+        return Some(String::from(
+            "
+static double linear(double alpha, double beta, double inp) {
+    return fma(beta, inp, alpha);
+}",
+        ));
+    }
+
+    fn avx2_code(&self) -> Option<String> {
+        return Some(String::from(LINEAR_AVX2_CODE));
+    }
+
+    fn as_line(&self) -> Option<(f64, f64)> {
+        return Some(self.params);
+    }
+
     fn set_to_constant_model(&mut self, constant: u64) -> bool {
         self.params = (constant as f64, 0.0);
         return true;
     }
+
+    fn is_constant(&self) -> bool {
+        return self.params.1 == 0.0;
+    }
+
+    fn is_monotone(&self) -> bool {
+        // fma rounds the exact (non-decreasing) value once
+        return self.params.1 >= 0.0;
+    }
 }
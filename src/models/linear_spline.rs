@@ -74,10 +74,144 @@ inline double linear(double alpha, double beta, double inp) {
         return String::from("linear");
     }
 
+    fn c89_code(&self) -> Option<String> {
+        return Some(String::from(
+            "
+static double linear(double alpha, double beta, double inp) {
+    return fma(beta, inp, alpha);
+}",
+        ));
+    }
+
+    fn avx2_code(&self) -> Option<String> {
+        return Some(String::from(LINEAR_AVX2_CODE));
+    }
+
+    fn as_line(&self) -> Option<(f64, f64)> {
+        return Some(self.params);
+    }
+
     fn set_to_constant_model(&mut self, constant: u64) -> bool {
         self.params = (constant as f64, 0.0);
         return true;
     }
+
+    fn is_constant(&self) -> bool {
+        return self.params.1 == 0.0;
+    }
+
+    fn is_monotone(&self) -> bool {
+        // fma rounds the exact (non-decreasing) value once
+        return self.params.1 >= 0.0;
+    }
+}
+
+// A continuous piecewise linear function: straight segments between knots
+// placed at evenly spaced ranks of the data (the first and last keys are
+// always knots). Neighboring segments share the knot between them, so
+// there is no jump at a knot, and the error bound is measured on this
+// connected function rather than on each segment alone.
+//
+// The knots are stored as one array of (key, position) pairs.
+pub struct ConnectedSplineModel {
+    knots: Vec<(f64, f64)>,
+    max_error: u64
+}
+
+impl ConnectedSplineModel {
+    pub fn new(data: &ModelDataWrapper, num_segments: usize) -> ConnectedSplineModel {
+        assert!(num_segments >= 1);
+        if data.len() == 0 {
+            return ConnectedSplineModel { knots: vec![(0.0, 0.0)], max_error: 0 };
+        }
+
+        let mut knots: Vec<(f64, f64)> = Vec::with_capacity(num_segments + 1);
+        for i in 0..=num_segments {
+            let rank = ((i * (data.len() - 1)) as f64 / num_segments as f64).round() as usize;
+            let knot = data.get(rank);
+
+            // duplicate keys would give a vertical segment
+            match knots.last() {
+                Some(last) if last.0 >= knot.0 => {},
+                _ => knots.push(knot)
+            }
+        }
+
+        let mut model = ConnectedSplineModel { knots, max_error: 0 };
+        model.max_error = max_error(&model, data);
+
+        return model;
+    }
+}
+
+impl Model for ConnectedSplineModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        let x = inp.as_float();
+        let (first, last) = (self.knots[0], self.knots[self.knots.len() - 1]);
+        if x <= first.0 {
+            return first.1;
+        }
+        if x >= last.0 {
+            return last.1;
+        }
+
+        // the segment starting at the last knot at or before x
+        let idx = self.knots.partition_point(|k| k.0 <= x) - 1;
+        let (a, b) = (self.knots[idx], self.knots[idx + 1]);
+        return a.1 + (b.1 - a.1) * ((x - a.0) / (b.0 - a.0));
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let flat: Vec<f64> = self.knots.iter().flat_map(|(k, p)| vec![*k, *p]).collect();
+        return vec![flat.into()];
+    }
+
+    fn code(&self) -> String {
+        return format!(
+            "
+inline double connected_spline(const double* knots, double inp) {{
+    const size_t last = {last};
+    if (inp <= knots[0]) return knots[1];
+    if (inp >= knots[2 * last]) return knots[2 * last + 1];
+
+    // knots[2 * lo] <= inp < knots[2 * hi]
+    size_t lo = 0, hi = last;
+    while (hi - lo > 1) {{
+        size_t mid = (lo + hi) / 2;
+        if (knots[2 * mid] <= inp) lo = mid;
+        else hi = mid;
+    }}
+
+    const double* a = knots + 2 * lo;
+    return a[1] + (a[3] - a[1]) * ((inp - a[0]) / (a[2] - a[0]));
+}}",
+            last = self.knots.len() - 1
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("connected_spline");
+    }
+
+    fn is_monotone(&self) -> bool {
+        // the knots are sorted by key, so this holds for sorted positions
+        return self.knots.windows(2).all(|w| w[0].1 <= w[1].1);
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
+    }
+
+    fn error_bound(&self) -> Option<u64> {
+        return Some(self.max_error);
+    }
 }
 
 #[cfg(test)]
@@ -108,4 +242,45 @@ mod tests {
         LinearSplineModel::new(&ModelData::empty());
     }
 
+    #[test]
+    fn test_connected_spline() {
+        let md = ModelData::IntKeyToIntPos((0..5000).map(|i| (i * i + (i % 7) * 3, i)).collect());
+        let container = ModelDataWrapper::new(&md);
+        let model = ConnectedSplineModel::new(&container, 64);
+        assert_eq!(model.knots.len(), 65);
+
+        // approaching an interior knot from the left (the end of one
+        // segment) gives the knot's position, which starts the next segment
+        for knot in &model.knots[1..model.knots.len() - 1] {
+            let at_knot = model.predict_to_float(knot.0.into());
+            assert_eq!(at_knot, knot.1);
+
+            let just_before = model.predict_to_float((knot.0 - 1e-6).into());
+            assert!((just_before - at_knot).abs() < 1e-3);
+        }
+
+        let bound = model.error_bound().unwrap();
+        for (x, y) in container.iter_int_int() {
+            let pred = model.predict_to_int(x.into());
+            assert!(u64::max(pred, y) - u64::min(pred, y) <= bound);
+        }
+
+        // more segments fit at least as well
+        let coarse = ConnectedSplineModel::new(&container, 4);
+        assert!(coarse.error_bound().unwrap() >= bound);
+        assert!(model.is_monotone() && coarse.is_monotone());
+    }
+
+    #[test]
+    fn test_connected_spline_small() {
+        let model = ConnectedSplineModel::new(&ModelDataWrapper::new(&ModelData::empty()), 8);
+        assert_eq!(model.predict_to_int(3.into()), 0);
+        assert_eq!(model.error_bound(), Some(0));
+
+        // duplicate keys collapse into one knot
+        let md = ModelData::IntKeyToIntPos(vec![(5, 0), (5, 1), (5, 2), (9, 3)]);
+        let model = ConnectedSplineModel::new(&ModelDataWrapper::new(&md), 3);
+        assert_eq!(model.knots, vec![(5.0, 0.0), (9.0, 3.0)]);
+        assert_eq!(model.predict_to_int(100.into()), 3);
+    }
 }
@@ -0,0 +1,203 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+use log::*;
+
+// Stores the position of 2^bits + 1 evenly spaced points of the key range,
+// and linearly interpolates between the two points surrounding a key. The
+// spacing between points is a power of two, so finding them is a shift.
+//
+// Layout of the (single) parameter array:
+//   [base (high 32 bits), base (low 32 bits), shift, table (2^bits + 1)]
+pub struct LookupTableModel {
+    base: u64,
+    shift: u8,
+    table_bits: u8,
+    table: Vec<u32>,
+    max_error: u64
+}
+
+impl LookupTableModel {
+    pub fn new(data: &ModelDataWrapper, bits: u8) -> LookupTableModel {
+        let num_points = (1 << bits) + 1;
+        if data.len() == 0 {
+            return LookupTableModel {
+                base: 0, shift: 0, table_bits: bits,
+                table: vec![0 ; num_points], max_error: 0
+            };
+        }
+
+        let base = data.get_key(0);
+        let range = data.get_key(data.len() - 1) - base;
+        let mut shift = 0;
+        while (range >> shift) >= (1 << bits) {
+            shift += 1;
+        }
+
+        // the position of the first key at or after each point (one past
+        // the last key for points beyond it)
+        let mut keys = data.iter_int_int().peekable();
+        let mut last_pos = 0;
+        let mut table = Vec::with_capacity(num_points);
+        for point in 0..num_points as u128 {
+            let point_key = base as u128 + (point << shift);
+            while let Some((x, y)) = keys.peek() {
+                if (*x as u128) >= point_key { break; }
+                last_pos = *y;
+                keys.next();
+            }
+
+            let pos = keys.peek().map(|(_, y)| *y).unwrap_or(last_pos + 1);
+            assert!(pos <= u64::from(u32::MAX),
+                    "lookup table positions must fit in 32 bits");
+            table.push(pos as u32);
+        }
+
+        let mut model = LookupTableModel {
+            base, shift, table_bits: bits, table, max_error: 0
+        };
+
+        model.max_error = data.iter_int_int()
+            .map(|(x, y)| {
+                let pred = model.predict_to_int(x.into());
+                u64::max(pred, y) - u64::min(pred, y)
+            })
+            .max().unwrap_or(0);
+
+        trace!("Lookup table with {} points and shift {} has max error {}",
+               num_points, shift, model.max_error);
+
+        return model;
+    }
+}
+
+impl Model for LookupTableModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        let key = inp.as_int();
+        if key < self.base {
+            return self.table[0] as f64;
+        }
+
+        let offset = key - self.base;
+        let idx = (offset >> self.shift) as usize;
+        if idx >= (1 << self.table_bits) {
+            return self.table[1 << self.table_bits] as f64;
+        }
+
+        let mask = (1u64 << self.shift) - 1;
+        let frac = (offset & mask) as f64 / (1u64 << self.shift) as f64;
+        let (lo, hi) = (self.table[idx] as f64, self.table[idx + 1] as f64);
+        return (hi - lo).mul_add(frac, lo);
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut params = vec![(self.base >> 32) as u32, self.base as u32, self.shift as u32];
+        params.extend_from_slice(&self.table);
+        return vec![params.into()];
+    }
+
+    fn code(&self) -> String {
+        return format!(
+            "
+inline double lookup_table(const uint32_t* params, const uint64_t inp) {{
+    const uint64_t base = ((uint64_t) params[0] << 32) | params[1];
+    const uint64_t shift = params[2];
+    const uint32_t* table = params + 3;
+    if (inp < base) return table[0];
+
+    const uint64_t offset = inp - base;
+    const uint64_t idx = offset >> shift;
+    if (idx >= {size}) return table[{size}];
+
+    const double frac = (double) (offset & ((1ull << shift) - 1)) / (double) (1ull << shift);
+    return std::fma((double) table[idx + 1] - (double) table[idx], frac, (double) table[idx]);
+}}",
+            size = 1u64 << self.table_bits
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("lookup_table");
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
+    }
+
+    fn error_bound(&self) -> Option<u64> {
+        return Some(self.max_error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty() {
+        let md = ModelData::empty();
+        let model = LookupTableModel::new(&ModelDataWrapper::new(&md), 4);
+        assert_eq!(model.predict_to_int(5.into()), 0);
+        assert_eq!(model.error_bound(), Some(0));
+    }
+
+    #[test]
+    fn test_linear_keys() {
+        // evenly spaced keys are interpolated exactly
+        let md = ModelData::IntKeyToIntPos((0..1024).map(|i| (1000 + i * 4, i)).collect());
+        let container = ModelDataWrapper::new(&md);
+        let model = LookupTableModel::new(&container, 6);
+
+        assert_eq!(model.error_bound(), Some(0));
+        assert_eq!(model.predict_to_int(0.into()), 0);
+        assert_eq!(model.predict_to_int(u64::MAX.into()), 1024);
+        assert_eq!(model.params()[0].len(), 3 + 65);
+    }
+
+    #[test]
+    fn test_error_bound() {
+        let md = ModelData::IntKeyToIntPos((0..2000).map(|i| (i * i, i)).collect());
+        let container = ModelDataWrapper::new(&md);
+
+        let coarse = LookupTableModel::new(&container, 4);
+        let fine = LookupTableModel::new(&container, 10);
+        for model in &[&coarse, &fine] {
+            let bound = model.error_bound().unwrap();
+            for (x, y) in container.iter_int_int() {
+                let pred = model.predict_to_int(x.into());
+                assert!(u64::max(pred, y) - u64::min(pred, y) <= bound);
+            }
+        }
+
+        // more points give a tighter fit
+        assert!(fine.error_bound().unwrap() < coarse.error_bound().unwrap());
+    }
+
+    #[test]
+    fn test_large_keys() {
+        // a key range that needs the full 64 bit shift arithmetic
+        let md = ModelData::IntKeyToIntPos(vec![(0, 0), (u64::MAX / 2, 1), (u64::MAX, 2)]);
+        let container = ModelDataWrapper::new(&md);
+        let model = LookupTableModel::new(&container, 1);
+        assert_eq!(model.predict_to_int(0.into()), 0);
+
+        let bound = model.error_bound().unwrap();
+        for (x, y) in container.iter_int_int() {
+            let pred = model.predict_to_int(x.into());
+            assert!(u64::max(pred, y) - u64::min(pred, y) <= bound);
+        }
+    }
+}
@@ -7,49 +7,311 @@
  
  
 
+mod adaptive;
 mod balanced_radix;
 mod bottom_up_plr;
+mod clamped;
 mod cubic_spline;
+mod dense;
+mod exception;
+mod exponential;
+mod fixed_point;
+mod gapped_linear;
 mod histogram;
+mod isotonic;
 mod linear;
 mod linear_spline;
+mod lookup_table;
 mod normal;
 mod pgm;
 mod radix;
+mod rank_transform;
+mod sampled_cdf;
 mod stdlib;
 mod utils;
 
+pub use adaptive::AdaptiveModel;
 pub use balanced_radix::BalancedRadixModel;
-pub use bottom_up_plr::BottomUpPLR;
+pub use bottom_up_plr::{BottomUpPLR, QuantizedPLRModel, SegmentLayout};
+pub use clamped::{ClampedLinearModel, ClampedCubicModel};
 pub use cubic_spline::CubicSplineModel;
-pub use histogram::EquidepthHistogramModel;
-pub use linear::LinearModel;
+pub use dense::{DenseRangeModel, is_dense_range};
+pub use exception::ExceptionModel;
+pub use exponential::ExponentialModel;
+pub use fixed_point::to_fixed_point;
+pub use gapped_linear::GappedLinearModel;
+pub use histogram::{EquidepthHistogramModel, LogHistogramModel};
+pub use isotonic::IsotonicModel;
+pub use linear::{LinearModel, LinearFit};
 pub use linear::RobustLinearModel;
 pub use linear::LogLinearModel;
-pub use linear_spline::LinearSplineModel;
+pub(crate) use linear::LINEAR_AVX2_CODE;
+pub use linear_spline::{LinearSplineModel, ConnectedSplineModel};
+pub use lookup_table::LookupTableModel;
 pub use normal::LogNormalModel;
 pub use normal::NormalModel;
 pub use pgm::PGM;
 pub use radix::RadixModel;
 pub use radix::RadixTable;
+pub use radix::EqualizedRadixTable;
+pub use radix::TwoLevelRadixModel;
+pub use rank_transform::RankTransformModel;
+pub use sampled_cdf::SampledCDFModel;
 pub use stdlib::StdFunctions;
+pub use utils::{first_non_finite, common_prefix_size};
 
 use std::collections::HashSet;
+use std::fmt;
 use std::io::Write;
+use std::sync::Arc;
 use byteorder::{WriteBytesExt, LittleEndian};
 use superslice::*;
 
+// what the positions of the data measure. Models are trained and errors are
+// measured in these units.
+//
+// With Offset(record_size), positions are byte offsets of records at least
+// record_size bytes long, and an error bound of e bytes covers at most
+// ceil(e / record_size) records on either side of the prediction. For
+// fixed-size records the two are exact: an error of k elements is an error
+// of k * record_size bytes. Variable-length records should use their
+// minimum length as the record size (or 1, for plain byte offsets).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PositionUnit {
+    Element,
+    Offset(u64)
+}
+
+// how scaled integer positions are rounded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rounding {
+    // to the nearest integer (halves round up), which keeps the scaled
+    // positions unbiased
+    Nearest,
+
+    // down, which biases every scaled position down by half a position on
+    // average
+    Down
+}
+
+impl Default for Rounding {
+    fn default() -> Rounding {
+        return Rounding::Nearest;
+    }
+}
+
+impl Default for PositionUnit {
+    fn default() -> PositionUnit {
+        return PositionUnit::Element;
+    }
+}
+
+impl PositionUnit {
+    // the number of whole records an error bound (in these units) can span
+    pub fn error_in_elements(&self, err: u64) -> u64 {
+        return match self {
+            PositionUnit::Element => err,
+            PositionUnit::Offset(record_size) => err.div_ceil(*record_size)
+        };
+    }
+
+    // an error bound that still holds once the prediction is rounded down
+    // to the start of a record (a whole number of records, in bytes)
+    pub fn align_error(&self, err: u64) -> u64 {
+        return match self {
+            PositionUnit::Element => err,
+            PositionUnit::Offset(record_size) => self.error_in_elements(err) * record_size
+        };
+    }
+
+    // the number of records in num_positions positions (in these units)
+    pub fn num_records(&self, num_positions: usize) -> usize {
+        return match self {
+            PositionUnit::Element => num_positions,
+            PositionUnit::Offset(record_size) => num_positions / *record_size as usize
+        };
+    }
+
+    // the position the generated lookup returns for a prediction: clamped
+    // to the last of num_positions, then rounded down to the start of a
+    // record
+    pub fn lookup_position(&self, pred: u64, num_positions: u64) -> u64 {
+        let pos = u64::min(pred, num_positions.saturating_sub(1));
+        return match self {
+            PositionUnit::Element => pos,
+            PositionUnit::Offset(record_size) => pos - pos % record_size
+        };
+    }
+}
+
+// how a key made of two columns is packed into a single u64 key: the first
+// column in the high first_bits bits, directly above the second column in
+// the low second_bits bits. Rows sorted by (first, second) then have sorted
+// packed keys, as long as every value fits in its column's bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompositeKey {
+    pub first_bits: u8,
+    pub second_bits: u8
+}
+
+impl CompositeKey {
+    pub fn new(first_bits: u8, second_bits: u8) -> CompositeKey {
+        assert!(first_bits > 0 && second_bits > 0 && first_bits + second_bits <= 64,
+                "composite key columns need between 1 and 64 bits in total");
+        return CompositeKey { first_bits, second_bits };
+    }
+
+    pub fn pack(&self, first: u64, second: u64) -> u64 {
+        assert!(self.first_bits == 64 || first >> self.first_bits == 0,
+                "{} does not fit in {} bits", first, self.first_bits);
+        assert!(second >> self.second_bits == 0,
+                "{} does not fit in {} bits", second, self.second_bits);
+        return (first << self.second_bits) | second;
+    }
+
+    // the C type of a column's values
+    pub fn c_type(bits: u8) -> &'static str {
+        return if bits <= 32 { "uint32_t" } else { "uint64_t" };
+    }
+}
+
+// a linear map applied to every key before it reaches the models, as
+// (key - offset) / scale in integer arithmetic (keys below the offset map to
+// 0). It is non-decreasing, so sorted keys stay sorted, but keys within the
+// same multiple of the scale become equal. For example, an offset of an
+// epoch and a scale of a granularity turns timestamps into bucket numbers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyTransform {
+    pub offset: u64,
+    pub scale: u64
+}
+
+impl KeyTransform {
+    pub fn new(offset: u64, scale: u64) -> KeyTransform {
+        assert!(scale > 0, "the key transform scale must be positive");
+        return KeyTransform { offset, scale };
+    }
+
+    pub fn apply(&self, key: u64) -> u64 {
+        return key.saturating_sub(self.offset) / self.scale;
+    }
+}
+
+// maps a float key to a u64 with the same order, so that float keys can be
+// indexed by the integer models (such as radix). The bit patterns of
+// positive floats already sort like the floats, so their sign bit is set to
+// put them above the negatives. The bit patterns of negative floats sort in
+// reverse, so all of their bits are flipped. -0.0 is the same key as 0.0,
+// and maps to the same value. NaN has no place in the order.
+pub fn float_to_monotonic_u64(key: f64) -> u64 {
+    assert!(!key.is_nan(), "NaN cannot be used as a key");
+    let bits = if key == 0.0 { 0 } else { key.to_bits() };
+    if bits >> 63 == 1 {
+        return !bits;
+    }
+    return bits | (1 << 63);
+}
+
+// the inverse of float_to_monotonic_u64 (-0.0 comes back as 0.0)
+pub fn monotonic_u64_to_float(key: u64) -> f64 {
+    if key >> 63 == 1 {
+        return f64::from_bits(key & !(1 << 63));
+    }
+    return f64::from_bits(!key);
+}
+
+// maps a string key to a u64 that never sorts it before a smaller string:
+// its first 8 bytes, big-endian, padded with zero bytes. Strings that share
+// their first 8 bytes (or differ only by trailing zero bytes within them)
+// map to the same value, so the order of the values is not strict.
+pub fn string_to_monotonic_u64(key: &[u8]) -> u64 {
+    let mut prefix = [0u8; 8];
+    let len = usize::min(key.len(), 8);
+    prefix[..len].copy_from_slice(&key[..len]);
+    return u64::from_be_bytes(prefix);
+}
+
+// what a lookup of a float key does with a key that is NaN or infinite,
+// which float_to_monotonic_u64 either rejects (NaN) or maps to the ends of
+// the order (infinities). Such keys are flagged as out of range, except for
+// infinities with Clamp.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NonFinitePolicy {
+    // return this position
+    Sentinel(u64),
+
+    // give infinities the first or last position, without flagging them.
+    // NaN gets position 0.
+    Clamp,
+
+    // return no position: RuntimeRMI::lookup_float gives None, and the
+    // generated lookup_float returns UINT64_MAX, which is never a position
+    Error
+}
+
+impl Default for NonFinitePolicy {
+    fn default() -> NonFinitePolicy {
+        return NonFinitePolicy::Sentinel(0);
+    }
+}
+
+impl NonFinitePolicy {
+    // parses "sentinel:<pos>", "clamp", or "error"
+    pub fn parse(s: &str) -> Option<NonFinitePolicy> {
+        if let Some(pos) = s.strip_prefix("sentinel:") {
+            return pos.trim().parse::<u64>().ok().map(NonFinitePolicy::Sentinel);
+        }
+
+        return match s {
+            "clamp" => Some(NonFinitePolicy::Clamp),
+            "error" => Some(NonFinitePolicy::Error),
+            _ => None
+        };
+    }
+
+    // true if the key is out of range under this policy
+    pub fn rejects(&self, key: f64) -> bool {
+        return match self {
+            NonFinitePolicy::Clamp => key.is_nan(),
+            _ => !key.is_finite()
+        };
+    }
+
+    // the position of a key this policy rejects, if it has one
+    pub fn position(&self) -> Option<u64> {
+        return match self {
+            NonFinitePolicy::Sentinel(pos) => Some(*pos),
+            NonFinitePolicy::Clamp => Some(0),
+            NonFinitePolicy::Error => None
+        };
+    }
+
+    // the position Clamp gives an infinite key, out of num_positions: the
+    // first for negative infinity and the last for positive infinity
+    pub fn clamped_position(&self, key: f64, num_positions: u64) -> Option<u64> {
+        if *self != NonFinitePolicy::Clamp || !key.is_infinite() {
+            return None;
+        }
+
+        return Some(if key < 0.0 { 0 } else { num_positions.saturating_sub(1) });
+    }
+}
+
 #[derive(Clone)]
 pub struct ModelDataWrapper<'a> {
-    model_data: &'a ModelData,
-    scaling_factor: f64
+    model_data: &'a ModelData<'a>,
+    scaling_factor: f64,
+    position_unit: PositionUnit,
+    num_positions: Option<usize>
 }
 
 impl <'a> ModelDataWrapper<'a> {
-    pub fn new(md: &'a ModelData) -> ModelDataWrapper<'a> {
+    pub fn new(md: &'a ModelData<'a>) -> ModelDataWrapper<'a> {
         return ModelDataWrapper {
             model_data: md,
-            scaling_factor: 1.0
+            scaling_factor: 1.0,
+            position_unit: PositionUnit::Element,
+            num_positions: None
         }
     }
 
@@ -57,10 +319,68 @@ impl <'a> ModelDataWrapper<'a> {
         self.scaling_factor = scale;
     }
 
+    pub fn set_position_unit(&mut self, unit: PositionUnit) {
+        if let PositionUnit::Offset(record_size) = unit {
+            assert!(record_size > 0, "record size must be positive");
+        }
+        self.position_unit = unit;
+    }
+
+    pub fn position_unit(&self) -> PositionUnit {
+        return self.position_unit;
+    }
+
+    // the size of the position space, for data that does not hold every
+    // position (such as the result of ModelData::compact_distinct)
+    pub fn set_num_positions(&mut self, num_positions: usize) {
+        self.num_positions = Some(num_positions);
+    }
+
     pub fn len(&self) -> usize {
         return self.model_data.len();
     }
 
+    // the size of the position space: the number of elements (or, if the
+    // positions are not indexes, one past the last position), or the
+    // offset just past the last record (unless set_num_positions was used)
+    pub fn num_positions(&self) -> usize {
+        if let Some(num_positions) = self.num_positions {
+            return num_positions;
+        }
+
+        return match self.position_unit {
+            PositionUnit::Element if self.len() == 0 => 0,
+            PositionUnit::Element => usize::max(
+                self.len(),
+                (self.model_data.get(self.len() - 1).1 as usize).saturating_add(1)
+            ),
+            PositionUnit::Offset(_) if self.len() == 0 => 0,
+            PositionUnit::Offset(record_size) =>
+                self.model_data.get(self.len() - 1).1 as usize + record_size as usize
+        };
+    }
+
+    // the index of the first position smaller than the one before it, if
+    // any. Positions do not have to be indexes (they can be sparse, with
+    // large gaps), but training assumes they never decrease.
+    pub fn first_decreasing_position(&self) -> Option<usize> {
+        return (1..self.len())
+            .find(|idx| self.model_data.get(*idx).1 < self.model_data.get(idx - 1).1);
+    }
+
+    // true if the positions are element indexes: each key's position is
+    // its index, or for a repeated key, the index of its first occurrence
+    pub fn positions_are_indexes(&self) -> bool {
+        if self.position_unit != PositionUnit::Element || self.num_positions() != self.len() {
+            return false;
+        }
+
+        return (0..self.len()).all(|idx| {
+            let (key, pos) = self.model_data.get(idx);
+            return pos == idx as f64 || (idx > 0 && self.model_data.get(idx - 1) == (key, pos));
+        });
+    }
+
     pub fn get(&self, idx: usize) -> (f64, f64) {
         let (x, y) = self.model_data.get(idx);
         return (x, y * self.scaling_factor);
@@ -87,23 +407,54 @@ impl <'a> ModelDataWrapper<'a> {
         return iter;
     }
 
+    // float keys with integer positions, and integer keys with float
+    // positions, without converting both through the same type. The scale
+    // applies to the position.
+    pub fn iter_float_int(&self) -> ModelDataFIIterator<'_> {
+        let mut iter = self.model_data.iter_float_int();
+        iter.set_scale(self.scaling_factor);
+        return iter;
+    }
+
+    pub fn iter_int_float(&self) -> ModelDataIFIterator<'_> {
+        let mut iter = self.model_data.iter_int_float();
+        iter.set_scale(self.scaling_factor);
+        return iter;
+    }
+
+    // the keys as model inputs, with their positions. Unlike iter_int_int,
+    // float keys stay floats rather than being truncated.
+    pub fn iter_input_int(&self) -> Box<dyn Iterator<Item = (ModelInput, u64)> + '_> {
+        if self.model_data.has_float_keys() {
+            return Box::new(self.iter_float_int().map(|(x, y)| (x.into(), y)));
+        }
+        return Box::new(self.iter_int_int().map(|(x, y)| (x.into(), y)));
+    }
+
+    pub fn has_float_keys(&self) -> bool {
+        return self.model_data.has_float_keys();
+    }
+
     pub fn as_int_int(&self) -> &[(u64, u64)] {
         return self.model_data.as_int_int();
     }
 
-    pub fn into_data(self) -> ModelData {
+    pub fn into_data(self) -> ModelData<'a> {
         return self.model_data.clone();
     }
 }
 
 #[derive(Clone)]
-pub enum ModelData {
+pub enum ModelData<'a> {
     IntKeyToIntPos(Vec<(u64, u64)>),
     #[allow(dead_code)]
     FloatKeyToIntPos(Vec<(f64, u64)>),
     #[allow(dead_code)]
     IntKeyToFloatPos(Vec<(u64, f64)>),
     FloatKeyToFloatPos(Vec<(f64, f64)>),
+    // sorted pairs owned by the caller (such as a memory mapped file),
+    // trained on in place. See ModelData::from_slice.
+    BorrowedIntKeyToIntPos(&'a [(u64, u64)]),
 }
 
 #[cfg(test)]
@@ -126,15 +477,15 @@ macro_rules! extract_and_convert_tuple {
 macro_rules! define_iterator_type {
     ($name: tt, $type1: ty, $type2: ty) => {
         pub struct $name<'a> {
-            data: &'a ModelData,
+            data: &'a ModelData<'a>,
             idx: usize,
             scale: f64,
             stop: usize
         }
 
         impl<'a> $name<'a> {
-            fn new(data: &'a ModelData) -> $name<'a> {
-                return $name { data: data, idx: 0, scale: 1.0, stop: data.len() };
+            fn new(data: &'a ModelData<'a>) -> $name<'a> {
+                return $name { data, idx: 0, scale: 1.0, stop: data.len() };
             }
 
             fn set_scale(&mut self, scale: f64) {
@@ -142,7 +493,8 @@ macro_rules! define_iterator_type {
             }
 
             pub fn bound(&mut self, start: usize, stop: usize) {
-                assert!(start < stop);
+                // an empty range is allowed (e.g. a leaf that got no keys)
+                assert!(start <= stop);
                 assert!(stop <= self.data.len());
                 self.idx = start;
                 self.stop = stop;
@@ -170,6 +522,9 @@ macro_rules! define_iterator_type {
                     ModelData::IntKeyToFloatPos(data) => {
                         extract_and_convert_tuple!(data, self.idx, $type1, $type2, self.scale)
                     }
+                    ModelData::BorrowedIntKeyToIntPos(data) => {
+                        extract_and_convert_tuple!(data, self.idx, $type1, $type2, self.scale)
+                    }
                 };
                 self.idx += 1;
 
@@ -183,10 +538,10 @@ macro_rules! define_iterator_type {
 define_iterator_type!(ModelDataFFIterator, f64, f64);
 define_iterator_type!(ModelDataIIIterator, u64, u64);
 //define_iterator_type_skip!(ModelDataIIIteratorSkip, u64, u64);
-//define_iterator_type!(ModelDataFIIterator, f64, u64);
-//define_iterator_type!(ModelDataIFIterator, u64, f64);
+define_iterator_type!(ModelDataFIIterator, f64, u64);
+define_iterator_type!(ModelDataIFIterator, u64, f64);
 
-impl ModelData {
+impl<'a> ModelData<'a> {
     pub fn iter_float_float(&self) -> ModelDataFFIterator {
         return ModelDataFFIterator::new(&self);
     }
@@ -197,13 +552,194 @@ impl ModelData {
     /*pub fn iter_int_int_skip(&self, factor: usize) -> ModelDataIIIteratorSkip {
         return ModelDataIIIteratorSkip::new(&self, factor);
     }*/
-    //pub fn iter_float_int(&self) -> ModelDataFIIterator { return ModelDataFIIterator::new(&self); }
-    //pub fn iter_int_float(&self) -> ModelDataIFIterator { return ModelDataIFIterator::new(&self); }
+    pub fn iter_float_int(&self) -> ModelDataFIIterator<'_> {
+        return ModelDataFIIterator::new(self);
+    }
+    pub fn iter_int_float(&self) -> ModelDataIFIterator<'_> {
+        return ModelDataIFIterator::new(self);
+    }
 
-    pub fn empty() -> ModelData {
+    pub fn empty() -> ModelData<'static> {
         return ModelData::FloatKeyToFloatPos(vec![]);
     }
 
+    // borrows sorted (key, position) pairs instead of copying them, so that
+    // a large read-only buffer can be trained on without a second copy in
+    // memory. The methods that change the data (such as
+    // positions_to_offsets) copy it first.
+    pub fn from_slice(data: &'a [(u64, u64)]) -> ModelData<'a> {
+        assert!(data.windows(2).all(|w| w[0].0 <= w[1].0), "keys must be sorted");
+        return ModelData::BorrowedIntKeyToIntPos(data);
+    }
+
+    // replaces borrowed data with a copy of it, which can be changed
+    fn make_owned(&mut self) {
+        if let ModelData::BorrowedIntKeyToIntPos(data) = self {
+            *self = ModelData::IntKeyToIntPos(data.to_vec());
+        }
+    }
+
+    // rescales positions from [0, num_positions) to [0, target), rounding
+    // integer positions to the nearest one. num_positions is in the data's
+    // position units (see ModelDataWrapper::num_positions), so this works
+    // for offsets too.
+    pub fn scale_targets_to(&mut self, target: u64, num_positions: usize) {
+        self.scale_targets_to_with(target, num_positions, Rounding::default());
+    }
+
+    // like scale_targets_to, with the given rounding of integer positions.
+    // Rounding to the nearest can round the last positions up to target,
+    // so they are clamped to target - 1. Float positions are scaled without
+    // rounding either way.
+    pub fn scale_targets_to_with(&mut self, target: u64, num_positions: usize,
+                                 rounding: Rounding) {
+        let half = match rounding {
+            Rounding::Nearest => num_positions as u128 / 2,
+            Rounding::Down => 0
+        };
+        let last = target.saturating_sub(1);
+        let scale = |y: u64| {
+            let scaled = (y as u128 * target as u128 + half) / num_positions as u128;
+            u64::min(scaled as u64, last)
+        };
+        let scale_f = target as f64 / num_positions as f64;
+        self.make_owned();
+        match self {
+            ModelData::FloatKeyToFloatPos(data) => for p in data.iter_mut() { p.1 *= scale_f; },
+            ModelData::FloatKeyToIntPos(data) => for p in data.iter_mut() { p.1 = scale(p.1); },
+            ModelData::IntKeyToFloatPos(data) => for p in data.iter_mut() { p.1 *= scale_f; },
+            ModelData::IntKeyToIntPos(data) => for p in data.iter_mut() { p.1 = scale(p.1); }
+            ModelData::BorrowedIntKeyToIntPos(_) => unreachable!()
+        };
+    }
+
+    // packs two key columns (row i is (first[i], second[i]), with the rows
+    // sorted) into keys mapping to row indexes
+    pub fn from_composite_keys(first: &[u64], second: &[u64],
+                               layout: CompositeKey) -> ModelData<'static> {
+        assert_eq!(first.len(), second.len(), "key columns must have the same length");
+        let data: Vec<(u64, u64)> = first.iter().zip(second.iter()).enumerate()
+            .map(|(idx, (f, s))| (layout.pack(*f, *s), idx as u64))
+            .collect();
+        assert!(data.windows(2).all(|w| w[0].0 <= w[1].0),
+                "composite keys must be sorted by the first column, then the second");
+        return ModelData::IntKeyToIntPos(data);
+    }
+
+    // maps sorted float keys to row indexes, with the keys transformed by
+    // float_to_monotonic_u64, so integer models can be trained on them
+    pub fn from_float_keys(keys: &[f64]) -> ModelData<'static> {
+        assert!(keys.windows(2).all(|w| w[0] <= w[1]), "float keys must be sorted");
+        let data: Vec<(u64, u64)> = keys.iter().enumerate()
+            .map(|(idx, k)| (float_to_monotonic_u64(*k), idx as u64))
+            .collect();
+        return ModelData::IntKeyToIntPos(data);
+    }
+
+    // sorted string keys (compared byte by byte), each mapped by
+    // string_to_monotonic_u64 to its 8 byte prefix. Strings with the same
+    // prefix become duplicate keys, in their original order.
+    pub fn from_string_keys<S: AsRef<[u8]>>(keys: &[S]) -> ModelData<'static> {
+        assert!(keys.windows(2).all(|w| w[0].as_ref() <= w[1].as_ref()),
+                "string keys must be sorted");
+        let data: Vec<(u64, u64)> = keys.iter().enumerate()
+            .map(|(idx, k)| (string_to_monotonic_u64(k.as_ref()), idx as u64))
+            .collect();
+        return ModelData::IntKeyToIntPos(data);
+    }
+
+    // turns element indexes into byte offsets of fixed-size records
+    pub fn positions_to_offsets(&mut self, record_size: u64) {
+        self.make_owned();
+        match self {
+            ModelData::FloatKeyToFloatPos(data) => for p in data.iter_mut() { p.1 *= record_size as f64; },
+            ModelData::FloatKeyToIntPos(data) => for p in data.iter_mut() { p.1 *= record_size; },
+            ModelData::IntKeyToFloatPos(data) => for p in data.iter_mut() { p.1 *= record_size as f64; },
+            ModelData::IntKeyToIntPos(data) => for p in data.iter_mut() { p.1 *= record_size; }
+            ModelData::BorrowedIntKeyToIntPos(_) => unreachable!()
+        };
+    }
+
+    // applies the transform to every (integer) key, keeping the positions
+    pub fn transform_keys(&self, transform: KeyTransform) -> ModelData<'static> {
+        return match self {
+            ModelData::IntKeyToIntPos(data) => ModelData::IntKeyToIntPos(
+                data.iter().map(|(k, p)| (transform.apply(*k), *p)).collect()),
+            ModelData::BorrowedIntKeyToIntPos(data) => ModelData::IntKeyToIntPos(
+                data.iter().map(|(k, p)| (transform.apply(*k), *p)).collect()),
+            ModelData::IntKeyToFloatPos(data) => ModelData::IntKeyToFloatPos(
+                data.iter().map(|(k, p)| (transform.apply(*k), *p)).collect()),
+            _ => panic!("key transforms only apply to integer keys")
+        };
+    }
+
+    // divides each position by divisor, rounding down, e.g. to map
+    // positions to the pages holding them
+    pub fn divide_positions(&self, divisor: u64) -> ModelData<'static> {
+        let d = divisor as f64;
+        return match self {
+            ModelData::IntKeyToIntPos(data) => ModelData::IntKeyToIntPos(
+                data.iter().map(|(k, p)| (*k, p / divisor)).collect()),
+            ModelData::BorrowedIntKeyToIntPos(data) => ModelData::IntKeyToIntPos(
+                data.iter().map(|(k, p)| (*k, p / divisor)).collect()),
+            ModelData::FloatKeyToIntPos(data) => ModelData::FloatKeyToIntPos(
+                data.iter().map(|(k, p)| (*k, p / divisor)).collect()),
+            ModelData::IntKeyToFloatPos(data) => ModelData::IntKeyToFloatPos(
+                data.iter().map(|(k, p)| (*k, (p / d).floor())).collect()),
+            ModelData::FloatKeyToFloatPos(data) => ModelData::FloatKeyToFloatPos(
+                data.iter().map(|(k, p)| (*k, (p / d).floor())).collect())
+        };
+    }
+
+    // maps the keys and positions into integer space, as
+    // floor(key * key_scale) and round(pos * pos_scale), so that models
+    // which need integer data (such as the radix models) can be used on
+    // float data. Both maps are non-decreasing, so sorted data stays
+    // sorted, but keys closer together than 1 / key_scale become equal,
+    // and values outside of [0, 2^64) saturate (negative keys should be
+    // shifted first). A larger key_scale keeps more keys distinct, but
+    // past 2^53 the scaled keys are no more precise than the floats.
+    // Lookups must quantize their keys with the same key_scale (as the
+    // lookup_quantized generated for NamedRMI::quantized_keys does), and
+    // divide the returned position by pos_scale.
+    pub fn quantize_to_int(&self, key_scale: f64, pos_scale: f64) -> ModelData<'static> {
+        assert!(key_scale > 0.0 && pos_scale > 0.0, "quantization scales must be positive");
+        return ModelData::IntKeyToIntPos(
+            (0..self.len()).map(|idx| {
+                let (x, y) = self.get(idx);
+                ((x * key_scale).floor() as u64, (y * pos_scale).round() as u64)
+            }).collect()
+        );
+    }
+
+    // keeps only the first entry of each run of equal keys. The positions
+    // are unchanged, so each key still maps to the position of its first
+    // occurrence in the full data (its lower bound), and the CDF has the
+    // same shape with far fewer points when there are long runs of
+    // duplicates. Since the data no longer holds every position, train on
+    // it with ModelDataWrapper::set_num_positions set to the full data's
+    // number of positions.
+    pub fn compact_distinct(&self) -> ModelData<'static> {
+        fn first_per_key<K: PartialEq + Copy, P: Copy>(data: &[(K, P)]) -> Vec<(K, P)> {
+            let mut compact: Vec<(K, P)> = Vec::new();
+            for (key, pos) in data.iter() {
+                if compact.last().map(|(last, _)| last != key).unwrap_or(true) {
+                    compact.push((*key, *pos));
+                }
+            }
+            return compact;
+        }
+
+        return match self {
+            ModelData::FloatKeyToFloatPos(data) => ModelData::FloatKeyToFloatPos(first_per_key(data)),
+            ModelData::FloatKeyToIntPos(data) => ModelData::FloatKeyToIntPos(first_per_key(data)),
+            ModelData::IntKeyToFloatPos(data) => ModelData::IntKeyToFloatPos(first_per_key(data)),
+            ModelData::IntKeyToIntPos(data) => ModelData::IntKeyToIntPos(first_per_key(data)),
+            ModelData::BorrowedIntKeyToIntPos(data) =>
+                ModelData::IntKeyToIntPos(first_per_key(data))
+        };
+    }
+
     #[cfg(test)]
     fn into_int_int(self) -> Vec<(u64, u64)> {
         return match self {
@@ -211,6 +747,7 @@ impl ModelData {
             ModelData::FloatKeyToIntPos(data) => vec_to_ii!(data),
             ModelData::IntKeyToFloatPos(data) => vec_to_ii!(data),
             ModelData::IntKeyToIntPos(data) => data,
+            ModelData::BorrowedIntKeyToIntPos(data) => data.to_vec(),
         };
     }
 
@@ -220,15 +757,21 @@ impl ModelData {
             ModelData::FloatKeyToIntPos(_data) => panic!("as_int_int on float/int model data"),
             ModelData::IntKeyToFloatPos(_data) => panic!("as_int_int on int/float model data"),
             ModelData::IntKeyToIntPos(data) => &data,
+            ModelData::BorrowedIntKeyToIntPos(data) => data,
         };
     }
 
+    pub fn has_float_keys(&self) -> bool {
+        return matches!(self, ModelData::FloatKeyToFloatPos(_) | ModelData::FloatKeyToIntPos(_));
+    }
+
     pub fn len(&self) -> usize {
         return match self {
             ModelData::FloatKeyToFloatPos(data) => data.len(),
             ModelData::FloatKeyToIntPos(data) => data.len(),
             ModelData::IntKeyToFloatPos(data) => data.len(),
             ModelData::IntKeyToIntPos(data) => data.len(),
+            ModelData::BorrowedIntKeyToIntPos(data) => data.len(),
         };
     }
 
@@ -238,6 +781,7 @@ impl ModelData {
             ModelData::FloatKeyToIntPos(data) => (data[idx].0, data[idx].1 as f64),
             ModelData::IntKeyToFloatPos(data) => (data[idx].0 as f64, data[idx].1),
             ModelData::IntKeyToIntPos(data) => (data[idx].0 as f64, data[idx].1 as f64),
+            ModelData::BorrowedIntKeyToIntPos(data) => (data[idx].0 as f64, data[idx].1 as f64),
         };
     }
 
@@ -246,25 +790,27 @@ impl ModelData {
             ModelData::FloatKeyToFloatPos(data) => data[idx].0 as u64,
             ModelData::FloatKeyToIntPos(data) => data[idx].0 as u64, 
             ModelData::IntKeyToFloatPos(data) => data[idx].0,
-            ModelData::IntKeyToIntPos(data) => data[idx].0
+            ModelData::IntKeyToIntPos(data) => data[idx].0,
+            ModelData::BorrowedIntKeyToIntPos(data) => data[idx].0
         };
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum ModelInput {
     Int(u64),
     Float(f64),
 }
 
 impl ModelInput {
-    fn as_float(&self) -> f64 {
+    pub fn as_float(&self) -> f64 {
         return match self {
             ModelInput::Int(x) => *x as f64,
             ModelInput::Float(x) => *x,
         };
     }
 
-    fn as_int(&self) -> u64 {
+    pub fn as_int(&self) -> u64 {
         return match self {
             ModelInput::Int(x) => *x,
             ModelInput::Float(x) => *x as u64,
@@ -308,7 +854,86 @@ pub enum ModelParam {
     FloatArray(Vec<f64>),
 }
 
+// an element of an integer array parameter that does not fit the C type the
+// array is emitted as (see ModelParam::validate)
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverflowError {
+    pub index: usize,
+    pub value: u64,
+    pub c_type: &'static str
+}
+
+impl fmt::Display for OverflowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "parameter element {} ({}) does not fit in a {}",
+                      self.index, self.value, self.c_type);
+    }
+}
+
+// the first of the values above max, as an OverflowError
+fn first_overflow<I: Iterator<Item = u64>>(values: I, max: u64,
+                                           c_type: &'static str) -> Result<(), OverflowError> {
+    for (index, value) in values.enumerate() {
+        if value > max {
+            return Err(OverflowError { index, value, c_type });
+        }
+    }
+    return Ok(());
+}
+
+// the shortest decimal literal that a C compiler parses back to exactly
+// this double. Very large and very small magnitudes use an exponent (e.g.
+// 1e-7), and integral values keep a ".0" so the literal is a double.
+fn c_double(v: f64) -> String {
+    assert!(v.is_finite(), "cannot write a non-finite parameter ({}) as a C literal", v);
+    return format!("{:?}", v);
+}
+
 impl ModelParam {
+    // builds an integer array parameter using the narrowest of ShortArray,
+    // Int32Array, and IntArray that can hold every value. The C type follows
+    // the choice, so model code should declare the array using `c_type()`
+    // rather than a hard-coded type. Since ShortArray is emitted as a (signed)
+    // short, it is only used when every value fits in an i16.
+    pub fn int_array_compact(values: Vec<u64>) -> ModelParam {
+        let max_val = values.iter().copied().max().unwrap_or(0);
+
+        if max_val <= i16::MAX as u64 {
+            return ModelParam::ShortArray(values.into_iter().map(|v| v as u16).collect());
+        }
+
+        if max_val <= u64::from(u32::MAX) {
+            return ModelParam::Int32Array(values.into_iter().map(|v| v as u32).collect());
+        }
+
+        return ModelParam::IntArray(values);
+    }
+
+    // a ShortArray of the values, or the first one that does not fit in the
+    // (signed) short it is emitted as. Unlike `v as u16`, this never wraps.
+    pub fn short_array(values: &[u64]) -> Result<ModelParam, OverflowError> {
+        first_overflow(values.iter().copied(), i16::MAX as u64, "short")?;
+        return Ok(ModelParam::ShortArray(values.iter().map(|v| *v as u16).collect()));
+    }
+
+    // an Int32Array of the values, or the first one above u32::MAX
+    pub fn int32_array(values: &[u64]) -> Result<ModelParam, OverflowError> {
+        first_overflow(values.iter().copied(), u64::from(u32::MAX), "uint32_t")?;
+        return Ok(ModelParam::Int32Array(values.iter().map(|v| *v as u32).collect()));
+    }
+
+    // checks that every element fits the C type the parameter is emitted
+    // as. Only a ShortArray can hold values that do not: it is a Vec<u16>,
+    // but a short is signed, so values above i16::MAX would come out
+    // negative in the generated code (and in the data files it reads).
+    pub fn validate(&self) -> Result<(), OverflowError> {
+        return match self {
+            ModelParam::ShortArray(arr) =>
+                first_overflow(arr.iter().map(|v| u64::from(*v)), i16::MAX as u64, "short"),
+            _ => Ok(())
+        };
+    }
+
     // size in bytes
     pub fn size(&self) -> usize {
         match self {
@@ -354,39 +979,29 @@ impl ModelParam {
         }
     }
 
+    // the value as a C literal. Floats are written so that the compiled
+    // constant is exactly the trained value (see c_double).
     pub fn c_val(&self) -> String {
         match self {
             ModelParam::Int(v) => format!("{}UL", v),
-            ModelParam::Float(v) => {
-                let mut tmp = format!("{:.}", v);
-                if !tmp.contains('.') {
-                    tmp.push_str(".0");
-                }
-                return tmp;
-            },
-            ModelParam::ShortArray(arr) => {
-                let itms: Vec<String> = arr.iter().map(|i| format!("{}", i)).collect();
-                return format!("{{ {} }}", itms.join(", "));
-            },
-            ModelParam::IntArray(arr) => {
-                let itms: Vec<String> = arr.iter().map(|i| format!("{}UL", i)).collect();
-                return format!("{{ {} }}", itms.join(", "));
-            },
-            ModelParam::Int32Array(arr) => {
-                let itms: Vec<String> = arr.iter().map(|i| format!("{}UL", i)).collect();
-                return format!("{{ {} }}", itms.join(", "));
-            },
-            ModelParam::FloatArray(arr) => {
-                let itms: Vec<String> = arr
-                    .iter()
-                    .map(|i| format!("{:.}", i))
-                    .map(|s| if !s.contains('.') { s + ".0" } else { s })
-                    .collect();
-                return format!("{{ {} }}", itms.join(", "));
-            }
+            ModelParam::Float(v) => c_double(*v),
+            ModelParam::ShortArray(_) | ModelParam::IntArray(_) |
+            ModelParam::Int32Array(_) | ModelParam::FloatArray(_) =>
+                format!("{{ {} }}", self.c_elements().join(", "))
         }
     }
 
+    // the elements of an array parameter as C literals
+    pub fn c_elements(&self) -> Vec<String> {
+        return match self {
+            ModelParam::ShortArray(arr) => arr.iter().map(|i| format!("{}", i)).collect(),
+            ModelParam::IntArray(arr) => arr.iter().map(|i| format!("{}UL", i)).collect(),
+            ModelParam::Int32Array(arr) => arr.iter().map(|i| format!("{}UL", i)).collect(),
+            ModelParam::FloatArray(arr) => arr.iter().map(|i| c_double(*i)).collect(),
+            ModelParam::Int(_) | ModelParam::Float(_) => panic!("Only arrays have elements")
+        };
+    }
+
     /* useful for debugging floating point issues
     pub fn as_bits(&self) -> u64 {
         return match self {
@@ -402,7 +1017,38 @@ impl ModelParam {
         return std::mem::discriminant(self) == std::mem::discriminant(other);
     }
 
+    // index of the first element that differs from other. Integers must
+    // match exactly, floats must be within tolerance.
+    pub fn first_difference(&self, other: &ModelParam, tolerance: f64) -> Option<usize> {
+        fn first_diff<T, F: Fn(&T, &T) -> bool>(a: &[T], b: &[T], eq: F) -> Option<usize> {
+            if let Some(idx) = a.iter().zip(b.iter()).position(|(x, y)| !eq(x, y)) {
+                return Some(idx);
+            }
+
+            if a.len() != b.len() {
+                return Some(usize::min(a.len(), b.len()));
+            }
+
+            return None;
+        }
+
+        let float_eq = |x: &f64, y: &f64| (x - y).abs() <= tolerance;
+        return match (self, other) {
+            (ModelParam::Int(a), ModelParam::Int(b)) => first_diff(&[*a], &[*b], u64::eq),
+            (ModelParam::Float(a), ModelParam::Float(b)) => first_diff(&[*a], &[*b], float_eq),
+            (ModelParam::ShortArray(a), ModelParam::ShortArray(b)) => first_diff(a, b, u16::eq),
+            (ModelParam::IntArray(a), ModelParam::IntArray(b)) => first_diff(a, b, u64::eq),
+            (ModelParam::Int32Array(a), ModelParam::Int32Array(b)) => first_diff(a, b, u32::eq),
+            (ModelParam::FloatArray(a), ModelParam::FloatArray(b)) => first_diff(a, b, float_eq),
+            _ => Some(0)
+        };
+    }
+
     pub fn write_to<T: Write>(&self, target: &mut T) -> Result<(), std::io::Error> {
+        if let Err(err) = self.validate() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()));
+        }
+
         match self {
             ModelParam::Int(v) => target.write_u64::<LittleEndian>(*v),
             ModelParam::Float(v) => target.write_f64::<LittleEndian>(*v),
@@ -513,8 +1159,14 @@ impl From<Vec<f64>> for ModelParam {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ModelRestriction {
     None,
+
+    // codegen lays out the parameters of a layer with several models as one
+    // array per parameter, so every model needs the same parameters. A model
+    // with an array parameter whose length depends on its data (samples,
+    // knots, a table) can only be used in a layer with one model.
     MustBeTop,
     MustBeBottom,
 }
@@ -528,6 +1180,12 @@ pub trait Model: Sync + Send {
         return f64::max(0.0, self.predict_to_float(inp).floor()) as u64;
     }
 
+    // the output of the model for each input, in order. Models may override
+    // this with something faster than one predict_to_float per input.
+    fn predict_batch(&self, inputs: &[ModelInput]) -> Vec<f64> {
+        return inputs.iter().map(|inp| self.predict_to_float(*inp)).collect();
+    }
+
     fn input_type(&self) -> ModelDataType;
     fn output_type(&self) -> ModelDataType;
 
@@ -536,6 +1194,43 @@ pub trait Model: Sync + Send {
     fn code(&self) -> String;
     fn function_name(&self) -> String;
 
+    // code() in C89 (see codegen::Dialect), computing exactly the same
+    // thing, or None if the model cannot be emitted as C89
+    fn c89_code(&self) -> Option<String> {
+        return None;
+    }
+
+    // code() as a Rust function that only needs core (see
+    // codegen::output_rust_no_std), taking every parameter and the key as a
+    // u64 and returning a u64, or None if the model cannot be emitted that
+    // way. Floats are not supported, since core has no fma to match the
+    // rounding of the trained models.
+    fn rust_code(&self) -> Option<String> {
+        return None;
+    }
+
+    // code() on four keys at once with AVX2 and FMA intrinsics, as
+    // {function_name}_avx2: the same parameters, then the keys as an
+    // __m256i, returning an __m256d (float output) or __m256i (integer
+    // output) of the four predictions. Each must be exactly what code()
+    // returns for that key. None if the model has no vectorized code, as
+    // for all but the simplest top models (see lookup_batch in codegen).
+    fn avx2_code(&self) -> Option<String> {
+        return None;
+    }
+
+    // (intercept, slope) if the model is a line over the key, which
+    // codegen can evaluate in fixed point (see FixedPointLine)
+    fn as_line(&self) -> Option<(f64, f64)> {
+        return None;
+    }
+
+    // true if code() only uses integer arithmetic, so fixed-point code can
+    // use it unchanged
+    fn integer_only(&self) -> bool {
+        return false;
+    }
+
     fn standard_functions(&self) -> HashSet<StdFunctions> {
         return HashSet::new();
     }
@@ -553,6 +1248,95 @@ pub trait Model: Sync + Send {
     fn set_to_constant_model(&mut self, _constant: u64) -> bool {
         return false;
     }
+
+    // true if the model predicts the same value for every key, e.g. because
+    // it was trained on too little data or replaced with a constant
+    fn is_constant(&self) -> bool {
+        return false;
+    }
+
+    // true if the prediction never decreases as the key grows: for any
+    // keys a <= b, predict_to_float(a) <= predict_to_float(b), and so
+    // predict_to_int(a) <= predict_to_int(b), in Rust and in the generated
+    // code. This must hold for every key, not only the trained ones, since
+    // a layer's models also see keys routed to them from outside of their
+    // training range. Callers may skip checks based on it, so a model
+    // must only return true when it is guaranteed.
+    fn is_monotone(&self) -> bool {
+        return false;
+    }
+
+    // for models that choose their own type during training (see
+    // AdaptiveModel), the type this one chose
+    fn chosen_type(&self) -> Option<&'static str> {
+        return None;
+    }
+}
+
+// allows a single trained model to be shared between several RMIs,
+// e.g. a top model reused across optimizer configurations.
+impl Model for Arc<dyn Model> {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        return self.as_ref().predict_to_float(inp);
+    }
+
+    fn predict_to_int(&self, inp: ModelInput) -> u64 {
+        return self.as_ref().predict_to_int(inp);
+    }
+
+    fn predict_batch(&self, inputs: &[ModelInput]) -> Vec<f64> {
+        return self.as_ref().predict_batch(inputs);
+    }
+
+    fn input_type(&self) -> ModelDataType { return self.as_ref().input_type(); }
+    fn output_type(&self) -> ModelDataType { return self.as_ref().output_type(); }
+
+    fn params(&self) -> Vec<ModelParam> { return self.as_ref().params(); }
+
+    fn code(&self) -> String { return self.as_ref().code(); }
+    fn function_name(&self) -> String { return self.as_ref().function_name(); }
+    fn c89_code(&self) -> Option<String> { return self.as_ref().c89_code(); }
+    fn rust_code(&self) -> Option<String> { return self.as_ref().rust_code(); }
+    fn avx2_code(&self) -> Option<String> { return self.as_ref().avx2_code(); }
+    fn as_line(&self) -> Option<(f64, f64)> { return self.as_ref().as_line(); }
+    fn integer_only(&self) -> bool { return self.as_ref().integer_only(); }
+
+    fn standard_functions(&self) -> HashSet<StdFunctions> {
+        return self.as_ref().standard_functions();
+    }
+
+    fn needs_bounds_check(&self) -> bool { return self.as_ref().needs_bounds_check(); }
+    fn restriction(&self) -> ModelRestriction { return self.as_ref().restriction(); }
+    fn error_bound(&self) -> Option<u64> { return self.as_ref().error_bound(); }
+    fn is_constant(&self) -> bool { return self.as_ref().is_constant(); }
+    fn is_monotone(&self) -> bool { return self.as_ref().is_monotone(); }
+    fn chosen_type(&self) -> Option<&'static str> { return self.as_ref().chosen_type(); }
+}
+
+// the number of parameter values a model stores, counting every element
+// of its arrays
+pub fn param_count(model: &dyn Model) -> usize {
+    return model.params().iter().map(|p| p.len()).sum();
+}
+
+// the largest distance between a model's prediction for a key and the
+// key's position
+pub fn max_error(model: &dyn Model, data: &ModelDataWrapper) -> u64 {
+    return data.iter_input_int()
+        .map(|(x, y)| {
+            let pred = model.predict_to_int(x);
+            u64::max(pred, y) - u64::min(pred, y)
+        })
+        .max().unwrap_or(0);
+}
+
+// the index of the candidate with the lowest max error on data, the one
+// with the fewest parameter values (and then the first) among equals
+pub fn most_accurate(candidates: &[Box<dyn Model>], data: &ModelDataWrapper) -> usize {
+    return candidates.iter().enumerate()
+        .min_by_key(|(_, m)| (max_error(m.as_ref(), data), param_count(m.as_ref())))
+        .map(|(idx, _)| idx)
+        .expect("no candidate models");
 }
 
 #[cfg(test)]
@@ -563,13 +1347,234 @@ mod tests {
     fn test_scale() {
         let mut v = ModelData::IntKeyToIntPos(vec![(0, 0), (1, 1), (3, 2), (100, 3)]);
 
-        v.scale_targets_to(50, 4);
+        v.scale_targets_to_with(50, 4, Rounding::Down);
 
         let results = v.as_int_int();
         assert_eq!(results[0].1, 0);
         assert_eq!(results[1].1, 12);
         assert_eq!(results[2].1, 25);
         assert_eq!(results[3].1, 37);
+
+        let mut v = ModelData::IntKeyToIntPos(vec![(0, 0), (1, 1), (3, 2), (100, 3)]);
+        v.scale_targets_to(50, 4);
+        let results: Vec<u64> = v.as_int_int().iter().map(|p| p.1).collect();
+        assert_eq!(results, vec![0, 13, 25, 38]);
+    }
+
+    #[test]
+    fn test_scale_rounding() {
+        // 1000 positions onto 300: the exact scaled positions have every
+        // fractional part in tenths
+        let exact: Vec<f64> = (0..1000).map(|y| y as f64 * 0.3).collect();
+        let errors = |rounding: Rounding| {
+            let mut v = ModelData::IntKeyToIntPos((0..1000).map(|i| (i, i)).collect());
+            v.scale_targets_to_with(300, 1000, rounding);
+            let errs: Vec<f64> = v.as_int_int().iter().zip(exact.iter())
+                .map(|(p, e)| p.1 as f64 - e)
+                .collect();
+            let bias = errs.iter().sum::<f64>() / errs.len() as f64;
+            // the last position is clamped (see test_scale_clamped)
+            let max = errs[..999].iter().map(|e| e.abs()).fold(0.0, f64::max);
+            return (bias, max);
+        };
+
+        // truncation is half a position low on average, and up to almost a
+        // whole position off
+        let (down_bias, down_max) = errors(Rounding::Down);
+        assert!((down_bias + 0.45).abs() < 1e-9, "{}", down_bias);
+        assert!(down_max > 0.85);
+
+        let (nearest_bias, nearest_max) = errors(Rounding::Nearest);
+        assert!(nearest_bias.abs() < 0.1, "{}", nearest_bias);
+        assert!(nearest_max <= 0.5 + 1e-9);
+    }
+
+    #[test]
+    fn test_scale_clamped() {
+        // 999 * 0.3 = 299.7 rounds to 300, past the last target position
+        let mut v = ModelData::IntKeyToIntPos((0..1000).map(|i| (i, i)).collect());
+        v.scale_targets_to(300, 1000);
+        let results = v.as_int_int();
+        assert_eq!(results[998].1, 299);
+        assert_eq!(results[999].1, 299);
+
+        // a single target position takes everything
+        let mut v = ModelData::IntKeyToIntPos(vec![(0, 0), (1, 1), (3, 2), (100, 3)]);
+        v.scale_targets_to(1, 4);
+        assert!(v.as_int_int().iter().all(|p| p.1 == 0));
+
+        // rounding down never reaches the target
+        let mut v = ModelData::IntKeyToIntPos((0..1000).map(|i| (i, i)).collect());
+        v.scale_targets_to_with(300, 1000, Rounding::Down);
+        assert_eq!(v.as_int_int()[999].1, 299);
+    }
+
+    #[test]
+    fn test_offsets() {
+        let mut v = ModelData::IntKeyToIntPos(vec![(0, 0), (1, 1), (3, 2), (100, 3)]);
+        v.positions_to_offsets(16);
+        assert_eq!(v.as_int_int()[3].1, 48);
+
+        // as elements, the offsets are sparse positions, whose space ends
+        // after the last one
+        let mut md = ModelDataWrapper::new(&v);
+        assert_eq!(md.num_positions(), 49);
+        md.set_position_unit(PositionUnit::Offset(16));
+        assert_eq!(md.num_positions(), 64);
+
+        // scaling offsets gives the same result as scaling elements
+        let mut scaled = v.clone();
+        scaled.scale_targets_to_with(50, 64, Rounding::Down);
+        let results = scaled.as_int_int();
+        assert_eq!(results[1].1, 12);
+        assert_eq!(results[3].1, 37);
+
+        assert_eq!(PositionUnit::Offset(16).error_in_elements(33), 3);
+        assert_eq!(PositionUnit::Offset(16).error_in_elements(32), 2);
+        assert_eq!(PositionUnit::Element.error_in_elements(33), 33);
+        assert_eq!(PositionUnit::Offset(16).align_error(33), 48);
+        assert_eq!(PositionUnit::Offset(16).align_error(0), 0);
+
+        assert_eq!(PositionUnit::Element.lookup_position(7, 5), 4);
+        assert_eq!(PositionUnit::Element.lookup_position(3, 5), 3);
+        assert_eq!(PositionUnit::Offset(16).lookup_position(47, 64), 32);
+        assert_eq!(PositionUnit::Offset(16).lookup_position(100, 64), 48);
+        assert_eq!(PositionUnit::Offset(16).lookup_position(5, 0), 0);
+    }
+
+    #[test]
+    fn test_from_slice() {
+        let pairs: Vec<(u64, u64)> = (0..1000).map(|i| (i * i, i)).collect();
+        let borrowed = ModelData::from_slice(&pairs);
+        let owned = ModelData::IntKeyToIntPos(pairs.clone());
+
+        // the data is read in place
+        assert_eq!(borrowed.len(), 1000);
+        assert_eq!(borrowed.as_int_int().as_ptr(), pairs.as_ptr());
+        assert_eq!(ModelDataWrapper::new(&borrowed).as_int_int().as_ptr(), pairs.as_ptr());
+        assert_eq!(borrowed.get(10), (100.0, 10.0));
+        assert_eq!(borrowed.get_key(10), 100);
+        assert!(borrowed.iter_float_int().eq(owned.iter_float_int()));
+
+        // and trains the same RMI as a copy of it
+        let from_slice = crate::train::train(&mut ModelDataWrapper::new(&borrowed),
+                                             "linear,linear", 16).unwrap();
+        let from_vec = crate::train::train(&mut ModelDataWrapper::new(&owned),
+                                           "linear,linear", 16).unwrap();
+        assert!(from_slice.approx_eq(&from_vec, 0.0).is_ok());
+
+        // changing the data copies it, leaving the slice alone
+        let mut offsets = borrowed.clone();
+        offsets.positions_to_offsets(8);
+        assert_eq!(offsets.as_int_int()[3], (9, 24));
+        assert_eq!(pairs[3], (9, 3));
+        assert_eq!(borrowed.as_int_int().as_ptr(), pairs.as_ptr());
+    }
+
+    #[test]
+    #[should_panic(expected = "keys must be sorted")]
+    fn test_from_slice_unsorted() {
+        ModelData::from_slice(&[(5, 0), (3, 1)]);
+    }
+
+    #[test]
+    fn test_quantize() {
+        let keys: Vec<f64> = (0..1000).map(|i| (i as f64 * 0.37).sin() * 0.01
+                                            + i as f64 * 0.003).collect();
+        let mut sorted = keys.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let v = ModelData::FloatKeyToFloatPos(
+            sorted.into_iter().enumerate().map(|(i, k)| (k, i as f64 * 0.5)).collect()
+        );
+
+        let q = v.quantize_to_int(1000.0, 2.0);
+        let results = q.as_int_int();
+        assert_eq!(results.len(), 1000);
+        for (idx, pair) in results.windows(2).enumerate() {
+            assert!(pair[0].0 <= pair[1].0);
+            assert!(pair[0].1 <= pair[1].1);
+            assert_eq!(pair[1].1, idx as u64 + 1);
+        }
+
+        // negative keys saturate, fractions round down
+        let v = ModelData::FloatKeyToIntPos(vec![(-1.5, 0), (0.25, 1), (2.75, 2)]);
+        let q = v.quantize_to_int(2.0, 1.0);
+        assert_eq!(q.as_int_int(), &[(0, 0), (0, 1), (5, 2)]);
+    }
+
+    #[test]
+    fn test_composite_keys() {
+        let layout = CompositeKey::new(16, 48);
+        assert_eq!(layout.pack(3, 5), (3 << 48) | 5);
+
+        let regions = vec![0, 0, 1, 1, 1, 7];
+        let times = vec![10, 20, 5, 6, 1 << 40, 0];
+        let md = ModelData::from_composite_keys(&regions, &times, layout);
+        let packed = md.as_int_int();
+        assert_eq!(packed[1], (20, 1));
+        assert_eq!(packed[4], ((1 << 48) | (1 << 40), 4));
+        assert_eq!(packed[5], (7 << 48, 5));
+
+        assert_eq!(CompositeKey::c_type(32), "uint32_t");
+        assert_eq!(CompositeKey::c_type(33), "uint64_t");
+    }
+
+    #[test]
+    fn test_monotonic_u64() {
+        let keys = [f64::NEG_INFINITY, -1e300, -1.0, -f64::MIN_POSITIVE, -0.0, 0.0,
+                    f64::MIN_POSITIVE, 1.0, 1.5, 1e300, f64::INFINITY];
+        let mapped: Vec<u64> = keys.iter().map(|k| float_to_monotonic_u64(*k)).collect();
+        assert!(mapped.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(mapped[4], mapped[5]);
+        assert_eq!(mapped[5], 1 << 63);
+
+        for (k, m) in keys.iter().zip(mapped.iter()) {
+            assert_eq!(monotonic_u64_to_float(*m), *k);
+        }
+        assert!(monotonic_u64_to_float(mapped[4]).is_sign_positive());
+
+        let md = ModelData::from_float_keys(&[-2.5, -0.0, 0.0, 3.25]);
+        let data = md.as_int_int();
+        assert_eq!(data[1], (1 << 63, 1));
+        assert_eq!(data[2], (1 << 63, 2));
+        assert!(data[0].0 < data[1].0 && data[2].0 < data[3].0);
+    }
+
+    #[test]
+    fn test_non_finite_policy() {
+        assert_eq!(NonFinitePolicy::parse("sentinel:7"), Some(NonFinitePolicy::Sentinel(7)));
+        assert_eq!(NonFinitePolicy::parse("clamp"), Some(NonFinitePolicy::Clamp));
+        assert_eq!(NonFinitePolicy::parse("error"), Some(NonFinitePolicy::Error));
+        assert_eq!(NonFinitePolicy::parse("sentinel"), None);
+        assert_eq!(NonFinitePolicy::parse("sentinel:-1"), None);
+
+        let clamp = NonFinitePolicy::Clamp;
+        assert_eq!(clamp.clamped_position(f64::NEG_INFINITY, 100), Some(0));
+        assert_eq!(clamp.clamped_position(f64::INFINITY, 100), Some(99));
+        assert_eq!(clamp.clamped_position(f64::NAN, 100), None);
+        assert_eq!(clamp.clamped_position(1.0, 100), None);
+        assert_eq!(NonFinitePolicy::Error.clamped_position(f64::INFINITY, 100), None);
+    }
+
+    #[test]
+    fn test_string_to_monotonic_u64() {
+        assert_eq!(string_to_monotonic_u64(b""), 0);
+        assert_eq!(string_to_monotonic_u64(b"a"), 0x61 << 56);
+        assert_eq!(string_to_monotonic_u64(b"abcdefgh"), 0x6162636465666768);
+
+        let keys = ["", "a", "ab", "abcdefgh", "abcdefghij", "abcdefghz", "b", "zzzzzzzzzzzz"];
+        let mapped: Vec<u64> = keys.iter().map(|k| string_to_monotonic_u64(k.as_bytes()))
+            .collect();
+        assert!(mapped.windows(2).all(|w| w[0] <= w[1]));
+
+        // only the first 8 bytes count
+        assert_eq!(mapped[3], mapped[4]);
+        assert_eq!(mapped[4], mapped[5]);
+
+        let md = ModelData::from_string_keys(&keys);
+        let data = md.as_int_int();
+        assert_eq!(data[4], (mapped[4], 4));
+        assert_eq!(data.len(), keys.len());
     }
 
     #[test]
@@ -581,4 +1586,118 @@ mod tests {
         let iterated: Vec<(u64, u64)> = v.iter_int_int().collect();
         assert_eq!(data, iterated);
     }
+
+    #[test]
+    fn test_iter_mixed() {
+        let v = ModelData::FloatKeyToIntPos(vec![(0.5, 1), (1.5, 2), (3.25, 3)]);
+        let iterated: Vec<(f64, u64)> = v.iter_float_int().collect();
+        assert_eq!(iterated, vec![(0.5, 1), (1.5, 2), (3.25, 3)]);
+
+        let v = ModelData::IntKeyToFloatPos(vec![(7, 0.5), (9, 1.5)]);
+        let iterated: Vec<(u64, f64)> = v.iter_int_float().collect();
+        assert_eq!(iterated, vec![(7, 0.5), (9, 1.5)]);
+
+        // only the positions are scaled
+        let v = ModelData::IntKeyToIntPos(vec![(3, 1), (5, 2), (10, 3)]);
+        let mut md = ModelDataWrapper::new(&v);
+        md.set_scale(0.5);
+        let iterated: Vec<(f64, u64)> = md.iter_float_int().collect();
+        assert_eq!(iterated, vec![(3.0, 0), (5.0, 1), (10.0, 1)]);
+        let iterated: Vec<(u64, f64)> = md.iter_int_float().collect();
+        assert_eq!(iterated, vec![(3, 0.5), (5, 1.0), (10, 1.5)]);
+    }
+
+    #[test]
+    fn test_compact_distinct() {
+        let v = ModelData::IntKeyToIntPos(vec![(1, 0), (1, 1), (1, 2), (4, 3), (7, 4), (7, 5)]);
+        let compact = v.compact_distinct();
+        assert_eq!(compact.as_int_int(), &[(1, 0), (4, 3), (7, 4)]);
+
+        let v = ModelData::FloatKeyToFloatPos(vec![(0.5, 0.0), (0.5, 1.0), (2.5, 2.0)]);
+        let compact: Vec<(f64, f64)> = v.compact_distinct().iter_float_float().collect();
+        assert_eq!(compact, vec![(0.5, 0.0), (2.5, 2.0)]);
+
+        let mut container = ModelDataWrapper::new(&v);
+        assert_eq!(container.num_positions(), 3);
+        container.set_num_positions(10);
+        assert_eq!(container.num_positions(), 10);
+    }
+
+    #[test]
+    fn test_int_array_compact() {
+        let short = ModelParam::int_array_compact(vec![0, 5, 32767]);
+        assert!(short.is_same_type(&ModelParam::ShortArray(vec![])));
+        assert_eq!(short.c_type(), "short");
+        assert_eq!(short.size(), 6);
+
+        let int32 = ModelParam::int_array_compact(vec![0, 40000, 7]);
+        assert!(int32.is_same_type(&ModelParam::Int32Array(vec![])));
+        assert_eq!(int32.c_type(), "uint32_t");
+        assert_eq!(int32.size(), 12);
+
+        let int64 = ModelParam::int_array_compact(vec![1 << 40, 2]);
+        assert!(int64.is_same_type(&ModelParam::IntArray(vec![])));
+        assert_eq!(int64.c_type(), "uint64_t");
+
+        // the binary output must match the width the C type says
+        let mut buf: Vec<u8> = Vec::new();
+        int32.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), int32.size());
+        assert_eq!(int32.c_val(), "{ 0UL, 40000UL, 7UL }");
+
+        assert!(ModelParam::int_array_compact(vec![]).is_same_type(&ModelParam::ShortArray(vec![])));
+    }
+
+    #[test]
+    fn test_overflow() {
+        // a short is signed, so a ShortArray holds at most i16::MAX
+        let short = ModelParam::short_array(&[0, 32767]).unwrap();
+        assert_eq!(short.validate(), Ok(()));
+        assert_eq!(ModelParam::short_array(&[1, 2, 32768]).unwrap_err(),
+                   OverflowError { index: 2, value: 32768, c_type: "short" });
+        assert_eq!(ModelParam::short_array(&[70_000]).unwrap_err().value, 70_000);
+
+        let wrapped = ModelParam::ShortArray(vec![5, 32768, u16::MAX]);
+        let err = wrapped.validate().unwrap_err();
+        assert_eq!(err, OverflowError { index: 1, value: 32768, c_type: "short" });
+        assert_eq!(err.to_string(), "parameter element 1 (32768) does not fit in a short");
+        let mut buf: Vec<u8> = Vec::new();
+        assert_eq!(wrapped.write_to(&mut buf).unwrap_err().kind(),
+                   std::io::ErrorKind::InvalidData);
+        assert!(buf.is_empty());
+
+        let int32 = ModelParam::int32_array(&[7, u64::from(u32::MAX)]).unwrap();
+        assert_eq!(int32.validate(), Ok(()));
+        assert_eq!(ModelParam::int32_array(&[u64::from(u32::MAX) + 1]).unwrap_err(),
+                   OverflowError { index: 0, value: 1 << 32, c_type: "uint32_t" });
+
+        // the other variants' element types match their C types
+        assert_eq!(ModelParam::IntArray(vec![u64::MAX]).validate(), Ok(()));
+        assert_eq!(ModelParam::FloatArray(vec![f64::MAX]).validate(), Ok(()));
+        assert_eq!(ModelParam::Int(u64::MAX).validate(), Ok(()));
+        for values in &[vec![0, 32767], vec![32768], vec![1 << 32]] {
+            assert_eq!(ModelParam::int_array_compact(values.clone()).validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_c_val_round_trip() {
+        let values = vec![0.1, 1.0 / 3.0, -2.0 / 7.0, 125.0, -0.0, 1e-7, 1e16,
+                          123456789.12345678, 9007199254740993.0, f64::MIN_POSITIVE,
+                          5e-324, f64::MAX, std::f64::consts::PI];
+        for v in values.iter() {
+            let lit = ModelParam::Float(*v).c_val();
+            assert_eq!(lit.parse::<f64>().unwrap().to_bits(), v.to_bits(), "{}", lit);
+            assert!(lit.contains('.') || lit.contains('e'), "{} is not a double literal", lit);
+        }
+
+        let arr = ModelParam::FloatArray(values.clone()).c_val();
+        let parsed: Vec<u64> = arr.trim_start_matches("{ ").trim_end_matches(" }")
+            .split(", ")
+            .map(|lit| lit.parse::<f64>().unwrap().to_bits())
+            .collect();
+        assert_eq!(parsed, values.iter().map(|v| v.to_bits()).collect::<Vec<u64>>());
+
+        assert_eq!(ModelParam::Float(125.0).c_val(), "125.0");
+    }
 }
@@ -25,6 +25,20 @@ fn phi(x: f64) -> f64 {
     return 1.0 / (1.0 + exp1(-1.65451 * x));
 }
 
+// with no data or a single distinct key, the standard deviation is
+// undefined or zero. Use a unit deviation (and a zero scale when there is
+// no data) so that the parameters and predictions stay finite. Anything
+// else non-finite is a blow-up on the data, which is left for training to
+// report.
+fn fix_degenerate(params: (f64, f64, f64), data: &ModelDataWrapper) -> (f64, f64, f64) {
+    let (mean, stdev, scale) = params;
+    if data.len() == 0 {
+        return (0.0, 1.0, 0.0);
+    }
+    let stdev = if stdev == 0.0 { 1.0 } else { stdev };
+    return (mean, stdev, scale);
+}
+
 fn ncdf(loc_data: &ModelDataWrapper) -> (f64, f64, f64) {
     let mut scale = -f64::INFINITY;
     let mut mean = 0.0;
@@ -77,7 +91,7 @@ pub struct NormalModel {
 
 impl NormalModel {
     pub fn new(data: &ModelDataWrapper) -> NormalModel {
-        return NormalModel { params: ncdf(data) };
+        return NormalModel { params: fix_degenerate(ncdf(data), data) };
     }
 }
 
@@ -138,9 +152,36 @@ mod ncdf_tests {
 
     #[test]
     fn test_empty() {
-        NormalModel::new(&ModelData::empty());
+        let ncdf_mod = NormalModel::new(&ModelData::empty());
+        assert_eq!(first_non_finite(&ncdf_mod.params()), None);
     }
 
+    #[test]
+    fn test_single_key() {
+        let md = ModelData::IntKeyToIntPos(vec![(5, 0), (5, 1), (5, 2)]);
+        let ncdf_mod = NormalModel::new(&ModelDataWrapper::new(&md));
+        assert_eq!(first_non_finite(&ncdf_mod.params()), None);
+        assert!(ncdf_mod.predict_to_float(5.into()).is_finite());
+    }
+
+    #[test]
+    fn test_blow_up() {
+        // the squared distances from the mean overflow
+        let md = ModelData::FloatKeyToIntPos(vec![(-1e300, 0), (0.0, 1), (1e300, 2)]);
+        let ncdf_mod = NormalModel::new(&ModelDataWrapper::new(&md));
+        assert_eq!(first_non_finite(&ncdf_mod.params()), Some(f64::INFINITY));
+        match crate::train::train(&mut ModelDataWrapper::new(&md), "normal,linear", 4) {
+            Err(crate::train::TrainingError::NonFiniteParameter { layer, .. }) =>
+                assert_eq!(layer, 0),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected training to fail")
+        }
+
+        // an infinite position makes the scale infinite
+        let md = ModelData::FloatKeyToFloatPos(vec![(1.0, 0.0), (2.0, f64::INFINITY)]);
+        let ncdf_mod = NormalModel::new(&ModelDataWrapper::new(&md));
+        assert_eq!(first_non_finite(&ncdf_mod.params()), Some(f64::INFINITY));
+    }
 }
 
 pub struct LogNormalModel {
@@ -150,7 +191,7 @@ pub struct LogNormalModel {
 impl LogNormalModel {
     pub fn new(data: &ModelDataWrapper) -> LogNormalModel {
         return LogNormalModel {
-            params: lncdf(data),
+            params: fix_degenerate(lncdf(data), data),
         };
     }
 }
@@ -213,7 +254,23 @@ mod lncdf_tests {
 
     #[test]
     fn test_empty() {
-        LogNormalModel::new(&ModelData::empty());
+        let lncdf_mod = LogNormalModel::new(&ModelData::empty());
+        assert_eq!(first_non_finite(&lncdf_mod.params()), None);
     }
 
+    #[test]
+    fn test_blow_up() {
+        // the logs of the keys are bounded, but an infinite position makes
+        // the scale infinite
+        let md = ModelData::FloatKeyToFloatPos(vec![(1.0, 0.0), (2.0, 1.0),
+                                                    (3.0, f64::INFINITY)]);
+        let lncdf_mod = LogNormalModel::new(&ModelDataWrapper::new(&md));
+        assert_eq!(first_non_finite(&lncdf_mod.params()), Some(f64::INFINITY));
+        match crate::train::train(&mut ModelDataWrapper::new(&md), "lognormal,linear", 4) {
+            Err(crate::train::TrainingError::NonFiniteParameter { layer, .. }) =>
+                assert_eq!(layer, 0),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected training to fail")
+        }
+    }
 }
@@ -9,6 +9,7 @@
 use crate::models::utils::{common_prefix_size, num_bits};
 use crate::models::*;
 use log::*;
+use superslice::*;
 
 pub struct RadixModel {
     params: (u8, u8),
@@ -43,6 +44,10 @@ impl Model for RadixModel {
     fn predict_to_int(&self, inp: ModelInput) -> u64 {
         let (left_shift, num_bits) = self.params;
 
+        if num_bits == 0 {
+            return 0;
+        }
+
         let as_int: u64 = inp.as_int();
         let res = (as_int << left_shift) >> (64 - num_bits);
 
@@ -61,6 +66,17 @@ impl Model for RadixModel {
     }
 
     fn code(&self) -> String {
+        // with no bits, every key goes to the first model. This is known
+        // when the code is generated, so lookups do not check for it.
+        if self.params.1 == 0 {
+            return String::from(
+                "
+inline uint64_t radix(uint64_t, uint64_t, uint64_t) {
+    return 0;
+}",
+            );
+        }
+
         return String::from(
             "
 inline uint64_t radix(uint64_t prefix_length, uint64_t bits, uint64_t inp) {
@@ -72,12 +88,81 @@ inline uint64_t radix(uint64_t prefix_length, uint64_t bits, uint64_t inp) {
     fn function_name(&self) -> String {
         return String::from("radix");
     }
+
+    fn c89_code(&self) -> Option<String> {
+        if self.params.1 == 0 {
+            return Some(String::from(
+                "
+static rmi_u64 radix(rmi_u64 prefix_length, rmi_u64 bits, rmi_u64 inp) {
+    (void) prefix_length;
+    (void) bits;
+    (void) inp;
+    return 0;
+}",
+            ));
+        }
+
+        return Some(String::from(
+            "
+static rmi_u64 radix(rmi_u64 prefix_length, rmi_u64 bits, rmi_u64 inp) {
+    return (inp << prefix_length) >> (64 - bits);
+}",
+        ));
+    }
+
+    fn rust_code(&self) -> Option<String> {
+        if self.params.1 == 0 {
+            return Some(String::from(
+                "
+#[inline]
+fn radix(_prefix_length: u64, _bits: u64, _inp: u64) -> u64 {
+    0
+}",
+            ));
+        }
+
+        return Some(String::from(
+            "
+#[inline]
+fn radix(prefix_length: u64, bits: u64, inp: u64) -> u64 {
+    (inp << prefix_length) >> (64 - bits)
+}",
+        ));
+    }
+
+    fn avx2_code(&self) -> Option<String> {
+        if self.params.1 == 0 {
+            return Some(String::from(
+                "
+inline __m256i radix_avx2(uint64_t, uint64_t, __m256i) {
+    return _mm256_setzero_si256();
+}",
+            ));
+        }
+
+        return Some(String::from(
+            "
+inline __m256i radix_avx2(uint64_t prefix_length, uint64_t bits, __m256i inp) {
+    return _mm256_srl_epi64(_mm256_sll_epi64(inp, _mm_set_epi64x(0, prefix_length)),
+                            _mm_set_epi64x(0, 64 - bits));
+}",
+        ));
+    }
+    fn integer_only(&self) -> bool {
+        return true;
+    }
     fn needs_bounds_check(&self) -> bool {
         return false;
     }
     fn restriction(&self) -> ModelRestriction {
         return ModelRestriction::MustBeTop;
     }
+    fn is_monotone(&self) -> bool {
+        // shifting out the common prefix of the training keys wraps keys
+        // with a different prefix around
+        let (left_shift, num_bits) = self.params;
+        return left_shift == 0 || num_bits == 0;
+    }
 }
 
 pub struct RadixTable {
@@ -88,7 +173,9 @@ pub struct RadixTable {
 
 impl RadixTable {
     pub fn new(data: &ModelDataWrapper, bits: u8) -> RadixTable {
-        let prefix = common_prefix_size(data);
+        // with very few keys, the common prefix can leave fewer than bits
+        // bits, so strip less of it
+        let prefix = u8::min(common_prefix_size(data), 64 - bits);
         let mut hint_table: Vec<u32> = vec![0 ; 1 << bits];
 
         let mut last_radix = 0;
@@ -161,6 +248,260 @@ inline uint64_t radix_table(const uint32_t* table, const uint64_t inp) {{
     fn restriction(&self) -> ModelRestriction {
         return ModelRestriction::None;
     }
+    fn is_monotone(&self) -> bool {
+        // as for RadixModel, masking out a common prefix wraps other keys
+        return self.prefix_bits == 0 && self.hint_table.windows(2).all(|w| w[0] <= w[1]);
+    }
+}
+
+// Like RadixTable, but instead of using a fixed number of radix bits over
+// the whole key range, stores 2^bits explicit bucket boundaries chosen so
+// that each bucket holds the same number of keys. A small radix table over
+// the boundaries narrows the search for the right bucket.
+//
+// Layout of the (single) parameter array:
+//   [prefix, guide (2^bits + 1 entries), boundaries (n), positions (n)]
+// Boundaries are the 32 most significant bits of each key after the common
+// prefix is removed.
+pub struct EqualizedRadixTable {
+    prefix_bits: u8,
+    guide_bits: u8,
+    guide: Vec<u32>,
+    boundaries: Vec<u32>,
+    positions: Vec<u32>
+}
+
+impl EqualizedRadixTable {
+    pub fn new(data: &ModelDataWrapper, bits: u8) -> EqualizedRadixTable {
+        // we only keep 32 bits of each key, so never strip more than that
+        let prefix = u8::min(common_prefix_size(data), 32);
+        let num_buckets = usize::min(1 << bits, data.len());
+
+        let mut boundaries = Vec::with_capacity(num_buckets);
+        let mut positions = Vec::with_capacity(num_buckets);
+        for bucket in 0..num_buckets {
+            let idx = bucket * data.len() / num_buckets;
+            let x = data.get_key(idx);
+            let y = data.get(idx).1 as u64;
+            boundaries.push(((x << prefix) >> 32) as u32);
+            positions.push(y as u32);
+        }
+
+        let mut guide: Vec<u32> = Vec::with_capacity((1 << bits) + 1);
+        for radix in 0..(1 << bits) as u64 {
+            let first_key = (radix << (32 - bits)) as u32;
+            guide.push(boundaries.lower_bound(&first_key) as u32);
+        }
+        guide.push(boundaries.len() as u32);
+
+        trace!("Equalized radix table using {} buckets with prefix {}",
+               num_buckets, prefix);
+
+        return EqualizedRadixTable {
+            prefix_bits: prefix,
+            guide_bits: bits,
+            guide, boundaries, positions
+        };
+    }
+}
+
+impl Model for EqualizedRadixTable {
+    fn predict_to_int(&self, inp: ModelInput) -> u64 {
+        if self.boundaries.is_empty() {
+            return 0;
+        }
+
+        let key = ((inp.as_int() << self.prefix_bits) >> 32) as u32;
+        let radix = (key >> (32 - self.guide_bits)) as usize;
+        let lb = self.guide[radix] as usize;
+        let ub = self.guide[radix + 1] as usize;
+        let bucket = lb + self.boundaries[lb..ub].upper_bound(&key);
+
+        return self.positions[usize::max(bucket, 1) - 1] as u64;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut table = vec![self.prefix_bits as u32];
+        table.extend_from_slice(&self.guide);
+        table.extend_from_slice(&self.boundaries);
+        table.extend_from_slice(&self.positions);
+        return vec![table.into()];
+    }
+
+    fn code(&self) -> String {
+        return format!(
+            "
+inline uint64_t radix_eq(const uint32_t* table, const uint64_t inp) {{
+    const uint32_t* guide = table + 1;
+    const uint32_t* boundaries = guide + {guide_size};
+    const uint32_t* positions = boundaries + {num_buckets};
+    if ({num_buckets} == 0) return 0;
+
+    uint32_t key = (uint32_t)((inp << table[0]) >> 32);
+    uint32_t radix = key >> (32 - {bits});
+    uint32_t l = guide[radix];
+    uint32_t h = guide[radix + 1];
+    while (l < h) {{
+        uint32_t mid = l + (h - l) / 2;
+        if (key >= boundaries[mid]) {{
+            l = mid + 1;
+        }} else {{
+            h = mid;
+        }}
+    }}
+    return positions[(l == 0 ? 0 : l - 1)];
+}}",
+            guide_size = self.guide.len(),
+            num_buckets = self.boundaries.len(),
+            bits = self.guide_bits
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("radix_eq");
+    }
+    fn needs_bounds_check(&self) -> bool {
+        return false;
+    }
+}
+
+// A radix table of radix tables, for sparse keys spread over a large range.
+// The high bits of a key (after the common prefix) select a first level
+// bucket. Within each occupied bucket, the keys' remaining bits are taken
+// relative to the bucket's smallest key and shifted so that the bucket's
+// range fits in low_bits bits, which index a second level table of
+// positions. Empty buckets get a single entry, so the space used follows
+// the occupied parts of the key range rather than the whole range.
+//
+// Both levels are stored in one parameter array (codegen emits a single
+// array parameter per model):
+//   [prefix, first level (2^high_bits + 1 entries of 4), second level]
+// where each first level entry is [offset of the bucket's second level
+// table, shift, high 32 bits of the bucket's min, low 32 bits of the min].
+// The last entry only marks the end of the second level.
+//
+// Like RadixTable, predictions are the position of the first key at or
+// after the start of the key's second level slot, and are monotonic.
+pub struct TwoLevelRadixModel {
+    prefix_bits: u8,
+    high_bits: u8,
+    first: Vec<u32>,
+    second: Vec<u32>
+}
+
+impl TwoLevelRadixModel {
+    pub fn new(data: &ModelDataWrapper, high_bits: u8, low_bits: u8) -> TwoLevelRadixModel {
+        assert!((1..=32).contains(&high_bits) && low_bits <= 32);
+        let prefix = u8::min(common_prefix_size(data), 64 - high_bits);
+        let keys: Vec<(u64, u32)> = data.iter_int_int()
+            .map(|(x, y)| (x << prefix, y as u32))
+            .collect();
+        let rest = |k: u64| (k << high_bits) >> high_bits;
+        let last_pos = keys.last().map(|(_, y)| *y).unwrap_or(0);
+
+        let mut first = Vec::with_capacity(4 * ((1 << high_bits) + 1));
+        let mut second = Vec::new();
+        let mut start = 0;
+        for bucket in 0..(1u64 << high_bits) {
+            let end = start + keys[start..].iter()
+                .take_while(|(k, _)| k >> (64 - high_bits) == bucket)
+                .count();
+            let next_pos = keys.get(end).map(|(_, y)| *y).unwrap_or(last_pos);
+
+            if start == end {
+                first.extend_from_slice(&[second.len() as u32, 0, 0, 0]);
+                second.push(next_pos);
+                continue;
+            }
+
+            let min = rest(keys[start].0);
+            let range = rest(keys[end - 1].0) - min;
+            let shift = (64 - range.leading_zeros() as u8).saturating_sub(low_bits);
+            first.extend_from_slice(&[second.len() as u32, shift as u32,
+                                      (min >> 32) as u32, min as u32]);
+
+            let mut idx = start;
+            for slot in 0..=(range >> shift) {
+                while rest(keys[idx].0) - min < slot << shift {
+                    idx += 1;
+                }
+                second.push(keys[idx].1);
+            }
+            second.push(next_pos);
+            start = end;
+        }
+        first.extend_from_slice(&[second.len() as u32, 0, 0, 0]);
+
+        trace!("Two level radix table with prefix {} and {} second level entries",
+               prefix, second.len());
+
+        return TwoLevelRadixModel { prefix_bits: prefix, high_bits, first, second };
+    }
+}
+
+impl Model for TwoLevelRadixModel {
+    fn predict_to_int(&self, inp: ModelInput) -> u64 {
+        let key = inp.as_int() << self.prefix_bits;
+        let bucket = (key >> (64 - self.high_bits)) as usize;
+        let rest = (key << self.high_bits) >> self.high_bits;
+
+        let entry = &self.first[4 * bucket..4 * bucket + 5];
+        let min = ((entry[2] as u64) << 32) | entry[3] as u64;
+        let last = (entry[4] - entry[0] - 1) as u64;
+        let slot = if rest < min { 0 } else { (rest - min) >> entry[1] };
+
+        return self.second[(entry[0] as u64 + u64::min(slot, last)) as usize] as u64;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut table = vec![self.prefix_bits as u32];
+        table.extend_from_slice(&self.first);
+        table.extend_from_slice(&self.second);
+        return vec![table.into()];
+    }
+
+    fn code(&self) -> String {
+        return format!(
+            "
+inline uint64_t radix2l(const uint32_t* table, const uint64_t inp) {{
+    const uint32_t* first = table + 1;
+    const uint32_t* second = first + {first_size};
+    uint64_t key = inp << table[0];
+    uint64_t rest = (key << {high_bits}) >> {high_bits};
+    const uint32_t* entry = first + 4 * (key >> (64 - {high_bits}));
+
+    uint64_t min = ((uint64_t) entry[2] << 32) | entry[3];
+    uint64_t last = entry[4] - entry[0] - 1;
+    uint64_t slot = (rest < min ? 0 : (rest - min) >> entry[1]);
+    return second[entry[0] + (slot < last ? slot : last)];
+}}",
+            first_size = self.first.len(),
+            high_bits = self.high_bits
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("radix2l");
+    }
+    fn restriction(&self) -> ModelRestriction {
+        // the table size depends on the data, so each layer can only hold
+        // one of them
+        return ModelRestriction::MustBeTop;
+    }
 }
 
 #[cfg(test)]
@@ -172,4 +513,122 @@ mod tests {
         RadixModel::new(&ModelData::empty());
     }
 
+    #[test]
+    fn test_single() {
+        let md = ModelData::IntKeyToIntPos(vec![(5, 0)]);
+        let container = ModelDataWrapper::new(&md);
+        assert_eq!(RadixModel::new(&container).predict_to_int(5.into()), 0);
+        assert_eq!(RadixTable::new(&container, 18).predict_to_int(5.into()), 0);
+
+        // the generated code returns 0 without checking the bits
+        let model = RadixModel::new(&container);
+        for code in &[model.code(), model.c89_code().unwrap(), model.avx2_code().unwrap()] {
+            assert!(!code.contains("bits == 0") && !code.contains("64 - bits"), "{}", code);
+        }
+        assert!(!model.rust_code().unwrap().contains("64 - bits"));
+
+        let md = ModelData::IntKeyToIntPos((0..100).map(|i| (i * 7, i)).collect());
+        let model = RadixModel::new(&ModelDataWrapper::new(&md));
+        assert!(model.code().contains("(inp << prefix_length) >> (64 - bits)"));
+        assert!(!model.code().contains("bits == 0"));
+    }
+
+    #[test]
+    fn test_radix_monotone() {
+        // keys below 2^32 share a prefix, and a key above it with the same
+        // low bits lands in the same slot as the small key
+        let md = ModelData::IntKeyToIntPos((0..1000).map(|i| (i << 20, i)).collect());
+        let container = ModelDataWrapper::new(&md);
+        let model = RadixModel::new(&container);
+        assert!(!model.is_monotone());
+        assert!(model.predict_to_int(((1 << 40) + (999 << 20)).into())
+                > model.predict_to_int((1u64 << 41).into()));
+        assert!(!RadixTable::new(&container, 8).is_monotone());
+
+        // without a common prefix, the top bits are monotone
+        let md = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * (u64::MAX / 999), i)).collect());
+        let container = ModelDataWrapper::new(&md);
+        assert!(RadixModel::new(&container).is_monotone());
+        assert!(RadixTable::new(&container, 8).is_monotone());
+    }
+
+    #[test]
+    fn test_equalized_empty() {
+        let md = ModelData::empty();
+        let model = EqualizedRadixTable::new(&ModelDataWrapper::new(&md), 4);
+        assert_eq!(model.predict_to_int(5.into()), 0);
+    }
+
+    #[test]
+    fn test_equalized_skewed() {
+        // most keys are packed into a tiny part of the key space
+        let mut keys: Vec<u64> = (0..1000).map(|i| 1_000_000 + i).collect();
+        keys.extend((1..25).map(|i| i * 100_000_000));
+        let md = ModelData::IntKeyToIntPos(
+            keys.into_iter().enumerate().map(|(idx, k)| (k, idx as u64)).collect()
+        );
+        let container = ModelDataWrapper::new(&md);
+        let model = EqualizedRadixTable::new(&container, 4);
+
+        // every prediction must be a lower bound within one bucket of the key
+        let bucket_size = (container.len() / 16) as u64 + 1;
+        for (x, y) in container.iter_int_int() {
+            let pred = model.predict_to_int(x.into());
+            assert!(pred <= y, "key {} predicted {} but was at {}", x, pred, y);
+            assert!(y - pred <= bucket_size, "key {} predicted {} but was at {}", x, pred, y);
+        }
+    }
+
+    #[test]
+    fn test_two_level_sparse() {
+        // 8 clusters of 512 keys, spaced 16 apart, spread over 2^24
+        let keys: Vec<u64> = (0..8u64)
+            .flat_map(|c| (0..512u64).map(move |i| (c << 21) + i * 16))
+            .collect();
+        let md = ModelData::IntKeyToIntPos(
+            keys.into_iter().enumerate().map(|(idx, k)| (k, idx as u64)).collect()
+        );
+        let container = ModelDataWrapper::new(&md);
+        let max_error = |model: &dyn Model| container.iter_int_int()
+            .map(|(x, y)| {
+                let pred = model.predict_to_int(x.into());
+                assert!(pred <= y, "key {} predicted {} but was at {}", x, pred, y);
+                y - pred
+            })
+            .max().unwrap();
+        let size = |model: &dyn Model| model.params().iter().map(|p| p.size()).sum::<usize>();
+
+        // each cluster fills one bucket, split into 32 slots of 16 keys
+        let model = TwoLevelRadixModel::new(&container, 3, 5);
+        let error = max_error(&model);
+        assert_eq!(error, 15);
+
+        let (bits, table) = (1..=24)
+            .map(|bits| (bits, RadixTable::new(&container, bits)))
+            .find(|(_, table)| max_error(table) <= error)
+            .unwrap();
+        assert_eq!(bits, 16);
+        assert!(size(&table) > 100 * size(&model));
+
+        // monotonic between keys, too
+        let mut last = 0;
+        for x in (0..(1 << 24)).step_by(7) {
+            let pred = model.predict_to_int(x.into());
+            assert!(pred >= last);
+            last = pred;
+        }
+    }
+
+    #[test]
+    fn test_two_level_small() {
+        let md = ModelData::empty();
+        let model = TwoLevelRadixModel::new(&ModelDataWrapper::new(&md), 4, 4);
+        assert_eq!(model.predict_to_int(5.into()), 0);
+
+        let md = ModelData::IntKeyToIntPos(vec![(5, 0), (5, 1), (u64::MAX, 2)]);
+        let container = ModelDataWrapper::new(&md);
+        let model = TwoLevelRadixModel::new(&container, 4, 4);
+        assert_eq!(model.predict_to_int(5.into()), 0);
+        assert_eq!(model.predict_to_int(u64::MAX.into()), 2);
+    }
 }
@@ -0,0 +1,168 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+use crate::models::utils::{locate_sample, sample_cdf};
+
+// A two stage model for heavily skewed keys. The first stage maps a key to
+// an approximate rank: it samples k keys like a sampled CDF does, but
+// interpolates between the indexes of the two samples around the key rather
+// than their positions. The second stage is a line from that approximate
+// rank to the position. Since the rank no longer depends on how
+// the keys are spread, the line fits data that no model on the raw keys can.
+//
+// The parameters are stored as one array: the k sampled keys, then the
+// intercept and slope of the line.
+pub struct RankTransformModel {
+    keys: Vec<f64>,
+    intercept: f64,
+    slope: f64,
+    max_error: u64
+}
+
+impl RankTransformModel {
+    pub fn new(data: &ModelDataWrapper, k: usize) -> RankTransformModel {
+        assert!(k >= 2, "a rank transform needs at least 2 samples");
+        if data.len() == 0 {
+            return RankTransformModel { keys: vec![0.0], intercept: 0.0, slope: 0.0,
+                                        max_error: 0 };
+        }
+
+        let keys = sample_cdf(data, k).into_iter().map(|(key, _)| key).collect();
+        let mut model = RankTransformModel { keys, intercept: 0.0, slope: 0.0, max_error: 0 };
+
+        let ranked = ModelData::FloatKeyToFloatPos(
+            data.iter_float_float().map(|(x, y)| (model.approximate_rank(x), y)).collect()
+        );
+        let (intercept, slope) = LinearModel::new(&ModelDataWrapper::new(&ranked))
+            .as_line().unwrap();
+        model.intercept = intercept;
+        model.slope = slope;
+
+        model.max_error = data.iter_int_int()
+            .map(|(x, y)| {
+                let pred = model.predict_to_int(x.into());
+                u64::max(pred, y) - u64::min(pred, y)
+            })
+            .max().unwrap_or(0);
+
+        return model;
+    }
+
+    // the index of the samples around the key, interpolated between them,
+    // in [0, k - 1]
+    fn approximate_rank(&self, x: f64) -> f64 {
+        let (lo, frac) = locate_sample(&self.keys, x);
+        return lo as f64 + frac;
+    }
+}
+
+impl Model for RankTransformModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        return self.slope.mul_add(self.approximate_rank(inp.as_float()), self.intercept);
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut values = self.keys.clone();
+        values.push(self.intercept);
+        values.push(self.slope);
+        return vec![values.into()];
+    }
+
+    fn code(&self) -> String {
+        return format!(
+            "
+inline double rank_transform(const double* params, double inp) {{
+    const size_t k = {k};
+    double frac;
+    size_t lo = locate_sample(params, k, inp, &frac);
+    return std::fma(params[k + 1], (double)lo + frac, params[k]);
+}}",
+            k = self.keys.len()
+        );
+    }
+
+    fn standard_functions(&self) -> HashSet<StdFunctions> {
+        let mut to_r = HashSet::new();
+        to_r.insert(StdFunctions::LocateSample);
+        return to_r;
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("rank_transform");
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
+    }
+
+    fn error_bound(&self) -> Option<u64> {
+        return Some(self.max_error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rank_transform_power_law() {
+        // Pareto distributed keys: each is 1000 * (n / (n - i))^2, so a
+        // quarter of the keys are below 1800 and the largest is 4 * 10^11
+        let n = 20_000u64;
+        let mut keys: Vec<u64> = (0..n)
+            .map(|i| (1000.0 * (n as f64 / (n - i) as f64).powi(2)) as u64)
+            .collect();
+        keys.dedup();
+        let md = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                           .map(|(i, k)| (*k, i as u64)).collect());
+        let container = ModelDataWrapper::new(&md);
+
+        let model = RankTransformModel::new(&container, 256);
+        assert_eq!(model.keys.len(), 256);
+        assert_eq!(model.params()[0].len(), 258);
+
+        let bound = model.error_bound().unwrap();
+        for (x, y) in container.iter_int_int() {
+            let pred = model.predict_to_int(x.into());
+            assert!(u64::max(pred, y) - u64::min(pred, y) <= bound);
+        }
+
+        // a line on the raw keys is off by about half of the data, over a
+        // hundred times worse
+        let line = LinearModel::new(&container);
+        let line_error = container.iter_int_int()
+            .map(|(x, y)| {
+                let pred = line.predict_to_int(x.into());
+                u64::max(pred, y) - u64::min(pred, y)
+            })
+            .max().unwrap();
+        assert!(line_error > keys.len() as u64 / 3, "{}", line_error);
+        assert!(bound * 100 < line_error, "{} vs {}", bound, line_error);
+    }
+
+    #[test]
+    fn test_rank_transform_small() {
+        let model = RankTransformModel::new(&ModelDataWrapper::new(&ModelData::empty()), 8);
+        assert_eq!(model.predict_to_int(3.into()), 0);
+        assert_eq!(model.error_bound(), Some(0));
+
+        let md = ModelData::IntKeyToIntPos(vec![(5, 0), (5, 1), (5, 2), (9, 3)]);
+        let model = RankTransformModel::new(&ModelDataWrapper::new(&md), 4);
+        assert_eq!(model.keys, vec![5.0, 9.0]);
+        assert_eq!(model.approximate_rank(7.0), 0.5);
+        assert_eq!(model.approximate_rank(100.0), 1.0);
+    }
+}
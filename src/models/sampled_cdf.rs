@@ -0,0 +1,149 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+use crate::models::utils::{locate_sample, sample_cdf};
+
+// Stores k (key, position) samples at evenly spaced ranks of the data (the
+// first and last keys are always sampled). A lookup binary searches the
+// sampled keys and linearly interpolates between the positions of the two
+// samples around the key. Keys outside of the samples get the position of
+// the nearest one. More samples give a closer fit, for any distribution.
+//
+// The samples are stored as one array: the k keys, then their k positions.
+pub struct SampledCDFModel {
+    keys: Vec<f64>,
+    positions: Vec<f64>,
+    max_error: u64
+}
+
+impl SampledCDFModel {
+    pub fn new(data: &ModelDataWrapper, k: usize) -> SampledCDFModel {
+        assert!(k >= 2, "a sampled CDF needs at least 2 samples");
+        if data.len() == 0 {
+            return SampledCDFModel { keys: vec![0.0], positions: vec![0.0], max_error: 0 };
+        }
+
+        let (keys, positions) = sample_cdf(data, k).into_iter().unzip();
+        let mut model = SampledCDFModel { keys, positions, max_error: 0 };
+        model.max_error = data.iter_int_int()
+            .map(|(x, y)| {
+                let pred = model.predict_to_int(x.into());
+                u64::max(pred, y) - u64::min(pred, y)
+            })
+            .max().unwrap_or(0);
+
+        return model;
+    }
+}
+
+impl Model for SampledCDFModel {
+    fn predict_to_float(&self, inp: ModelInput) -> f64 {
+        let (lo, frac) = locate_sample(&self.keys, inp.as_float());
+        if frac == 0.0 {
+            return self.positions[lo];
+        }
+
+        let (p0, p1) = (self.positions[lo], self.positions[lo + 1]);
+        return p0 + (p1 - p0) * frac;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut samples = self.keys.clone();
+        samples.extend_from_slice(&self.positions);
+        return vec![samples.into()];
+    }
+
+    fn code(&self) -> String {
+        return format!(
+            "
+inline double sampled_cdf(const double* samples, double inp) {{
+    const size_t k = {k};
+    const double* positions = samples + k;
+    double frac;
+    size_t lo = locate_sample(samples, k, inp, &frac);
+    if (frac == 0.0) return positions[lo];
+    return positions[lo] + (positions[lo + 1] - positions[lo]) * frac;
+}}",
+            k = self.keys.len()
+        );
+    }
+
+    fn standard_functions(&self) -> HashSet<StdFunctions> {
+        let mut to_r = HashSet::new();
+        to_r.insert(StdFunctions::LocateSample);
+        return to_r;
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("sampled_cdf");
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
+    }
+
+    fn error_bound(&self) -> Option<u64> {
+        return Some(self.max_error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampled_cdf() {
+        let md = ModelData::IntKeyToIntPos((0..5000).map(|i| (i * i * i, i)).collect());
+        let container = ModelDataWrapper::new(&md);
+
+        let mut last_bound = u64::MAX;
+        for k in &[2, 16, 128, 1024] {
+            let model = SampledCDFModel::new(&container, *k);
+            assert_eq!(model.keys.len(), *k);
+
+            // the samples themselves are exact
+            for (key, pos) in model.keys.iter().zip(model.positions.iter()) {
+                assert_eq!(model.predict_to_float((*key).into()), *pos);
+            }
+
+            let bound = model.error_bound().unwrap();
+            for (x, y) in container.iter_int_int() {
+                let pred = model.predict_to_int(x.into());
+                assert!(u64::max(pred, y) - u64::min(pred, y) <= bound);
+            }
+
+            // more samples fit at least as well
+            assert!(bound <= last_bound);
+            last_bound = bound;
+        }
+        assert!(last_bound < 5);
+    }
+
+    #[test]
+    fn test_sampled_cdf_small() {
+        let model = SampledCDFModel::new(&ModelDataWrapper::new(&ModelData::empty()), 8);
+        assert_eq!(model.predict_to_int(3.into()), 0);
+        assert_eq!(model.error_bound(), Some(0));
+
+        // duplicate keys keep their first sample
+        let md = ModelData::IntKeyToIntPos(vec![(5, 0), (5, 1), (5, 2), (9, 3)]);
+        let model = SampledCDFModel::new(&ModelDataWrapper::new(&md), 4);
+        assert_eq!(model.keys, vec![5.0, 9.0]);
+        assert_eq!(model.positions, vec![0.0, 3.0]);
+        assert_eq!(model.predict_to_int(100.into()), 3);
+        assert_eq!(model.predict_to_int(7.into()), 1);
+    }
+}
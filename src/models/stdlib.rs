@@ -9,18 +9,30 @@
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub enum StdFunctions {
     EXP1,
+    ExpClamped,
     PHI,
     BinarySearch,
+    LogBucket,
+    LocateSample,
+    DoubleFromBits,
 }
 
 impl StdFunctions {
     pub fn decl(&self) -> &'static str {
         match self {
             StdFunctions::EXP1 => "inline double exp1(double x);",
+            StdFunctions::ExpClamped => "inline double exp_clamped(double x);",
             StdFunctions::PHI => "inline double phi(double x);",
             StdFunctions::BinarySearch => {
                 "uint64_t bs_lower_bound(const uint64_t a[], uint64_t n, uint64_t x);"
             }
+            StdFunctions::LogBucket => {
+                "inline uint64_t log_bucket(double inp, double shift, double log_min, double scale, uint64_t num_buckets);"
+            }
+            StdFunctions::LocateSample => {
+                "inline size_t locate_sample(const double* keys, size_t k, double inp, double* frac);"
+            }
+            StdFunctions::DoubleFromBits => "inline double double_from_bits(uint64_t bits);",
         }
     }
 
@@ -34,6 +46,13 @@ inline double exp1(double x) {
   x *= x; x *= x;
   return x;
 }
+"
+            }
+            StdFunctions::ExpClamped => {
+                "
+inline double exp_clamped(double x) {
+  return (x < -708.0 ? 0.0 : std::exp(x));
+}
 "
             }
             StdFunctions::PHI => {
@@ -59,6 +78,43 @@ uint64_t bs_upper_bound(const uint64_t a[], uint64_t n, uint64_t x) {
     return l;
 }
 
+"
+            }
+            StdFunctions::LogBucket => {
+                "
+inline uint64_t log_bucket(double inp, double shift, double log_min, double scale, uint64_t num_buckets) {
+  double bucket = (std::log(inp + shift) - log_min) * scale;
+  if (!(bucket > 0.0)) return 0;
+  return (bucket >= num_buckets - 1 ? num_buckets - 1 : (uint64_t) bucket);
+}
+"
+            }
+            StdFunctions::LocateSample => {
+                "
+inline size_t locate_sample(const double* keys, size_t k, double inp, double* frac) {
+  *frac = 0.0;
+  if (inp <= keys[0]) return 0;
+  if (inp >= keys[k - 1]) return k - 1;
+
+  // keys[lo] <= inp < keys[hi]
+  size_t lo = 0, hi = k - 1;
+  while (hi - lo > 1) {
+    size_t mid = (lo + hi) / 2;
+    if (keys[mid] <= inp) lo = mid;
+    else hi = mid;
+  }
+  *frac = (inp - keys[lo]) / (keys[hi] - keys[lo]);
+  return lo;
+}
+"
+            }
+            StdFunctions::DoubleFromBits => {
+                "
+inline double double_from_bits(uint64_t bits) {
+  double d;
+  std::memcpy(&d, &bits, sizeof(d));
+  return d;
+}
 "
             }
         }
@@ -11,7 +11,17 @@ use superslice::*;
 use log::*;
 
 
+// the number of radix bits to use so that no radix exceeds largest_target.
+// Returns 0 (a constant radix) if the only target is 0.
 pub fn num_bits(largest_target: u64) -> u8 {
+    if largest_target == 0 {
+        return 0;
+    }
+
+    if largest_target < 3 {
+        return 1;
+    }
+
     let mut nbits = 0;
     while (1 << (nbits+1)) - 1 <= largest_target {
         nbits += 1;
@@ -104,6 +114,56 @@ pub fn radix_index(points: &[u64], num_bits: u8) -> Vec<u64> {
 }
 
 
+// returns the first NaN or infinite value among the given parameters, if any
+pub fn first_non_finite(params: &[ModelParam]) -> Option<f64> {
+    for param in params {
+        match param {
+            ModelParam::Float(v) if !v.is_finite() => return Some(*v),
+            ModelParam::FloatArray(arr) => {
+                if let Some(v) = arr.iter().find(|v| !v.is_finite()) {
+                    return Some(*v);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    return None;
+}
+
+// k (key, position) samples at evenly spaced ranks of the data, including
+// the first and last keys. A duplicate key keeps its first sample, so that
+// interpolating between the samples never divides by zero.
+pub fn sample_cdf(data: &ModelDataWrapper, k: usize) -> Vec<(f64, f64)> {
+    let mut samples: Vec<(f64, f64)> = Vec::with_capacity(k);
+    for i in 0..k {
+        let rank = ((i * (data.len() - 1)) as f64 / (k - 1) as f64).round() as usize;
+        let (key, pos) = data.get(rank);
+        if samples.last().map(|(last, _)| *last < key).unwrap_or(true) {
+            samples.push((key, pos));
+        }
+    }
+
+    return samples;
+}
+
+// the index of the last sampled key at or below x, and how far x is from it
+// to the next key, in [0, 1). Keys outside of the samples get the nearest
+// one, at 0.
+pub fn locate_sample(keys: &[f64], x: f64) -> (usize, f64) {
+    let last = keys.len() - 1;
+    if x <= keys[0] {
+        return (0, 0.0);
+    }
+    if x >= keys[last] {
+        return (last, 0.0);
+    }
+
+    // keys[lo] <= x < keys[lo + 1]
+    let lo = keys.partition_point(|k| *k <= x) - 1;
+    return (lo, (x - keys[lo]) / (keys[lo + 1] - keys[lo]));
+}
+
 macro_rules! plr_with {
     ($plr: ty, $delta: ident, $data: ident) => {{
         let mut plr = <$plr>::new($delta);
@@ -177,4 +237,17 @@ mod tests {
 
         assert_eq!(common_prefix_size(&data), 64-4);
     }
+
+    #[test]
+    fn test_first_non_finite() {
+        let ok = vec![ModelParam::Int(5), ModelParam::Float(1.5),
+                      ModelParam::FloatArray(vec![0.0, -2.0])];
+        assert_eq!(first_non_finite(&ok), None);
+
+        let nan = vec![ModelParam::Float(1.0), ModelParam::FloatArray(vec![0.0, f64::NAN])];
+        assert!(first_non_finite(&nan).unwrap().is_nan());
+
+        let inf = vec![ModelParam::Float(f64::NEG_INFINITY)];
+        assert_eq!(first_non_finite(&inf), Some(f64::NEG_INFINITY));
+    }
 }
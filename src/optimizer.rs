@@ -1,15 +1,22 @@
 use crate::models::*;
 use crate::train;
 use crate::codegen;
+use crate::runtime::RuntimeRMI;
 use log::*;
 use json::*;
 use indicatif::{ProgressBar};
 use rayon::prelude::*;
-use std::collections::BTreeSet;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
 use tabular::{Table, row};
 
-const TOP_ONLY_LAYERS: &[&str] = &["radix", "radix18", "radix22", "robust_linear"];
+const TOP_ONLY_LAYERS: &[&str] = &["radix", "radix18", "radix22", "radix_eq", "radix2l",
+                                   "robust_linear", "sampled_cdf", "gapped_linear"];
 const ANYWHERE_LAYERS: &[&str] = &["linear", "cubic", "linear_spline"];
+const BOTTOM_ONLY_LAYERS: &[&str] = &["plr_q"];
 //const SPECIALTY_TOP_LAYERS: &[&str] = &["histogram", "loglinear", "normal", "lognormal", "bradix"];
 
 fn get_branching_factors() -> Vec<u64> {
@@ -20,11 +27,84 @@ fn get_branching_factors() -> Vec<u64> {
     return branching_factors;
 }
 
-fn pareto_front(results: &[RMIStatistics]) -> Vec<RMIStatistics> {
+// the leaf error suggest_branching_factor aims for: the last-mile search of
+// 8 keys of 8 bytes touches a single cache line, so more leaves only add size
+const TARGET_LEAF_ERROR: f64 = 8.0;
+
+// a starting point for the branching factor of a two layer RMI over
+// data_len keys whose top model has an average error of top_model_error:
+// the smallest branching factor the optimizer tries whose leaves are
+// estimated to be off by at most TARGET_LEAF_ERROR, as the top model's
+// error split over b leaves plus the noise of a random walk over n/b keys,
+// top_model_error / b + sqrt(data_len / b) / 2. On smooth keys the first
+// term shrinks faster, so fewer leaves may do.
+pub fn suggest_branching_factor(data_len: usize, top_model_error: f64) -> u64 {
+    let leaf_error = |b: u64| {
+        let b = b as f64;
+        top_model_error / b + (data_len as f64 / b).sqrt() / 2.0
+    };
+
+    let branching_factors = get_branching_factors();
+    return branching_factors.iter().copied()
+        .find(|b| leaf_error(*b) <= TARGET_LEAF_ERROR)
+        .unwrap_or(*branching_factors.last().unwrap());
+}
+
+// which error the optimizer trades off against size
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Objective {
+    // the average log2 error, for the best typical lookup time
+    AvgError,
+    // the maximum log2 error, for the best worst-case lookup time
+    MaxError,
+    // both: a config is only dominated by one that is no worse on size,
+    // average error, and maximum error. The front is larger, since a config
+    // with a better worst case survives at the same size and average error.
+    // Fronts are still sorted and narrowed by the average error.
+    Composite
+}
+
+impl Default for Objective {
+    fn default() -> Objective {
+        return Objective::AvgError;
+    }
+}
+
+// which phases of the search to run. The first phase measures every model
+// combination at a few branching factors; the second measures every
+// branching factor for the combinations on the first phase's Pareto front.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phases {
+    // only the first phase, for a quick, coarse front
+    FirstOnly,
+    Both
+}
+
+impl Default for Phases {
+    fn default() -> Phases {
+        return Phases::Both;
+    }
+}
+
+// the slowest acceptable lookup for auto_build, in nanoseconds per lookup
+// (as estimated by estimated_ns_per_lookup)
+#[derive(Clone, Copy, Debug)]
+pub struct LatencyBudget {
+    pub ns_per_lookup: f64
+}
+
+// orders configs that tie on size and error, so that the selected configs
+// do not depend on the order they were measured in
+fn config_order(a: &RMIStatistics, b: &RMIStatistics) -> Ordering {
+    return a.models.cmp(&b.models)
+        .then(a.branching_factor.cmp(&b.branching_factor));
+}
+
+fn pareto_front(results: &[RMIStatistics], objective: Objective) -> Vec<RMIStatistics> {
     let mut on_front: Vec<RMIStatistics> = Vec::new();
 
     for result in results.iter() {
-        if results.iter().any(|v| result.dominated_by(v)) {
+        if results.iter().any(|v| result.dominated_by(v, objective)) {
             // not on the front
             continue;
         }
@@ -35,29 +115,90 @@ fn pareto_front(results: &[RMIStatistics]) -> Vec<RMIStatistics> {
     return on_front;
 }
 
-fn narrow_front(results: &[RMIStatistics], desired_size: usize) -> Vec<RMIStatistics> {
+// the order fronts are reported in: by error, then by size
+fn front_order(a: &RMIStatistics, b: &RMIStatistics, objective: Objective) -> Ordering {
+    return a.error(objective).partial_cmp(&b.error(objective)).unwrap()
+        .then(a.size.cmp(&b.size))
+        .then_with(|| config_order(a, b));
+}
+
+// adds a measured config to a front (sorted by error, as select_front
+// returns it), for configs tried after the optimizer ran. Configs the
+// candidate dominates are removed. Returns false, leaving the front as it
+// was, if the candidate is dominated by a config on the front or is
+// already on it.
+pub fn insert_into_front(front: &mut Vec<RMIStatistics>, candidate: RMIStatistics,
+                         objective: Objective) -> bool {
+    if front.iter().any(|r| candidate.dominated_by(r, objective)
+                        || r.has_config(&candidate.models, candidate.branching_factor)) {
+        return false;
+    }
+
+    front.retain(|r| !r.dominated_by(&candidate, objective));
+    let idx = front.partition_point(|r| front_order(r, &candidate, objective) == Ordering::Less);
+    front.insert(idx, candidate);
+    return true;
+}
+
+// thins the front down to desired_size configs by repeatedly dropping one
+// of the two neighbors (by size) with the smallest gap between them. With
+// bias = 0, the gap is the ratio of their sizes, which spreads the kept
+// configs evenly over sizes. Raising bias (up to 1) blends in the relative
+// gap between their errors, which is largest among the accurate configs, so
+// more of those are kept. Configs in pinned (models and branching factor),
+// such as one already in production, are never dropped; the other slots are
+// filled as usual. If more configs are pinned than fit, all of them are
+// kept, along with the smallest config.
+fn narrow_front(results: &[RMIStatistics], desired_size: usize,
+                objective: Objective, bias: f64,
+                pinned: &[(String, u64)]) -> Vec<RMIStatistics> {
     assert!(desired_size >= 2);
+    assert!((0.0..=1.0).contains(&bias), "narrowing bias must be between 0 and 1");
     if results.len() <= desired_size {
         return results.to_vec();
     }
 
     let mut tmp = results.to_vec();
     tmp.sort_by(
-        |a, b| a.size.partial_cmp(&b.size).unwrap()
+        |a, b| a.size.cmp(&b.size).then_with(|| config_order(a, b))
     );
 
+    let is_pinned = |r: &RMIStatistics| pinned.iter().any(|(m, b)| r.has_config(m, *b));
     let best_mod = tmp.remove(0);
     while tmp.len() > desired_size - 1 {
-        // find the two items closest in size and remove less accuracte one.
+        // find the two closest items and remove less accuracte one.
+        let gap = |idx1: usize, idx2: usize| {
+            let size_gap = ((tmp[idx2].size as f64) / (tmp[idx1].size as f64)).log2();
+            if bias == 0.0 {
+                return size_gap;
+            }
+
+            // errors of zero (or equal infinite ones) have no finite ratio:
+            // equal errors have no gap, and any other pair the largest one
+            let (err1, err2) = (tmp[idx1].error(objective), tmp[idx2].error(objective));
+            let error_gap = if err1 == err2 {
+                0.0
+            } else {
+                let ratio = (err1 / err2).log2().abs();
+                if ratio.is_finite() { ratio } else { f64::INFINITY }
+            };
+            (1.0 - bias) * size_gap + bias * error_gap
+        };
         let smallest_gap =
             (0..tmp.len()-1).zip(1..tmp.len())
-            .map(|(idx1, idx2)| (idx1, idx2,
-                                 (tmp[idx2].size as f64) / (tmp[idx1].size as f64)))
-            .min_by(|(_, _, v1), (_, _, v2)| v1.partial_cmp(v2).unwrap()).unwrap();
+            .filter(|(idx1, idx2)| !is_pinned(&tmp[*idx1]) || !is_pinned(&tmp[*idx2]))
+            .map(|(idx1, idx2)| (idx1, idx2, gap(idx1, idx2)))
+            .min_by(|(_, _, v1), (_, _, v2)| v1.total_cmp(v2));
+        let smallest_gap = match smallest_gap {
+            Some(g) => g,
+            None => break // only pinned configs are left
+        };
 
-        let err1 = tmp[smallest_gap.0].average_log2_error;
-        let err2 = tmp[smallest_gap.1].average_log2_error;
-        if err1 > err2 {
+        let err1 = tmp[smallest_gap.0].error(objective);
+        let err2 = tmp[smallest_gap.1].error(objective);
+        if is_pinned(&tmp[smallest_gap.0]) {
+            tmp.remove(smallest_gap.1);
+        } else if err1 > err2 {
             tmp.remove(smallest_gap.0);
         } else {
             tmp.remove(smallest_gap.0);
@@ -73,12 +214,21 @@ fn narrow_front(results: &[RMIStatistics], desired_size: usize) -> Vec<RMIStatis
 
 fn first_phase_configs() -> Vec<(String, u64)> {
     let mut results = Vec::new();
-    let mut all_top_models = Vec::new();
-    all_top_models.extend_from_slice(TOP_ONLY_LAYERS);
-    all_top_models.extend_from_slice(ANYWHERE_LAYERS);
-    
-    for top_model in all_top_models {
-        for bottom_model in ANYWHERE_LAYERS {
+    let registered_anywhere = train::registered_names(ModelRestriction::None);
+    let mut all_top_models: Vec<String> = Vec::new();
+    all_top_models.extend(TOP_ONLY_LAYERS.iter().map(|m| m.to_string()));
+    all_top_models.extend(train::registered_names(ModelRestriction::MustBeTop));
+    all_top_models.extend(ANYWHERE_LAYERS.iter().map(|m| m.to_string()));
+    all_top_models.extend(registered_anywhere.iter().cloned());
+
+    let mut all_bottom_models: Vec<String> = Vec::new();
+    all_bottom_models.extend(ANYWHERE_LAYERS.iter().map(|m| m.to_string()));
+    all_bottom_models.extend(registered_anywhere);
+    all_bottom_models.extend(BOTTOM_ONLY_LAYERS.iter().map(|m| m.to_string()));
+    all_bottom_models.extend(train::registered_names(ModelRestriction::MustBeBottom));
+
+    for top_model in all_top_models.iter() {
+        for bottom_model in all_bottom_models.iter() {
             for branching_factor in get_branching_factors().iter().step_by(5) {
                 results.push((format!("{},{}", top_model, bottom_model), *branching_factor));
             }
@@ -88,9 +238,10 @@ fn first_phase_configs() -> Vec<(String, u64)> {
     return results;
 }
 
-fn second_phase_configs(first_phase: &[RMIStatistics]) -> Vec<(String, u64)> {
+fn second_phase_configs(first_phase: &[RMIStatistics],
+                        objective: Objective) -> Vec<(String, u64)> {
     let qualifying_model_configs = {
-        let on_front = pareto_front(first_phase);
+        let on_front = pareto_front(first_phase, objective);
         let mut qualifying = BTreeSet::new();
         for result in on_front {
             qualifying.insert(result.models.clone());
@@ -120,7 +271,11 @@ pub struct RMIStatistics {
     pub branching_factor: u64,
     pub average_log2_error: f64,
     pub max_log2_error: f64,
-    pub size: u64
+    pub size: u64,
+    pub error_percentiles: Option<train::ErrorPercentiles>,
+
+    // NaN for measurements read from a cache written before it was stored
+    pub exact_fraction: f64
 }
 
 impl RMIStatistics {
@@ -130,19 +285,35 @@ impl RMIStatistics {
             max_log2_error: rmi.model_max_log2_error,
             size: codegen::rmi_size(&rmi.rmi, true),
             models: rmi.models.clone(),
-            branching_factor: rmi.branching_factor
+            branching_factor: rmi.branching_factor,
+            error_percentiles: rmi.error_percentiles.clone(),
+            exact_fraction: rmi.exact_fraction
         };
     }
 
-    fn dominated_by(&self, other: &RMIStatistics) -> bool {
-        if self.size < other.size { return false; }
-        if self.average_log2_error < other.average_log2_error { return false; }
+    fn error(&self, objective: Objective) -> f64 {
+        return match objective {
+            Objective::AvgError | Objective::Composite => self.average_log2_error,
+            Objective::MaxError => self.max_log2_error
+        };
+    }
 
-        if self.size == other.size && self.average_log2_error <= other.average_log2_error {
-            return false;
-        }
+    // the errors compared for dominance, besides the size
+    fn criteria(&self, objective: Objective) -> Vec<f64> {
+        return match objective {
+            Objective::Composite => vec![self.average_log2_error, self.max_log2_error],
+            _ => vec![self.error(objective)]
+        };
+    }
+
+    fn dominated_by(&self, other: &RMIStatistics, objective: Objective) -> bool {
+        let (self_errs, other_errs) = (self.criteria(objective), other.criteria(objective));
+        if self.size < other.size { return false; }
+        if self_errs.iter().zip(other_errs.iter()).any(|(s, o)| s < o) { return false; }
 
-        if self.size <= other.size && self.average_log2_error == other.average_log2_error {
+        // other is no worse on any criterion, so it dominates unless they tie
+        // on all of them
+        if self.size == other.size && self_errs == other_errs {
             return false;
         }
 
@@ -153,7 +324,39 @@ impl RMIStatistics {
         return self.models == models && self.branching_factor == branching_factor;
     }
 
-    pub fn display_table(itms: &[RMIStatistics]) {
+    pub fn display_table(itms: &[RMIStatistics], verbose: bool) {
+        if verbose {
+            // also show the error percentiles, in positions, and the
+            // percentage of keys predicted exactly
+            let mut table = Table::new("{:<} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>}");
+            table.add_row(row!("Models", "Branch", "   AvgLg2",
+                               "   MaxLg2", "   Size (b)",
+                               "   P50", "   P90", "   P99", "   Max", "   Exact"));
+            for itm in itms {
+                let pcts = match &itm.error_percentiles {
+                    Some(p) => vec![p.p50, p.p90, p.p99, p.p100].into_iter()
+                        .map(|v| format!("   {}", v)).collect(),
+                    None => vec![String::from("   -"); 4]
+                };
+                let exact = if itm.exact_fraction.is_nan() {
+                    String::from("   -")
+                } else {
+                    format!("   {:.2}%", itm.exact_fraction * 100.0)
+                };
+                table.add_row(row!(itm.models.clone(),
+                                   format!("{:10}", itm.branching_factor),
+                                   format!("     {:2.5}", itm.average_log2_error),
+                                   format!("     {:2.5}", itm.max_log2_error),
+                                   format!("     {}", itm.size),
+                                   pcts[0].clone(), pcts[1].clone(),
+                                   pcts[2].clone(), pcts[3].clone(),
+                                   exact));
+            }
+
+            print!("{}", table);
+            return;
+        }
+
         let mut table = Table::new("{:<} {:>} {:>} {:>} {:>}");
         table.add_row(row!("Models", "Branch", "   AvgLg2",
                            "   MaxLg2", "   Size (b)"));
@@ -168,6 +371,60 @@ impl RMIStatistics {
         print!("{}", table);
     }
     
+    // writes a header and one line per config, for analysis elsewhere
+    pub fn write_csv<W: Write>(rows: &[RMIStatistics], w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "models,branching_factor,average_log2_error,max_log2_error,size")?;
+        for row in rows {
+            // model lists contain commas, so quote them
+            writeln!(w, "\"{}\",{},{},{},{}",
+                     row.models, row.branching_factor,
+                     row.average_log2_error, row.max_log2_error, row.size)?;
+        }
+
+        return Ok(());
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let percentiles = match &self.error_percentiles {
+            Some(p) => object!("p50" => p.p50, "p90" => p.p90, "p99" => p.p99, "p100" => p.p100),
+            None => JsonValue::Null
+        };
+
+        return object!(
+            "layers" => self.models.clone(),
+            "branching factor" => self.branching_factor,
+            "average log2 error" => self.average_log2_error,
+            "max log2 error" => self.max_log2_error,
+            "size" => self.size,
+            "error percentiles" => percentiles,
+            "exact fraction" => self.exact_fraction
+        );
+    }
+
+    fn from_json(obj: &JsonValue) -> Option<RMIStatistics> {
+        let pcts = &obj["error percentiles"];
+        let error_percentiles = if pcts.is_null() {
+            None
+        } else {
+            Some(train::ErrorPercentiles {
+                p50: pcts["p50"].as_u64()?,
+                p90: pcts["p90"].as_u64()?,
+                p99: pcts["p99"].as_u64()?,
+                p100: pcts["p100"].as_u64()?
+            })
+        };
+
+        return Some(RMIStatistics {
+            models: String::from(obj["layers"].as_str()?),
+            branching_factor: obj["branching factor"].as_u64()?,
+            average_log2_error: obj["average log2 error"].as_f64()?,
+            max_log2_error: obj["max log2 error"].as_f64()?,
+            size: obj["size"].as_u64()?,
+            error_percentiles,
+            exact_fraction: obj["exact fraction"].as_f64().unwrap_or(f64::NAN)
+        });
+    }
+
     pub fn to_grid_spec(&self, namespace: &str) -> JsonValue {
         return object!(
             "layers" => self.models.clone(),
@@ -180,30 +437,1032 @@ impl RMIStatistics {
     }
 }
 
-fn measure_rmis(data: &ModelData, configs: &[(String, u64)]) -> Vec<RMIStatistics> {
+// a fingerprint of the keys and positions (and the query keys, if the
+// errors were measured on them), so that cached measurements are only
+// reused for the data they were taken on (FNV-1a)
+fn data_hash(data: &ModelData, queries: Option<&[u64]>) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut add = |word: u64| {
+        for byte in word.to_le_bytes().iter() {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+
+    for idx in 0..data.len() {
+        let (key, pos) = data.get(idx);
+        for word in &[data.get_key(idx), key.to_bits(), pos.to_bits()] {
+            add(*word);
+        }
+    }
+
+    for query in queries.unwrap_or(&[]) {
+        add(*query);
+    }
+
+    return hash;
+}
+
+// statistics measured by an earlier search, stored in a JSON file along
+// with the hash of the data they were measured on. A cache file for other
+// data (or one that cannot be read) is ignored, and overwritten on save.
+pub struct MeasurementCache {
+    path: String,
+    data_hash: u64,
+    entries: Vec<RMIStatistics>
+}
+
+impl MeasurementCache {
+    pub fn load(path: &str, data: &ModelData, queries: Option<&[u64]>) -> MeasurementCache {
+        let data_hash = data_hash(data, queries);
+        let mut cache = MeasurementCache { path: String::from(path), data_hash, entries: Vec::new() };
+
+        let parsed = match std::fs::read_to_string(path) {
+            Ok(raw) => json::parse(&raw).ok(),
+            Err(_) => {
+                info!("No measurement cache at {}, starting a new one", path);
+                return cache;
+            }
+        };
+
+        let parsed = match parsed {
+            Some(p) => p,
+            None => {
+                warn!("Could not parse the measurement cache at {}, ignoring it", path);
+                return cache;
+            }
+        };
+
+        if parsed["data hash"].as_str() != Some(&format!("{:016x}", data_hash)) {
+            warn!("The measurement cache at {} is for different data, ignoring it", path);
+            return cache;
+        }
+
+        cache.entries = parsed["configs"].members()
+            .filter_map(RMIStatistics::from_json)
+            .collect();
+        info!("Loaded {} cached measurements from {}", cache.entries.len(), path);
+        return cache;
+    }
+
+    // the cached statistics of a config, if it was measured with (at least)
+    // the requested information
+    fn get(&self, models: &str, branching_factor: u64,
+           error_percentiles: bool) -> Option<&RMIStatistics> {
+        return self.entries.iter()
+            .find(|r| r.has_config(models, branching_factor))
+            .filter(|r| !error_percentiles || r.error_percentiles.is_some());
+    }
+
+    fn insert(&mut self, stats: RMIStatistics) {
+        self.entries.retain(|r| !r.has_config(&stats.models, stats.branching_factor));
+        self.entries.push(stats);
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let configs: Vec<JsonValue> = self.entries.iter().map(|r| r.to_json()).collect();
+        let out = object!(
+            "data hash" => format!("{:016x}", self.data_hash),
+            "configs" => configs
+        );
+
+        let f = std::fs::File::create(&self.path)?;
+        let mut bw = std::io::BufWriter::new(f);
+        out.write(&mut bw)?;
+        return Ok(());
+    }
+}
+
+fn top_model_type(models: &str) -> String {
+    return models.split(',').next().unwrap().to_string();
+}
+
+// the average and maximum log2 error of an RMI over the query keys, in the
+// same form as the errors measured during training: log2(2 * err + 2),
+// where err is the error bound of the bottom model each query is routed
+// to. A query whose lower bound (its true position) is outside that bound
+// counts with the distance to it instead, since the lookup has to search
+// that far.
+fn query_log2_errors(rmi: &RuntimeRMI, keys: &[u64], queries: &[u64]) -> (f64, f64) {
+    let mut avg = 0.0;
+    let mut max: f64 = 0.0;
+    for (n, query) in queries.iter().enumerate() {
+        let pos = keys.partition_point(|k| k < query) as u64;
+        let pred = rmi.predict((*query).into());
+        let err = u64::max(rmi.error_bound_for((*query).into()),
+                           u64::max(pred, pos) - u64::min(pred, pos));
+
+        let log2_error = train::log2_window(err);
+        avg += (log2_error - avg) / ((n + 1) as f64);
+        max = f64::max(max, log2_error);
+    }
+
+    return (avg, max);
+}
+
+// the indexes of configs, grouped by top model (and otherwise in order), so
+// each top model is only needed by consecutive configs
+fn top_model_order(configs: &[(String, u64)]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..configs.len()).collect();
+    order.sort_by_key(|idx| (top_model_type(&configs[*idx].0), configs[*idx].1));
+    return order;
+}
+
+// at most max_concurrent RMIs (if given) are trained at the same time, since
+// each one holds all of its models until its statistics are taken. The
+// shared top models count too: the configs are measured grouped by top model,
+// and each batch only holds the top models of its own configs. If queries
+// are given, the errors are measured over them (see query_log2_errors)
+// instead of over the training data. Once the deadline (if any) has passed,
+// no more configs are started: the ones already training finish, and the
+// rest are left out of the results.
+fn measure_rmis(data: &ModelData, configs: &[(String, u64)],
+                options: &train::TrainingOptions,
+                max_concurrent: Option<usize>,
+                queries: Option<&[u64]>,
+                deadline: Option<Instant>) -> Vec<RMIStatistics> {
+    let expired = || deadline.map(|d| Instant::now() >= d).unwrap_or(false);
+    if expired() {
+        return Vec::new();
+    }
+
+    // the top model only depends on its type and the branching factor, so
+    // train each distinct one once and share it between configs.
+    let top_key = |idx: usize| (top_model_type(&configs[idx].0), configs[idx].1);
+    let num_top_models = configs.iter()
+        .map(|(models, branch_factor)| (top_model_type(models), *branch_factor))
+        .collect::<BTreeSet<(String, u64)>>().len();
+    info!("Training {} distinct top models for {} configs", num_top_models, configs.len());
+
+    let keys: Vec<u64> = match queries {
+        Some(_) => ModelDataWrapper::new(data).iter_int_int().map(|(k, _)| k).collect(),
+        None => Vec::new()
+    };
+
     let pbar = ProgressBar::new(configs.len() as u64);
-    configs.par_iter()
-        .map(|(models, branch_factor)| {
-            let mut md = ModelDataWrapper::new(data);
-            let res = train::train(&mut md, models, *branch_factor);
-            pbar.inc(1);
-            RMIStatistics::from_trained(&res)
-        }).collect()
-}
-
-pub fn find_pareto_efficient_configs(data: &ModelData, restrict: usize)
-                                     -> Vec<RMIStatistics>{
-    let initial_configs  = first_phase_configs();
-    let first_phase_results = measure_rmis(data, &initial_configs);
-
-    let next_configs = second_phase_configs(&first_phase_results);
-    let second_phase_results = measure_rmis(data, &next_configs);
-    
-    let mut final_front = pareto_front(&second_phase_results);
-    final_front = narrow_front(&final_front, restrict);
-    final_front.sort_by(
-        |a, b| a.average_log2_error.partial_cmp(&b.average_log2_error).unwrap()
-    );
+    let batch_size = usize::max(max_concurrent.unwrap_or(configs.len()), 1);
+    let mut top_models: HashMap<(String, u64), Arc<dyn Model>> = HashMap::new();
+    let mut results = Vec::with_capacity(configs.len());
+    for batch in top_model_order(configs).chunks(batch_size) {
+        if expired() {
+            break;
+        }
+
+        // since the configs are grouped by top model, a top model the batch
+        // does not use is not used by any later batch either
+        let needed: BTreeSet<(String, u64)> = batch.iter().map(|idx| top_key(*idx)).collect();
+        top_models.retain(|key, _| needed.contains(key));
+        let to_train: Vec<&(String, u64)> = needed.iter()
+            .filter(|key| !top_models.contains_key(key))
+            .collect();
+        // once the deadline has passed, no more top models are trained, and
+        // the configs using them are left out
+        let trained: Vec<((String, u64), Arc<dyn Model>)> = to_train.par_iter()
+            .filter_map(|(model_type, branch_factor)| {
+                if expired() {
+                    return None;
+                }
+
+                let mut md = ModelDataWrapper::new(data);
+                let top = train::train_top_model(&mut md, model_type, *branch_factor);
+                Some(((model_type.clone(), *branch_factor), Arc::from(top)))
+            }).collect();
+        top_models.extend(trained);
+
+        let batch_results: Vec<(usize, RMIStatistics)> = batch.par_iter()
+            .filter_map(|idx| {
+                // checked as each config starts, since rayon starts them
+                // as threads become free
+                if expired() {
+                    return None;
+                }
+
+                let (models, branch_factor) = &configs[*idx];
+                let top = top_models.get(&top_key(*idx))?;
+                let mut md = ModelDataWrapper::new(data);
+                let res = train::train_with_top_model(&mut md, models, *branch_factor,
+                                                      Some(Box::new(Arc::clone(top))),
+                                                      options);
+                pbar.inc(1);
+                // only the statistics are kept, the RMI is dropped here
+                match res {
+                    Ok(rmi) => {
+                        let mut stats = RMIStatistics::from_trained(&rmi);
+                        if let Some(queries) = queries {
+                            let (avg, max) = query_log2_errors(&RuntimeRMI::new(rmi), &keys,
+                                                               queries);
+                            stats.average_log2_error = avg;
+                            stats.max_log2_error = max;
+                        }
+                        Some((*idx, stats))
+                    },
+                    Err(e) => {
+                        warn!("Skipping RMI {} with branching factor {}: {}",
+                              models, branch_factor, e);
+                        None
+                    }
+                }
+            }).collect();
+        results.extend(batch_results);
+    }
+
+    // back in the order of configs
+    results.sort_by_key(|(idx, _)| *idx);
+    return results.into_iter().map(|(_, stats)| stats).collect();
+}
+
+// like measure_rmis, but configs found in the cache are not measured again,
+// and new measurements are added to it
+fn measure_rmis_cached(data: &ModelData, configs: &[(String, u64)],
+                       options: &train::TrainingOptions,
+                       max_concurrent: Option<usize>,
+                       queries: Option<&[u64]>,
+                       cache: Option<&mut MeasurementCache>,
+                       deadline: Option<Instant>) -> Vec<RMIStatistics> {
+    let cache = match cache {
+        Some(c) => c,
+        None => return measure_rmis(data, configs, options, max_concurrent, queries, deadline)
+    };
+
+    let mut results = Vec::with_capacity(configs.len());
+    let mut to_measure = Vec::new();
+    for (models, branch_factor) in configs {
+        match cache.get(models, *branch_factor, options.error_percentiles) {
+            Some(stats) => results.push(stats.clone()),
+            None => to_measure.push((models.clone(), *branch_factor))
+        }
+    }
+
+    info!("Reusing {} cached measurements, measuring {} configs",
+          results.len(), to_measure.len());
+    for stats in measure_rmis(data, &to_measure, options, max_concurrent, queries, deadline) {
+        cache.insert(stats.clone());
+        results.push(stats);
+    }
+
+    if let Err(e) = cache.save() {
+        warn!("Could not write the measurement cache to {}: {}", cache.path, e);
+    }
+
+    return results;
+}
+
+// a summary of one optimizer run: the selected configs, along with what the
+// search measured to find them
+#[derive(Clone, Debug)]
+pub struct OptimizerReport {
+    // the (at most restrict) configs to use, sorted by error
+    pub selected: Vec<RMIStatistics>,
+
+    // the number of configs measured in total, and in each phase (the
+    // second phase count is 0 if it was skipped)
+    pub measured_count: usize,
+    pub phase1_count: usize,
+    pub phase2_count: usize,
 
-    return final_front;
+    // the number of configs the search planned to measure. Fewer are
+    // measured if the time budget ran out (or training some failed).
+    pub planned_count: usize,
+    pub budget_exhausted: bool,
+
+    // the size of the Pareto front the selected configs were picked from
+    pub front_size: usize,
+
+    // the smallest and largest size, and the lowest and highest error (by
+    // the objective), of any measured config
+    pub size_range: (u64, u64),
+    pub error_range: (f64, f64),
+
+    pub phase1_elapsed: Duration,
+    pub phase2_elapsed: Duration,
+    pub elapsed: Duration
+}
+
+// for callers that only want the selected configs
+impl From<OptimizerReport> for Vec<RMIStatistics> {
+    fn from(report: OptimizerReport) -> Vec<RMIStatistics> {
+        return report.selected;
+    }
+}
+
+// if error_percentiles is set, the returned statistics include
+// percentiles of the per-key error (at the cost of memory during training).
+// The objective picks which error is traded off against size, and the
+// results are sorted by it. If max_concurrent is given, no more than that
+// many RMIs are held in memory at once. narrow_bias is passed to
+// narrow_front (0 spreads the results evenly over sizes). If cache_path is
+// given, measurements stored there (for the same data) are reused, and every
+// measurement is written back to it. With Phases::FirstOnly, the front is
+// picked from the first phase's results and the second phase is skipped.
+//
+// If queries (keys, e.g. a sample of a read workload) are given, configs
+// are ranked by their errors on those keys, each mapped to the position of
+// its lower bound in data, rather than on every key in data. The models are
+// still trained on all of data; only the keys the errors are measured on
+// change. The error percentiles are still over data.
+//
+// With a time budget, no config measurement is started after the budget
+// has run out (those already running finish, so the search can take
+// somewhat longer), and the second phase is skipped if the first used it
+// all up (its configs still count as planned, and the budget as exhausted).
+// The front is then picked from every config that was measured.
+//
+// The pinned configs (models and branching factor), such as one already in
+// production, are measured along with the first phase, and always reported
+// (see narrow_front), even if other configs beat them.
+#[allow(clippy::too_many_arguments)]
+pub fn find_pareto_efficient_configs(data: &ModelData, restrict: usize,
+                                     error_percentiles: bool,
+                                     objective: Objective,
+                                     max_concurrent: Option<usize>,
+                                     narrow_bias: f64,
+                                     cache_path: Option<&str>,
+                                     phases: Phases,
+                                     queries: Option<&[u64]>,
+                                     time_budget: Option<Duration>,
+                                     pinned: &[(String, u64)])
+                                     -> std::result::Result<OptimizerReport, train::TrainingError> {
+    return search_configs(data, restrict, error_percentiles, objective, max_concurrent,
+                          narrow_bias, cache_path, phases, queries, time_budget, pinned)
+        .map(|r| r.0);
+}
+
+// the (at most restrict) Pareto efficient configs to report, sorted by
+// error, along with the pinned ones, whether or not they are on the front
+fn select_front(results: &[RMIStatistics], restrict: usize,
+                objective: Objective, narrow_bias: f64,
+                pinned: &[RMIStatistics]) -> Vec<RMIStatistics> {
+    let mut front = pareto_front(results, objective);
+    for stats in pinned {
+        if !front.iter().any(|r| r.has_config(&stats.models, stats.branching_factor)) {
+            front.push(stats.clone());
+        }
+    }
+
+    let pins: Vec<(String, u64)> = pinned.iter()
+        .map(|stats| (stats.models.clone(), stats.branching_factor))
+        .collect();
+    let mut front = narrow_front(&front, restrict, objective, narrow_bias, &pins);
+    front.sort_by(|a, b| front_order(a, b, objective));
+    return front;
+}
+
+// like find_pareto_efficient_configs, but also returns the statistics of
+// every config measured along the way (first phase, then second phase)
+#[allow(clippy::too_many_arguments)]
+pub fn search_configs(data: &ModelData, restrict: usize,
+                      error_percentiles: bool,
+                      objective: Objective,
+                      max_concurrent: Option<usize>,
+                      narrow_bias: f64,
+                      cache_path: Option<&str>,
+                      phases: Phases,
+                      queries: Option<&[u64]>,
+                      time_budget: Option<Duration>,
+                      pinned: &[(String, u64)])
+                      -> std::result::Result<(OptimizerReport, Vec<RMIStatistics>),
+                                            train::TrainingError> {
+    if data.len() == 0 {
+        return Err(train::TrainingError::EmptyData);
+    }
+
+    // no queries means measuring on data, as without them
+    let queries = queries.filter(|q| !q.is_empty());
+
+    let start = Instant::now();
+    let deadline = time_budget.map(|budget| start + budget);
+    let expired = || deadline.map(|d| Instant::now() >= d).unwrap_or(false);
+    let options = train::TrainingOptions { error_percentiles, ..Default::default() };
+    let mut cache = cache_path.map(|path| MeasurementCache::load(path, data, queries));
+    let mut initial_configs = first_phase_configs();
+    for (models, branch_factor) in pinned {
+        if !initial_configs.iter().any(|(m, b)| m == models && b == branch_factor) {
+            initial_configs.push((models.clone(), *branch_factor));
+        }
+    }
+    let first_phase_results = measure_rmis_cached(data, &initial_configs, &options,
+                                                  max_concurrent, queries, cache.as_mut(),
+                                                  deadline);
+    let phase1_elapsed = start.elapsed();
+
+    // the second phase configs are planned even if the budget leaves no
+    // time to measure them
+    let wants_second_phase = phases != Phases::FirstOnly;
+    let next_configs = if wants_second_phase {
+        second_phase_configs(&first_phase_results, objective)
+    } else {
+        Vec::new()
+    };
+    let planned_count = initial_configs.len() + next_configs.len();
+
+    let run_second_phase = wants_second_phase && !expired();
+    let second_phase_results = if run_second_phase {
+        measure_rmis_cached(data, &next_configs, &options, max_concurrent, queries,
+                            cache.as_mut(), deadline)
+    } else {
+        Vec::new()
+    };
+    let phase2_elapsed = start.elapsed() - phase1_elapsed;
+
+    let phase1_count = first_phase_results.len();
+    let phase2_count = second_phase_results.len();
+    let mut all_results = first_phase_results;
+    all_results.extend(second_phase_results);
+    let budget_exhausted = (expired() && all_results.len() < planned_count)
+        || (wants_second_phase && !run_second_phase);
+
+    // the front is picked from the last phase that ran, or from everything
+    // measured if the budget cut the search short
+    let front_from = if budget_exhausted || !run_second_phase {
+        &all_results[..]
+    } else {
+        &all_results[phase1_count..]
+    };
+    let pinned_results: Vec<RMIStatistics> = all_results.iter()
+        .filter(|r| pinned.iter().any(|(m, b)| r.has_config(m, *b)))
+        .cloned().collect();
+    let selected = select_front(front_from, restrict, objective, narrow_bias, &pinned_results);
+    let front_size = pareto_front(front_from, objective).len();
+
+    let size_range = all_results.iter()
+        .fold((u64::MAX, 0), |(lo, hi), r| (u64::min(lo, r.size), u64::max(hi, r.size)));
+    let error_range = all_results.iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), r| {
+            (f64::min(lo, r.error(objective)), f64::max(hi, r.error(objective)))
+        });
+
+    let report = OptimizerReport {
+        selected,
+        measured_count: all_results.len(),
+        phase1_count,
+        phase2_count,
+        planned_count,
+        budget_exhausted,
+        front_size,
+        size_range,
+        error_range,
+        phase1_elapsed,
+        phase2_elapsed,
+        elapsed: start.elapsed()
+    };
+    return Ok((report, all_results));
+}
+
+// the cost model auto_build ranks configs by: evaluating a model, one step
+// of the last mile search, and reading parameters that are not cached
+const NS_PER_MODEL: f64 = 5.0;
+const NS_PER_PROBE: f64 = 10.0;
+const NS_PER_MISS: f64 = 80.0;
+const CACHE_BYTES: u64 = 1 << 20;
+
+// the estimated lookup latency of a config, in nanoseconds: a model
+// evaluation per layer, a miss to read the last layer's parameters once the
+// RMI outgrows the cache, and a probe per bit of the (average) search
+// window. It only depends on the measured size and error, so the same data
+// and budget always pick the same config, but it tracks the generated code
+// only roughly.
+fn estimated_ns_per_lookup(stats: &RMIStatistics) -> f64 {
+    let layers = stats.models.split(',').count() as f64;
+    let params = if stats.size > CACHE_BYTES { NS_PER_MISS } else { 0.0 };
+    return layers * NS_PER_MODEL + params + stats.average_log2_error * NS_PER_PROBE;
+}
+
+// picks the smallest config whose estimated lookup latency (see
+// estimated_ns_per_lookup) is within budget. Only the configs on the Pareto
+// front are candidates: any other config is beaten on error by one that is
+// no larger. Returns None if no config meets the budget.
+pub fn auto_build(data: &ModelData, budget: LatencyBudget)
+                  -> std::result::Result<Option<(RMIStatistics, RuntimeRMI)>,
+                                         train::TrainingError> {
+    let (_report, all) = search_configs(data, usize::MAX, false, Objective::default(), None,
+                                        0.0, None, Phases::default(), None, None, &[])?;
+    let front = pareto_front(&all, Objective::default());
+    return smallest_within_budget(data, &front, budget);
+}
+
+// trains the smallest of the candidates whose estimated lookup latency is
+// within budget
+fn smallest_within_budget(data: &ModelData, candidates: &[RMIStatistics],
+                          budget: LatencyBudget)
+                          -> std::result::Result<Option<(RMIStatistics, RuntimeRMI)>,
+                                                train::TrainingError> {
+    let picked = candidates.iter()
+        .filter(|c| estimated_ns_per_lookup(c) <= budget.ns_per_lookup)
+        .min_by(|a, b| a.size.cmp(&b.size).then_with(|| config_order(a, b)));
+    let picked = match picked {
+        Some(p) => p.clone(),
+        None => return Ok(None)
+    };
+    info!("RMI {} with branching factor {} ({} bytes): about {:.1} ns per lookup",
+          picked.models, picked.branching_factor, picked.size, estimated_ns_per_lookup(&picked));
+
+    let mut container = ModelDataWrapper::new(data);
+    let trained = train::train(&mut container, &picked.models, picked.branching_factor)?;
+    return Ok(Some((picked, RuntimeRMI::new(trained))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(size: u64, average_log2_error: f64, max_log2_error: f64) -> RMIStatistics {
+        return RMIStatistics {
+            models: String::from("linear,linear"),
+            branching_factor: size,
+            average_log2_error,
+            max_log2_error,
+            size,
+            error_percentiles: None,
+            exact_fraction: 0.0
+        };
+    }
+
+    #[test]
+    fn test_objective() {
+        // same size, so only the error decides dominance
+        let low_avg = stats(100, 2.0, 10.0);
+        let low_max = stats(100, 3.0, 5.0);
+
+        assert!(low_max.dominated_by(&low_avg, Objective::AvgError));
+        assert!(!low_avg.dominated_by(&low_max, Objective::AvgError));
+        assert!(low_avg.dominated_by(&low_max, Objective::MaxError));
+        assert!(!low_max.dominated_by(&low_avg, Objective::MaxError));
+
+        let results = vec![low_avg, low_max];
+        let front = pareto_front(&results, Objective::default());
+        assert_eq!(front.len(), 1);
+        assert_eq!(front[0].average_log2_error, 2.0);
+
+        let front = pareto_front(&results, Objective::MaxError);
+        assert_eq!(front.len(), 1);
+        assert_eq!(front[0].max_log2_error, 5.0);
+    }
+
+    #[test]
+    fn test_composite_objective() {
+        // with the same size and average error, only the maximum error
+        // separates these two
+        let low_max = stats(100, 2.0, 5.0);
+        let high_max = stats(100, 2.0, 10.0);
+        assert!(!high_max.dominated_by(&low_max, Objective::AvgError));
+        assert!(high_max.dominated_by(&low_max, Objective::Composite));
+        assert!(!low_max.dominated_by(&high_max, Objective::Composite));
+        assert!(!low_max.dominated_by(&low_max.clone(), Objective::Composite));
+
+        // better on average but worse on the maximum: neither dominates
+        let low_avg = stats(100, 1.0, 12.0);
+        assert!(!low_avg.dominated_by(&low_max, Objective::Composite));
+        assert!(!low_max.dominated_by(&low_avg, Objective::Composite));
+
+        // larger and worse on both is dominated under every objective
+        let worse = stats(200, 3.0, 11.0);
+        for objective in &[Objective::AvgError, Objective::MaxError, Objective::Composite] {
+            assert!(worse.dominated_by(&low_max, *objective));
+        }
+
+        let results = vec![low_max, high_max, low_avg, worse];
+        let front = pareto_front(&results, Objective::default());
+        assert_eq!(front.len(), 1);
+        let mut front = pareto_front(&results, Objective::Composite);
+        front.sort_by(|a, b| front_order(a, b, Objective::Composite));
+        let errors: Vec<(f64, f64)> = front.iter()
+            .map(|r| (r.average_log2_error, r.max_log2_error))
+            .collect();
+        assert_eq!(errors, vec![(1.0, 12.0), (2.0, 5.0)]);
+    }
+
+    #[test]
+    fn test_suggest_branching_factor() {
+        assert_eq!(suggest_branching_factor(0, 0.0), 64);
+        assert_eq!(suggest_branching_factor(usize::MAX, f64::MAX), 1 << 24);
+
+        let random = |n: u64| {
+            let mut state: u64 = 7;
+            let mut keys: Vec<u64> = (0..n).map(|_| {
+                state = state.wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                state >> 20
+            }).collect();
+            keys.sort_unstable();
+            keys.dedup();
+            keys
+        };
+
+        // the suggestion is the optimum a grid search finds on squares and
+        // random keys, and an upper bound on the smoother i^1.5
+        let n = 100_000u64;
+        let datasets = vec![((0..n).map(|i| i * i).collect::<Vec<u64>>(), true),
+                            (random(n), true),
+                            (random(20_000), true),
+                            ((0..n).map(|i| (i as f64).powf(1.5) as u64).collect(), false)];
+
+        for (keys, exact) in datasets {
+            let md = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                               .map(|(i, k)| (*k, i as u64)).collect());
+            let top = train::train_single_layer(&mut ModelDataWrapper::new(&md),
+                                                "linear").unwrap();
+            let suggested = suggest_branching_factor(md.len(), top.model_avg_error);
+
+            // the smallest branching factor of a grid search whose leaves
+            // are within the target on average
+            let searched = get_branching_factors().into_iter()
+                .find(|b| {
+                    let rmi = train::train(&mut ModelDataWrapper::new(&md), "linear,linear",
+                                           *b).unwrap();
+                    rmi.model_avg_error <= TARGET_LEAF_ERROR
+                })
+                .unwrap();
+            if exact {
+                assert_eq!(suggested, searched);
+            } else {
+                assert!(suggested >= searched, "suggested {}, searched {}", suggested, searched);
+            }
+        }
+    }
+
+    #[test]
+    fn test_insert_into_front() {
+        let sizes = |front: &[RMIStatistics]| front.iter().map(|r| r.size).collect::<Vec<u64>>();
+        let mut front = vec![stats(800, 2.0, 2.0), stats(400, 4.0, 4.0), stats(100, 8.0, 8.0)];
+
+        // dominated by (400, 4.0)
+        assert!(!insert_into_front(&mut front, stats(500, 5.0, 5.0), Objective::AvgError));
+        // already on the front
+        assert!(!insert_into_front(&mut front, stats(400, 4.0, 4.0), Objective::AvgError));
+        assert_eq!(sizes(&front), vec![800, 400, 100]);
+
+        // incomparable: smaller than (400, 4.0) but less accurate
+        assert!(insert_into_front(&mut front, stats(200, 6.0, 6.0), Objective::AvgError));
+        assert_eq!(sizes(&front), vec![800, 400, 200, 100]);
+
+        // dominates (800, 2.0) and (400, 4.0)
+        assert!(insert_into_front(&mut front, stats(300, 1.5, 1.5), Objective::AvgError));
+        assert_eq!(sizes(&front), vec![300, 200, 100]);
+
+        // the result is the front of everything inserted
+        let all = vec![stats(800, 2.0, 2.0), stats(400, 4.0, 4.0), stats(100, 8.0, 8.0),
+                       stats(500, 5.0, 5.0), stats(200, 6.0, 6.0), stats(300, 1.5, 1.5)];
+        let mut expected = pareto_front(&all, Objective::AvgError);
+        expected.sort_by(|a, b| front_order(a, b, Objective::AvgError));
+        assert_eq!(sizes(&front), sizes(&expected));
+    }
+
+    #[test]
+    fn test_empty_data() {
+        let res = find_pareto_efficient_configs(&ModelData::empty(), 10, false,
+                                                Objective::default(), None, 0.0, None,
+                                                Phases::default(), None, None, &[]);
+        assert!(matches!(res, Err(train::TrainingError::EmptyData)));
+    }
+
+    #[test]
+    fn test_first_phase_only() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        // pinned to a branching factor the search never tries on its own
+        let pin = vec![(String::from("linear,linear"), 1000)];
+        let (report, all) = search_configs(&data, 3, false, Objective::default(), None, 0.0,
+                                           None, Phases::FirstOnly, None, None, &pin).unwrap();
+        assert_eq!(report.phase2_count, 0);
+        assert_eq!(report.phase1_count, all.len());
+        assert_eq!(report.measured_count, all.len());
+        assert!(report.front_size >= report.selected.len());
+        assert!(report.elapsed >= report.phase1_elapsed);
+        assert!(all.iter().all(|r| r.size >= report.size_range.0 && r.size <= report.size_range.1));
+        assert!(all.iter().any(|r| r.average_log2_error == report.error_range.0));
+        let planned_count = report.planned_count;
+        let front: Vec<RMIStatistics> = report.into();
+
+        // only the first phase's configs (and the pinned one) are measured
+        let mut first = first_phase_configs();
+        first.extend(pin.iter().cloned());
+        assert_eq!(all.len(), first.len());
+        assert_eq!(planned_count, first.len());
+        assert!(all.iter().all(|r| first.iter().any(|(m, bf)| r.has_config(m, *bf))));
+        // bottom only layers are tried, but only at the bottom
+        assert!(first.iter().any(|(m, _)| m.ends_with(",plr_q")));
+        assert!(!first.iter().any(|(m, _)| m.starts_with("plr_q,")));
+
+        // the pinned config takes one of the 3 slots
+        assert!(front.iter().any(|r| r.has_config("linear,linear", 1000)));
+        assert!(!front.is_empty() && front.len() <= 3);
+        assert!(front.iter().all(|r| all.iter().any(|v| v.has_config(&r.models,
+                                                                        r.branching_factor))));
+        for pair in front.windows(2) {
+            assert!(pair[0].average_log2_error <= pair[1].average_log2_error);
+        }
+    }
+
+    #[test]
+    fn test_time_budget() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+
+        // a budget the search stays within changes nothing
+        let (report, all) = search_configs(&data, 3, false, Objective::default(), None, 0.0,
+                                           None, Phases::FirstOnly, None,
+                                           Some(Duration::from_secs(3600)), &[]).unwrap();
+        assert!(!report.budget_exhausted);
+        assert_eq!(report.planned_count, first_phase_configs().len());
+        assert_eq!(report.measured_count, report.planned_count);
+        assert_eq!(all.len(), report.planned_count);
+
+        // a spent budget starts no measurements, and skips the second phase
+        let (report, all) = search_configs(&data, 3, false, Objective::default(), None, 0.0,
+                                           None, Phases::Both, None,
+                                           Some(Duration::from_nanos(1)), &[]).unwrap();
+        assert!(report.budget_exhausted);
+        assert_eq!(report.planned_count, first_phase_configs().len());
+        assert_eq!(report.measured_count, 0);
+        assert_eq!(report.phase2_count, 0);
+        assert!(all.is_empty() && report.selected.is_empty());
+
+        // whichever phase the budget runs out in, a search that measured
+        // fewer configs than planned reports the budget as exhausted
+        for millis in [1, 5, 20, 100].iter() {
+            let (report, _) = search_configs(&data, 3, false, Objective::default(), None, 0.0,
+                                             None, Phases::Both, None,
+                                             Some(Duration::from_millis(*millis)), &[])
+                .unwrap();
+            assert!(report.budget_exhausted || report.measured_count == report.planned_count,
+                    "{:?}", report);
+        }
+
+        // nothing is trained once the deadline has passed
+        let configs: Vec<(String, u64)> = ["linear,linear", "cubic,linear"]
+            .iter().map(|m| (m.to_string(), 16)).collect();
+        let options = train::TrainingOptions::default();
+        let past = Some(Instant::now());
+        assert!(measure_rmis(&data, &configs, &options, Some(1), None, past).is_empty());
+    }
+
+    #[test]
+    fn test_smallest_within_budget() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let candidates = vec![
+            RMIStatistics { models: String::from("linear,linear"), branching_factor: 64,
+                            size: 64 * 16 + 16, ..stats(0, 0.0, 0.0) },
+            RMIStatistics { models: String::from("linear,linear"), branching_factor: 8,
+                            size: 8 * 16 + 16, ..stats(0, 0.0, 0.0) }
+        ];
+
+        // any latency is acceptable: the smallest candidate is picked
+        let budget = LatencyBudget { ns_per_lookup: f64::INFINITY };
+        let (picked, rmi) = smallest_within_budget(&data, &candidates, budget)
+            .unwrap().unwrap();
+        assert_eq!(picked.branching_factor, 8);
+        let keys: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        assert_eq!(rmi.lookup(&keys, 25).0, 5);
+
+        // no lookup is that fast
+        let budget = LatencyBudget { ns_per_lookup: 0.0 };
+        assert!(smallest_within_budget(&data, &candidates, budget).unwrap().is_none());
+
+        // a budget between the estimates takes the more accurate one, every time
+        let candidates = vec![
+            RMIStatistics { models: String::from("linear,linear"), branching_factor: 64,
+                            size: 64 * 16 + 16, ..stats(0, 2.0, 3.0) },
+            RMIStatistics { models: String::from("linear,linear"), branching_factor: 8,
+                            size: 8 * 16 + 16, ..stats(0, 6.0, 8.0) }
+        ];
+        assert_eq!(estimated_ns_per_lookup(&candidates[0]),
+                   2.0 * NS_PER_MODEL + 2.0 * NS_PER_PROBE);
+        let budget = LatencyBudget { ns_per_lookup: estimated_ns_per_lookup(&candidates[0]) };
+        for _ in 0..3 {
+            let (picked, _) = smallest_within_budget(&data, &candidates, budget).unwrap().unwrap();
+            assert_eq!(picked.branching_factor, 64);
+        }
+
+        // parameters that do not fit in the cache cost a miss
+        let large = RMIStatistics { size: CACHE_BYTES + 1, ..candidates[0].clone() };
+        assert_eq!(estimated_ns_per_lookup(&large),
+                   estimated_ns_per_lookup(&candidates[0]) + NS_PER_MISS);
+    }
+
+    #[test]
+    fn test_max_concurrent() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let configs: Vec<(String, u64)> = ["linear,linear", "cubic,linear", "radix,linear"]
+            .iter().map(|m| (m.to_string(), 16)).collect();
+        let options = train::TrainingOptions::default();
+
+        let key = |r: &RMIStatistics| (r.models.clone(), r.size);
+        let mut all = measure_rmis(&data, &configs, &options, None, None, None);
+        let mut one = measure_rmis(&data, &configs, &options, Some(1), None, None);
+        let mut two = measure_rmis(&data, &configs, &options, Some(2), None, None);
+        all.sort_by_key(key);
+        one.sort_by_key(key);
+        two.sort_by_key(key);
+
+        assert_eq!(all.len(), 3);
+        for (a, b) in all.iter().zip(one.iter()).chain(all.iter().zip(two.iter())) {
+            assert_eq!(a.models, b.models);
+            assert_eq!(a.average_log2_error, b.average_log2_error);
+        }
+
+        // configs sharing a top model are measured together, but the
+        // results stay in the order of the configs
+        let configs: Vec<(String, u64)> = [("linear,linear", 16), ("cubic,linear", 16),
+                                           ("linear,cubic", 16), ("linear,linear", 32),
+                                           ("cubic,cubic", 16)]
+            .iter().map(|(m, b)| (m.to_string(), *b)).collect();
+        assert_eq!(top_model_order(&configs), vec![1, 4, 0, 2, 3]);
+        let config_of = |r: &RMIStatistics| (r.models.clone(), r.branching_factor);
+        for max_concurrent in [None, Some(1), Some(2)].iter() {
+            let results = measure_rmis(&data, &configs, &options, *max_concurrent, None, None);
+            assert_eq!(results.iter().map(config_of).collect::<Vec<_>>(), configs);
+        }
+    }
+
+    #[test]
+    fn test_query_errors() {
+        // the keys are dense at the start and sparse after, so an RMI with
+        // few models fits the sparse part well and the dense part badly
+        let keys: Vec<u64> = (0..5000).chain((1..5000).map(|i| 5000 + i * 1000)).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let configs = vec![(String::from("linear,linear"), 4)];
+        let options = train::TrainingOptions::default();
+
+        let all = &measure_rmis(&data, &configs, &options, None, None, None)[0];
+        let sparse: Vec<u64> = keys[6000..].to_vec();
+        let on_sparse = &measure_rmis(&data, &configs, &options, None, Some(&sparse), None)[0];
+        assert_eq!(on_sparse.size, all.size);
+        assert!(on_sparse.average_log2_error < all.average_log2_error,
+                "{} vs {}", on_sparse.average_log2_error, all.average_log2_error);
+        assert!(on_sparse.max_log2_error <= all.max_log2_error);
+
+        // the queries need not be training keys
+        let between: Vec<u64> = sparse.iter().map(|k| k + 1).collect();
+        let on_between = &measure_rmis(&data, &configs, &options, None, Some(&between), None)[0];
+        assert!(on_between.average_log2_error < all.average_log2_error);
+    }
+
+    #[test]
+    fn test_narrow_bias() {
+        // a front whose errors level off as the size grows
+        let front: Vec<RMIStatistics> = [(100, 20.0), (200, 12.0), (400, 8.0), (800, 6.0),
+                                         (1600, 5.0), (3200, 4.5), (6400, 4.2)]
+            .iter().map(|(size, err)| stats(*size, *err, *err)).collect();
+        let sizes = |r: Vec<RMIStatistics>| r.iter().map(|s| s.size).collect::<Vec<u64>>();
+
+        // evenly spread sizes (all gaps are equal, the first is dropped)
+        let even = sizes(narrow_front(&front, 4, Objective::AvgError, 0.0, &[]));
+        assert_eq!(even, vec![100, 1600, 3200, 6400]);
+
+        // only the error gaps: the big jumps at small sizes are kept
+        let by_error = sizes(narrow_front(&front, 4, Objective::AvgError, 1.0, &[]));
+        assert_eq!(by_error.len(), 4);
+        assert_eq!(by_error[0], 100);
+        assert!(by_error.contains(&200));
+    }
+
+    #[test]
+    fn test_narrow_zero_errors() {
+        // exact configs have an error of zero, so some error ratios are 0/0 or x/0
+        let front: Vec<RMIStatistics> = [(100, 3.0), (200, 1.0), (400, 0.0), (800, 0.0),
+                                         (1600, 0.0), (3200, 0.0)]
+            .iter().map(|(size, err)| stats(*size, *err, *err)).collect();
+        for bias in [0.0, 0.5, 1.0].iter() {
+            let narrowed = narrow_front(&front, 3, Objective::MaxError, *bias, &[]);
+            assert_eq!(narrowed.len(), 3);
+            assert_eq!(narrowed[0].size, 100);
+        }
+
+        // with only the error gaps, one of the exact configs is kept
+        let by_error = narrow_front(&front, 3, Objective::MaxError, 1.0, &[]);
+        assert_eq!(by_error.iter().filter(|r| r.max_log2_error == 0.0).count(), 1);
+    }
+
+    #[test]
+    fn test_narrow_pinned() {
+        let front: Vec<RMIStatistics> = [(100, 20.0), (200, 12.0), (400, 8.0), (800, 6.0),
+                                         (1600, 5.0), (3200, 4.5), (6400, 4.2)]
+            .iter().map(|(size, err)| stats(*size, *err, *err)).collect();
+        let sizes = |r: Vec<RMIStatistics>| r.iter().map(|s| s.size).collect::<Vec<u64>>();
+        let pin = |sizes: &[u64]| sizes.iter()
+            .map(|s| (String::from("linear,linear"), *s))
+            .collect::<Vec<(String, u64)>>();
+
+        // 400 is dropped without the pin (see test_narrow_bias); with it, one
+        // of the others gives up its slot
+        let pinned = sizes(narrow_front(&front, 4, Objective::AvgError, 0.0, &pin(&[400])));
+        assert_eq!(pinned.len(), 4);
+        assert_eq!(pinned[0], 100);
+        assert!(pinned.contains(&400));
+
+        // pinned configs that are not on the front change nothing
+        assert_eq!(sizes(narrow_front(&front, 4, Objective::AvgError, 0.0, &pin(&[300]))),
+                   vec![100, 1600, 3200, 6400]);
+
+        // pinning more than fits keeps all of them
+        let pinned = sizes(narrow_front(&front, 3, Objective::AvgError, 0.0,
+                                        &pin(&[200, 800, 3200, 6400])));
+        assert_eq!(pinned, vec![100, 200, 800, 3200, 6400]);
+    }
+
+    #[test]
+    fn test_select_pinned() {
+        let front: Vec<RMIStatistics> = [(100, 20.0), (200, 12.0), (400, 8.0), (800, 6.0),
+                                         (1600, 5.0), (3200, 4.5), (6400, 4.2)]
+            .iter().map(|(size, err)| stats(*size, *err, *err)).collect();
+        // a config 200 beats on both size and error, so it is not on the front
+        let dominated = stats(300, 13.0, 13.0);
+        let mut results = front.clone();
+        results.push(dominated.clone());
+
+        let sizes = |r: Vec<RMIStatistics>| r.iter().map(|s| s.size).collect::<Vec<u64>>();
+        let unpinned = sizes(select_front(&results, 4, Objective::AvgError, 0.0, &[]));
+        assert!(!unpinned.contains(&300));
+        let pinned = sizes(select_front(&results, 4, Objective::AvgError, 0.0, &[dominated]));
+        assert_eq!(pinned.len(), 4);
+        assert!(pinned.contains(&300));
+    }
+
+    #[test]
+    fn test_deterministic_ties() {
+        let config = |models: &str, branching_factor: u64, size: u64, err: f64| RMIStatistics {
+            models: String::from(models),
+            branching_factor,
+            average_log2_error: err,
+            max_log2_error: err,
+            size,
+            error_percentiles: None,
+            exact_fraction: 0.0
+        };
+        let results = vec![
+            config("linear,linear", 64, 100, 8.0), config("cubic,linear", 64, 100, 8.0),
+            config("radix,linear", 128, 100, 8.0),
+            config("linear,linear", 256, 400, 6.0), config("linear,linear", 128, 400, 6.0),
+            config("cubic,linear", 512, 1600, 4.0), config("linear_spline,linear", 512, 1600, 4.0),
+            config("linear,linear", 4096, 6400, 2.0)
+        ];
+        let chosen = |results: &[RMIStatistics]| select_front(results, 3, Objective::AvgError, 0.0,
+                                                              &[])
+            .into_iter().map(|r| (r.models, r.branching_factor)).collect::<Vec<_>>();
+
+        let expected = chosen(&results);
+        assert_eq!(expected.len(), 3);
+        assert_eq!(expected[2], (String::from("cubic,linear"), 64));
+
+        let mut reordered = results.clone();
+        reordered.reverse();
+        assert_eq!(chosen(&reordered), expected);
+        for _ in 0..results.len() {
+            reordered.rotate_left(3);
+            assert_eq!(chosen(&reordered), expected);
+        }
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let rows = vec![stats(100, 2.0, 10.0), stats(200, 1.5, 4.25)];
+        let mut out = Vec::new();
+        RMIStatistics::write_csv(&rows, &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "models,branching_factor,average_log2_error,max_log2_error,size");
+        assert_eq!(lines[2], "\"linear,linear\",200,1.5,4.25,200");
+    }
+
+    #[test]
+    fn test_measurement_cache() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let path = std::env::temp_dir().join("rmi_test_measurement_cache.json");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let configs: Vec<(String, u64)> = ["linear,linear", "cubic,linear"]
+            .iter().map(|m| (m.to_string(), 16)).collect();
+        let options = train::TrainingOptions { error_percentiles: true, ..Default::default() };
+        let mut cache = MeasurementCache::load(path, &data, None);
+        let measured = measure_rmis_cached(&data, &configs, &options, None, None,
+                                           Some(&mut cache), None);
+
+        // a new cache for the same data reads every measurement back
+        let cache = MeasurementCache::load(path, &data, None);
+        assert_eq!(cache.entries.len(), 2);
+        for stats in measured.iter() {
+            let cached = cache.get(&stats.models, 16, true).unwrap();
+            assert_eq!(cached.average_log2_error, stats.average_log2_error);
+            assert_eq!(cached.max_log2_error, stats.max_log2_error);
+            assert_eq!(cached.size, stats.size);
+            assert_eq!(cached.error_percentiles, stats.error_percentiles);
+        }
+        assert!(cache.get("linear,linear", 32, false).is_none());
+
+        // measurements cached before the exact fraction was stored still load
+        let mut old = measured[0].to_json();
+        old.remove("exact fraction");
+        let old = RMIStatistics::from_json(&old).unwrap();
+        assert_eq!(old.average_log2_error, measured[0].average_log2_error);
+        assert!(old.exact_fraction.is_nan());
+
+        // other data does not use it
+        let other = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * 3, i)).collect());
+        assert!(MeasurementCache::load(path, &other, None).entries.is_empty());
+        // nor do measurements on query keys
+        assert!(MeasurementCache::load(path, &data, Some(&[5, 10])).entries.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,569 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+use crate::train::{self, TrainedRMI, TrainingError};
+
+// evaluates a trained RMI in-process, the same way the generated code would,
+// without going through codegen.
+pub struct RuntimeRMI {
+    trained: TrainedRMI
+}
+
+impl RuntimeRMI {
+    pub fn new(trained: TrainedRMI) -> RuntimeRMI {
+        return RuntimeRMI { trained };
+    }
+
+    // trains an RMI with the given layers and branching factor on data
+    // (with the default training options) and wraps it, for one call use.
+    // train::train_with_options gives full control over training.
+    pub fn build(data: &ModelData, models: &str,
+                 branching_factor: u64) -> Result<RuntimeRMI, TrainingError> {
+        let trained = train::train(&mut ModelDataWrapper::new(data), models, branching_factor)?;
+        return Ok(RuntimeRMI::new(trained));
+    }
+
+    pub fn trained(&self) -> &TrainedRMI {
+        return &self.trained;
+    }
+
+    // the number of keys the RMI indexes (see TrainedRMI::num_keys), as the
+    // generated rmi_count returns it
+    pub fn len(&self) -> usize {
+        return self.trained.num_keys;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn into_trained(self) -> TrainedRMI {
+        return self.trained;
+    }
+
+    // the key the models see: the key itself, or its transform if the RMI
+    // was trained on transformed keys
+    fn model_input(&self, key: ModelInput) -> ModelInput {
+        return match (self.trained.key_transform, key) {
+            (Some(transform), ModelInput::Int(k)) => transform.apply(k).into(),
+            _ => key
+        };
+    }
+
+    // walks the key down the non-leaf layers and returns the index of the
+    // bottom model it lands on, without evaluating the bottom model
+    pub fn route(&self, key: ModelInput) -> usize {
+        let key = self.model_input(key);
+        let layers = &self.trained.rmi;
+        let mut idx = 0;
+        for layer_idx in 0..layers.len() - 1 {
+            // merged bottom models are reached through the leaf map
+            let next_layer_size = match &self.trained.leaf_map {
+                Some(map) if layer_idx == layers.len() - 2 => map.len(),
+                _ => layers[layer_idx + 1].len()
+            } as u64;
+            let pred = layers[layer_idx][idx].predict_to_int(key);
+            idx = u64::min(next_layer_size - 1, pred) as usize;
+        }
+
+        if let Some(map) = &self.trained.leaf_map {
+            idx = map[idx] as usize;
+        }
+
+        return idx;
+    }
+
+    // returns the maximum error of the bottom model the key is routed to:
+    // the bound stored during training (which includes any error slack),
+    // falling back to the global maximum error. Models that guarantee their
+    // own bound (e.g. PGM) never report less than that bound.
+    pub fn error_bound_for(&self, key: ModelInput) -> u64 {
+        let idx = self.route(key);
+        let leaf = &self.trained.rmi[self.trained.rmi.len() - 1][idx];
+
+        let stored = self.trained.last_layer_max_l1s.get(idx)
+            .copied()
+            .unwrap_or(self.trained.model_max_error);
+
+        return match leaf.error_bound() {
+            Some(bound) => u64::max(bound, stored),
+            None => stored
+        };
+    }
+
+    // the position predicted for the key by the bottom model it is routed to
+    // (or by the top model, if that bottom model was pruned)
+    pub fn predict(&self, key: ModelInput) -> u64 {
+        let idx = self.route(key);
+        let key = self.model_input(key);
+        if let Some(pruned) = self.trained.pruned.as_ref().filter(|p| p.is_pruned(idx)) {
+            return pruned.predict(self.trained.rmi[0][0].as_ref(), key);
+        }
+
+        return self.trained.rmi[self.trained.rmi.len() - 1][idx].predict_to_int(key);
+    }
+
+    // finds the lower bound of the key in the sorted keys the RMI was
+    // trained on, by binary searching the error window around the
+    // prediction. Returns the position and the number of keys compared.
+    // If the key falls outside the window (e.g. it was not in the training
+    // data), the rest of the keys are searched as well.
+    pub fn lookup(&self, keys: &[u64], key: u64) -> (usize, usize) {
+        if keys.is_empty() {
+            return (0, 0);
+        }
+
+        let (lo, hi, probes) = self.search_window(keys, key);
+        let (pos, search_probes) = lower_bound_counting(&keys[lo..hi], key);
+        return (lo + pos, probes + search_probes);
+    }
+
+    // like lookup, for an RMI trained on float keys mapped by
+    // float_to_monotonic_u64 (as in ModelData::from_float_keys), whose
+    // mapped keys are given. A NaN or infinite key the policy rejects is an
+    // error, with the position the policy gives it. A clamped infinity gets
+    // the first or last position without a search, as in the generated
+    // lookup_float.
+    pub fn lookup_float(&self, keys: &[u64], key: f64,
+                        policy: NonFinitePolicy) -> Result<(usize, usize), OutOfRangeKey> {
+        if policy.rejects(key) {
+            return Err(OutOfRangeKey { pos: policy.position().map(|pos| pos as usize) });
+        }
+        if let Some(pos) = policy.clamped_position(key, keys.len() as u64) {
+            return Ok((pos as usize, 0));
+        }
+
+        return Ok(self.lookup(keys, float_to_monotonic_u64(key)));
+    }
+
+    // like lookup, for an RMI trained on string keys mapped by
+    // string_to_monotonic_u64 (as in ModelData::from_string_keys). Both the
+    // mapped keys and the sorted strings are given. The mapped keys find the
+    // strings that share the key's 8 byte prefix, and those are binary
+    // searched for the lower bound of the key itself.
+    pub fn lookup_string<S: AsRef<[u8]>>(&self, keys: &[u64], strings: &[S],
+                                         key: &[u8]) -> (usize, usize) {
+        assert_eq!(keys.len(), strings.len(), "every string needs its mapped key");
+        let prefix = string_to_monotonic_u64(key);
+        let (start, mut probes) = self.lookup(keys, prefix);
+        let end = start + keys[start..].partition_point(|k| *k == prefix);
+
+        // the strings with the same prefix
+        let (mut lo, mut hi) = (start, end);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            probes += 1;
+            if strings[mid].as_ref() < key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        return (lo, probes);
+    }
+
+    // like lookup, but with a position the caller expects the key to be
+    // near (e.g. the result of the previous lookup). If the hint is inside
+    // the error window, the search gallops outwards from it, which is
+    // faster than searching the whole window when the hint is close.
+    // Otherwise the hint is ignored. The result is the same either way.
+    pub fn lookup_hinted(&self, keys: &[u64], key: u64, hint: usize) -> (usize, usize) {
+        if keys.is_empty() {
+            return (0, 0);
+        }
+
+        let (mut lo, mut hi, mut probes) = self.search_window(keys, key);
+        if hint >= lo && hint < hi {
+            let mut step = 1;
+            probes += 1;
+            if keys[hint] < key {
+                // the lower bound is after the hint
+                let mut prev = hint;
+                while hint + step < hi {
+                    probes += 1;
+                    if keys[hint + step] >= key {
+                        hi = hint + step;
+                        break;
+                    }
+                    prev = hint + step;
+                    step *= 2;
+                }
+                lo = prev + 1;
+            } else {
+                // the lower bound is at or before the hint
+                let mut prev = hint;
+                while hint >= lo + step {
+                    probes += 1;
+                    if keys[hint - step] < key {
+                        lo = hint - step + 1;
+                        break;
+                    }
+                    prev = hint - step;
+                    step *= 2;
+                }
+                hi = prev;
+            }
+        }
+
+        let (pos, search_probes) = lower_bound_counting(&keys[lo..hi], key);
+        return (lo + pos, probes + search_probes);
+    }
+
+    // the tighter of two windows that hold the key's position: the error
+    // window around the prediction, and the hint_err positions on either
+    // side of the caller's hint. Returns a position and an error bound that
+    // cover the overlap of the two, or the prediction's own window if they
+    // do not overlap (the hint is wrong, so it is ignored).
+    pub fn hinted_window(&self, key: u64, hint: u64, hint_err: u64) -> (u64, u64) {
+        let guess = self.predict(key.into());
+        let err = self.error_bound_for(key.into());
+        let (lo, hi) = (guess.saturating_sub(err), guess.saturating_add(err));
+        let (hint_lo, hint_hi) = (hint.saturating_sub(hint_err), hint.saturating_add(hint_err));
+        if hint_lo > hi || hint_hi < lo {
+            return (guess, err);
+        }
+
+        let (lo, hi) = (u64::max(lo, hint_lo), u64::min(hi, hint_hi));
+        return (lo + (hi - lo) / 2, (hi - lo).div_ceil(2));
+    }
+
+    // the keys bracketing the key: the position of the greatest key <= key
+    // (its first position, if it is duplicated), and the position of the
+    // first key > key. Either is None if there is no such key, so a key
+    // below every key gives (None, Some(0)), one above every key gives
+    // (Some(last), None), and no keys give (None, None). The key is in the
+    // data if keys[lower] == key, and otherwise falls strictly between the
+    // two.
+    pub fn lookup_bracket(&self, keys: &[u64], key: u64) -> (Option<usize>, Option<usize>) {
+        if keys.is_empty() {
+            return (None, None);
+        }
+
+        let next_after = |pos: usize| {
+            let next = pos + keys[pos..].partition_point(|k| *k <= keys[pos]);
+            if next < keys.len() { Some(next) } else { None }
+        };
+
+        let (lower, _) = self.lookup(keys, key);
+        if lower < keys.len() && keys[lower] == key {
+            return (Some(lower), next_after(lower));
+        } else if lower == 0 {
+            return (None, Some(0));
+        }
+
+        let (pos, _) = self.lookup(keys, keys[lower - 1]);
+        return (Some(pos), if lower < keys.len() { Some(lower) } else { None });
+    }
+
+    // the range of keys that holds the key's lower bound: the error window
+    // around the prediction, or the keys before or after it if the key is
+    // outside of the window. Also returns the number of keys compared.
+    fn search_window(&self, keys: &[u64], key: u64) -> (usize, usize, usize) {
+        let pred = u64::min(self.predict(key.into()), keys.len() as u64 - 1) as usize;
+        let err = self.error_bound_for(key.into()) as usize;
+        let lo = pred.saturating_sub(err);
+        let hi = usize::min(keys.len(), pred.saturating_add(err).saturating_add(1));
+
+        if lo > 0 && keys[lo - 1] >= key {
+            return (0, lo, 1);
+        } else if hi < keys.len() && keys[hi - 1] < key {
+            return (hi, keys.len(), 1);
+        }
+
+        return (lo, hi, 0);
+    }
+}
+
+// a float key that lookup_float did not look up, and the position its
+// NonFinitePolicy gives it (None with NonFinitePolicy::Error)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutOfRangeKey {
+    pub pos: Option<usize>
+}
+
+fn lower_bound_counting(keys: &[u64], key: u64) -> (usize, usize) {
+    let mut probes = 0;
+    let (mut lo, mut hi) = (0, keys.len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        probes += 1;
+        if keys[mid] < key {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+
+    return (lo, probes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train::{train, train_with_options, TrainingOptions};
+
+    #[test]
+    fn test_error_bound_for() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let rmi = RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap()
+        );
+
+        let bounds: Vec<u64> = (0..1000)
+            .map(|i: u64| rmi.error_bound_for((i * i).into()))
+            .collect();
+
+        assert!(bounds.iter().all(|b| *b <= rmi.trained().model_max_error));
+        assert_eq!(*bounds.iter().max().unwrap(), rmi.trained().model_max_error);
+    }
+
+    #[test]
+    fn test_len() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        for spec in &["linear", "linear,linear", "cubic,linear,linear"] {
+            let rmi = RuntimeRMI::build(&data, spec, 16).unwrap();
+            assert_eq!(rmi.len(), 1000);
+            assert!(!rmi.is_empty());
+        }
+
+        // the keys, not the pages, with a position divisor
+        let options = TrainingOptions { position_divisor: Some(64), ..Default::default() };
+        let trained = train_with_options(&mut ModelDataWrapper::new(&data), "linear,linear", 16,
+                                         &options).unwrap();
+        assert_eq!(RuntimeRMI::new(trained).len(), 1000);
+    }
+
+    #[test]
+    fn test_route() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let rmi = RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap()
+        );
+
+        let top = &rmi.trained().rmi[0][0];
+        let routes: Vec<usize> = (0..1000).map(|i: u64| rmi.route((i * i).into())).collect();
+        for (i, route) in routes.iter().enumerate() {
+            let key = (i * i) as u64;
+            assert_eq!(*route as u64, u64::min(15, top.predict_to_int(key.into())));
+        }
+
+        // routes are monotonic in the key, and clamped to the last leaf
+        assert!(routes.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(rmi.route(u64::MAX.into()), 15);
+    }
+
+    #[test]
+    fn test_error_bound_for_pgm() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * 3, i)).collect());
+        let rmi = RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,pgm", 4).unwrap()
+        );
+
+        let expected = rmi.trained().rmi[1][0].error_bound().unwrap();
+        assert_eq!(rmi.error_bound_for(30.into()), expected);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let keys: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let rmi = RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap()
+        );
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(rmi.lookup(&keys, *key).0, i);
+        }
+
+        // keys that were not trained on still find their lower bound
+        assert_eq!(rmi.lookup(&keys, 2).0, 2);
+        assert_eq!(rmi.lookup(&keys, u64::MAX).0, keys.len());
+        assert_eq!(rmi.lookup(&[], 5), (0, 0));
+    }
+
+    #[test]
+    fn test_build() {
+        let keys: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let rmi = RuntimeRMI::build(&data, "linear,linear", 16).unwrap();
+        assert_eq!(rmi.trained().branching_factor, 16);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(rmi.lookup(&keys, *key).0, i);
+        }
+
+        assert!(matches!(RuntimeRMI::build(&ModelData::empty(), "linear,linear", 16),
+                         Err(TrainingError::EmptyData)));
+    }
+
+    #[test]
+    fn test_lookup_hinted() {
+        let keys: Vec<u64> = (0..1000).map(|i| (i / 2) * (i / 2)).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let rmi = RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap()
+        );
+
+        let (mut exact_probes, mut plain_probes) = (0, 0);
+        for (i, key) in keys.iter().enumerate() {
+            let expected = rmi.lookup(&keys, *key);
+            for hint in &[i, i.saturating_sub(3), i + 5, 0, 999, 5000] {
+                assert_eq!(rmi.lookup_hinted(&keys, *key, *hint).0, expected.0);
+            }
+            exact_probes += rmi.lookup_hinted(&keys, *key, i).1;
+            plain_probes += expected.1;
+
+            // absent keys, past either end of the window
+            assert_eq!(rmi.lookup_hinted(&keys, key + 1, i).0, rmi.lookup(&keys, key + 1).0);
+        }
+        assert!(exact_probes < plain_probes);
+        assert_eq!(rmi.lookup_hinted(&keys, u64::MAX, 3).0, keys.len());
+        assert_eq!(rmi.lookup_hinted(&[], 5, 3), (0, 0));
+    }
+
+    #[test]
+    fn test_hinted_window() {
+        let keys: Vec<u64> = (0..1000).map(|i| (i / 2) * (i / 2)).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let rmi = RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap()
+        );
+
+        for (i, key) in keys.iter().enumerate() {
+            // the first position of the key
+            let pos = rmi.lookup(&keys, *key).0 as u64;
+            let covers = |(guess, err): (u64, u64)| {
+                u64::max(guess, pos) - u64::min(guess, pos) <= err
+            };
+            let model = (rmi.predict((*key).into()), rmi.error_bound_for((*key).into()));
+
+            // a close hint narrows the window to its own
+            let (guess, err) = rmi.hinted_window(*key, i as u64, 2);
+            assert!(covers((guess, err)));
+            assert!(err <= u64::min(2, model.1));
+
+            // a loose hint leaves the model's window, and a wrong one is ignored
+            let loose = rmi.hinted_window(*key, i as u64, 100_000);
+            assert!(covers(loose) && loose.1 <= model.1);
+            assert_eq!(rmi.hinted_window(*key, i as u64 + 100_000, 1), model);
+        }
+    }
+
+    #[test]
+    fn test_lookup_bracket() {
+        let keys: Vec<u64> = vec![10, 20, 20, 20, 30, 40];
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let rmi = RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,linear", 2).unwrap()
+        );
+
+        // exact matches
+        assert_eq!(rmi.lookup_bracket(&keys, 10), (Some(0), Some(1)));
+        assert_eq!(rmi.lookup_bracket(&keys, 20), (Some(1), Some(4)));
+        assert_eq!(rmi.lookup_bracket(&keys, 40), (Some(5), None));
+
+        // between keys
+        assert_eq!(rmi.lookup_bracket(&keys, 25), (Some(1), Some(4)));
+        assert_eq!(rmi.lookup_bracket(&keys, 35), (Some(4), Some(5)));
+
+        // outside of the keys
+        assert_eq!(rmi.lookup_bracket(&keys, 5), (None, Some(0)));
+        assert_eq!(rmi.lookup_bracket(&keys, 50), (Some(5), None));
+        assert_eq!(rmi.lookup_bracket(&[], 5), (None, None));
+    }
+
+    #[test]
+    fn test_lookup_float() {
+        let floats: Vec<f64> = (0..1000).map(|i| (i as f64 - 500.0) * 0.75).collect();
+        let data = ModelData::from_float_keys(&floats);
+        let keys: Vec<u64> = data.iter_int_int().map(|(k, _)| k).collect();
+        let rmi = RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap()
+        );
+
+        for policy in &[NonFinitePolicy::default(), NonFinitePolicy::Sentinel(7),
+                        NonFinitePolicy::Clamp, NonFinitePolicy::Error] {
+            for (i, key) in floats.iter().enumerate() {
+                assert_eq!(rmi.lookup_float(&keys, *key, *policy).unwrap().0, i);
+            }
+            assert_eq!(rmi.lookup_float(&keys, -0.1, *policy).unwrap().0, 500);
+        }
+
+        // by default, a non-finite key is out of range at position 0
+        let out_of_range = |pos| Err(OutOfRangeKey { pos });
+        for key in &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert_eq!(rmi.lookup_float(&keys, *key, NonFinitePolicy::default()),
+                       out_of_range(Some(0)));
+            assert_eq!(rmi.lookup_float(&keys, *key, NonFinitePolicy::Sentinel(7)),
+                       out_of_range(Some(7)));
+            assert_eq!(rmi.lookup_float(&keys, *key, NonFinitePolicy::Error),
+                       out_of_range(None));
+        }
+
+        // clamped infinities go to either end of the keys
+        let clamp = NonFinitePolicy::Clamp;
+        assert_eq!(rmi.lookup_float(&keys, f64::NAN, clamp), out_of_range(Some(0)));
+        assert_eq!(rmi.lookup_float(&keys, f64::NEG_INFINITY, clamp).unwrap().0, 0);
+        assert_eq!(rmi.lookup_float(&keys, f64::INFINITY, clamp).unwrap().0, keys.len() - 1);
+    }
+
+    #[test]
+    fn test_lookup_string() {
+        // many strings share their first 8 bytes
+        let mut strings: Vec<String> = (0..300).map(|i| format!("key{:05}", i * 7)).collect();
+        strings.extend((0..300).map(|i| format!("prefixed/{:04}", i)));
+        strings.extend((0..300).map(|i| format!("z{:03}", i)));
+        strings.sort();
+        let data = ModelData::from_string_keys(&strings);
+        let keys: Vec<u64> = data.iter_int_int().map(|(k, _)| k).collect();
+        let rmi = RuntimeRMI::new(
+            train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap()
+        );
+
+        for (i, key) in strings.iter().enumerate() {
+            assert_eq!(rmi.lookup_string(&keys, &strings, key.as_bytes()).0, i);
+        }
+
+        // absent strings find their lower bound
+        for key in &["", "key00001", "prefixed/", "prefixed/0150x", "prefixed0", "zz"] {
+            let expected = strings.partition_point(|s| s.as_str() < *key);
+            assert_eq!(rmi.lookup_string(&keys, &strings, key.as_bytes()).0, expected, "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_key_transform() {
+        // timestamps in milliseconds, one per second, after an epoch
+        let epoch = 1_600_000_000_000;
+        let keys: Vec<u64> = (0..1000).map(|i| epoch + i * 1000 + (i % 3) * 7).collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let options = TrainingOptions {
+            key_transform: Some(KeyTransform::new(epoch, 1000)),
+            ..Default::default()
+        };
+        let rmi = RuntimeRMI::new(
+            train_with_options(&mut ModelDataWrapper::new(&data), "linear,linear", 16,
+                               &options).unwrap()
+        );
+
+        // the models were fit to the seconds since the epoch, which are exact
+        assert_eq!(rmi.trained().model_max_error, 0);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(rmi.predict((*key).into()), i as u64);
+            assert_eq!(rmi.lookup(&keys, *key).0, i);
+        }
+        assert_eq!(rmi.lookup(&keys, 5).0, 0);
+    }
+}
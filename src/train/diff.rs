@@ -0,0 +1,217 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+use crate::models::*;
+use crate::train::TrainedRMI;
+
+// how one element of a parameter changed
+#[derive(Clone, Debug, PartialEq)]
+pub enum Delta {
+    // integers are compared exactly
+    Int { old: u64, new: u64 },
+
+    // abs is new - old, and rel is abs / |old| (None if old is 0)
+    Float { old: f64, new: f64, abs: f64, rel: Option<f64> }
+}
+
+// a changed element of a parameter of a model in an RMI
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamDelta {
+    pub layer: usize,
+    pub model: usize,
+    pub param: usize,
+    pub element: usize,
+    pub delta: Delta
+}
+
+// a difference in the shape of two RMIs. The parameters of models (or
+// parameters) that differ in shape are not compared.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StructuralDiff {
+    NumLayers { old: usize, new: usize },
+    LayerSize { layer: usize, old: usize, new: usize },
+    ModelType { layer: usize, model: usize, old: String, new: String },
+    NumParams { layer: usize, model: usize, old: usize, new: usize },
+    ParamType { layer: usize, model: usize, param: usize },
+    ParamLength { layer: usize, model: usize, param: usize, old: usize, new: usize }
+}
+
+// the differences between the parameters of two trained RMIs (see
+// diff_params)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParamDiff {
+    pub changes: Vec<ParamDelta>,
+    pub structural: Vec<StructuralDiff>
+}
+
+impl ParamDiff {
+    // true if the RMIs have the same shape and parameters
+    pub fn is_empty(&self) -> bool {
+        return self.changes.is_empty() && self.structural.is_empty();
+    }
+
+    // the largest relative change of a float parameter, if any changed
+    // from a non-zero value
+    pub fn max_relative_change(&self) -> Option<f64> {
+        return self.changes.iter()
+            .filter_map(|change| match change.delta {
+                Delta::Float { rel: Some(rel), .. } => Some(rel.abs()),
+                _ => None
+            })
+            .fold(None, |max, rel| Some(f64::max(max.unwrap_or(rel), rel)));
+    }
+}
+
+enum Values {
+    Ints(Vec<u64>),
+    Floats(Vec<f64>)
+}
+
+fn values(param: &ModelParam) -> Values {
+    return match param {
+        ModelParam::Int(v) => Values::Ints(vec![*v]),
+        ModelParam::Float(v) => Values::Floats(vec![*v]),
+        ModelParam::ShortArray(a) => Values::Ints(a.iter().map(|v| u64::from(*v)).collect()),
+        ModelParam::IntArray(a) => Values::Ints(a.clone()),
+        ModelParam::Int32Array(a) => Values::Ints(a.iter().map(|v| u64::from(*v)).collect()),
+        ModelParam::FloatArray(a) => Values::Floats(a.clone())
+    };
+}
+
+// the changed elements of two parameters of the same type and length
+fn element_deltas(old: &ModelParam, new: &ModelParam) -> Vec<(usize, Delta)> {
+    return match (values(old), values(new)) {
+        (Values::Ints(old), Values::Ints(new)) => old.into_iter().zip(new)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(idx, (old, new))| (idx, Delta::Int { old, new }))
+            .collect(),
+        (Values::Floats(old), Values::Floats(new)) => old.into_iter().zip(new)
+            .enumerate()
+            .filter(|(_, (old, new))| old.to_bits() != new.to_bits())
+            .map(|(idx, (old, new))| {
+                let abs = new - old;
+                let rel = if old == 0.0 { None } else { Some(abs / old.abs()) };
+                (idx, Delta::Float { old, new, abs, rel })
+            })
+            .collect(),
+        _ => panic!("Cannot compare parameters of different types")
+    };
+}
+
+// compares the parameters of two trained RMIs, such as before and after a
+// change to training. Models are matched by layer and index. Each element
+// that changed is reported with its old and new value, and differences in
+// shape (the number of layers or models, model types, or the number, types,
+// and lengths of parameters) are reported instead of the parameters they
+// affect.
+pub fn diff_params(old: &TrainedRMI, new: &TrainedRMI) -> ParamDiff {
+    let mut diff = ParamDiff::default();
+    if old.rmi.len() != new.rmi.len() {
+        diff.structural.push(StructuralDiff::NumLayers { old: old.rmi.len(),
+                                                         new: new.rmi.len() });
+    }
+
+    for (layer, (l1, l2)) in old.rmi.iter().zip(new.rmi.iter()).enumerate() {
+        if l1.len() != l2.len() {
+            diff.structural.push(StructuralDiff::LayerSize { layer, old: l1.len(),
+                                                             new: l2.len() });
+        }
+
+        for (model, (m1, m2)) in l1.iter().zip(l2.iter()).enumerate() {
+            if m1.function_name() != m2.function_name() {
+                diff.structural.push(StructuralDiff::ModelType {
+                    layer, model, old: m1.function_name(), new: m2.function_name()
+                });
+                continue;
+            }
+
+            let (p1, p2) = (m1.params(), m2.params());
+            if p1.len() != p2.len() {
+                diff.structural.push(StructuralDiff::NumParams { layer, model, old: p1.len(),
+                                                                 new: p2.len() });
+                continue;
+            }
+
+            for (param, (v1, v2)) in p1.iter().zip(p2.iter()).enumerate() {
+                if !v1.is_same_type(v2) {
+                    diff.structural.push(StructuralDiff::ParamType { layer, model, param });
+                } else if v1.len() != v2.len() {
+                    diff.structural.push(StructuralDiff::ParamLength {
+                        layer, model, param, old: v1.len(), new: v2.len()
+                    });
+                } else {
+                    diff.changes.extend(element_deltas(v1, v2).into_iter().map(
+                        |(element, delta)| ParamDelta { layer, model, param, element, delta }
+                    ));
+                }
+            }
+        }
+    }
+
+    return diff;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train::train;
+
+    #[test]
+    fn test_diff_params() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let trained = || train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+        assert!(diff_params(&trained(), &trained()).is_empty());
+
+        // one more key moves the top line and some of the bottom lines
+        let shifted = ModelData::IntKeyToIntPos((0..1001).map(|i| (i * i, i)).collect());
+        let other = train(&mut ModelDataWrapper::new(&shifted), "linear,linear", 8).unwrap();
+        let diff = diff_params(&trained(), &other);
+        assert!(diff.structural.is_empty());
+        assert!(!diff.changes.is_empty());
+
+        let old_top = trained().rmi[0][0].params();
+        let new_top = other.rmi[0][0].params();
+        let top_changes: Vec<&ParamDelta> = diff.changes.iter()
+            .filter(|c| c.layer == 0)
+            .collect();
+        assert_eq!(top_changes.len(), 2);
+        for change in top_changes {
+            assert_eq!((change.model, change.element), (0, 0));
+            let (old, new) = (old_top[change.param].as_float(),
+                              new_top[change.param].as_float());
+            assert_eq!(change.delta, Delta::Float { old, new, abs: new - old,
+                                                   rel: Some((new - old) / old.abs()) });
+        }
+        assert!(diff.max_relative_change().unwrap() > 0.0);
+    }
+
+    #[test]
+    fn test_diff_params_structure() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let linear = train(&mut ModelDataWrapper::new(&data), "linear,linear", 8).unwrap();
+        let cubic = train(&mut ModelDataWrapper::new(&data), "cubic,linear", 4).unwrap();
+        let diff = diff_params(&linear, &cubic);
+        assert_eq!(diff.structural, vec![
+            StructuralDiff::ModelType { layer: 0, model: 0, old: String::from("linear"),
+                                        new: String::from("cubic") },
+            StructuralDiff::LayerSize { layer: 1, old: 8, new: 4 }
+        ]);
+
+        let single = train(&mut ModelDataWrapper::new(&data), "linear", 1).unwrap();
+        assert_eq!(diff_params(&linear, &single).structural[0],
+                   StructuralDiff::NumLayers { old: 2, new: 1 });
+
+        // integer parameters are compared exactly
+        assert_eq!(element_deltas(&ModelParam::Int(3), &ModelParam::Int(5)),
+                   vec![(0, Delta::Int { old: 3, new: 5 })]);
+        assert_eq!(element_deltas(&ModelParam::FloatArray(vec![0.0, 2.0, 4.0]),
+                                  &ModelParam::FloatArray(vec![1.0, 2.0, 3.0])),
+                   vec![(0, Delta::Float { old: 0.0, new: 1.0, abs: 1.0, rel: None }),
+                        (2, Delta::Float { old: 4.0, new: 3.0, abs: -1.0, rel: Some(-0.25) })]);
+    }
+}
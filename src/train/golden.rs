@@ -0,0 +1,158 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+
+// Accuracy regression tests: each built-in model is trained as a single
+// layer index over a few fixed, representative datasets, and its max log2
+// error must stay within a band around the recorded value. A change that
+// moves a value out of its band changed the fitting math; if the change is
+// intended, update the recorded value.
+
+use crate::models::*;
+use crate::train::train_single_layer;
+
+const NUM_KEYS: usize = 10_000;
+
+// a fixed-seed xorshift, returning values uniform in [0, 1)
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        return (self.0 >> 11) as f64 / (1u64 << 53) as f64;
+    }
+
+    // standard normal, by the Box-Muller transform
+    fn normal(&mut self) -> f64 {
+        let u1 = f64::max(self.next(), f64::MIN_POSITIVE);
+        let u2 = self.next();
+        return (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+    }
+}
+
+fn fixture(name: &str) -> ModelData<'static> {
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    let mut keys: Vec<u64> = (0..NUM_KEYS).map(|i| {
+        let key = match name {
+            "uniform" => rng.next() * 1e12,
+            "normal" => 1e12 + rng.normal() * 1e10,
+            "lognormal" => (rng.normal() * 2.0).exp() * 1e6,
+            "clustered" => (i % 10) as f64 * 1e11 + rng.next() * 1e6,
+            "skewed" => rng.next().powi(4) * 1e12,
+            _ => panic!("Unknown fixture: {}", name)
+        };
+        key as u64
+    }).collect();
+    keys.sort_unstable();
+
+    return ModelData::IntKeyToIntPos(keys.into_iter().enumerate()
+                                     .map(|(pos, key)| (key, pos as u64)).collect());
+}
+
+const FIXTURES: &[&str] = &["uniform", "normal", "lognormal", "clustered", "skewed"];
+
+// plr and pgm are left out, since their error is bounded by construction
+const MODELS: &[&str] = &["linear", "linear_minimax", "robust_linear", "linear_spline", "cubic",
+                          "linear_clamped", "cubic_clamped", "loglinear", "normal", "lognormal",
+                          "exponential", "radix_eq", "lookup_table12"];
+
+// (fixture, model, max log2 error)
+const GOLDEN: &[(&str, &str, f64)] = &[
+    ("uniform", "linear", 7.04),
+    ("uniform", "linear_minimax", 6.86),
+    ("uniform", "robust_linear", 7.04),
+    ("uniform", "linear_spline", 7.51),
+    ("uniform", "cubic", 7.51),
+    ("uniform", "linear_clamped", 7.04),
+    ("uniform", "cubic_clamped", 7.51),
+    ("uniform", "loglinear", 12.62),
+    ("uniform", "normal", 10.32),
+    ("uniform", "lognormal", 11.64),
+    ("uniform", "exponential", 11.60),
+    ("uniform", "radix_eq", 1.00),
+    ("uniform", "lookup_table12", 3.00),
+    ("normal", "linear", 13.87),
+    ("normal", "linear_minimax", 11.97),
+    ("normal", "robust_linear", 13.87),
+    ("normal", "linear_spline", 12.51),
+    ("normal", "cubic", 12.18),
+    ("normal", "linear_clamped", 13.87),
+    ("normal", "cubic_clamped", 12.18),
+    ("normal", "loglinear", 16.88),
+    ("normal", "normal", 8.47),
+    ("normal", "lognormal", 8.39),
+    ("normal", "exponential", 12.92),
+    ("normal", "radix_eq", 1.00),
+    ("normal", "lookup_table12", 3.81),
+    ("lognormal", "linear", 15.99),
+    ("lognormal", "linear_minimax", 13.25),
+    ("lognormal", "robust_linear", 17.99),
+    ("lognormal", "linear_spline", 14.25),
+    ("lognormal", "cubic", 14.22),
+    ("lognormal", "linear_clamped", 15.99),
+    ("lognormal", "cubic_clamped", 14.22),
+    ("lognormal", "loglinear", 21.79),
+    ("lognormal", "normal", 14.29),
+    ("lognormal", "lognormal", 8.47),
+    ("lognormal", "exponential", 13.04),
+    ("lognormal", "radix_eq", 2.00),
+    ("lognormal", "lookup_table12", 12.14),
+    ("clustered", "linear", 9.97),
+    ("clustered", "linear_minimax", 9.97),
+    ("clustered", "robust_linear", 9.97),
+    ("clustered", "linear_spline", 10.97),
+    ("clustered", "cubic", 10.97),
+    ("clustered", "linear_clamped", 9.97),
+    ("clustered", "cubic_clamped", 10.97),
+    ("clustered", "loglinear", 12.83),
+    ("clustered", "normal", 11.12),
+    ("clustered", "lognormal", 12.96),
+    ("clustered", "exponential", 11.88),
+    ("clustered", "radix_eq", 3.00),
+    ("clustered", "lookup_table12", 10.96),
+    ("skewed", "linear", 12.61),
+    ("skewed", "linear_minimax", 12.19),
+    ("skewed", "robust_linear", 12.61),
+    ("skewed", "linear_spline", 13.19),
+    ("skewed", "cubic", 12.71),
+    ("skewed", "linear_clamped", 12.61),
+    ("skewed", "cubic_clamped", 12.71),
+    ("skewed", "loglinear", 13.18),
+    ("skewed", "normal", 12.14),
+    ("skewed", "lognormal", 11.63),
+    ("skewed", "exponential", 12.47),
+    ("skewed", "radix_eq", 6.46),
+    ("skewed", "lookup_table12", 10.19),
+];
+
+const TOLERANCE: f64 = 0.25;
+
+#[test]
+fn test_golden() {
+    assert_eq!(GOLDEN.len(), FIXTURES.len() * MODELS.len());
+
+    let mut failures = Vec::new();
+    for fixture_name in FIXTURES {
+        let data = fixture(fixture_name);
+        for model in MODELS {
+            let (_, _, expected) = GOLDEN.iter()
+                .find(|(f, m, _)| f == fixture_name && m == model)
+                .unwrap_or_else(|| panic!("No golden value for {} on {}", model, fixture_name));
+
+            let rmi = train_single_layer(&mut ModelDataWrapper::new(&data), model).unwrap();
+            assert_eq!(rmi.models, *model);
+            let err = rmi.model_max_log2_error;
+            if (err - expected).abs() > TOLERANCE {
+                failures.push(format!("{} on {}: max log2 error {:.2}, expected {:.2}",
+                                      model, fixture_name, err, expected));
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "accuracy changed:\n{}", failures.join("\n"));
+}
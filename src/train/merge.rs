@@ -0,0 +1,257 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+use crate::models::*;
+use crate::train::{train_model, exact_fraction, log2_window, ErrorPercentiles, TrainedRMI};
+use log::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// groups adjacent buckets until there are at most max_groups, always merging
+// the adjacent pair of groups with the fewest keys between them (the
+// leftmost, on a tie). Empty buckets are merged first, then the near-empty
+// ones, whose models are often constant. Returns the group of each bucket.
+fn group_buckets(sizes: &[u64], max_groups: usize) -> Vec<u64> {
+    let n = sizes.len();
+
+    // each group is identified by its first bucket
+    let mut size = sizes.to_vec();
+    let mut next: Vec<usize> = (1..=n).collect();
+    let mut prev: Vec<usize> = (0..n).map(|i| i.wrapping_sub(1)).collect();
+    let mut alive = vec![true; n];
+
+    let mut candidates: BinaryHeap<Reverse<(u64, usize)>> = (0..n.saturating_sub(1))
+        .map(|i| Reverse((size[i] + size[i + 1], i)))
+        .collect();
+
+    let mut num_groups = n;
+    while num_groups > max_groups {
+        let Reverse((combined, left)) = candidates.pop().unwrap();
+        let right = next[left];
+
+        // skip pairs that have changed since they were queued
+        if !alive[left] || right == n || size[left] + size[right] != combined {
+            continue;
+        }
+
+        size[left] = combined;
+        alive[right] = false;
+        next[left] = next[right];
+        if next[left] != n {
+            prev[next[left]] = left;
+            candidates.push(Reverse((size[left] + size[next[left]], left)));
+        }
+        if left != 0 {
+            candidates.push(Reverse((size[prev[left]] + size[left], prev[left])));
+        }
+        num_groups -= 1;
+    }
+
+    let mut groups = Vec::with_capacity(n);
+    let mut group = 0;
+    for (bucket, is_start) in alive.iter().enumerate() {
+        if *is_start && bucket != 0 {
+            group += 1;
+        }
+        groups.push(group);
+    }
+    return groups;
+}
+
+// the keys of several buckets, in order (the top model need not route them
+// monotonically), as float keys or integer keys
+fn sorted_data(pairs: Vec<(ModelInput, u64)>, float_keys: bool) -> ModelData<'static> {
+    if float_keys {
+        let mut pairs: Vec<(f64, u64)> = pairs.into_iter()
+            .map(|(x, y)| (x.as_float(), y)).collect();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        return ModelData::FloatKeyToIntPos(pairs);
+    }
+
+    let mut pairs: Vec<(u64, u64)> = pairs.into_iter().map(|(x, y)| (x.as_int(), y)).collect();
+    pairs.sort_unstable();
+    return ModelData::IntKeyToIntPos(pairs);
+}
+
+// merges adjacent bottom models until at most max_models are stored. The
+// top model still selects one of the original buckets, which is mapped to
+// its stored model through rmi.leaf_map. Each merged model is retrained on
+// the keys of all of its buckets; a bucket that is not merged keeps its
+// model and its stored error. Only two layer RMIs without pruned leaves
+// are merged.
+pub fn merge_leaves(rmi: &mut TrainedRMI, data: &ModelDataWrapper, max_models: usize) {
+    assert!(max_models >= 1, "at least one bottom model must be stored");
+    if rmi.rmi.len() != 2 || rmi.pruned.is_some() {
+        info!("Merging bottom models only applies to two layer RMIs without pruned leaves, \
+               skipping");
+        return;
+    }
+
+    let num_leaves = rmi.rmi[1].len();
+    if num_leaves <= max_models {
+        return;
+    }
+
+    let top = &rmi.rmi[0][0];
+    let mut buckets: Vec<Vec<(ModelInput, u64)>> = vec![Vec::new(); num_leaves];
+    for (x, y) in data.iter_input_int() {
+        let leaf = u64::min(num_leaves as u64 - 1, top.predict_to_int(x)) as usize;
+        buckets[leaf].push((x, y));
+    }
+
+    let sizes: Vec<u64> = buckets.iter().map(|b| b.len() as u64).collect();
+    let leaf_map = group_buckets(&sizes, max_models);
+    let num_groups = *leaf_map.last().unwrap() as usize + 1;
+
+    let model_type = rmi.models.rsplit(',').next().unwrap().to_string();
+    let mut old_models = std::mem::take(&mut rmi.rmi[1]).into_iter();
+    let mut models: Vec<Box<dyn Model>> = Vec::with_capacity(num_groups);
+    let mut max_errors = Vec::with_capacity(num_groups);
+    let mut leaf_sizes = Vec::with_capacity(num_groups);
+    let mut all_errors = Vec::new();
+    let mut num_exact: u64 = 0;
+
+    let mut start = 0;
+    for group in 0..num_groups {
+        let end = leaf_map[start..].iter().position(|g| *g != group as u64)
+            .map(|len| start + len).unwrap_or(num_leaves);
+
+        let (model, stored_error) = if end - start == 1 {
+            (old_models.next().unwrap(), Some(rmi.last_layer_max_l1s[start]))
+        } else {
+            for _ in start..end {
+                old_models.next();
+            }
+            let md = sorted_data(buckets[start..end].concat(), data.has_float_keys());
+            (train_model(&model_type, &ModelDataWrapper::new(&md)), None)
+        };
+
+        let mut max_error = 0;
+        for (x, y) in buckets[start..end].iter().flatten() {
+            let pred = model.predict_to_int(*x);
+            let err = u64::max(pred, *y) - u64::min(pred, *y);
+            max_error = u64::max(max_error, err);
+            all_errors.push(err);
+            if err == 0 {
+                num_exact += 1;
+            }
+        }
+
+        models.push(model);
+        max_errors.push(stored_error.unwrap_or(max_error));
+        leaf_sizes.push(sizes[start..end].iter().sum::<u64>());
+        start = end;
+    }
+
+    let num_rows = data.len() as f64;
+    let (max_idx, max_error) = max_errors.iter().enumerate()
+        .max_by_key(|(idx, err)| (**err, Reverse(*idx)))
+        .map(|(idx, err)| (idx, *err))
+        .unwrap();
+    rmi.model_max_error = max_error;
+    rmi.model_max_error_idx = max_idx;
+    rmi.model_avg_error = leaf_sizes.iter().zip(max_errors.iter())
+        .map(|(n, err)| n * err).sum::<u64>() as f64 / num_rows;
+    rmi.model_avg_l2_error = leaf_sizes.iter().zip(max_errors.iter())
+        .map(|(n, err)| ((n * err) as f64).powf(2.0) / num_rows).sum::<f64>();
+    rmi.model_avg_log2_error = leaf_sizes.iter().zip(max_errors.iter())
+        .map(|(n, err)| (*n as f64) * log2_window(*err)).sum::<f64>() / num_rows;
+    rmi.model_max_log2_error = log2_window(max_error);
+    if rmi.error_percentiles.is_some() {
+        rmi.error_percentiles = Some(ErrorPercentiles::from_errors(all_errors));
+    }
+    rmi.exact_fraction = exact_fraction(num_exact, data.len());
+
+    info!("Merged {} bottom models into {}", num_leaves, num_groups);
+    rmi.rmi[1] = models;
+    rmi.last_layer_max_l1s = max_errors;
+    rmi.leaf_sizes = leaf_sizes;
+    rmi.leaf_map = Some(leaf_map);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train::{train, train_with_options, TrainingOptions};
+    use crate::runtime::RuntimeRMI;
+
+    #[test]
+    fn test_group_buckets() {
+        assert_eq!(group_buckets(&[5, 0, 0, 7, 1, 9], 6), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(group_buckets(&[5, 0, 0, 7, 1, 9], 4), vec![0, 0, 0, 1, 2, 3]);
+        assert_eq!(group_buckets(&[5, 0, 0, 7, 1, 9], 3), vec![0, 0, 0, 1, 1, 2]);
+        assert_eq!(group_buckets(&[5, 0, 0, 7, 1, 9], 1), vec![0; 6]);
+        assert_eq!(group_buckets(&[3], 1), vec![0]);
+    }
+
+    #[test]
+    fn test_merge_leaves() {
+        // two dense clusters far apart: most of the buckets between them
+        // are empty
+        let keys: Vec<u64> = (0..2000).chain((0..2000).map(|i| 1_000_000_000 + i * 3))
+            .collect();
+        let md = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                           .map(|(i, k)| (*k, i as u64)).collect());
+        let container = ModelDataWrapper::new(&md);
+
+        let plain = train(&mut ModelDataWrapper::new(&md), "linear,linear", 256).unwrap();
+        assert_eq!(plain.rmi[1].len(), 256);
+        assert!(plain.leaf_map.is_none());
+        assert!(plain.bucket_occupancy_stats().empty_count > 100);
+
+        let opts = TrainingOptions { max_leaf_models: Some(16), ..Default::default() };
+        let rmi = train_with_options(&mut ModelDataWrapper::new(&md), "linear,linear", 256,
+                                     &opts).unwrap();
+        assert_eq!(rmi.branching_factor, 256);
+        assert_eq!(rmi.rmi[1].len(), 16);
+        assert_eq!(rmi.last_layer_max_l1s.len(), 16);
+        assert_eq!(rmi.leaf_sizes.iter().sum::<u64>(), keys.len() as u64);
+        let leaf_map = rmi.leaf_map.as_ref().unwrap();
+        assert_eq!(leaf_map.len(), 256);
+        assert!(leaf_map.windows(2).all(|w| w[1] == w[0] || w[1] == w[0] + 1));
+
+        // every stored error holds, and lookups find every key
+        let rmi = RuntimeRMI::new(rmi);
+        for (x, y) in container.iter_int_int() {
+            let pred = rmi.predict(x.into());
+            assert!(u64::max(pred, y) - u64::min(pred, y) <= rmi.error_bound_for(x.into()));
+            assert_eq!(rmi.lookup(&keys, x).0 as u64, y);
+        }
+
+        // under the cap already, so nothing is merged
+        let opts = TrainingOptions { max_leaf_models: Some(256), ..Default::default() };
+        let rmi = train_with_options(&mut ModelDataWrapper::new(&md), "linear,linear", 256,
+                                     &opts).unwrap();
+        assert!(rmi.leaf_map.is_none());
+        assert!(rmi.approx_eq(&plain, 0.0).is_ok());
+    }
+
+    #[test]
+    fn test_merge_float_keys() {
+        // sixteen keys per integer, so a merged line fit to the truncated
+        // keys would be off by about 8
+        let keys: Vec<f64> = (0..2000).map(|i| i as f64 / 16.0)
+            .chain((0..2000).map(|i| 1e9 + i as f64 / 16.0))
+            .collect();
+        let md = ModelData::FloatKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let container = ModelDataWrapper::new(&md);
+
+        let opts = TrainingOptions { max_leaf_models: Some(2), ..Default::default() };
+        let rmi = train_with_options(&mut ModelDataWrapper::new(&md), "linear,linear", 256,
+                                     &opts).unwrap();
+        assert_eq!(rmi.rmi[1].len(), 2);
+        assert!(rmi.last_layer_max_l1s.iter().all(|err| *err <= 1), "{:?}",
+                rmi.last_layer_max_l1s);
+
+        let rmi = RuntimeRMI::new(rmi);
+        for (x, y) in container.iter_input_int() {
+            let pred = rmi.predict(x);
+            assert!(u64::max(pred, y) - u64::min(pred, y) <= rmi.error_bound_for(x));
+        }
+    }
+}
@@ -7,10 +7,238 @@
  
 
 use crate::models::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use log::*;
+use json::{object, JsonValue};
 
 mod two_layer;
 mod multi_layer;
 mod lower_bound_correction;
+mod registry;
+mod prune;
+mod merge;
+mod diff;
+#[cfg(test)]
+mod golden;
+
+#[allow(unused_imports)]
+pub use registry::{register_model, registered_names, ModelConstructor};
+pub use prune::PrunedLeaves;
+#[allow(unused_imports)]
+pub use diff::{diff_params, Delta, ParamDelta, ParamDiff, StructuralDiff};
+
+#[derive(Debug)]
+pub enum TrainingError {
+    // a fitted model ended up with a NaN or infinite parameter, which would
+    // produce garbage predictions (and uncompilable code)
+    NonFiniteParameter { model: String, layer: usize, index: usize, value: f64 },
+
+    // with strict diagnostics, a bottom model had a larger maximum error
+    // than always predicting the midpoint of its positions
+    WorseThanConstant { model: String, index: usize, max_error: u64, baseline: u64 },
+
+    // there is nothing to train on
+    EmptyData,
+
+    // key transforms are integer arithmetic, so they cannot apply to float keys
+    FloatKeyTransform,
+
+    // the position of the key at this index is smaller than the one before
+    // it, and the bottom models are not built for noisy positions
+    DecreasingPositions { index: usize }
+}
+
+impl fmt::Display for TrainingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrainingError::NonFiniteParameter { model, layer, index, value } =>
+                write!(f, "model {} (layer {}, index {}) has a non-finite parameter ({}); \
+                           check the input data for NaN or infinite keys",
+                       model, layer, index, value),
+            TrainingError::WorseThanConstant { model, index, max_error, baseline } =>
+                write!(f, "bottom model {} at index {} has a max error of {}, \
+                           worse than the constant baseline of {}",
+                       model, index, max_error, baseline),
+            TrainingError::EmptyData =>
+                write!(f, "cannot train an RMI on an empty dataset"),
+            TrainingError::FloatKeyTransform =>
+                write!(f, "key transforms only apply to integer keys"),
+            TrainingError::DecreasingPositions { index } =>
+                write!(f, "the position of key {} is smaller than the one before it; only \
+                           bottom models for noisy positions ({}) accept that",
+                       index, NOISY_POSITION_MODELS.join(", "))
+        }
+    }
+}
+
+// extra room added to the stored error bound of each bottom model, so
+// that lookups stay correct after a limited number of inserts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorSlack {
+    // add a fixed number of positions. Each inserted key shifts the
+    // position of later keys by one, so this tolerates that many inserts.
+    Absolute(u64),
+
+    // grow each bound by a percentage of itself. This is only a heuristic:
+    // a model with a small bound gets little slack, and no insert count
+    // is guaranteed to be covered.
+    Percent(f64)
+}
+
+impl ErrorSlack {
+    // parses either a number of positions ("16") or a percentage ("5%")
+    pub fn parse(s: &str) -> Option<ErrorSlack> {
+        if let Some(pct) = s.strip_suffix('%') {
+            return pct.trim().parse::<f64>().ok()
+                .filter(|p| p.is_finite() && *p >= 0.0)
+                .map(ErrorSlack::Percent);
+        }
+
+        return s.trim().parse::<u64>().ok().map(ErrorSlack::Absolute);
+    }
+
+    pub fn pad(&self, err: u64) -> u64 {
+        return match self {
+            ErrorSlack::Absolute(slack) => err.saturating_add(*slack),
+            ErrorSlack::Percent(pct) =>
+                err.saturating_add((err as f64 * pct / 100.0).ceil() as u64)
+        };
+    }
+}
+
+// whether to compare each bottom model against a constant model
+// predicting the midpoint of its positions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Diagnostics {
+    Off,
+    // log a warning for each bottom model worse than its baseline
+    Warn,
+    // fail training if any bottom model is worse than its baseline
+    Strict
+}
+
+impl Default for Diagnostics {
+    fn default() -> Diagnostics {
+        return Diagnostics::Off;
+    }
+}
+
+// optional extra work to do while training
+#[derive(Clone, Default)]
+pub struct TrainingOptions {
+    // record the error of every key to compute error percentiles
+    // (uses an extra 8 bytes of memory per key)
+    pub error_percentiles: bool,
+
+    // widen the stored last layer errors
+    pub error_slack: Option<ErrorSlack>,
+
+    // compare bottom models against a constant baseline
+    pub diagnostics: Diagnostics,
+
+    // replace bottom models that are no more accurate than the top model
+    // with the top model's own prediction (two layer RMIs only)
+    pub prune_leaves: bool,
+
+    // store at most this many bottom models, by merging adjacent ones
+    // (two layer RMIs without pruned leaves only)
+    pub max_leaf_models: Option<usize>,
+
+    // if the keys are a dense integer range, train a single dense_range
+    // model (which is exact) instead of the requested RMI
+    pub detect_dense_ranges: bool,
+
+    // train on the transformed keys. The transform is kept in the trained
+    // RMI, so that lookups apply it to their keys as well. A pre-trained
+    // top model must have been trained on the transformed keys.
+    pub key_transform: Option<KeyTransform>,
+
+    // train on the positions divided by this (rounded down), such as the
+    // number of records (or bytes, with offsets) in a page, so that the
+    // RMI predicts the page of a key rather than its position. The errors
+    // are then in pages as well.
+    pub position_divisor: Option<u64>,
+
+    // measure the errors of the bottom models from the position the
+    // generated lookup returns (see PositionUnit::lookup_position) rather
+    // than from the raw prediction, so that the stored bounds are exactly
+    // the search windows the emitted code needs. With offsets, the errors are
+    // then whole records, and can be tighter than aligning the raw errors.
+    pub lookup_window_errors: bool
+}
+
+// a bottom model whose max error exceeds that of a constant model
+// predicting the midpoint of the positions it was assigned
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorseThanConstant {
+    pub index: usize,
+    pub max_error: u64,
+    pub baseline: u64
+}
+
+// the max error of always predicting the midpoint of [min_pos, max_pos]
+fn constant_baseline(min_pos: u64, max_pos: u64) -> u64 {
+    let midpoint = min_pos + (max_pos - min_pos) / 2;
+    return max_pos - midpoint;
+}
+
+// the fraction of num_rows keys that were predicted exactly (0 without keys)
+fn exact_fraction(num_exact: u64, num_rows: usize) -> f64 {
+    if num_rows == 0 {
+        return 0.0;
+    }
+    return num_exact as f64 / num_rows as f64;
+}
+
+// percentiles of the absolute difference between the predicted and actual
+// position of each key, in positions
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorPercentiles {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p100: u64
+}
+
+impl ErrorPercentiles {
+    pub fn from_errors(mut errors: Vec<u64>) -> ErrorPercentiles {
+        errors.sort_unstable();
+
+        // nearest-rank percentile
+        let rank = |p: f64| {
+            if errors.is_empty() { return 0; }
+            let idx = ((p * errors.len() as f64).ceil() as usize).max(1) - 1;
+            return errors[usize::min(idx, errors.len() - 1)];
+        };
+
+        return ErrorPercentiles {
+            p50: rank(0.50),
+            p90: rank(0.90),
+            p99: rank(0.99),
+            p100: rank(1.0)
+        };
+    }
+}
+
+// how evenly the training keys are spread over the bottom models, in keys
+// per model
+#[derive(Clone, Debug, PartialEq)]
+pub struct OccupancyStats {
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub stddev: f64,
+    pub empty_count: usize
+}
+
+// a summary of one layer of a trained RMI
+#[derive(Debug, PartialEq)]
+pub struct LayerInfo {
+    pub model_type: String,
+    pub num_models: usize,
+    pub num_constant: usize
+}
 
 pub struct TrainedRMI {
     pub model_avg_error: f64,
@@ -22,7 +250,44 @@ pub struct TrainedRMI {
     pub last_layer_max_l1s: Vec<u64>,
     pub rmi: Vec<Vec<Box<dyn Model>>>,
     pub models: String,
-    pub branching_factor: u64
+    pub branching_factor: u64,
+    pub error_percentiles: Option<ErrorPercentiles>,
+
+    // the fraction of the training keys whose predicted position is
+    // exactly their position (a residual of 0), for which the last-mile
+    // search has no work to do
+    pub exact_fraction: f64,
+
+    // only filled in when diagnostics are enabled
+    pub worse_than_constant: Vec<WorseThanConstant>,
+
+    // the units of all predictions and errors
+    pub position_unit: PositionUnit,
+
+    // the bottom models replaced by the top model's prediction, if pruning
+    // was requested
+    pub pruned: Option<PrunedLeaves>,
+
+    // the number of training keys routed to each bottom model
+    pub leaf_sizes: Vec<u64>,
+
+    // the number of keys the RMI indexes, which RuntimeRMI::len returns:
+    // the training keys (ModelData::len). If those were sampled from a
+    // larger dataset (as with --downsample), set it to the dataset's size,
+    // which the generated rmi_count returns.
+    pub num_keys: usize,
+
+    // the stored bottom model for each output of the top model, if
+    // adjacent bottom models were merged
+    pub leaf_map: Option<Vec<u64>>,
+
+    // applied to each key before the top model, if the RMI was trained on
+    // transformed keys
+    pub key_transform: Option<KeyTransform>,
+
+    // if set, the RMI was trained on the positions divided by this, so its
+    // predictions and errors are pages (see TrainingOptions)
+    pub position_divisor: Option<u64>
 }
 
 impl TrainedRMI {
@@ -39,33 +304,310 @@ impl TrainedRMI {
         let pred = leaf_models[target].predict_to_int(lookup_key.into());
         return (pred, self.last_layer_max_l1s[target]);
     }
+
+    // the number of distinct positions a lookup can return, for data with
+    // num_positions positions: the number of pages, with a position
+    // divisor
+    pub fn num_lookup_positions(&self, num_positions: usize) -> usize {
+        return match self.position_divisor {
+            Some(divisor) => num_positions.div_ceil(divisor as usize),
+            None => num_positions
+        };
+    }
+
+    // the number of bottom models replaced by the top model's prediction
+    pub fn num_pruned(&self) -> usize {
+        return self.pruned.as_ref().map(|p| p.count()).unwrap_or(0);
+    }
+
+    // summarizes how many training keys were routed to each bottom model.
+    // A large standard deviation or many empty models suggest the upper
+    // layers partition the keys poorly.
+    pub fn bucket_occupancy_stats(&self) -> OccupancyStats {
+        let sizes = &self.leaf_sizes;
+        if sizes.is_empty() {
+            return OccupancyStats { min: 0, max: 0, mean: 0.0, stddev: 0.0, empty_count: 0 };
+        }
+
+        let mean = sizes.iter().sum::<u64>() as f64 / sizes.len() as f64;
+        let variance = sizes.iter()
+            .map(|&n| (n as f64 - mean).powi(2))
+            .sum::<f64>() / sizes.len() as f64;
+
+        return OccupancyStats {
+            min: *sizes.iter().min().unwrap(),
+            max: *sizes.iter().max().unwrap(),
+            mean,
+            stddev: variance.sqrt(),
+            empty_count: sizes.iter().filter(|&&n| n == 0).count()
+        };
+    }
+
+    // reports, for each layer, the type of model used, how many models it
+    // holds, and how many of those predict a constant (e.g. because they
+    // received no training data).
+    pub fn layer_info(&self) -> Vec<LayerInfo> {
+        return self.rmi.iter().map(|layer| LayerInfo {
+            model_type: layer[0].function_name(),
+            num_models: layer.len(),
+            num_constant: layer.iter().filter(|m| m.is_constant()).count()
+        }).collect();
+    }
+
+    // for a last layer of models that chose their own types (e.g.
+    // adaptive6), how many chose each type, by type name. Other last
+    // layers count as their single model type.
+    pub fn leaf_type_mix(&self) -> Vec<(String, usize)> {
+        let mut mix: BTreeMap<String, usize> = BTreeMap::new();
+        for model in self.rmi[self.rmi.len() - 1].iter() {
+            let name = model.chosen_type()
+                .map(String::from)
+                .unwrap_or_else(|| model.function_name());
+            *mix.entry(name).or_insert(0) += 1;
+        }
+        return mix.into_iter().collect();
+    }
+
+    // the whole trained RMI as a JSON document: each layer's models with
+    // their function names and parameters, the stored errors, and whatever
+    // else a lookup needs (the key transform, pruned leaves, and leaf map).
+    // Each parameter has its C type (e.g. "double" or "uint64_t[]") and its
+    // value. Floats are written as JSON numbers, with enough digits to read
+    // back the same double.
+    pub fn to_json(&self) -> JsonValue {
+        let layers: Vec<JsonValue> = self.rmi.iter().map(|layer| {
+            let models: Vec<JsonValue> = layer.iter().map(|model| {
+                let params: Vec<JsonValue> = model.params().iter().map(param_json).collect();
+                object!(
+                    "function" => model.function_name(),
+                    "params" => params
+                )
+            }).collect();
+            JsonValue::from(models)
+        }).collect();
+
+        let position_unit = match self.position_unit {
+            PositionUnit::Element => JsonValue::from("element"),
+            PositionUnit::Offset(record_size) => object!("record size" => record_size)
+        };
+
+        let key_transform = match self.key_transform {
+            Some(t) => object!("offset" => t.offset, "scale" => t.scale),
+            None => JsonValue::Null
+        };
+
+        let pruned = match &self.pruned {
+            Some(p) => {
+                let leaves: Vec<usize> = (0..p.leaves.len()).filter(|l| p.is_pruned(*l)).collect();
+                object!("leaves" => leaves, "scale" => p.scale)
+            },
+            None => JsonValue::Null
+        };
+
+        return object!(
+            "models" => self.models.clone(),
+            "branching factor" => self.branching_factor,
+            "position unit" => position_unit,
+            "key transform" => key_transform,
+            "position divisor" => self.position_divisor,
+            "layers" => layers,
+            "last layer errors" => self.last_layer_max_l1s.clone(),
+            "leaf sizes" => self.leaf_sizes.clone(),
+            "pruned" => pruned,
+            "leaf map" => self.leaf_map.clone(),
+            "max error" => self.model_max_error,
+            "max error index" => self.model_max_error_idx,
+            "average error" => self.model_avg_error,
+            "average log2 error" => self.model_avg_log2_error,
+            "max log2 error" => self.model_max_log2_error,
+            "exact fraction" => self.exact_fraction
+        );
+    }
+
+    // checks that two trained RMIs have the same structure and that all
+    // of their parameters agree (floats within tolerance). On a mismatch,
+    // returns a description of the first difference found.
+    pub fn approx_eq(&self, other: &TrainedRMI, tolerance: f64) -> Result<(), String> {
+        if self.models != other.models {
+            return Err(format!("model types differ: {} vs {}", self.models, other.models));
+        }
+
+        if self.branching_factor != other.branching_factor {
+            return Err(format!("branching factors differ: {} vs {}",
+                               self.branching_factor, other.branching_factor));
+        }
+
+        if self.rmi.len() != other.rmi.len() {
+            return Err(format!("number of layers differ: {} vs {}",
+                               self.rmi.len(), other.rmi.len()));
+        }
+
+        for (layer_idx, (l1, l2)) in self.rmi.iter().zip(other.rmi.iter()).enumerate() {
+            if l1.len() != l2.len() {
+                return Err(format!("layer {} sizes differ: {} vs {}",
+                                   layer_idx, l1.len(), l2.len()));
+            }
+
+            for (model_idx, (m1, m2)) in l1.iter().zip(l2.iter()).enumerate() {
+                if m1.function_name() != m2.function_name() {
+                    return Err(format!("layer {} model {} types differ: {} vs {}",
+                                       layer_idx, model_idx,
+                                       m1.function_name(), m2.function_name()));
+                }
+
+                let (p1, p2) = (m1.params(), m2.params());
+                if p1.len() != p2.len() {
+                    return Err(format!("layer {} model {} parameter counts differ: {} vs {}",
+                                       layer_idx, model_idx, p1.len(), p2.len()));
+                }
+
+                for (param_idx, (v1, v2)) in p1.iter().zip(p2.iter()).enumerate() {
+                    if let Some(el) = v1.first_difference(v2, tolerance) {
+                        return Err(format!("layer {} model {} parameter {} differs at element {}",
+                                           layer_idx, model_idx, param_idx, el));
+                    }
+                }
+            }
+        }
+
+        if let Some(idx) = ModelParam::from(self.last_layer_max_l1s.clone())
+            .first_difference(&other.last_layer_max_l1s.clone().into(), 0.0) {
+            return Err(format!("last layer errors differ at model {}", idx));
+        }
+
+        return Ok(());
+    }
+}
+
+fn param_json(param: &ModelParam) -> JsonValue {
+    let value = match param {
+        ModelParam::Int(v) => JsonValue::from(*v),
+        ModelParam::Float(v) => JsonValue::from(*v),
+        ModelParam::ShortArray(a) => JsonValue::from(a.clone()),
+        ModelParam::IntArray(a) => JsonValue::from(a.clone()),
+        ModelParam::Int32Array(a) => JsonValue::from(a.clone()),
+        ModelParam::FloatArray(a) => JsonValue::from(a.clone())
+    };
+
+    return object!(
+        "type" => format!("{}{}", param.c_type(), param.c_type_mod()),
+        "value" => value
+    );
 }
 
+// the bottom models that fit positions which sometimes decrease: the other
+// models assume positions never do
+const NOISY_POSITION_MODELS: &[&str] = &["isotonic", "isotonic8", "isotonic16",
+                                         "linear_exceptions"];
+
 fn train_model(model_type: &str, data: &ModelDataWrapper) -> Box<dyn Model> {
+    #[cfg(test)]
+    {
+        if let Some(model) = registry::train_overridden(model_type, data) {
+            return model;
+        }
+    }
+
     let model: Box<dyn Model> = match model_type {
         "linear" => Box::new(LinearModel::new(data)),
+        "linear_minimax" => Box::new(LinearModel::with_fit(data, LinearFit::Minimax)),
+        "linear_centered" => Box::new(LinearModel::with_fit(data, LinearFit::Centered)),
+        "linear_anchored" => Box::new(LinearModel::with_fit(data, LinearFit::Anchored)),
+        "linear_exceptions" => Box::new(ExceptionModel::with_fraction(
+            Box::new(LinearModel::new(data)), data, 0.01)),
         "robust_linear" => Box::new(RobustLinearModel::new(data)),
+        "gapped_linear" => Box::new(GappedLinearModel::new(data, 64)),
         "linear_spline" => Box::new(LinearSplineModel::new(data)),
+        "connected_spline16" => Box::new(ConnectedSplineModel::new(data, 16)),
+        "connected_spline64" => Box::new(ConnectedSplineModel::new(data, 64)),
+        "connected_spline256" => Box::new(ConnectedSplineModel::new(data, 256)),
+        "sampled_cdf" => Box::new(SampledCDFModel::new(data, 256)),
+        "rank_transform" => Box::new(RankTransformModel::new(data, 256)),
+        "isotonic" => Box::new(IsotonicModel::new(data, 256)),
+        "isotonic8" => Box::new(IsotonicModel::with_slots(data, 8)),
+        "isotonic16" => Box::new(IsotonicModel::with_slots(data, 16)),
         "cubic" => Box::new(CubicSplineModel::new(data)),
+        "adaptive4" => Box::new(AdaptiveModel::new(data, 4)),
+        "adaptive6" => Box::new(AdaptiveModel::new(data, 6)),
+        "dense_range" => Box::new(DenseRangeModel::new(data)),
+        "linear_clamped" => Box::new(ClampedLinearModel::new(data)),
+        "cubic_clamped" => Box::new(ClampedCubicModel::new(data)),
         "loglinear" => Box::new(LogLinearModel::new(data)),
         "normal" => Box::new(NormalModel::new(data)),
         "lognormal" => Box::new(LogNormalModel::new(data)),
+        "exponential" => Box::new(ExponentialModel::new(data)),
         "radix" => Box::new(RadixModel::new(data)),
         "radix8" => Box::new(RadixTable::new(data, 8)),
         "radix18" => Box::new(RadixTable::new(data, 18)),
         "radix22" => Box::new(RadixTable::new(data, 22)),
         "radix26" => Box::new(RadixTable::new(data, 26)),
         "radix28" => Box::new(RadixTable::new(data, 28)),
+        "radix_eq" => Box::new(EqualizedRadixTable::new(data, 16)),
+        "radix2l" => Box::new(TwoLevelRadixModel::new(data, 10, 10)),
+        "lookup_table8" => Box::new(LookupTableModel::new(data, 8)),
+        "lookup_table12" => Box::new(LookupTableModel::new(data, 12)),
+        "lookup_table16" => Box::new(LookupTableModel::new(data, 16)),
+        "lookup_table20" => Box::new(LookupTableModel::new(data, 20)),
         "bradix" => Box::new(BalancedRadixModel::new(data)),
         "histogram" => Box::new(EquidepthHistogramModel::new(data)),
+        "log_histogram64" => Box::new(LogHistogramModel::new(data, 64)),
+        "log_histogram256" => Box::new(LogHistogramModel::new(data, 256)),
+        "log_histogram1024" => Box::new(LogHistogramModel::new(data, 1024)),
         "plr" => Box::new(BottomUpPLR::new(data)),
+        "plr_aos" => Box::new(BottomUpPLR::with_layout(data, SegmentLayout::ArrayOfStructs)),
+        "plr_q" => Box::new(QuantizedPLRModel::new(data, 16)),
         "pgm" => Box::new(PGM::new(data)),
-        _ => panic!("Unknown model type: {}", model_type),
+        _ => registry::train_registered(model_type, data)
+            .unwrap_or_else(|| panic!("Unknown model type: {}", model_type)),
     };
 
     return model;
 }
 
+// the log2 of the search window for an error bound of err, which is how
+// training and the optimizer report errors: the 2 * err + 1 positions
+// around a prediction, rounded up to 2 * (err + 1)
+pub fn log2_window(err: u64) -> f64 {
+    return (2.0 * (err as f64 + 1.0)).log2();
+}
+
+// ceil(log2(err + 1)), the number of bits of err, which is how generated
+// code reports errors with log2 errors. This is ceil(log2_window(err)) - 1,
+// computed exactly: 2^result - 1 >= err, so the search window fits in
+// 2^(result + 1).
+pub fn log2_error_bits(err: u64) -> u64 {
+    return u64::from(64 - err.leading_zeros());
+}
+
+// fits the single model with the lowest max error that stores at most
+// max_params parameter values. Tries the fixed-size models, then radix and
+// lookup tables with more and more bits until they exceed the budget.
+pub fn fit_within_params(data: &ModelDataWrapper, max_params: usize) -> Box<dyn Model> {
+    assert!(max_params >= 2, "no model has fewer than 2 parameters");
+
+    let mut candidates: Vec<Box<dyn Model>> = ["linear", "cubic", "linear_spline",
+                                                "loglinear", "normal", "lognormal"]
+        .iter()
+        .map(|model_type| train_model(model_type, data))
+        .collect();
+
+    // the radix table needs the prefix and the radix bits to fit in a key
+    let radix_bits = 64 - common_prefix_size(data);
+    for bits in 1..=u8::min(radix_bits, 28) {
+        if 1 + (1 << bits) > max_params { break; }
+        candidates.push(Box::new(RadixTable::new(data, bits)));
+    }
+
+    for bits in 1..=28 {
+        if 4 + (1 << bits) > max_params { break; }
+        candidates.push(Box::new(LookupTableModel::new(data, bits)));
+    }
+
+    candidates.retain(|m| param_count(m.as_ref()) <= max_params);
+    let best = most_accurate(&candidates, data);
+    return candidates.swap_remove(best);
+}
+
 fn validate(model_spec: &[String]) {
     let num_layers = model_spec.len();
     let empty_data = ModelData::empty();
@@ -95,6 +637,13 @@ fn validate(model_spec: &[String]) {
     }
 }
 
+// true if every key is the same (the keys are sorted, so the first and last
+// are enough). Many models divide by the range of the keys, which is zero.
+fn has_single_key(data: &ModelDataWrapper) -> bool {
+    let last = data.len() - 1;
+    return data.get(0).0 == data.get(last).0 && data.get_key(0) == data.get_key(last);
+}
+
 /*fn test_rmi_input(test_key: u64, data: &ModelDataWrapper, rmi: &TrainedRMI) {
     let correct = data.lower_bound(test_key);
     println!("Predicting {}", test_key);
@@ -108,8 +657,115 @@ fn validate(model_spec: &[String]) {
              correct);
 }*/
 
+fn check_finite(rmi: TrainedRMI) -> Result<TrainedRMI, TrainingError> {
+    for (layer, models) in rmi.rmi.iter().enumerate() {
+        for (index, model) in models.iter().enumerate() {
+            if let Some(value) = first_non_finite(&model.params()) {
+                return Err(TrainingError::NonFiniteParameter {
+                    model: model.function_name(), layer, index, value
+                });
+            }
+        }
+    }
+
+    return Ok(rmi);
+}
+
+fn check_against_constant(rmi: &TrainedRMI, diagnostics: Diagnostics)
+                          -> Result<(), TrainingError> {
+    let last_layer = &rmi.rmi[rmi.rmi.len() - 1];
+    for worse in rmi.worse_than_constant.iter() {
+        let model = last_layer[worse.index].function_name();
+        if diagnostics == Diagnostics::Strict {
+            return Err(TrainingError::WorseThanConstant {
+                model, index: worse.index,
+                max_error: worse.max_error, baseline: worse.baseline
+            });
+        }
+
+        warn!("Bottom model {} at index {} has a max error of {}, \
+               worse than the constant baseline of {}",
+              model, worse.index, worse.max_error, worse.baseline);
+    }
+
+    return Ok(());
+}
+
 pub fn train(data: &mut ModelDataWrapper,
-             model_spec: &str, branch_factor: u64) -> TrainedRMI {
+             model_spec: &str, branch_factor: u64) -> Result<TrainedRMI, TrainingError> {
+    return train_with_options(data, model_spec, branch_factor, &TrainingOptions::default());
+}
+
+pub fn train_with_options(data: &mut ModelDataWrapper,
+                          model_spec: &str, branch_factor: u64,
+                          options: &TrainingOptions) -> Result<TrainedRMI, TrainingError> {
+    return train_with_top_model(data, model_spec, branch_factor, None, options);
+}
+
+// trains an index that is a single model of the given type, mapping keys
+// directly to positions
+pub fn train_single_layer(data: &mut ModelDataWrapper,
+                          model_type: &str) -> Result<TrainedRMI, TrainingError> {
+    return train(data, model_type, 1);
+}
+
+// trains only the top model of an RMI with the given branching factor,
+// exactly as `train` would. The result can be passed to
+// `train_with_top_model` to train several RMIs that share a top model.
+pub fn train_top_model(data: &mut ModelDataWrapper,
+                       model_type: &str, branch_factor: u64) -> Box<dyn Model> {
+    let num_positions = data.num_positions();
+    data.set_scale(branch_factor as f64 / num_positions as f64);
+    let top_model = train_model(model_type, data);
+    data.set_scale(1.0);
+    return top_model;
+}
+
+// like `train`, but if `top_model` is given, it is used as the root of the RMI
+// instead of fitting a new one. The top model must have been trained (with
+// `train_top_model`) on the same data, model type, and branching factor.
+pub fn train_with_top_model(data: &mut ModelDataWrapper,
+                            model_spec: &str, branch_factor: u64,
+                            top_model: Option<Box<dyn Model>>,
+                            options: &TrainingOptions)
+                            -> Result<TrainedRMI, TrainingError> {
+    if data.len() == 0 {
+        return Err(TrainingError::EmptyData);
+    }
+    if let Some(index) = data.first_decreasing_position() {
+        let last_model = model_spec.rsplit(',').next().unwrap();
+        if !NOISY_POSITION_MODELS.contains(&last_model) {
+            return Err(TrainingError::DecreasingPositions { index });
+        }
+    }
+
+    if let Some(transform) = options.key_transform {
+        if data.has_float_keys() {
+            return Err(TrainingError::FloatKeyTransform);
+        }
+        let transformed_keys = data.clone().into_data().transform_keys(transform);
+        let mut transformed = ModelDataWrapper::new(&transformed_keys);
+        transformed.set_position_unit(data.position_unit());
+        transformed.set_num_positions(data.num_positions());
+        let options = TrainingOptions { key_transform: None, ..options.clone() };
+        let mut res = train_with_top_model(&mut transformed, model_spec, branch_factor,
+                                           top_model, &options)?;
+        res.key_transform = Some(transform);
+        return Ok(res);
+    }
+
+    if let Some(divisor) = options.position_divisor {
+        assert!(divisor > 0, "the position divisor must be positive");
+        let pages = data.clone().into_data().divide_positions(divisor);
+        let mut paged = ModelDataWrapper::new(&pages);
+        paged.set_num_positions(data.num_positions().div_ceil(divisor as usize));
+        let options = TrainingOptions { position_divisor: None, ..options.clone() };
+        let mut res = train_with_top_model(&mut paged, model_spec, branch_factor,
+                                           top_model, &options)?;
+        res.position_divisor = Some(divisor);
+        return Ok(res);
+    }
+
     let (model_list, last_model): (Vec<String>, String) = {
         let mut all_models: Vec<String> = model_spec.split(',').map(String::from).collect();
         validate(&all_models);
@@ -117,12 +773,609 @@ pub fn train(data: &mut ModelDataWrapper,
         (all_models, last)
     };
 
-    if model_list.len() == 1 && data.len() > 1_000_000 {
-        let res = two_layer::train_two_layer(data, &model_list[0],
-                                             &last_model, branch_factor);
-        return res;
+    assert!(top_model.is_none() || !model_list.is_empty(),
+            "a pre-trained top model requires at least two layers");
+
+    // the lower bound corrections of the two layer trainer assume that
+    // positions are element indexes (not offsets, pages, or sparse IDs),
+    // and that each leaf's own predictions are used (which pruning would
+    // change)
+    let res = if options.detect_dense_ranges && data.positions_are_indexes()
+        && is_dense_range(data) {
+        info!("Keys are a dense range, using a single dense_range model instead of {}",
+              model_spec);
+        multi_layer::train_multi_layer(data, &[], String::from("dense_range"), 1,
+                                       None, options)
+    } else if has_single_key(data) {
+        // a dense_range model predicts the first position for the key (and
+        // any smaller key), and its error covers the rest of the positions
+        warn!("Every key is the same, using a single dense_range model instead of {}",
+              model_spec);
+        multi_layer::train_multi_layer(data, &[], String::from("dense_range"), 1,
+                                       None, options)
+    } else if model_list.len() == 1 && data.len() > 1_000_000 && !options.prune_leaves
+        && data.positions_are_indexes() {
+        two_layer::train_two_layer(data, &model_list[0],
+                                   &last_model, branch_factor,
+                                   top_model, options)
+    } else {
+        // it is not a simple, two layer rmi
+        multi_layer::train_multi_layer(data, &model_list, last_model, branch_factor,
+                                       top_model, options)
+    };
+
+    let mut res = check_finite(res)?;
+    check_against_constant(&res, options.diagnostics)?;
+    if options.prune_leaves {
+        prune::prune_leaves(&mut res, data);
+    }
+    if let Some(max_models) = options.max_leaf_models {
+        merge::merge_leaves(&mut res, data, max_models);
+    }
+    if let Some(slack) = options.error_slack {
+        for err in res.last_layer_max_l1s.iter_mut() {
+            *err = slack.pad(*err);
+        }
+    }
+
+    return Ok(res);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn test_data() -> ModelData<'static> {
+        return ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+    }
+
+    #[test]
+    fn test_approx_eq() {
+        let data = test_data();
+        let rmi1 = train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap();
+        let rmi2 = train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap();
+        assert!(rmi1.approx_eq(&rmi2, 0.0).is_ok());
+
+        let mut rmi3 = train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap();
+        rmi3.rmi[1][3].set_to_constant_model(5);
+        assert!(rmi1.approx_eq(&rmi3, 1e-6).is_err());
+
+        let rmi4 = train(&mut ModelDataWrapper::new(&data), "linear,linear", 32).unwrap();
+        assert!(rmi1.approx_eq(&rmi4, 1e-6).is_err());
+    }
+
+    #[test]
+    fn test_pretrained_top_model() {
+        let data = test_data();
+        let mut md = ModelDataWrapper::new(&data);
+
+        for spec in &["linear,linear", "cubic,linear_spline", "radix,linear"] {
+            let from_scratch = train(&mut md, spec, 32).unwrap();
+
+            let top_type = spec.split(',').next().unwrap();
+            let top: Arc<dyn Model> = Arc::from(train_top_model(&mut md, top_type, 32));
+            let cached1 = train_with_top_model(&mut md, spec, 32, Some(Box::new(Arc::clone(&top))),
+                                               &TrainingOptions::default()).unwrap();
+            let cached2 = train_with_top_model(&mut md, spec, 32, Some(Box::new(top)),
+                                               &TrainingOptions::default()).unwrap();
+
+            assert_eq!(from_scratch.approx_eq(&cached1, 0.0), Ok(()));
+            assert_eq!(from_scratch.approx_eq(&cached2, 0.0), Ok(()));
+            assert_eq!(from_scratch.model_max_error, cached1.model_max_error);
+        }
+    }
+
+    #[test]
+    fn test_layer_info() {
+        // a linear top model on quadratic keys leaves some leaves without data
+        let data = test_data();
+        let rmi = train(&mut ModelDataWrapper::new(&data), "linear,linear", 256).unwrap();
+        let info = rmi.layer_info();
+
+        assert_eq!(info.len(), 2);
+        assert_eq!(info[0], LayerInfo { model_type: String::from("linear"),
+                                        num_models: 1, num_constant: 0 });
+        assert_eq!(info[1].model_type, "linear");
+        assert_eq!(info[1].num_models, 256);
+        assert!(info[1].num_constant > 0);
+        assert!(info[1].num_constant < 256);
+    }
+
+    #[test]
+    fn test_error_percentiles() {
+        let p = ErrorPercentiles::from_errors((1..=100).rev().collect());
+        assert_eq!(p, ErrorPercentiles { p50: 50, p90: 90, p99: 99, p100: 100 });
+        assert_eq!(ErrorPercentiles::from_errors(vec![]).p100, 0);
+
+        let data = test_data();
+        let opts = TrainingOptions { error_percentiles: true, ..Default::default() };
+        for spec in &["linear,linear", "cubic,linear,linear"] {
+            let rmi = train_with_top_model(&mut ModelDataWrapper::new(&data), spec, 16,
+                                           None, &opts).unwrap();
+            let p = rmi.error_percentiles.unwrap();
+            assert!(p.p50 <= p.p90 && p.p90 <= p.p99 && p.p99 <= p.p100);
+            assert!(p.p100 <= rmi.model_max_error);
+        }
+
+        let rmi = train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap();
+        assert!(rmi.error_percentiles.is_none());
+    }
+
+    #[test]
+    fn test_exact_fraction() {
+        // evenly spaced keys are predicted exactly, except where rounding
+        // lands a prediction just below the position
+        let spaced = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * 3, i)).collect());
+        let rmi = train(&mut ModelDataWrapper::new(&spaced), "linear,linear", 16).unwrap();
+        assert!(rmi.exact_fraction > 0.5, "{}", rmi.exact_fraction);
+        assert!(rmi.exact_fraction <= 1.0);
+
+        // the fraction counts the keys the final RMI predicts exactly,
+        // after any leaves were pruned or merged
+        let data = test_data();
+        let pruned = TrainingOptions { prune_leaves: true, ..Default::default() };
+        let merged = TrainingOptions { max_leaf_models: Some(8), ..Default::default() };
+        for opts in &[TrainingOptions::default(), pruned, merged] {
+            for spec in &["linear,linear", "cubic,linear,linear"] {
+                let rmi = train_with_options(&mut ModelDataWrapper::new(&data), spec, 64,
+                                             opts).unwrap();
+                let fraction = rmi.exact_fraction;
+                let rmi = crate::runtime::RuntimeRMI::new(rmi);
+                let num_exact = data.iter_int_int()
+                    .filter(|(x, y)| rmi.predict((*x).into()) == *y)
+                    .count();
+                assert_eq!(fraction, num_exact as f64 / data.len() as f64, "{}", spec);
+            }
+        }
+
+        let empty = train(&mut ModelDataWrapper::new(&ModelData::empty()), "linear,linear", 4);
+        assert_eq!(empty.map(|rmi| rmi.exact_fraction).unwrap_or(0.0), 0.0);
+    }
+
+    #[test]
+    fn test_sparse_positions() {
+        use crate::runtime::RuntimeRMI;
+
+        // record IDs rather than ranks: gaps of varying size, a few huge
+        // ones, and some repeated IDs
+        let mut state: u64 = 7;
+        let mut id = 1_000_000u64;
+        let pairs: Vec<(u64, u64)> = (0..20_000u64).map(|i| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            id += match i % 1000 {
+                0 => 1 << 36,
+                _ => (state >> 33) % 50
+            };
+            (i * 17 + (state >> 60), id)
+        }).collect();
+        let data = ModelData::IntKeyToIntPos(pairs.clone());
+        let container = ModelDataWrapper::new(&data);
+        assert_eq!(container.first_decreasing_position(), None);
+        assert_eq!(container.num_positions() as u64, id + 1);
+
+        for spec in &["linear,linear", "cubic,linear", "linear,linear,linear"] {
+            let rmi = RuntimeRMI::new(train(&mut ModelDataWrapper::new(&data), spec, 64)
+                                      .unwrap());
+
+            // the keys are spread over the leaves by their positions
+            assert!(rmi.trained().leaf_sizes.iter().filter(|s| **s > 0).count() > 16, "{}", spec);
+
+            for (key, pos) in pairs.iter() {
+                let pred = PositionUnit::Element.lookup_position(rmi.predict((*key).into()),
+                                                                 id + 1);
+                let err = u64::max(pred, *pos) - u64::min(pred, *pos);
+                assert!(err <= rmi.error_bound_for((*key).into()),
+                        "{}: key {} at {} predicted at {}", spec, key, pos, pred);
+            }
+        }
+
+        // repeated IDs can end below the number of keys, but are not indexes
+        assert!(!container.positions_are_indexes());
+        let repeated = ModelData::IntKeyToIntPos((0..1000).map(|i| (i, i / 2)).collect());
+        assert_eq!(ModelDataWrapper::new(&repeated).num_positions(), 1000);
+        assert!(!ModelDataWrapper::new(&repeated).positions_are_indexes());
+        let duplicates = ModelData::IntKeyToIntPos(vec![(1, 0), (1, 0), (1, 0), (4, 3)]);
+        assert!(ModelDataWrapper::new(&duplicates).positions_are_indexes());
+
+        // decreasing positions are an error, unless the bottom models are
+        // built for noisy positions
+        let decreasing = ModelData::IntKeyToIntPos(vec![(1, 10), (2, 20), (3, 15), (4, 30)]);
+        assert_eq!(ModelDataWrapper::new(&decreasing).first_decreasing_position(), Some(2));
+        let res = train(&mut ModelDataWrapper::new(&decreasing), "linear,linear", 2);
+        assert!(matches!(res, Err(TrainingError::DecreasingPositions { index: 2 })));
+        assert!(train(&mut ModelDataWrapper::new(&decreasing), "linear,isotonic8", 2).is_ok());
+    }
+
+    #[test]
+    fn test_adaptive_leaves() {
+        // evenly spaced keys, then quadratic ones, then steps of two
+        // slopes, so each type suits some of the buckets
+        let keys: Vec<u64> = (0..10_000u64).map(|i| i * 1000)
+            .chain((0..10_000u64).map(|i| 10_000_000 + i * i))
+            .chain((0..10_000u64).map(|i| 200_000_000 + (i % 100) * (1 + (i % 100) / 50 * 50)
+                                      + (i / 100) * 100_000))
+            .collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let train_leaves = |leaves: &str| {
+            return train(&mut ModelDataWrapper::new(&data), &format!("linear,{}", leaves), 64)
+                .unwrap();
+        };
+
+        let adaptive = train_leaves("adaptive6");
+        let mix = adaptive.leaf_type_mix();
+        assert!(mix.len() > 1, "{:?}", mix);
+        assert_eq!(mix.iter().map(|(_, count)| count).sum::<usize>(), 64);
+
+        // each bucket's error is at most that of a line or a cubic, with the
+        // same top model routing the keys
+        for single in &["linear", "cubic"] {
+            let trained = train_leaves(single);
+            assert_eq!(trained.leaf_type_mix(), vec![(single.to_string(), 64)]);
+            for (adaptive_err, err) in adaptive.last_layer_max_l1s.iter()
+                .zip(trained.last_layer_max_l1s.iter()) {
+                assert!(adaptive_err <= err);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lookup_window_errors() {
+        let keys: Vec<u64> = (0..20_000u64).map(|i| i * i + i % 7).collect();
+        let opts = TrainingOptions { lookup_window_errors: true, ..Default::default() };
+        for (spec, record_size) in &[("linear,linear", 1), ("linear,linear", 24),
+                                     ("cubic,linear", 24), ("linear,linear,linear", 24)] {
+            let mut data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                                     .map(|(i, k)| (*k, i as u64)).collect());
+            let unit = if *record_size == 1 {
+                PositionUnit::Element
+            } else {
+                data.positions_to_offsets(*record_size);
+                PositionUnit::Offset(*record_size)
+            };
+            let container = || {
+                let mut container = ModelDataWrapper::new(&data);
+                container.set_position_unit(unit);
+                container
+            };
+            let num_positions = container().num_positions() as u64;
+            let raw = train(&mut container(), spec, 64).unwrap();
+            let windowed = train_with_options(&mut container(), spec, 64, &opts).unwrap();
+
+            // the windows are whole records, and narrower than the raw
+            // errors once those are aligned to records, as the generated
+            // code emits them
+            assert!(windowed.last_layer_max_l1s.iter().all(|e| e % record_size == 0));
+            if *record_size == 1 {
+                assert_eq!(windowed.model_max_error, raw.model_max_error);
+            } else {
+                assert!(windowed.model_max_error < unit.align_error(raw.model_max_error),
+                        "{}: {} vs {}", spec, windowed.model_max_error, raw.model_max_error);
+            }
+
+            // every key, and (with lower bound corrections, which only two
+            // layer RMIs have) the keys on either side of each leaf boundary
+            let runtime = crate::runtime::RuntimeRMI::new(windowed);
+            let mut queries = keys.clone();
+            if spec.matches(',').count() == 1 {
+                for pair in keys.windows(2) {
+                    if runtime.route(pair[0].into()) != runtime.route(pair[1].into()) {
+                        queries.extend_from_slice(&[pair[0] + 1, pair[1] - 1]);
+                    }
+                }
+                assert!(queries.len() > keys.len() + 64, "{}", spec);
+            }
+
+            // the lower bound of each query is within the emitted error of
+            // the emitted prediction
+            for query in queries.iter() {
+                let target = keys.partition_point(|k| k < query) as u64 * record_size;
+                let guess = unit.lookup_position(runtime.predict((*query).into()), num_positions);
+                let err = unit.align_error(runtime.error_bound_for((*query).into()));
+                assert!(u64::max(guess, target) - u64::min(guess, target) <= err,
+                        "{}: {} at {} guessed {} +- {}", spec, query, target, guess, err);
+            }
+
+            // and the compiled lookup returns the same predictions
+            if cfg!(feature = "codegen-roundtrip") {
+                let result = crate::codegen::verify_compiled(runtime.into_trained(),
+                                                             num_positions as usize, &queries,
+                                                             "c++", &std::env::temp_dir());
+                assert!(result.is_ok(), "{}: {}", spec, result.unwrap_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_position_divisor() {
+        let data = ModelData::IntKeyToIntPos((0..10_000).map(|i| (i * i, i)).collect());
+        let plain = train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap();
+        let opts = TrainingOptions { position_divisor: Some(64), ..Default::default() };
+        let paged = train_with_options(&mut ModelDataWrapper::new(&data), "linear,linear", 16,
+                                       &opts).unwrap();
+        assert_eq!(paged.position_divisor, Some(64));
+        assert_eq!(paged.num_lookup_positions(10_000), 157);
+        assert_eq!(plain.num_lookup_positions(10_000), 10_000);
+        assert_eq!(paged.to_json()["position divisor"].as_u64(), Some(64));
+
+        // the errors are in pages, so they shrink by about the page size
+        assert!(paged.model_max_error * 16 < plain.model_max_error,
+                "{} vs {}", paged.model_max_error, plain.model_max_error);
+
+        // each key's page is within its error bound of the clamped prediction
+        let runtime = crate::runtime::RuntimeRMI::new(paged);
+        for (key, pos) in data.iter_int_int() {
+            let pred = u64::min(runtime.predict(key.into()), 156);
+            let page = pos / 64;
+            assert!(u64::max(pred, page) - u64::min(pred, page)
+                    <= runtime.error_bound_for(key.into()), "{}", key);
+        }
+
+        // so is the generated lookup's
+        if cfg!(feature = "codegen-roundtrip") {
+            let keys: Vec<u64> = data.iter_int_int().map(|(k, _)| k).chain(vec![u64::MAX])
+                .collect();
+            let result = crate::codegen::verify_compiled(runtime.into_trained(), 10_000, &keys,
+                                                         "c++", &std::env::temp_dir());
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+        }
+
+        let floats = ModelData::FloatKeyToFloatPos(vec![(0.5, 63.0), (1.5, 64.0)]);
+        assert_eq!(floats.divide_positions(64).get(0).1, 0.0);
+        assert_eq!(floats.divide_positions(64).get(1).1, 1.0);
+    }
+
+    #[test]
+    fn test_error_slack() {
+        assert_eq!(ErrorSlack::Absolute(8).pad(10), 18);
+        assert_eq!(ErrorSlack::Percent(25.0).pad(10), 13);
+        assert_eq!(ErrorSlack::Percent(25.0).pad(0), 0);
+        assert_eq!(ErrorSlack::parse("16"), Some(ErrorSlack::Absolute(16)));
+        assert_eq!(ErrorSlack::parse("2.5%"), Some(ErrorSlack::Percent(2.5)));
+        assert_eq!(ErrorSlack::parse("-1%"), None);
+        assert_eq!(ErrorSlack::parse("abc"), None);
+
+        let data = test_data();
+        let plain = train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap();
+        let opts = TrainingOptions { error_slack: Some(ErrorSlack::Absolute(8)),
+                                     ..Default::default() };
+        let padded = train_with_options(&mut ModelDataWrapper::new(&data),
+                                        "linear,linear", 16, &opts).unwrap();
+
+        for (p, e) in padded.last_layer_max_l1s.iter().zip(plain.last_layer_max_l1s.iter()) {
+            assert_eq!(*p, e + 8);
+        }
+    }
+
+    #[test]
+    fn test_non_finite_key() {
+        let mut pts: Vec<(f64, f64)> = (0..100).map(|i| (i as f64, i as f64)).collect();
+        pts.push((f64::INFINITY, 100.0));
+        let data = ModelData::FloatKeyToFloatPos(pts);
+
+        match train(&mut ModelDataWrapper::new(&data), "linear,linear", 4) {
+            Err(TrainingError::NonFiniteParameter { layer, .. }) => assert_eq!(layer, 0),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected training to fail on an infinite key")
+        }
+    }
+
+    #[test]
+    fn test_constant_baseline() {
+        assert_eq!(constant_baseline(5, 5), 0);
+        assert_eq!(constant_baseline(0, 10), 5);
+        assert_eq!(constant_baseline(0, 11), 6);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let data = test_data();
+        let warn = TrainingOptions { diagnostics: Diagnostics::Warn, ..Default::default() };
+        let strict = TrainingOptions { diagnostics: Diagnostics::Strict, ..Default::default() };
+
+        // a normal CDF fits each bucket of the quadratic keys poorly
+        let rmi = train_with_options(&mut ModelDataWrapper::new(&data),
+                                     "linear,normal", 4, &warn).unwrap();
+        assert!(!rmi.worse_than_constant.is_empty());
+        for w in rmi.worse_than_constant.iter() {
+            assert!(w.max_error > w.baseline);
+            assert!(w.max_error <= rmi.last_layer_max_l1s[w.index]);
+        }
+
+        match train_with_options(&mut ModelDataWrapper::new(&data), "linear,normal", 4, &strict) {
+            Err(TrainingError::WorseThanConstant { model, .. }) => assert_eq!(model, "ncdf"),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected strict diagnostics to fail")
+        }
+
+        // disabled by default
+        let rmi = train(&mut ModelDataWrapper::new(&data), "linear,normal", 4).unwrap();
+        assert!(rmi.worse_than_constant.is_empty());
+
+        assert!(train_with_options(&mut ModelDataWrapper::new(&data),
+                                   "linear,linear", 16, &strict).is_ok());
+    }
+
+    #[test]
+    fn test_fit_within_params() {
+        let data = test_data();
+        let md = ModelDataWrapper::new(&data);
+
+        for budget in &[2, 4, 16, 300, 5000] {
+            let model = fit_within_params(&md, *budget);
+            assert!(param_count(model.as_ref()) <= *budget);
+        }
+
+        // a bigger budget never gives a less accurate model
+        let errors: Vec<u64> = [2, 4, 16, 300, 5000].iter()
+            .map(|b| max_error(fit_within_params(&md, *b).as_ref(), &md))
+            .collect();
+        assert!(errors.windows(2).all(|w| w[1] <= w[0]));
+        assert!(errors[4] < errors[0]);
+        assert_eq!(fit_within_params(&md, 2).function_name(), "linear");
     }
 
-    // it is not a simple, two layer rmi
-    return multi_layer::train_multi_layer(data, &model_list, last_model, branch_factor);
+    #[test]
+    fn test_key_transform_data() {
+        // only every other position is held, so the position space is
+        // larger than the data
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * 10, i * 2)).collect());
+        let mut container = ModelDataWrapper::new(&data);
+        container.set_num_positions(10_000);
+        let plain = train(&mut container, "linear,linear", 16).unwrap();
+        let options = TrainingOptions { key_transform: Some(KeyTransform::new(0, 1)),
+                                        ..Default::default() };
+        let transformed = train_with_options(&mut container, "linear,linear", 16, &options)
+            .unwrap();
+        assert!(transformed.approx_eq(&plain, 0.0).is_ok());
+
+        let floats = ModelData::FloatKeyToIntPos(vec![(0.5, 0), (1.5, 1)]);
+        match train_with_options(&mut ModelDataWrapper::new(&floats), "linear,linear", 2,
+                                 &options) {
+            Err(TrainingError::FloatKeyTransform) => {},
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected a key transform of float keys to fail")
+        }
+    }
+
+    #[test]
+    fn test_empty_data() {
+        let data = ModelData::empty();
+        match train(&mut ModelDataWrapper::new(&data), "linear,linear", 4) {
+            Err(TrainingError::EmptyData) => {},
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected training on empty data to fail")
+        }
+    }
+
+    #[test]
+    fn test_single_key() {
+        let data = ModelData::IntKeyToIntPos(vec![(5, 0)]);
+        for spec in &["linear,linear", "robust_linear,linear", "radix,linear",
+                      "bradix,linear", "radix18,linear", "cubic,linear,linear"] {
+            let rmi = train(&mut ModelDataWrapper::new(&data), spec, 4).unwrap();
+            assert_eq!(rmi.model_max_error, 0, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_detect_dense_ranges() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i + 100, i)).collect());
+        let opts = TrainingOptions { detect_dense_ranges: true, ..Default::default() };
+        let rmi = train_with_options(&mut ModelDataWrapper::new(&data),
+                                     "linear,linear", 16, &opts).unwrap();
+        assert_eq!(rmi.models, "dense_range");
+        assert_eq!(rmi.rmi.len(), 1);
+        assert_eq!(rmi.model_max_error, 0);
+
+        // off by default, and only for dense keys
+        let rmi = train(&mut ModelDataWrapper::new(&data), "linear,linear", 16).unwrap();
+        assert_eq!(rmi.models, "linear,linear");
+        let rmi = train_with_options(&mut ModelDataWrapper::new(&test_data()),
+                                     "linear,linear", 16, &opts).unwrap();
+        assert_eq!(rmi.models, "linear,linear");
+    }
+
+    #[test]
+    fn test_bucket_occupancy_stats() {
+        let data = test_data();
+        let rmi = train(&mut ModelDataWrapper::new(&data), "linear,linear", 256).unwrap();
+        let stats = rmi.bucket_occupancy_stats();
+
+        assert_eq!(rmi.leaf_sizes.len(), 256);
+        assert_eq!(rmi.leaf_sizes.iter().sum::<u64>(), data.len() as u64);
+        assert!(stats.empty_count > 0);
+        assert_eq!(stats.min, 0);
+        assert!(stats.max as f64 > stats.mean);
+        assert!((stats.mean - data.len() as f64 / 256.0).abs() < 1e-9);
+        assert!(stats.stddev > 0.0);
+
+        // a single layer puts every key in one model
+        let rmi = train(&mut ModelDataWrapper::new(&data), "linear", 1).unwrap();
+        let stats = rmi.bucket_occupancy_stats();
+        assert_eq!(stats.min, data.len() as u64);
+        assert_eq!(stats.stddev, 0.0);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let rmi = train(&mut ModelDataWrapper::new(&data), "radix,linear", 8).unwrap();
+        let doc = json::parse(&rmi.to_json().dump()).unwrap();
+
+        assert_eq!(doc["models"].as_str(), Some("radix,linear"));
+        assert_eq!(doc["branching factor"].as_u64(), Some(8));
+        assert_eq!(doc["position unit"].as_str(), Some("element"));
+        assert!(doc["key transform"].is_null() && doc["leaf map"].is_null());
+        assert_eq!(doc["max error"].as_u64(), Some(rmi.model_max_error));
+        assert_eq!(doc["last layer errors"].len(), 8);
+
+        let layers: Vec<&JsonValue> = doc["layers"].members().collect();
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].len(), 1);
+        assert_eq!(layers[0][0]["function"].as_str(), Some("radix"));
+        assert_eq!(layers[0][0]["params"][0]["type"].as_str(), Some("uint64_t"));
+
+        // every bottom model's floats read back exactly
+        for (model, json_model) in rmi.rmi[1].iter().zip(layers[1].members()) {
+            assert_eq!(json_model["function"].as_str(), Some("linear"));
+            for (param, json_param) in model.params().iter().zip(json_model["params"].members()) {
+                assert_eq!(json_param["type"].as_str(), Some("double"));
+                assert_eq!(json_param["value"].as_f64(), Some(param.as_float()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_single_distinct_key() {
+        let data = ModelData::IntKeyToIntPos((0..1000).map(|i| (42, i)).collect());
+        for spec in &["linear,linear", "radix,linear", "histogram,linear", "bradix,linear",
+                      "cubic", "plr"] {
+            let rmi = train(&mut ModelDataWrapper::new(&data), spec, 16).unwrap();
+            assert_eq!(rmi.models, "dense_range");
+            assert_eq!(rmi.model_max_error, 999);
+
+            let model = &rmi.rmi[0][0];
+            assert_eq!(model.predict_to_int(42.into()), 0);
+            assert_eq!(model.predict_to_int(0.into()), 0);
+        }
+
+        let floats = ModelData::FloatKeyToIntPos((0..10).map(|i| (2.5, i)).collect());
+        let rmi = train(&mut ModelDataWrapper::new(&floats), "linear,linear", 4).unwrap();
+        assert_eq!(rmi.model_max_error, 9);
+
+        // distinct float keys that truncate to the same integer
+        let floats = ModelData::FloatKeyToIntPos(vec![(2.25, 0), (2.5, 1), (2.75, 2)]);
+        let rmi = train(&mut ModelDataWrapper::new(&floats), "linear,linear", 4).unwrap();
+        assert_eq!(rmi.rmi.len(), 2);
+    }
+
+    #[test]
+    fn test_train_compact_distinct() {
+        // 500 distinct keys, each repeated 10 to 130 times
+        let keys: Vec<u64> = (0..500u64)
+            .flat_map(|i| std::iter::repeat(i * i).take((10 + (i * 37) % 121) as usize))
+            .collect();
+        let data = ModelData::IntKeyToIntPos(keys.iter().enumerate()
+                                             .map(|(i, k)| (*k, i as u64)).collect());
+        let compact = data.compact_distinct();
+        assert_eq!(compact.len(), 500);
+
+        let mut container = ModelDataWrapper::new(&compact);
+        container.set_num_positions(keys.len());
+        let rmi = crate::runtime::RuntimeRMI::new(train(&mut container, "linear,linear", 16)
+                                                  .unwrap());
+
+        // every key of the full data is found at its first occurrence
+        for (idx, key) in keys.iter().enumerate() {
+            let first = keys.partition_point(|k| k < key);
+            assert_eq!(rmi.lookup(&keys, *key).0, first);
+            if idx == first {
+                let pred = rmi.predict((*key).into()) as usize;
+                let err = usize::max(pred, first) - usize::min(pred, first);
+                assert!(err as u64 <= rmi.trained().model_max_error);
+            }
+        }
+    }
 }
@@ -6,17 +6,22 @@
 // < end copyright > 
  
 use crate::models::*;
-use crate::train::{train_model, TrainedRMI};
+use crate::train::{train_model, constant_baseline, exact_fraction, log2_window, TrainedRMI,
+                   TrainingOptions, ErrorPercentiles, Diagnostics, WorseThanConstant};
 use log::*;
 
 pub fn train_multi_layer(data: &mut ModelDataWrapper,
                          model_list: &[String],
                          last_model: String,
-                         branch_factor: u64) -> TrainedRMI {
+                         branch_factor: u64,
+                         pretrained_top: Option<Box<dyn Model>>,
+                         options: &TrainingOptions) -> TrainedRMI {
     
+    let mut pretrained_top = pretrained_top;
     let mut rmi: Vec<Vec<Box<dyn Model>>> = Vec::new();
     let mut data_partitions = vec![data.clone().into_data()];
     let num_rows = data_partitions[0].len();
+    let num_positions = data.num_positions();
 
     let mut current_model_count = 1;
     for (_layer_idx, model_type) in model_list.iter().enumerate() {
@@ -32,8 +37,11 @@ pub fn train_multi_layer(data: &mut ModelDataWrapper,
             let mut md_container = ModelDataWrapper::new(&model_data);
 
             // not at the last layer -- rescale
-            md_container.set_scale(next_layer_size as f64 / num_rows as f64);
-            let model = train_model(model_type.as_str(), &md_container);
+            md_container.set_scale(next_layer_size as f64 / num_positions as f64);
+            let model = match pretrained_top.take() {
+                Some(top) => top,
+                None => train_model(model_type.as_str(), &md_container)
+            };
 
             // rescale back for next layer
             md_container.set_scale(1.0);
@@ -60,18 +68,27 @@ pub fn train_multi_layer(data: &mut ModelDataWrapper,
     info!("Training last level {} model", last_model);
     let mut last_layer = Vec::new();
     let mut last_layer_max_l1s: Vec<u64> = Vec::new();
+    let mut leaf_sizes: Vec<u64> = Vec::new();
     let mut model_avg_error: f64 = 0.0;
     let mut model_avg_l2_error: f64 = 0.0;
     let mut model_avg_log2_error: f64 = 0.0;
     let mut model_max_log2_error: f64 = 0.0;
     let mut model_max_error = 0;
     let mut model_max_error_idx = 0;
+    let mut all_errors: Vec<u64> = if options.error_percentiles {
+        Vec::with_capacity(num_rows)
+    } else {
+        Vec::new()
+    };
+    let mut worse_than_constant = Vec::new();
+    let mut num_exact: u64 = 0;
 
     let mut n = 1;
     for (midx, model_data) in data_partitions.into_iter().enumerate() {
         let md_container = ModelDataWrapper::new(&model_data);
         let last_model = train_model(last_model.as_str(), &md_container);
         let mut max_error = 0;
+        let (mut min_pos, mut max_pos) = (u64::MAX, 0);
         
         for (idx, (x, y)) in md_container.iter_int_int().enumerate() {
             let pred = last_model.predict_to_int(x.into());
@@ -84,15 +101,38 @@ pub fn train_multi_layer(data: &mut ModelDataWrapper,
                           bound, err, x, idx, pred, y);
                 }
             }
+            let err = if options.lookup_window_errors {
+                let pos = data.position_unit().lookup_position(pred, num_positions as u64);
+                u64::max(y, pos) - u64::min(y, pos)
+            } else {
+                err
+            };
+
+            if options.error_percentiles {
+                all_errors.push(err);
+            }
+            if err == 0 {
+                num_exact += 1;
+            }
 
             max_error = u64::max(max_error, err);
+            min_pos = u64::min(min_pos, y);
+            max_pos = u64::max(max_pos, y);
             model_avg_error += ((max_error as f64) - model_avg_error) / (n as f64);
             model_avg_l2_error += ((max_error as f64).powf(2.0) - model_avg_l2_error) / (n as f64);
-            let log2_error = ((2 * max_error + 2) as f64).log2();
+            let log2_error = log2_window(max_error);
             model_avg_log2_error += (log2_error - model_avg_log2_error) / (n as f64);
             model_max_log2_error = f64::max(model_max_log2_error, log2_error);
             n += 1;
         }
+
+        if options.diagnostics != Diagnostics::Off && min_pos <= max_pos {
+            let baseline = constant_baseline(min_pos, max_pos);
+            if max_error > baseline {
+                worse_than_constant.push(WorseThanConstant { index: midx, max_error, baseline });
+            }
+        }
+
         if max_error > model_max_error {
             model_max_error = max_error;
             model_max_error_idx = midx;
@@ -100,6 +140,7 @@ pub fn train_multi_layer(data: &mut ModelDataWrapper,
 
         last_layer.push(last_model);
         last_layer_max_l1s.push(max_error);
+        leaf_sizes.push(md_container.len() as u64);
     }
     rmi.push(last_layer);
 
@@ -112,7 +153,25 @@ pub fn train_multi_layer(data: &mut ModelDataWrapper,
         model_max_log2_error,
         last_layer_max_l1s,
         rmi,
-        models: format!("{},{}", model_list.join(","), last_model),
-        branching_factor: branch_factor
+        models: if model_list.is_empty() {
+            last_model
+        } else {
+            format!("{},{}", model_list.join(","), last_model)
+        },
+        branching_factor: branch_factor,
+        error_percentiles: if options.error_percentiles {
+            Some(ErrorPercentiles::from_errors(all_errors))
+        } else {
+            None
+        },
+        exact_fraction: exact_fraction(num_exact, num_rows),
+        worse_than_constant,
+        position_unit: data.position_unit(),
+        pruned: None,
+        leaf_sizes,
+        num_keys: num_rows,
+        leaf_map: None,
+        key_transform: None,
+        position_divisor: None
     };
 }
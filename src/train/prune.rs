@@ -0,0 +1,149 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+
+use crate::models::*;
+use crate::train::{exact_fraction, TrainedRMI};
+use log::*;
+
+// the leaves of a two layer RMI that were replaced by the top model's own
+// prediction, because they were no more accurate than it over their keys
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrunedLeaves {
+    pub leaves: Vec<bool>,
+
+    // positions per unit of top model output (the top model is trained to
+    // predict a leaf index, not a position)
+    pub scale: f64,
+
+    // predictions are clamped to the last position, as in generated code
+    pub num_positions: u64
+}
+
+impl PrunedLeaves {
+    pub fn count(&self) -> usize {
+        return self.leaves.iter().filter(|p| **p).count();
+    }
+
+    pub fn is_pruned(&self, leaf: usize) -> bool {
+        return self.leaves.get(leaf).copied().unwrap_or(false);
+    }
+
+    // the position the top model predicts for the key, at most the last one
+    pub fn predict(&self, top: &dyn Model, key: ModelInput) -> u64 {
+        let pred = f64::max(0.0, (top.predict_to_float(key) * self.scale).floor()) as u64;
+        return u64::min(pred, self.num_positions.saturating_sub(1));
+    }
+}
+
+// replaces each leaf whose max error is at least that of the top model's
+// prediction (over the same keys) with that prediction, and lowers the
+// leaf's stored error to the top model's. Only two layer RMIs whose models
+// both output floats are pruned.
+pub fn prune_leaves(rmi: &mut TrainedRMI, data: &ModelDataWrapper) {
+    if rmi.rmi.len() != 2
+        || !matches!(rmi.rmi[0][0].output_type(), ModelDataType::Float)
+        || !matches!(rmi.rmi[1][0].output_type(), ModelDataType::Float) {
+        info!("Leaf pruning only applies to two layer RMIs with float outputs, skipping");
+        return;
+    }
+
+    let num_leaves = rmi.rmi[1].len();
+    let mut pruned = PrunedLeaves {
+        leaves: vec![false; num_leaves],
+        scale: data.num_positions() as f64 / num_leaves as f64,
+        num_positions: data.num_positions() as u64
+    };
+
+    let top = &rmi.rmi[0][0];
+    let mut leaf_errors = vec![0; num_leaves];
+    let mut top_errors = vec![0; num_leaves];
+    let mut leaf_exact = vec![0; num_leaves];
+    let mut top_exact = vec![0; num_leaves];
+    for (x, y) in data.iter_int_int() {
+        let leaf = u64::min(num_leaves as u64 - 1, top.predict_to_int(x.into())) as usize;
+
+        let leaf_pred = rmi.rmi[1][leaf].predict_to_int(x.into());
+        let top_pred = pruned.predict(top.as_ref(), x.into());
+        leaf_errors[leaf] = u64::max(leaf_errors[leaf],
+                                     u64::max(leaf_pred, y) - u64::min(leaf_pred, y));
+        top_errors[leaf] = u64::max(top_errors[leaf],
+                                    u64::max(top_pred, y) - u64::min(top_pred, y));
+        leaf_exact[leaf] += (leaf_pred == y) as u64;
+        top_exact[leaf] += (top_pred == y) as u64;
+    }
+
+    let mut num_exact = 0;
+    for leaf in 0..num_leaves {
+        if top_errors[leaf] <= leaf_errors[leaf] {
+            pruned.leaves[leaf] = true;
+            rmi.last_layer_max_l1s[leaf] = top_errors[leaf];
+            num_exact += top_exact[leaf];
+        } else {
+            num_exact += leaf_exact[leaf];
+        }
+    }
+    rmi.exact_fraction = exact_fraction(num_exact, data.len());
+
+    let (max_idx, max_error) = rmi.last_layer_max_l1s.iter().enumerate()
+        .max_by_key(|(_, err)| **err)
+        .map(|(idx, err)| (idx, *err))
+        .unwrap();
+    rmi.model_max_error = max_error;
+    rmi.model_max_error_idx = max_idx;
+
+    info!("Pruned {} of {} leaf models", pruned.count(), num_leaves);
+    rmi.pruned = Some(pruned);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train::{train, train_with_options, TrainingOptions};
+
+    #[test]
+    fn test_prune_leaves() {
+        let opts = TrainingOptions { prune_leaves: true, ..Default::default() };
+
+        // the top model alone is (nearly) exact on evenly spaced keys
+        let md = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * 3, i)).collect());
+        let rmi = train_with_options(&mut ModelDataWrapper::new(&md),
+                                     "linear,linear", 16, &opts).unwrap();
+        assert!(rmi.num_pruned() > 8);
+        assert!(rmi.model_max_error <= 1);
+
+        // the top model predicts the last position for keys past the last
+        // one, not a position past it
+        let pruned = rmi.pruned.as_ref().unwrap();
+        assert_eq!(pruned.predict(rmi.rmi[0][0].as_ref(), 1_000_000.into()), 999);
+
+        // on quadratic keys, the leaves are needed
+        let md = ModelData::IntKeyToIntPos((0..1000).map(|i| (i * i, i)).collect());
+        let container = ModelDataWrapper::new(&md);
+        let plain = train(&mut ModelDataWrapper::new(&md), "linear,linear", 16).unwrap();
+        let rmi = train_with_options(&mut ModelDataWrapper::new(&md),
+                                     "linear,linear", 16, &opts).unwrap();
+        assert!(rmi.num_pruned() < 16);
+        assert!(rmi.model_max_error <= plain.model_max_error);
+        assert_eq!(plain.num_pruned(), 0);
+
+        let pruned = rmi.pruned.as_ref().unwrap();
+        for (x, y) in container.iter_int_int() {
+            let leaf = u64::min(15, rmi.rmi[0][0].predict_to_int(x.into())) as usize;
+            let pred = if pruned.is_pruned(leaf) {
+                pruned.predict(rmi.rmi[0][0].as_ref(), x.into())
+            } else {
+                rmi.rmi[1][leaf].predict_to_int(x.into())
+            };
+            assert!(u64::max(pred, y) - u64::min(pred, y) <= rmi.last_layer_max_l1s[leaf]);
+        }
+
+        // more than two layers are left alone
+        let rmi = train_with_options(&mut ModelDataWrapper::new(&md),
+                                     "linear,linear,linear", 16, &opts).unwrap();
+        assert!(rmi.pruned.is_none());
+    }
+}
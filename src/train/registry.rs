@@ -0,0 +1,229 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+use std::sync::RwLock;
+#[cfg(test)]
+use std::sync::{Mutex, MutexGuard};
+
+// builds a model from its training data and the number at the end of the
+// model type, if any (e.g. 16 for "mymodel16")
+pub type ModelConstructor = fn(&ModelDataWrapper, Option<u64>) -> Box<dyn Model>;
+
+struct RegisteredModel {
+    name: String,
+    constructor: ModelConstructor,
+    restriction: ModelRestriction
+}
+
+// the registered models. The process-wide one is REGISTRY; tests use their
+// own, so their models are not seen by other tests (such as the optimizer's,
+// which try every registered model).
+struct Registry {
+    models: Vec<RegisteredModel>
+}
+
+impl Registry {
+    const fn new() -> Registry {
+        return Registry { models: Vec::new() };
+    }
+
+    fn register(&mut self, name: &str, constructor: ModelConstructor,
+                restriction: ModelRestriction) {
+        assert!(!name.is_empty() && !name.ends_with(|c: char| c.is_ascii_digit()),
+                "registered model names must not end with a digit");
+
+        self.models.retain(|m| m.name != name);
+        self.models.push(RegisteredModel { name: String::from(name), constructor, restriction });
+    }
+
+    // the constructor for a model type, and the type's numeric suffix
+    fn find(&self, model_type: &str) -> Option<(ModelConstructor, Option<u64>)> {
+        let exact = self.models.iter().find(|m| m.name == model_type);
+        if let Some(m) = exact {
+            return Some((m.constructor, None));
+        }
+
+        let (name, suffix) = split_suffix(model_type);
+        return self.models.iter()
+            .find(|m| m.name == name)
+            .map(|m| (m.constructor, suffix));
+    }
+
+    fn names(&self, restriction: ModelRestriction) -> Vec<String> {
+        return self.models.iter()
+            .filter(|m| m.restriction == restriction)
+            .map(|m| m.name.clone())
+            .collect();
+    }
+}
+
+static REGISTRY: RwLock<Registry> = RwLock::new(Registry::new());
+
+// makes a model type available to training (and to the optimizer) by name,
+// in addition to the built-in types. A model type that names a built-in
+// model still refers to the built-in one. Registering a name again
+// replaces the earlier registration.
+pub fn register_model(name: &str, constructor: ModelConstructor,
+                      restriction: ModelRestriction) {
+    REGISTRY.write().unwrap().register(name, constructor, restriction);
+}
+
+// splits a model type into its name and numeric suffix ("foo16" -> "foo", 16)
+fn split_suffix(model_type: &str) -> (&str, Option<u64>) {
+    let name = model_type.trim_end_matches(|c: char| c.is_ascii_digit());
+    return (name, model_type[name.len()..].parse().ok());
+}
+
+pub fn train_registered(model_type: &str, data: &ModelDataWrapper) -> Option<Box<dyn Model>> {
+    // the lock is released before training
+    let found = REGISTRY.read().unwrap().find(model_type);
+    return found.map(|(constructor, suffix)| constructor(data, suffix));
+}
+
+// maps a model type to the constructor that should train it instead, or
+// None to resolve the type as usual
+#[cfg(test)]
+type Resolver = Box<dyn Fn(&str) -> Option<ModelConstructor> + Send + Sync>;
+
+#[cfg(test)]
+static OVERRIDE: RwLock<Option<Resolver>> = RwLock::new(None);
+
+// held by the test whose override is active, so that tests overriding
+// models run one at a time
+#[cfg(test)]
+static OVERRIDE_LOCK: Mutex<()> = Mutex::new(());
+
+// restores the built-in resolution of model types when dropped
+#[cfg(test)]
+pub struct ModelOverride {
+    _lock: MutexGuard<'static, ()>
+}
+
+#[cfg(test)]
+impl Drop for ModelOverride {
+    fn drop(&mut self) {
+        *OVERRIDE.write().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+// for tests of experimental models: until the returned guard is dropped,
+// every model type the resolver maps to a constructor is trained with it
+// (given the type's numeric suffix, as with register_model), ahead of the
+// built-in and registered types. This covers the whole pipeline, including
+// the restriction checks, so an override of a built-in name changes every
+// RMI trained with that name.
+//
+// The optimizer's layer lists are not changed: overriding a built-in type it
+// tries (e.g. "linear") changes what it measures under that name, but a new
+// name is only tried if it is also registered with register_model.
+//
+// The override is process-wide, since training runs on rayon's threads.
+// Overriding tests wait for each other, but other tests running at the same
+// time see the override too, so only override types no other test trains.
+#[cfg(test)]
+pub fn override_models<F>(resolver: F) -> ModelOverride
+where F: Fn(&str) -> Option<ModelConstructor> + Send + Sync + 'static {
+    let lock = OVERRIDE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    *OVERRIDE.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(resolver));
+    return ModelOverride { _lock: lock };
+}
+
+#[cfg(test)]
+pub fn train_overridden(model_type: &str, data: &ModelDataWrapper) -> Option<Box<dyn Model>> {
+    let current = OVERRIDE.read().unwrap_or_else(|e| e.into_inner());
+    let constructor = (current.as_ref()?)(model_type)?;
+    return Some(constructor(data, split_suffix(model_type).1));
+}
+
+// the names of all registered models with the given restriction
+pub fn registered_names(restriction: ModelRestriction) -> Vec<String> {
+    return REGISTRY.read().unwrap().names(restriction);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_suffix() {
+        assert_eq!(split_suffix("radix18"), ("radix", Some(18)));
+        assert_eq!(split_suffix("linear"), ("linear", None));
+    }
+
+    #[test]
+    fn test_register() {
+        fn constant(_data: &ModelDataWrapper, suffix: Option<u64>) -> Box<dyn Model> {
+            let mut model = LinearModel::new(&ModelDataWrapper::new(&ModelData::empty()));
+            model.set_to_constant_model(suffix.unwrap_or(0));
+            return Box::new(model);
+        }
+
+        // a registry of its own, so the optimizer tests do not try it
+        let mut registry = Registry::new();
+        registry.register("registry_test_constant", constant, ModelRestriction::MustBeBottom);
+
+        let data = ModelData::empty();
+        let md = ModelDataWrapper::new(&data);
+        let train = |model_type: &str| registry.find(model_type)
+            .map(|(constructor, suffix)| constructor(&md, suffix));
+        let model = train("registry_test_constant7").unwrap();
+        assert_eq!(model.predict_to_int(100.into()), 7);
+        assert_eq!(train("registry_test_constant").unwrap().predict_to_int(100.into()), 0);
+
+        assert!(train("registry_test_missing").is_none());
+        assert_eq!(registry.names(ModelRestriction::MustBeBottom),
+                   vec![String::from("registry_test_constant")]);
+        assert!(registry.names(ModelRestriction::None).is_empty());
+        assert!(!registered_names(ModelRestriction::MustBeBottom)
+                .contains(&String::from("registry_test_constant")));
+    }
+
+    #[test]
+    fn test_override_models() {
+        fn cubic(data: &ModelDataWrapper, _suffix: Option<u64>) -> Box<dyn Model> {
+            return Box::new(CubicSplineModel::new(data));
+        }
+        fn centered(data: &ModelDataWrapper, _suffix: Option<u64>) -> Box<dyn Model> {
+            return Box::new(LinearModel::with_fit(data, LinearFit::Centered));
+        }
+
+        let md = ModelData::IntKeyToIntPos((0..2000).map(|i| (i * i, i)).collect());
+        let keys: Vec<u64> = md.iter_int_int().map(|(k, _)| k).collect();
+        {
+            // a built-in name (that no other test uses) and a new one
+            let _guard = override_models(|model_type| match model_type {
+                "radix26" => Some(cubic as ModelConstructor),
+                "override_test_centered" => Some(centered as ModelConstructor),
+                _ => None
+            });
+
+            let trained = crate::train::train(&mut ModelDataWrapper::new(&md),
+                                              "radix26,override_test_centered", 32).unwrap();
+            assert_eq!(trained.rmi[0][0].function_name(), "cubic");
+            assert_eq!(trained.rmi[1].len(), 32);
+
+            // the error bounds hold, and the generated code agrees
+            let rmi = crate::runtime::RuntimeRMI::new(trained);
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(rmi.lookup(&keys, *key).0, i);
+            }
+            if cfg!(feature = "codegen-roundtrip") {
+                let result = crate::codegen::verify_compiled(rmi.into_trained(), md.len(), &keys,
+                                                             "c++", &std::env::temp_dir());
+                assert!(result.is_ok(), "{}", result.unwrap_err());
+            }
+        }
+
+        let restored = crate::train::train_single_layer(&mut ModelDataWrapper::new(&md),
+                                                        "radix26").unwrap();
+        assert_ne!(restored.rmi[0][0].function_name(), "cubic");
+        assert!(train_overridden("override_test_centered",
+                                 &ModelDataWrapper::new(&md)).is_none());
+    }
+}
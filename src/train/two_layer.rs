@@ -6,7 +6,8 @@
 // < end copyright > 
  
 use crate::models::*;
-use crate::train::{validate, train_model, TrainedRMI};
+use crate::train::{validate, train_model, constant_baseline, exact_fraction, log2_window,
+                   TrainedRMI, TrainingOptions, ErrorPercentiles, Diagnostics, WorseThanConstant};
 use crate::train::lower_bound_correction::LowerBoundCorrection;
 use log::*;
 use superslice::*;
@@ -96,17 +97,35 @@ fn build_models_from(data: &ModelDataWrapper,
 
 pub fn train_two_layer(md_container: &mut ModelDataWrapper,
                        layer1_model: &str, layer2_model: &str,
-                       num_leaf_models: u64) -> TrainedRMI {
+                       num_leaf_models: u64,
+                       pretrained_top: Option<Box<dyn Model>>,
+                       options: &TrainingOptions) -> TrainedRMI {
     validate(&[String::from(layer1_model), String::from(layer2_model)]);
 
     let num_rows = md_container.len();
+    let num_positions = md_container.num_positions() as u64;
+    let position_unit = md_container.position_unit();
+    let lookup_position = |pred: u64| if options.lookup_window_errors {
+        position_unit.lookup_position(pred, num_positions)
+    } else {
+        pred
+    };
 
-    info!("Training top-level {} model layer", layer1_model);
-    md_container.set_scale(num_leaf_models as f64 / num_rows as f64);
-    let top_model = train_model(layer1_model, &md_container);
+    let top_model = match pretrained_top {
+        Some(model) => {
+            info!("Using pre-trained top-level {} model layer", layer1_model);
+            model
+        },
+        None => {
+            info!("Training top-level {} model layer", layer1_model);
+            md_container.set_scale(num_leaf_models as f64 / md_container.num_positions() as f64);
+            let model = train_model(layer1_model, &md_container);
+            md_container.set_scale(1.0);
+            model
+        }
+    };
 
     info!("Training second-level {} model layer (num models = {})", layer2_model, num_leaf_models);
-    md_container.set_scale(1.0);
 
     // find a prediction boundary near the middle
     let midpoint_model = num_leaf_models / 2;
@@ -178,17 +197,52 @@ pub fn train_two_layer(md_container: &mut ModelDataWrapper,
     info!("Computing last level errors...");
     // evaluate model, compute last level errors
     let mut last_layer_max_l1s = vec![(0, 0) ; num_leaf_models as usize];
+    let mut all_errors: Vec<u64> = if options.error_percentiles {
+        Vec::with_capacity(num_rows)
+    } else {
+        Vec::new()
+    };
+    let diagnostics = options.diagnostics != Diagnostics::Off;
+    let mut leaf_pos_ranges: Vec<(u64, u64)> = if diagnostics {
+        vec![(u64::MAX, 0) ; num_leaf_models as usize]
+    } else {
+        Vec::new()
+    };
+    let mut num_exact: u64 = 0;
     for &(x, y) in md_container.as_int_int() {
         let leaf_idx = top_model.predict_to_int(x.into());
         let target = u64::min(num_leaf_models - 1, leaf_idx) as usize;
         
-        let pred = leaf_models[target].predict_to_int(x.into());
+        let pred = lookup_position(leaf_models[target].predict_to_int(x.into()));
         let err = error_between(pred, y);
+        if options.error_percentiles {
+            all_errors.push(err);
+        }
+        if err == 0 {
+            num_exact += 1;
+        }
+
+        if diagnostics {
+            let (lo, hi) = leaf_pos_ranges[target];
+            leaf_pos_ranges[target] = (u64::min(lo, y), u64::max(hi, y));
+        }
 
         let cur_val = last_layer_max_l1s[target];
         last_layer_max_l1s[target] = (cur_val.0 + 1, u64::max(err, cur_val.1));
     }    
 
+    // compare against the constant baseline before the lower bound
+    // corrections below, which a constant model would need as well
+    let worse_than_constant: Vec<WorseThanConstant> = leaf_pos_ranges.iter()
+        .zip(last_layer_max_l1s.iter())
+        .enumerate()
+        .filter(|(_, (_, (n, _)))| *n > 0)
+        .map(|(index, ((lo, hi), (_, max_error)))| WorseThanConstant {
+            index, max_error: *max_error, baseline: constant_baseline(*lo, *hi)
+        })
+        .filter(|w| w.max_error > w.baseline)
+        .collect();
+
     // for lower bound searches, we need to make sure that:
     //   (1) a query for the first key in the next leaf minus one 
     //       includes the key in the next leaf. (upper error)
@@ -201,7 +255,9 @@ pub fn train_two_layer(md_container: &mut ModelDataWrapper,
         let curr_err = last_layer_max_l1s[leaf_idx].1;
         let upper_error = {
             let (idx_of_next, key_of_next) = lb_corrections.next(leaf_idx);
-            let pred = leaf_models[leaf_idx].predict_to_int((key_of_next - 1).into());
+            let pred = lookup_position(
+                leaf_models[leaf_idx].predict_to_int((key_of_next - 1).into())
+            );
             error_between(pred, idx_of_next + 1)
         };
         
@@ -211,7 +267,9 @@ pub fn train_two_layer(md_container: &mut ModelDataWrapper,
             let prev_idx = if leaf_idx == 0 { 0 } else { leaf_idx - 1 };
             let first_idx = lb_corrections.next_index(prev_idx);
 
-            let pred = leaf_models[leaf_idx].predict_to_int((first_key_before + 1).into());
+            let pred = lookup_position(
+                leaf_models[leaf_idx].predict_to_int((first_key_before + 1).into())
+            );
             error_between(pred, first_idx)
         };
           
@@ -248,10 +306,11 @@ pub fn train_two_layer(md_container: &mut ModelDataWrapper,
         .map(|(n, err)| ((n*err) as f64).powf(2.0) / num_rows as f64).sum::<f64>();
     
     let model_avg_log2_error: f64 = last_layer_max_l1s
-        .iter().map(|(n, err)| (*n as f64)*((2*err + 2) as f64).log2()).sum::<f64>() / num_rows as f64;
+        .iter().map(|(n, err)| (*n as f64) * log2_window(*err)).sum::<f64>() / num_rows as f64;
 
-    let model_max_log2_error: f64 = (model_max_error as f64).log2();
+    let model_max_log2_error: f64 = log2_window(model_max_error);
     
+    let leaf_sizes = last_layer_max_l1s.iter().map(|(n, _err)| *n).collect();
     let final_errors = last_layer_max_l1s.into_iter()
         .map(|(_n, err)| err).collect();
     
@@ -265,7 +324,21 @@ pub fn train_two_layer(md_container: &mut ModelDataWrapper,
         last_layer_max_l1s: final_errors,
         rmi: vec![vec![top_model], leaf_models],
         models: format!("{},{}", layer1_model, layer2_model),
-        branching_factor: num_leaf_models
+        branching_factor: num_leaf_models,
+        error_percentiles: if options.error_percentiles {
+            Some(ErrorPercentiles::from_errors(all_errors))
+        } else {
+            None
+        },
+        exact_fraction: exact_fraction(num_exact, num_rows),
+        worse_than_constant,
+        position_unit: md_container.position_unit(),
+        pruned: None,
+        leaf_sizes,
+        num_keys: num_rows,
+        leaf_map: None,
+        key_transform: None,
+        position_divisor: None
     };
 
 }
@@ -0,0 +1,133 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+
+// Builds RMIs with the rmi binary, compiles the generated code into a
+// shared library, loads it with libloading, and checks that every key of
+// the data is within the error the compiled lookup reports of the position
+// it returns (each leaf's error with --data-path, which -e requires, and the
+// RMI's largest error otherwise). This needs a C++ compiler (c++), so it only runs with the
+// codegen-roundtrip feature:
+//
+//     cargo test --features codegen-roundtrip
+#![cfg(feature = "codegen-roundtrip")]
+#![allow(clippy::needless_return)]
+
+use std::path::Path;
+use std::process::Command;
+
+type LoadFn = unsafe extern "C" fn(*const std::os::raw::c_char) -> bool;
+type LookupFn = unsafe extern "C" fn(u64, *mut usize) -> u64;
+type CleanupFn = unsafe extern "C" fn();
+
+// sorted keys with gaps of different sizes and a few runs of duplicates
+fn test_keys() -> Vec<u64> {
+    let mut keys: Vec<u64> = (0..20_000u64)
+        .map(|i| i * i * 31 + (i % 7) * 1000)
+        .chain((0..20_000u64).step_by(500).map(|i| i * i * 31))
+        .collect();
+    keys.sort_unstable();
+    return keys;
+}
+
+// the keys in the binary format the rmi binary reads: the number of keys,
+// then the keys, as little endian 64 bit integers
+fn write_keys(path: &Path, keys: &[u64]) {
+    let mut bytes = (keys.len() as u64).to_le_bytes().to_vec();
+    for key in keys {
+        bytes.extend_from_slice(&key.to_le_bytes());
+    }
+    std::fs::write(path, bytes).unwrap();
+}
+
+// the generated functions are in the RMI's namespace, so a shim exports
+// them with C names the library can be searched for
+fn shim(namespace: &str, disk: bool) -> String {
+    let (load, lookup) = if disk {
+        (format!("return {}::load(dir);", namespace),
+         format!("return {}::lookup(key, err);", namespace))
+    } else {
+        (String::from("(void) dir; return true;"),
+         format!("*err = {ns}::rmi_info().max_error; return {ns}::lookup(key);", ns = namespace))
+    };
+    return format!("#include \"{ns}.h\"
+extern \"C\" bool roundtrip_load(char const* dir) {{ {load} }}
+extern \"C\" uint64_t roundtrip_lookup(uint64_t key, size_t* err) {{ {lookup} }}
+extern \"C\" void roundtrip_cleanup() {{ {ns}::cleanup(); }}
+", ns = namespace, load = load, lookup = lookup);
+}
+
+fn check(namespace: &str, models: &str, branching_factor: u64, extra_args: &[&str]) {
+    let dir = std::env::temp_dir().join(format!("rmi_codegen_roundtrip_{}", namespace));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("params")).unwrap();
+
+    let keys = test_keys();
+    write_keys(&dir.join("keys_uint64"), &keys);
+    let disk = extra_args.contains(&"--data-path");
+    let status = Command::new(env!("CARGO_BIN_EXE_rmi"))
+        .current_dir(&dir)
+        .args(["keys_uint64", namespace, models, &branching_factor.to_string()])
+        .args(if disk { &["-e"][..] } else { &[] })
+        .args(extra_args)
+        .status()
+        .unwrap();
+    assert!(status.success(), "{}: rmi failed", namespace);
+
+    std::fs::write(dir.join("shim.cpp"), shim(namespace, disk)).unwrap();
+    let library = dir.join(format!("lib{}.so", namespace));
+    let compiled = Command::new("c++")
+        .current_dir(&dir)
+        .args(["-std=c++17", "-O2", "-shared", "-fPIC", "-o"])
+        .arg(&library)
+        .arg(format!("{}.cpp", namespace))
+        .arg("shim.cpp")
+        .output()
+        .expect("the codegen-roundtrip tests need a C++ compiler (c++)");
+    assert!(compiled.status.success(), "{}: {}", namespace,
+            String::from_utf8_lossy(&compiled.stderr));
+
+    unsafe {
+        let lib = libloading::Library::new(&library).unwrap();
+        let load: libloading::Symbol<LoadFn> = lib.get(b"roundtrip_load").unwrap();
+        let lookup: libloading::Symbol<LookupFn> = lib.get(b"roundtrip_lookup").unwrap();
+        let cleanup: libloading::Symbol<CleanupFn> = lib.get(b"roundtrip_cleanup").unwrap();
+
+        let params = std::ffi::CString::new(dir.join("params").to_str().unwrap()).unwrap();
+        assert!(load(params.as_ptr()), "{}: could not load the parameters", namespace);
+
+        // the position of a key is its first index, as the rmi binary stores it
+        for (idx, key) in keys.iter().enumerate() {
+            if idx > 0 && keys[idx - 1] == *key {
+                continue;
+            }
+            let mut err: usize = 0;
+            let pos = lookup(*key, &mut err);
+            let diff = u64::max(pos, idx as u64) - u64::min(pos, idx as u64);
+            assert!(diff <= err as u64, "{}: key {} at {} looked up at {} +- {}",
+                    namespace, key, idx, pos, err);
+        }
+        cleanup();
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_layer_types() {
+    check("rt_linear", "linear,linear", 1024, &[]);
+    check("rt_cubic", "cubic,linear", 256, &[]);
+    check("rt_radix", "radix,linear", 4096, &[]);
+    check("rt_spline", "robust_linear,linear_spline", 512, &[]);
+    check("rt_three", "linear,linear,linear", 64, &[]);
+    check("rt_isotonic", "linear,isotonic16", 256, &[]);
+}
+
+#[test]
+fn test_data_path() {
+    check("rt_disk", "linear,linear", 1024, &["--data-path", "params"]);
+    check("rt_disk_cubic", "cubic,cubic", 4096, &["--data-path", "params"]);
+}